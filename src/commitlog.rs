@@ -1,27 +1,146 @@
-use std::{fs::{remove_file, File}, io::Write};
+use std::{fmt, fs::{self, remove_file, File}, io::Write, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
 
 use crate::utils;
 
+pub mod group_commit;
+
+/*
+------------------------------------------------------------------------
+| magic(4) | version(4) | frame | frame | ...
+------------------------------------------------------------------------
+frame:
+| payload_len(4) | crc32c(payload)(4) | payload |
+payload:
+| entry | sequence(8) | timestamp(8) |
+または、WriteBatchをまとめた
+| 3(1) | entry_count(8) | entry | entry | ... | sequence(8) | timestamp(8) |
+
+entry:
+| cmd(1) | key_len(8) | key | value_len(8) | value |
+cmdはレコードの種類で、PUT(1)は値を持ち、DELETE(2)はkey_lenとkeyだけを持つ
+値が"\0"でもcmdで区別するので、削除にはならない
+sequenceはバージョンの順序を決める単調増加の番号で、timestampは書き込んだ時刻(参考情報)
+version 1のログはtimestamp(8)だけを持ち、それをsequenceとして扱う
+*/
+pub const COMMITLOG_MAGIC: [u8; 4] = *b"LSMC";
+pub const COMMITLOG_VERSION: u32 = 2;
+const COMMITLOG_VERSION_WITHOUT_SEQUENCE: u32 = 1;
+const HEADER_SIZE: usize = 8;
+const FRAME_HEADER_SIZE: usize = 8;
+const BATCH_MARKER: u8 = 3;
+
+// コミットログの末尾が壊れていた場合の復旧方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    // 壊れたレコードが見つかった時点でエラーを返す
+    Fail,
+    // 最後の正常なレコードまでを復元し、それ以降(後続のログも含む)は捨てる
+    #[default]
+    TruncateTail,
+    // 壊れたレコードを読み飛ばして、残りのログの復元を続ける
+    SkipCorrupted,
+}
+
+// コミットログをいつfsyncするか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    // 書き込みのたびにfsyncする
+    Always,
+    // 前回のfsyncから指定時間が経過したらfsyncする
+    // 書き込みが途切れても、start_interval_syncerのスレッドが残りをfsyncする
+    Interval(Duration),
+    // 前回のfsyncから指定バイト数以上書き込んだらfsyncする
+    Bytes(usize),
+    // OSに任せる
+    #[default]
+    Never,
+}
+
+#[derive(Debug)]
+pub struct CommitLogRecord {
+    // 1つのフレームに含まれるエントリ. WriteBatchなら複数になる
+    pub entries: Vec<CommitLogEntry>,
+    pub sequence: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug)]
+pub struct ReplayedLog {
+    pub records: Vec<CommitLogRecord>,
+    pub corrupted: bool,
+}
+
 #[derive(Debug)]
 pub struct CommitLog {
     dir: String,
     file_name: String,
     file: File,
+    sync_mode: SyncMode,
+    // try_cloneしたものとは同じファイルを指すので、fsyncの記録も共有する
+    sync_state: Arc<Mutex<SyncState>>,
+}
+
+#[derive(Debug)]
+struct SyncState {
+    last_synced: Instant,
+    unsynced_bytes: usize,
+}
+
+impl SyncState {
+    fn new() -> Arc<Mutex<SyncState>> {
+        Arc::new(Mutex::new(SyncState {
+            last_synced: Instant::now(),
+            unsynced_bytes: 0,
+        }))
+    }
 }
 
 impl CommitLog {
     pub fn new(dir: &str) -> Result<CommitLog, String> {
+        Self::with_sync_mode(dir, SyncMode::default())
+    }
+
+    pub fn with_sync_mode(dir: &str, sync_mode: SyncMode) -> Result<CommitLog, String> {
         let now = utils::get_timestamp();
         let file_name = format!("commit_{}.log", now);
         let filepath = format!("{}/{}", dir, &file_name);
-        let file = File::create(&filepath).map_err(|e| e.to_string())?;
+        let mut file = File::create(&filepath).map_err(|e| e.to_string())?;
+        file.write_all(&Self::encode_header()).map_err(|e| e.to_string())?;
+        // 作成したログ自体がディレクトリから消えないようにする
+        file.sync_all().map_err(|e| e.to_string())?;
+        utils::sync_dir(dir)?;
         Ok(CommitLog {
             dir: dir.to_string(),
             file_name,
             file,
+            sync_mode,
+            sync_state: SyncState::new(),
         })
     }
 
+    // 同じディレクトリ、同じSyncModeで新しいログを作る
+    pub fn rotate(&mut self) -> Result<CommitLog, String> {
+        if self.sync_mode != SyncMode::Never {
+            self.sync()?;
+        }
+        Self::with_sync_mode(&self.dir, self.sync_mode)
+    }
+
+    fn encode_header() -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.extend_from_slice(&COMMITLOG_MAGIC);
+        buf.extend_from_slice(&COMMITLOG_VERSION.to_ne_bytes());
+        buf
+    }
+
+    pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(&utils::crc32c(payload).to_ne_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
     pub fn try_clone(&self) -> Result<Self, String> {
         let file = self.file.try_clone();
         match file {
@@ -29,6 +148,8 @@ impl CommitLog {
                 dir: self.dir.clone(),
                 file_name: self.file_name.clone(),
                 file: f,
+                sync_mode: self.sync_mode,
+                sync_state: self.sync_state.clone(),
             }),
             Err(e) => Err(e.to_string()),
         }
@@ -37,24 +158,125 @@ impl CommitLog {
     // bufを入れてもいい
     // ページサイズを超えるようであれば、パディングを入れてもいい
     // 全てパフォーマンスを計測してから決める
-    fn append(&mut self, entry: &CommitLogEntry, timestamp: u64) {
-        let mut buf = entry.encode();
-        buf.extend_from_slice(&timestamp.to_ne_bytes());
-        self.file.write_all(&buf).map_err(|e| e.to_string()).unwrap();
+    fn append(&mut self, entry: &CommitLogEntry, sequence: u64) -> Result<(), String> {
+        let buf = Self::encode_record(entry, sequence, utils::get_timestamp());
+        self.write_frames(&buf, false)
+    }
+
+    // フレーム化したレコード
+    pub fn encode_record(entry: &CommitLogEntry, sequence: u64, timestamp: u64) -> Vec<u8> {
+        let mut payload = entry.encode();
+        payload.extend_from_slice(&sequence.to_ne_bytes());
+        payload.extend_from_slice(&timestamp.to_ne_bytes());
+        Self::encode_frame(&payload)
+    }
+
+    // 複数のエントリを1つのフレームにまとめる
+    // リプレイ時はフレーム単位でCRCを確認するので、全て適用されるか全て捨てられるかのどちらかになる
+    // バッチ内のエントリは全て同じsequenceになる
+    pub fn encode_batch(entries: &[CommitLogEntry], sequence: u64, timestamp: u64) -> Vec<u8> {
+        let mut payload = vec![BATCH_MARKER];
+        payload.extend_from_slice(&entries.len().to_ne_bytes());
+        for entry in entries {
+            payload.extend_from_slice(&entry.encode());
+        }
+        payload.extend_from_slice(&sequence.to_ne_bytes());
+        payload.extend_from_slice(&timestamp.to_ne_bytes());
+        Self::encode_frame(&payload)
+    }
+
+    // エンコード済みのフレームをまとめて書き込む
+    // syncがtrueならSyncModeに関わらずfsyncする
+    pub fn write_frames(&mut self, buf: &[u8], sync: bool) -> Result<(), String> {
+        self.file.write_all(buf).map_err(|e| e.to_string())?;
+        let mut sync_state = self.sync_state.lock().map_err(|e| e.to_string())?;
+        sync_state.unsynced_bytes += buf.len();
+        if sync || self.should_sync(&sync_state) {
+            self.sync_locked(&mut sync_state)?;
+        }
+        Ok(())
+    }
+
+    fn should_sync(&self, sync_state: &SyncState) -> bool {
+        match self.sync_mode {
+            SyncMode::Always => true,
+            SyncMode::Interval(interval) => sync_state.last_synced.elapsed() >= interval,
+            SyncMode::Bytes(bytes) => sync_state.unsynced_bytes >= bytes,
+            SyncMode::Never => false,
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<(), String> {
+        let mut sync_state = self.sync_state.lock().map_err(|e| e.to_string())?;
+        self.sync_locked(&mut sync_state)
     }
 
-    pub fn write_put(&mut self, key: &str, value: &str, timestamp: u64) {
+    fn sync_locked(&self, sync_state: &mut SyncState) -> Result<(), String> {
+        if sync_state.unsynced_bytes == 0 {
+            return Ok(());
+        }
+        self.file.sync_data().map_err(|e| e.to_string())?;
+        sync_state.unsynced_bytes = 0;
+        sync_state.last_synced = Instant::now();
+        Ok(())
+    }
+
+    // 前回のfsyncからintervalが経過していて、fsyncしていない書き込みがあればfsyncする
+    // 次に確認するまでの時間を返す
+    pub fn sync_if_elapsed(&mut self, interval: Duration) -> Result<Duration, String> {
+        let mut sync_state = self.sync_state.lock().map_err(|e| e.to_string())?;
+        let elapsed = sync_state.last_synced.elapsed();
+        if elapsed < interval {
+            return Ok(interval - elapsed);
+        }
+        self.sync_locked(&mut sync_state)?;
+        Ok(interval)
+    }
+
+    // SyncMode::Intervalのとき、書き込みがなくても時間が来たらfsyncするスレッドを起動する
+    // commitlogが捨てられたらスレッドも終わる
+    pub fn start_interval_syncer(commitlog: &Arc<Mutex<CommitLog>>) -> Result<Option<thread::JoinHandle<()>>, String> {
+        let interval = match commitlog.lock().map_err(|e| e.to_string())?.sync_mode {
+            SyncMode::Interval(interval) => interval,
+            _ => return Ok(None),
+        };
+        let commitlog = Arc::downgrade(commitlog);
+        Ok(Some(thread::spawn(move || {
+            let mut wait = interval;
+            loop {
+                // intervalが0のときに空回りしない
+                thread::sleep(wait.max(Duration::from_millis(1)));
+                let commitlog = match commitlog.upgrade() {
+                    Some(commitlog) => commitlog,
+                    None => return,
+                };
+                let ret = commitlog
+                    .lock()
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut commitlog| commitlog.sync_if_elapsed(interval));
+                wait = match ret {
+                    Ok(wait) => wait,
+                    Err(e) => {
+                        eprintln!("ERROR: commit log sync failed: {}", e);
+                        interval
+                    },
+                };
+            }
+        })))
+    }
+
+    pub fn write_put(&mut self, key: &[u8], value: &[u8], sequence: u64) -> Result<(), String> {
         let entry = CommitLogEntry::new("PUT", key, Some(value));
-        self.append(&entry, timestamp);
+        self.append(&entry, sequence)
     }
 
-    pub fn write_delete(&mut self, key: &str, timestamp: u64) {
+    pub fn write_delete(&mut self, key: &[u8], sequence: u64) -> Result<(), String> {
         let entry = CommitLogEntry::new("DELETE", key, None);
-        self.append(&entry, timestamp);
+        self.append(&entry, sequence)
     }
 
     pub fn delete_log(&self) -> Result<(), String> {
-        remove_file(&self.get_file_path()).map_err(|e| e.to_string())
+        remove_file(self.get_file_path()).map_err(|e| e.to_string())
     }
 
     pub fn get_file_path(&self) -> String {
@@ -65,16 +287,202 @@ impl CommitLog {
         &self.dir
     }
 
+    // dir内に残っているコミットログを作成順に返す
+    pub fn list_logs(dir: &str) -> Result<Vec<String>, String> {
+        let mut logs = vec![];
+        for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            let file_name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let created_at = file_name
+                .strip_prefix("commit_")
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(|timestamp| timestamp.parse::<u64>().ok());
+            if let Some(created_at) = created_at {
+                logs.push((created_at, format!("{}/{}", dir, file_name)));
+            }
+        }
+        logs.sort();
+        Ok(logs.into_iter().map(|(_, path)| path).collect())
+    }
+
+    pub fn replay(path: &str, mode: RecoveryMode) -> Result<ReplayedLog, String> {
+        let mut reader = CommitLogReader::open(path)?;
+        let mut records = vec![];
+        let mut corrupted = false;
+        while let Some(record) = reader.next() {
+            match record {
+                Ok(record) => records.push(record),
+                Err(e) if mode == RecoveryMode::Fail => return Err(e),
+                Err(e) => {
+                    eprintln!("WARN: {}", e);
+                    corrupted = true;
+                    if mode == RecoveryMode::TruncateTail || !reader.skip_frame() {
+                        break;
+                    }
+                },
+            }
+        }
+        Ok(ReplayedLog { records, corrupted })
+    }
+}
+
+// コミットログをフレーム単位で読む
+// 壊れたフレームに当たったらそのエラーを返して止まる
+pub struct CommitLogReader {
+    path: String,
+    buf: Vec<u8>,
+    offset: usize,
+    // ヘッダーもフレームも持たない旧形式のログ
+    legacy: bool,
+    version: u32,
+    stopped: bool,
+}
+
+impl CommitLogReader {
+    pub fn open(path: &str) -> Result<CommitLogReader, String> {
+        let buf = fs::read(path).map_err(|e| e.to_string())?;
+        let (legacy, version, offset) = if buf.len() < HEADER_SIZE && COMMITLOG_MAGIC.starts_with(&buf[..buf.len().min(4)]) {
+            // ヘッダーの書き込み中に落ちたログ
+            (false, COMMITLOG_VERSION, buf.len())
+        } else if buf.starts_with(&COMMITLOG_MAGIC) {
+            let version = u32::from_ne_bytes(buf[4..HEADER_SIZE].try_into().unwrap());
+            if version != COMMITLOG_VERSION && version != COMMITLOG_VERSION_WITHOUT_SEQUENCE {
+                return Err(format!("unsupported commit log version {} in {}", version, path));
+            }
+            (false, version, HEADER_SIZE)
+        } else {
+            (true, COMMITLOG_VERSION_WITHOUT_SEQUENCE, 0)
+        };
+        Ok(CommitLogReader {
+            path: path.to_string(),
+            buf,
+            offset,
+            legacy,
+            version,
+            stopped: false,
+        })
+    }
+
+    // 壊れたフレームを飛ばして続きから読めるようにする
+    // フレーム長が信用できない場合はfalseを返す
+    pub fn skip_frame(&mut self) -> bool {
+        if self.legacy {
+            return false;
+        }
+        let payload_len = self.buf.get(self.offset..(self.offset + 4))
+            .map(|len| u32::from_ne_bytes(len.try_into().unwrap()) as usize);
+        match payload_len {
+            Some(payload_len) if self.offset + FRAME_HEADER_SIZE + payload_len <= self.buf.len() => {
+                self.offset += FRAME_HEADER_SIZE + payload_len;
+                self.stopped = false;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn decode_frame(data: &[u8], version: u32) -> Result<(CommitLogRecord, usize), String> {
+        let payload_len = u32::from_ne_bytes(
+                data.get(0..4)
+                    .ok_or("payload_len is not found")?
+                    .try_into()
+                    .map_err(|e: std::array::TryFromSliceError| e.to_string())?) as usize;
+        let checksum = u32::from_ne_bytes(
+                data.get(4..FRAME_HEADER_SIZE)
+                    .ok_or("checksum is not found")?
+                    .try_into()
+                    .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
+        let payload = data.get(FRAME_HEADER_SIZE..(FRAME_HEADER_SIZE + payload_len))
+            .ok_or("payload is truncated")?;
+        if utils::crc32c(payload) != checksum {
+            return Err("checksum mismatch".to_owned());
+        }
+        let (record, size) = CommitLogRecord::decode(payload, version)?;
+        if size != payload_len {
+            return Err(format!("payload_len mismatch: expected {}, actual {}", payload_len, size));
+        }
+        Ok((record, FRAME_HEADER_SIZE + payload_len))
+    }
+}
+
+impl Iterator for CommitLogReader {
+    type Item = Result<CommitLogRecord, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stopped || self.offset >= self.buf.len() {
+            return None;
+        }
+        let data = &self.buf[self.offset..];
+        let decoded = if self.legacy {
+            CommitLogRecord::decode(data, self.version)
+        } else {
+            Self::decode_frame(data, self.version)
+        };
+        match decoded {
+            Ok((record, size)) => {
+                self.offset += size;
+                Some(Ok(record))
+            },
+            Err(e) => {
+                self.stopped = true;
+                Some(Err(format!("corrupted commit log {} at offset {}: {}", self.path, self.offset, e)))
+            },
+        }
+    }
 }
 
+impl CommitLogRecord {
+    // versionはレコードを書いたログのバージョン
+    pub fn decode(data: &[u8], version: u32) -> Result<(CommitLogRecord, usize), String> {
+        let (entries, size) = match data.first() {
+            Some(&BATCH_MARKER) => Self::decode_batch(data)?,
+            _ => {
+                let (entry, size) = CommitLogEntry::decode(data)?;
+                (vec![entry], size)
+            },
+        };
+        let sequence = Self::decode_u64(data, size, "sequence")?;
+        if version == COMMITLOG_VERSION_WITHOUT_SEQUENCE {
+            // timestampをsequenceとして使う
+            return Ok((CommitLogRecord { entries, sequence, timestamp: sequence }, size + 8));
+        }
+        let timestamp = Self::decode_u64(data, size + 8, "timestamp")?;
+        Ok((CommitLogRecord { entries, sequence, timestamp }, size + 16))
+    }
+
+    fn decode_u64(data: &[u8], offset: usize, name: &str) -> Result<u64, String> {
+        let bytes = offset.checked_add(8)
+            .and_then(|end| data.get(offset..end))
+            .ok_or(format!("{} is not found", name))?;
+        Ok(u64::from_ne_bytes(bytes.try_into().map_err(|e: std::array::TryFromSliceError| e.to_string())?))
+    }
+
+    fn decode_batch(data: &[u8]) -> Result<(Vec<CommitLogEntry>, usize), String> {
+        let count = Self::decode_u64(data, 1, "entry_count")? as usize;
+        let mut offset = 9;
+        // countが壊れていても大きな領域を確保しないようにする
+        let mut entries = Vec::with_capacity(count.min(data.len()));
+        for _ in 0..count {
+            let (entry, size) = CommitLogEntry::decode(&data[offset..])?;
+            entries.push(entry);
+            offset += size;
+        }
+        Ok((entries, offset))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CommitLogEntry {
     pub cmd: CommitLogCmd,
-    pub key: String,
-    pub value: Option<String>,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
 }
 
 impl CommitLogEntry {
-    pub fn new(cmd: &str, key: &str, value: Option<&str>) -> CommitLogEntry {
+    pub fn new(cmd: &str, key: &[u8], value: Option<&[u8]>) -> CommitLogEntry {
         let cmd = match cmd {
             "PUT" => CommitLogCmd::Put,
             "DELETE" => CommitLogCmd::Delete,
@@ -82,15 +490,8 @@ impl CommitLogEntry {
         };
         CommitLogEntry {
             cmd,
-            key: key.to_string(),
-            value: value.map(|s| s.to_string()),
-        }
-    }
-
-    pub fn to_string(&self) -> String {
-        match self.cmd {
-            CommitLogCmd::Put => format!("PUT {} {}", self.key, self.value.clone().unwrap()),
-            CommitLogCmd::Delete => format!("DELETE {}", self.key),
+            key: key.to_vec(),
+            value: value.map(|s| s.to_vec()),
         }
     }
 
@@ -100,22 +501,75 @@ impl CommitLogEntry {
                 let mut buf = Vec::new();
                 buf.push(1u8);
                 buf.extend_from_slice(&self.key.len().to_ne_bytes());
-                buf.extend_from_slice(self.key.as_bytes());
+                buf.extend_from_slice(&self.key);
                 buf.extend_from_slice(&self.value.clone().unwrap().len().to_ne_bytes());
-                buf.extend_from_slice(self.value.clone().unwrap().as_bytes());
+                buf.extend_from_slice(&self.value.clone().unwrap());
                 buf
             }
             CommitLogCmd::Delete => {
                 let mut buf = Vec::new();
                 buf.push(2u8);
                 buf.extend_from_slice(&self.key.len().to_ne_bytes());
-                buf.extend_from_slice(self.key.as_bytes());
+                buf.extend_from_slice(&self.key);
                 buf
             }
         }
     }
+
+    pub fn decode(data: &[u8]) -> Result<(CommitLogEntry, usize), String> {
+        let cmd = match data.first() {
+            Some(1) => CommitLogCmd::Put,
+            Some(2) => CommitLogCmd::Delete,
+            Some(cmd) => return Err(format!("invalid command: {}", cmd)),
+            None => return Err("cmd is not found".to_owned()),
+        };
+        let (key, mut offset) = Self::decode_arg(data, 1)?;
+        let value = match cmd {
+            CommitLogCmd::Put => {
+                let (value, next) = Self::decode_arg(data, offset)?;
+                offset = next;
+                Some(value)
+            },
+            CommitLogCmd::Delete => None,
+        };
+        Ok((CommitLogEntry { cmd, key, value }, offset))
+    }
+
+    // offsetから arg_len | arg を読み、argと次のoffsetを返す
+    // arg_lenが壊れていてもオーバーフローでpanicしないように、加算は全て確かめる
+    fn decode_arg(data: &[u8], offset: usize) -> Result<(Vec<u8>, usize), String> {
+        let arg_at = offset.checked_add(8).ok_or("arg_len is not found")?;
+        let len = u64::from_ne_bytes(
+                data.get(offset..arg_at)
+                    .ok_or("arg_len is not found")?
+                    .try_into()
+                    .map_err(|e: std::array::TryFromSliceError| e.to_string())?) as usize;
+        let end = len.checked_add(8)
+            .and_then(|n| offset.checked_add(n))
+            .ok_or("arg_len is too large")?;
+        let arg = data.get(arg_at..end)
+                .ok_or("arg is not found")?
+                .to_vec();
+        Ok((arg, end))
+    }
+}
+
+impl fmt::Display for CommitLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cmd {
+            CommitLogCmd::Put => write!(
+                f,
+                "PUT {} {}",
+                String::from_utf8_lossy(&self.key),
+                String::from_utf8_lossy(self.value.as_ref().unwrap()),
+            ),
+            CommitLogCmd::Delete => write!(f, "DELETE {}", String::from_utf8_lossy(&self.key)),
+        }
+    }
 }
 
+// 値はsstable::RecordTypeと同じ. 種類を足すときはBATCH_MARKERと重ならない値にする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CommitLogCmd {
     Put = 1,
     Delete,
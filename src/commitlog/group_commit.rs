@@ -0,0 +1,136 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Condvar, Mutex};
+
+use super::CommitLog;
+
+/*
+リーダー/フォロワー方式のグループコミット
+書き込みスレッドはエンコード済みのフレームをキューに積み、
+リーダーがいなければ自分がリーダーとなってキューの中身をまとめて書き込み、fsyncする
+リーダーが書き込んでいる間に積まれたレコードは次のグループになる
+チケットは積まれた順に振られ、committed未満のチケットは書き込み済み
+書き込みに一度失敗したら、以降は何も書かずに全てのコミットを失敗させる(fail-stop)
+ログを開き直すまで戻らないので、enqueueはPOISONED_ERROR_PREFIXで始まるエラーを返す
+*/
+
+pub const POISONED_ERROR_PREFIX: &str = "commit log is poisoned";
+
+#[derive(Debug, Default)]
+struct GroupCommitState {
+    pending: Vec<u8>,
+    pending_sync: bool,
+    next_ticket: u64,
+    committed: u64,
+    leader_active: bool,
+    // 書き込みに失敗したグループの先頭チケットとエラー
+    // ログの状態がわからなくなるので、以降のコミットは全て失敗させる
+    failed_from: Option<(u64, String)>,
+}
+
+#[derive(Debug, Default)]
+pub struct GroupCommit {
+    state: Mutex<GroupCommitState>,
+    cond: Condvar,
+    groups: AtomicU64,
+    records: AtomicU64,
+    max_group_size: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GroupCommitStats {
+    pub groups: u64,
+    pub records: u64,
+    pub max_group_size: u64,
+}
+
+impl GroupCommitStats {
+    pub fn average_group_size(&self) -> f64 {
+        if self.groups == 0 {
+            return 0.0;
+        }
+        self.records as f64 / self.groups as f64
+    }
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // frameがcommitlogに書き込まれる(syncがtrueならfsyncされる)まで待つ
+    pub fn commit(&self, commitlog: &Mutex<CommitLog>, frame: &[u8], sync: bool) -> Result<(), String> {
+        let ticket = self.enqueue(frame, sync)?;
+        self.wait(commitlog, ticket)
+    }
+
+    // frameをキューに積んでチケットを返す. ログにはenqueueした順に書き込まれる
+    pub fn enqueue(&self, frame: &[u8], sync: bool) -> Result<u64, String> {
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        if let Some((_, e)) = &state.failed_from {
+            return Err(format!("{} by a failed write: {}", POISONED_ERROR_PREFIX, e));
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.extend_from_slice(frame);
+        state.pending_sync |= sync;
+        Ok(ticket)
+    }
+
+    // ticketのframeが書き込まれるまで待つ
+    pub fn wait(&self, commitlog: &Mutex<CommitLog>, ticket: u64) -> Result<(), String> {
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        loop {
+            if ticket < state.committed {
+                return match &state.failed_from {
+                    Some((from, e)) if *from <= ticket => Err(e.clone()),
+                    _ => Ok(()),
+                };
+            }
+            if state.leader_active {
+                state = self.cond.wait(state).map_err(|e| e.to_string())?;
+                continue;
+            }
+
+            // リーダーとして、積まれている分をまとめて書き込む
+            state.leader_active = true;
+            let buf = std::mem::take(&mut state.pending);
+            let sync = std::mem::take(&mut state.pending_sync);
+            let start = state.committed;
+            let end = state.next_ticket;
+            let failed = state.failed_from.is_some();
+            drop(state);
+
+            // 失敗したグループより後ろは、ログに穴があいたまま書き足さないように捨てる
+            let ret = if failed {
+                Ok(())
+            } else {
+                self.record_group(end - start);
+                commitlog
+                    .lock()
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut commitlog| commitlog.write_frames(&buf, sync))
+            };
+
+            state = self.state.lock().map_err(|e| e.to_string())?;
+            if let Err(e) = ret {
+                state.failed_from.get_or_insert((start, e));
+            }
+            state.committed = end;
+            state.leader_active = false;
+            self.cond.notify_all();
+        }
+    }
+
+    fn record_group(&self, size: u64) {
+        self.groups.fetch_add(1, Ordering::Relaxed);
+        self.records.fetch_add(size, Ordering::Relaxed);
+        self.max_group_size.fetch_max(size, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> GroupCommitStats {
+        GroupCommitStats {
+            groups: self.groups.load(Ordering::Relaxed),
+            records: self.records.load(Ordering::Relaxed),
+            max_group_size: self.max_group_size.load(Ordering::Relaxed),
+        }
+    }
+}
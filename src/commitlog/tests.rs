@@ -1,6 +1,8 @@
 use std::path::Path;
 
-use crate::commitlog::{CommitLog, CommitLogEntry};
+use std::{sync::{Arc, Barrier, Mutex}, thread, time::Duration};
+
+use crate::commitlog::{group_commit::{GroupCommit, POISONED_ERROR_PREFIX}, CommitLog, CommitLogCmd, CommitLogEntry, CommitLogReader, RecoveryMode, SyncMode, COMMITLOG_MAGIC};
 
 /*
 ------------------------------------------------------------------------
@@ -14,7 +16,7 @@ DELETE: 2
 #[cfg(target_pointer_width = "64")]
 #[test]
 fn test_cl_put_encode() {
-    let entry = CommitLogEntry::new("PUT", "key", Some("value"));
+    let entry = CommitLogEntry::new("PUT", b"key", Some(b"value".as_slice()));
     let buf = entry.encode();
     assert_eq!(buf, vec![
         1,                      // cmd: PUT 
@@ -36,7 +38,7 @@ fn test_cl_utf8() {
 #[cfg(target_pointer_width = "64")]
 #[test]
 fn test_cl_put_encode_key_utf8() {
-    let entry = CommitLogEntry::new("PUT", "キー", Some("バリュー"));
+    let entry = CommitLogEntry::new("PUT", "キー".as_bytes(), Some("バリュー".as_bytes()));
     let buf = entry.encode();
     assert_eq!(buf, vec![
         1,                                                          // cmd: PUT 
@@ -50,7 +52,7 @@ fn test_cl_put_encode_key_utf8() {
 #[cfg(target_pointer_width = "64")]
 #[test]
 fn test_cl_delete_encode() {
-    let entry = CommitLogEntry::new("DELETE", "key", None);
+    let entry = CommitLogEntry::new("DELETE", b"key", None);
     let buf = entry.encode();
     assert_eq!(buf, vec![
         2,                      // cmd: DELETE
@@ -62,7 +64,7 @@ fn test_cl_delete_encode() {
 #[cfg(target_pointer_width = "64")]
 #[test]
 fn test_cl_delete_encode_key_utf8() {
-    let entry = CommitLogEntry::new("DELETE", "キー", None);
+    let entry = CommitLogEntry::new("DELETE", "キー".as_bytes(), None);
     let buf = entry.encode();
     assert_eq!(buf, vec![
         2,                           // cmd: DELETE
@@ -78,4 +80,382 @@ fn test_cl_delete_log_file() {
     let commit_log = CommitLog::new(dir).unwrap();
     commit_log.delete_log().unwrap();
     assert!(!Path::new(&commit_log.get_file_path()).exists());
-}
\ No newline at end of file
+}
+#[test]
+fn test_cl_entry_decode() {
+    let entry = CommitLogEntry::new("PUT", "キー".as_bytes(), Some("バリュー".as_bytes()));
+    let (decoded, size) = CommitLogEntry::decode(&entry.encode()).unwrap();
+    assert_eq!(decoded, entry);
+    assert_eq!(size, entry.encode().len());
+
+    let entry = CommitLogEntry::new("DELETE", b"key", None);
+    let (decoded, size) = CommitLogEntry::decode(&entry.encode()).unwrap();
+    assert_eq!(decoded, entry);
+    assert_eq!(size, 12);
+
+    // "\0"の値もPUTのまま
+    let entry = CommitLogEntry::new("PUT", b"key", Some(b"\0"));
+    let (decoded, _) = CommitLogEntry::decode(&entry.encode()).unwrap();
+    assert_eq!(decoded.cmd, CommitLogCmd::Put);
+    assert_eq!(decoded.value, Some(b"\0".to_vec()));
+}
+
+#[test]
+fn test_cl_entry_decode_invalid() {
+    assert!(CommitLogEntry::decode(&[]).is_err());
+    assert!(CommitLogEntry::decode(&[3, 0, 0]).is_err());
+    // arg0_lenだけ書かれて途切れている
+    assert!(CommitLogEntry::decode(&[1, 3, 0, 0, 0, 0, 0, 0, 0, 107]).is_err());
+    // arg0_lenが壊れていてusize::MAXに近くてもpanicしない
+    for len in [u64::MAX, u64::MAX - 7, u64::MAX - 8] {
+        let data = [vec![1], len.to_ne_bytes().to_vec(), b"key".to_vec()].concat();
+        let err = CommitLogEntry::decode(&data).unwrap_err();
+        assert!(err.contains("arg"), "{}", err);
+    }
+}
+
+fn write_logs(dir: &str, tail: &[u8]) -> CommitLog {
+    if Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let mut commit_log = CommitLog::new(dir).unwrap();
+    commit_log.write_put(b"key1", b"value1", 1).unwrap();
+    commit_log.write_delete(b"key1", 2).unwrap();
+    commit_log.write_put(b"key2", b"value2", 3).unwrap();
+    let mut file = std::fs::OpenOptions::new().append(true).open(commit_log.get_file_path()).unwrap();
+    std::io::Write::write_all(&mut file, tail).unwrap();
+    commit_log
+}
+
+#[test]
+fn test_cl_replay() {
+    let dir = "/tmp/test_cl_replay";
+    let commit_log = write_logs(dir, &[]);
+
+    let replayed = CommitLog::replay(&commit_log.get_file_path(), RecoveryMode::Fail).unwrap();
+    assert!(!replayed.corrupted);
+    assert_eq!(replayed.records.len(), 3);
+    assert_eq!(replayed.records[0].entries, vec![CommitLogEntry::new("PUT", b"key1", Some(b"value1".as_slice()))]);
+    assert_eq!(replayed.records[0].sequence, 1);
+    assert_eq!(replayed.records[1].entries, vec![CommitLogEntry::new("DELETE", b"key1", None)]);
+    assert_eq!(replayed.records[1].sequence, 2);
+    assert_eq!(replayed.records[2].entries, vec![CommitLogEntry::new("PUT", b"key2", Some(b"value2".as_slice()))]);
+    assert_eq!(replayed.records[2].sequence, 3);
+
+    assert_eq!(CommitLog::list_logs(dir).unwrap(), vec![commit_log.get_file_path()]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_replay_torn_tail() {
+    let dir = "/tmp/test_cl_replay_torn_tail";
+    // 書き込み途中で落ちたPUT
+    let commit_log = write_logs(dir, &[1, 4, 0, 0, 0, 0, 0, 0, 0, 107, 101]);
+
+    assert!(CommitLog::replay(&commit_log.get_file_path(), RecoveryMode::Fail).is_err());
+
+    let replayed = CommitLog::replay(&commit_log.get_file_path(), RecoveryMode::TruncateTail).unwrap();
+    assert!(replayed.corrupted);
+    assert_eq!(replayed.records.len(), 3);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(target_endian = "little")]
+#[test]
+fn test_cl_encode_frame() {
+    let frame = CommitLog::encode_frame(b"123456789");
+    assert_eq!(frame, [
+        vec![9, 0, 0, 0],               // payload_len: 9
+        0xE306_9283u32.to_le_bytes().to_vec(), // crc32c("123456789")
+        b"123456789".to_vec(),
+    ].concat());
+}
+
+#[test]
+fn test_cl_reader_stops_at_invalid_frame() {
+    let dir = "/tmp/test_cl_reader_stops_at_invalid_frame";
+    let commit_log = write_logs(dir, &[]);
+    let path = commit_log.get_file_path();
+
+    // 2番目のフレームのpayloadを1bit反転させる
+    let mut buf = std::fs::read(&path).unwrap();
+    let second_frame = 8 + 8 + CommitLogEntry::new("PUT", b"key1", Some(b"value1".as_slice())).encode().len() + 16;
+    buf[second_frame + 8] ^= 1;
+    std::fs::write(&path, &buf).unwrap();
+
+    let mut reader = CommitLogReader::open(&path).unwrap();
+    assert_eq!(reader.next().unwrap().unwrap().entries, vec![CommitLogEntry::new("PUT", b"key1", Some(b"value1".as_slice()))]);
+    let err = reader.next().unwrap().unwrap_err();
+    assert!(err.contains("checksum mismatch"), "{}", err);
+    assert!(reader.next().is_none());
+
+    let replayed = CommitLog::replay(&path, RecoveryMode::TruncateTail).unwrap();
+    assert!(replayed.corrupted);
+    assert_eq!(replayed.records.len(), 1);
+
+    let replayed = CommitLog::replay(&path, RecoveryMode::SkipCorrupted).unwrap();
+    assert!(replayed.corrupted);
+    assert_eq!(replayed.records.len(), 2);
+    assert_eq!(replayed.records[1].entries, vec![CommitLogEntry::new("PUT", b"key2", Some(b"value2".as_slice()))]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_reader_legacy_log() {
+    let dir = "/tmp/test_cl_reader_legacy_log";
+    if Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    // ヘッダーもフレームもない旧形式
+    let path = format!("{}/commit_1.log", dir);
+    let mut buf = CommitLogEntry::new("PUT", b"key1", Some(b"value1".as_slice())).encode();
+    buf.extend_from_slice(&1u64.to_ne_bytes());
+    buf.extend_from_slice(&CommitLogEntry::new("DELETE", b"key1", None).encode());
+    buf.extend_from_slice(&2u64.to_ne_bytes());
+    std::fs::write(&path, &buf).unwrap();
+
+    let replayed = CommitLog::replay(&path, RecoveryMode::Fail).unwrap();
+    assert_eq!(replayed.records.len(), 2);
+    assert_eq!(replayed.records[1].entries, vec![CommitLogEntry::new("DELETE", b"key1", None)]);
+    assert_eq!(replayed.records[1].sequence, 2);
+    assert_eq!(replayed.records[1].timestamp, 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_reader_version1_log() {
+    let dir = "/tmp/test_cl_reader_version1_log";
+    if Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    // sequenceを持たず、timestampだけを持つversion 1のログ
+    let path = format!("{}/commit_1.log", dir);
+    let mut buf = COMMITLOG_MAGIC.to_vec();
+    buf.extend_from_slice(&1u32.to_ne_bytes());
+    let mut payload = CommitLogEntry::new("PUT", b"key1", Some(b"value1".as_slice())).encode();
+    payload.extend_from_slice(&1234u64.to_ne_bytes());
+    buf.extend_from_slice(&CommitLog::encode_frame(&payload));
+    std::fs::write(&path, &buf).unwrap();
+
+    let replayed = CommitLog::replay(&path, RecoveryMode::Fail).unwrap();
+    assert_eq!(replayed.records.len(), 1);
+    assert_eq!(replayed.records[0].sequence, 1234);
+    assert_eq!(replayed.records[0].timestamp, 1234);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_sync_mode() {
+    let dir = "/tmp/test_cl_sync_mode";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+
+    let mut always = CommitLog::with_sync_mode(dir, SyncMode::Always).unwrap();
+    always.write_put(b"key1", b"value1", 1).unwrap();
+    assert_eq!(always.sync_state.lock().unwrap().unsynced_bytes, 0);
+
+    let mut never = CommitLog::with_sync_mode(dir, SyncMode::Never).unwrap();
+    never.write_put(b"key1", b"value1", 1).unwrap();
+    let written = never.sync_state.lock().unwrap().unsynced_bytes;
+    assert!(written > 0);
+    never.sync().unwrap();
+    assert_eq!(never.sync_state.lock().unwrap().unsynced_bytes, 0);
+
+    let mut bytes = CommitLog::with_sync_mode(dir, SyncMode::Bytes(written * 2)).unwrap();
+    bytes.write_put(b"key1", b"value1", 1).unwrap();
+    assert_eq!(bytes.sync_state.lock().unwrap().unsynced_bytes, written);
+    bytes.write_put(b"key1", b"value1", 2).unwrap();
+    assert_eq!(bytes.sync_state.lock().unwrap().unsynced_bytes, 0);
+
+    let mut interval = CommitLog::with_sync_mode(dir, SyncMode::Interval(Duration::from_secs(3600))).unwrap();
+    interval.write_put(b"key1", b"value1", 1).unwrap();
+    assert_eq!(interval.sync_state.lock().unwrap().unsynced_bytes, written);
+    let mut interval = CommitLog::with_sync_mode(dir, SyncMode::Interval(Duration::ZERO)).unwrap();
+    interval.write_put(b"key1", b"value1", 1).unwrap();
+    assert_eq!(interval.sync_state.lock().unwrap().unsynced_bytes, 0);
+
+    // ローテーション時には残りをfsyncし、同じSyncModeを引き継ぐ
+    let mut bytes = CommitLog::with_sync_mode(dir, SyncMode::Bytes(written * 2)).unwrap();
+    bytes.write_put(b"key1", b"value1", 1).unwrap();
+    let rotated = bytes.rotate().unwrap();
+    assert_eq!(bytes.sync_state.lock().unwrap().unsynced_bytes, 0);
+    assert_eq!(rotated.sync_mode, SyncMode::Bytes(written * 2));
+
+    // try_cloneしたものは同じファイルを指すので、どちらでfsyncしても両方に反映される
+    let mut never = CommitLog::with_sync_mode(dir, SyncMode::Never).unwrap();
+    let mut cloned = never.try_clone().unwrap();
+    never.write_put(b"key1", b"value1", 1).unwrap();
+    assert_eq!(cloned.sync_state.lock().unwrap().unsynced_bytes, written);
+    cloned.sync().unwrap();
+    assert_eq!(never.sync_state.lock().unwrap().unsynced_bytes, 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_interval_syncer() {
+    let dir = "/tmp/test_cl_interval_syncer";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+
+    let interval = Duration::from_millis(200);
+    let commit_log = Arc::new(Mutex::new(CommitLog::with_sync_mode(dir, SyncMode::Interval(interval)).unwrap()));
+    let handle = CommitLog::start_interval_syncer(&commit_log).unwrap().unwrap();
+
+    // 書き込みは1回だけで、次の書き込みは来ない
+    commit_log.lock().unwrap().write_put(b"key1", b"value1", 1).unwrap();
+    assert!(commit_log.lock().unwrap().sync_state.lock().unwrap().unsynced_bytes > 0);
+    thread::sleep(interval * 3);
+    assert_eq!(commit_log.lock().unwrap().sync_state.lock().unwrap().unsynced_bytes, 0);
+
+    // commitlogが捨てられたらスレッドも終わる
+    drop(commit_log);
+    handle.join().unwrap();
+
+    // Interval以外ではスレッドを起動しない
+    let commit_log = Arc::new(Mutex::new(CommitLog::with_sync_mode(dir, SyncMode::Never).unwrap()));
+    assert!(CommitLog::start_interval_syncer(&commit_log).unwrap().is_none());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_group_commit() {
+    let dir = "/tmp/test_cl_group_commit";
+    if Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let commit_log = Arc::new(Mutex::new(CommitLog::with_sync_mode(dir, SyncMode::Always).unwrap()));
+    let group_commit = Arc::new(GroupCommit::new());
+
+    let handles: Vec<_> = (0..8u64).map(|t| {
+        let commit_log = commit_log.clone();
+        let group_commit = group_commit.clone();
+        thread::spawn(move || {
+            for i in 0..50u64 {
+                let entry = CommitLogEntry::new("PUT", format!("key{}_{}", t, i).as_bytes(), Some(b"value".as_slice()));
+                let frame = CommitLog::encode_record(&entry, t * 100 + i, 0);
+                group_commit.commit(&commit_log, &frame, false).unwrap();
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let path = commit_log.lock().unwrap().get_file_path();
+    let replayed = CommitLog::replay(&path, RecoveryMode::Fail).unwrap();
+    assert_eq!(replayed.records.len(), 400);
+    // 各スレッドの書き込み順は保たれる
+    for t in 0..8u64 {
+        let sequences: Vec<u64> = replayed.records.iter()
+            .map(|record| record.sequence)
+            .filter(|sequence| sequence / 100 == t)
+            .collect();
+        assert_eq!(sequences, (0..50).map(|i| t * 100 + i).collect::<Vec<_>>());
+    }
+
+    let stats = group_commit.stats();
+    assert_eq!(stats.records, 400);
+    assert!(stats.groups >= 1 && stats.groups <= 400);
+    assert!(stats.max_group_size >= 1 && stats.max_group_size <= 8);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_group_commit_batches() {
+    let dir = "/tmp/test_cl_group_commit_batches";
+    if Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let commit_log = Arc::new(Mutex::new(CommitLog::with_sync_mode(dir, SyncMode::Always).unwrap()));
+    let group_commit = Arc::new(GroupCommit::new());
+    // 全てのスレッドが積み終わってから待ち始めるので、最初のリーダーが全員分をまとめて書く
+    let barrier = Arc::new(Barrier::new(8));
+
+    let handles: Vec<_> = (0..8u64).map(|t| {
+        let commit_log = commit_log.clone();
+        let group_commit = group_commit.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let entry = CommitLogEntry::new("PUT", format!("key{}", t).as_bytes(), Some(b"value".as_slice()));
+            let ticket = group_commit.enqueue(&CommitLog::encode_record(&entry, t, 0), true).unwrap();
+            barrier.wait();
+            group_commit.wait(&commit_log, ticket).unwrap();
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let stats = group_commit.stats();
+    assert_eq!(stats.records, 8);
+    assert_eq!(stats.groups, 1);
+    assert_eq!(stats.max_group_size, 8);
+    assert_eq!(commit_log.lock().unwrap().sync_state.lock().unwrap().unsynced_bytes, 0);
+
+    let path = commit_log.lock().unwrap().get_file_path();
+    let replayed = CommitLog::replay(&path, RecoveryMode::Fail).unwrap();
+    assert_eq!(replayed.records.len(), 8);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_group_commit_fail_stop() {
+    let dir = "/tmp/test_cl_group_commit_fail_stop";
+    if Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    let commit_log = CommitLog::with_sync_mode(dir, SyncMode::Always).unwrap();
+    let path = commit_log.get_file_path();
+    let commit_log = Mutex::new(commit_log);
+    let group_commit = GroupCommit::new();
+    let frame = |i: u64| CommitLog::encode_record(&CommitLogEntry::new("PUT", format!("key{}", i).as_bytes(), Some(b"value".as_slice())), i, 0);
+
+    group_commit.commit(&commit_log, &frame(1), false).unwrap();
+    // 読み込み専用で開き直して、書き込みを失敗させる
+    let writable = std::mem::replace(&mut commit_log.lock().unwrap().file, std::fs::File::open(&path).unwrap());
+    let err = group_commit.commit(&commit_log, &frame(2), false).unwrap_err();
+    assert!(!err.starts_with(POISONED_ERROR_PREFIX), "{}", err);
+
+    // 書けるように戻しても、ログを開き直すまでは全て失敗させる
+    commit_log.lock().unwrap().file = writable;
+    let err = group_commit.commit(&commit_log, &frame(3), false).unwrap_err();
+    assert!(err.starts_with(POISONED_ERROR_PREFIX), "{}", err);
+
+    let replayed = CommitLog::replay(&path, RecoveryMode::Fail).unwrap();
+    assert_eq!(replayed.records.iter().map(|record| record.sequence).collect::<Vec<_>>(), vec![1]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cl_replay_batch() {
+    let dir = "/tmp/test_cl_replay_batch";
+    let mut commit_log = write_logs(dir, &[]);
+    let entries = vec![
+        CommitLogEntry::new("PUT", b"key3", Some(b"value3".as_slice())),
+        CommitLogEntry::new("DELETE", b"key2", None),
+    ];
+    commit_log.write_frames(&CommitLog::encode_batch(&entries, 4, 40), false).unwrap();
+
+    let replayed = CommitLog::replay(&commit_log.get_file_path(), RecoveryMode::Fail).unwrap();
+    assert_eq!(replayed.records.len(), 4);
+    assert_eq!(replayed.records[3].entries, entries);
+    assert_eq!(replayed.records[3].sequence, 4);
+    assert_eq!(replayed.records[3].timestamp, 40);
+
+    // 書き込み途中で落ちたバッチは1件も復元されない
+    let batch = CommitLog::encode_batch(&entries, 5, 50);
+    let mut file = std::fs::OpenOptions::new().append(true).open(commit_log.get_file_path()).unwrap();
+    std::io::Write::write_all(&mut file, &batch[..batch.len() - 1]).unwrap();
+    let replayed = CommitLog::replay(&commit_log.get_file_path(), RecoveryMode::TruncateTail).unwrap();
+    assert!(replayed.corrupted);
+    assert_eq!(replayed.records.len(), 4);
+    std::fs::remove_dir_all(dir).unwrap();
+}
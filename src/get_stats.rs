@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// getがSSTableを探したときの統計
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GetStats {
+    // memtableになくSSTableを探したgetの回数
+    pub gets: u64,
+    // indexとblockを読んだSSTableの数の合計と、1回のgetでの最大
+    pub lookups: u64,
+    pub max_lookups: u64,
+    // metaのキーの範囲にkeyが入らず、読まなかったSSTableの数
+    pub key_range_skipped: u64,
+    // 残りのSSTableに新しいバージョンがないとわかり、途中で打ち切ったgetの回数
+    pub early_stopped: u64,
+    // 今のSSTableの数. getが読みうるSSTableの最大
+    pub depth: u64,
+}
+
+impl GetStats {
+    pub fn lookups_per_get(&self) -> f64 {
+        if self.gets == 0 {
+            return 0.0;
+        }
+        self.lookups as f64 / self.gets as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GetStatsCounter {
+    gets: AtomicU64,
+    lookups: AtomicU64,
+    max_lookups: AtomicU64,
+    key_range_skipped: AtomicU64,
+    early_stopped: AtomicU64,
+}
+
+impl GetStatsCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, lookups: u64, key_range_skipped: u64, early_stopped: bool) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        self.lookups.fetch_add(lookups, Ordering::Relaxed);
+        self.max_lookups.fetch_max(lookups, Ordering::Relaxed);
+        self.key_range_skipped.fetch_add(key_range_skipped, Ordering::Relaxed);
+        if early_stopped {
+            self.early_stopped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self, depth: u64) -> GetStats {
+        GetStats {
+            gets: self.gets.load(Ordering::Relaxed),
+            lookups: self.lookups.load(Ordering::Relaxed),
+            max_lookups: self.max_lookups.load(Ordering::Relaxed),
+            key_range_skipped: self.key_range_skipped.load(Ordering::Relaxed),
+            early_stopped: self.early_stopped.load(Ordering::Relaxed),
+            depth,
+        }
+    }
+}
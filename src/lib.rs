@@ -1,113 +1,137 @@
 pub mod memtable;
 pub mod commitlog;
+pub mod get_stats;
+pub mod prefix_extractor;
+pub mod scan;
+pub mod snapshot;
 pub mod sstable;
 pub mod utils;
+pub mod version_set;
+pub mod write_batch;
+pub mod write_stall;
 mod thread_pool;
 
-use std::{collections::HashMap, sync::{Arc, Mutex, RwLock}, thread::{self, sleep, spawn}};
+use std::{collections::VecDeque, ops::{Bound, RangeBounds}, sync::{atomic::{AtomicU64, Ordering}, mpsc::{channel, Receiver, RecvTimeoutError, Sender}, Arc, Condvar, Mutex, RwLock}, thread::{self, spawn}};
 
-use memtable::MemTable;
-use commitlog::CommitLog;
-use sstable::{compaction::Compaction, reader::SSTableReaderManager, SSTableWriter};
+use prefix_extractor::{prefix_upper_bound, PrefixExtractor};
+use memtable::{skiplist::SkipListMemTableFactory, MemTable, MemTableFactory};
+use get_stats::{GetStats, GetStatsCounter};
+use commitlog::{group_commit::{GroupCommit, GroupCommitStats}, CommitLog, CommitLogCmd, CommitLogEntry, RecoveryMode, SyncMode};
+use scan::{MemTableCursor, ScanCursor, ScanIterator};
+use snapshot::{Snapshot, SnapshotList};
+use sstable::{block_cache::{BlockCache, BlockCacheStats, DEFAULT_BLOCK_CACHE_CAPACITY}, bloom::{BloomFilterCounter, BloomFilterStats, DEFAULT_BITS_PER_KEY}, index_cache::{IndexCache, IndexCacheStats}, compaction::Compaction, iterator::SSTableIterator, reader::SSTableReaderManager, SSTableWriter};
+use version_set::{Version, VersionSet};
+use write_batch::WriteBatch;
+use write_stall::{WriteController, WriteStallConf, WriteStallStats};
 
 use utils::*;
 
 use std::io::ErrorKind;
 
-pub type Key = String;
-pub type Value = String;
+pub type Key = Vec<u8>;
+pub type Value = Vec<u8>;
 
 #[derive(Debug)]
 pub struct SharedSSTableReader {
-    inner: Mutex<HashMap<String, Arc<SSTableReaderManager>>>,
+    // 読むべきSSTableの集合. MANIFESTに記録してから入れ替える
+    versions: VersionSet,
+    // コンパクションで残すべきバージョンを決めるのに使う
+    snapshots: Arc<SnapshotList>,
+    // 全てのreaderで共有する
+    block_cache: Arc<BlockCache>,
+    index_cache: Arc<IndexCache>,
     pub sst_dir: String,
     pub index_file_suffix: String,
 }
 
 impl SharedSSTableReader {
-    pub fn new(sst_dir: &str, index_file_suffix: &str) -> Arc<Self> {
-        let inner = HashMap::new();
-        Arc::new(SharedSSTableReader {
-            inner: Mutex::new(inner),
+    pub fn new(sst_dir: &str, index_file_suffix: &str) -> Result<Arc<Self>, String> {
+        Self::with_caches(
+            sst_dir,
+            index_file_suffix,
+            Arc::new(BlockCache::new(DEFAULT_BLOCK_CACHE_CAPACITY)),
+            Arc::new(IndexCache::new(None)),
+        )
+    }
+
+    // sst_dirのMANIFESTを読み直す. MANIFESTに載っていないSSTableは消す
+    pub fn with_caches(sst_dir: &str, index_file_suffix: &str, block_cache: Arc<BlockCache>, index_cache: Arc<IndexCache>) -> Result<Arc<Self>, String> {
+        let versions = VersionSet::open(sst_dir, index_file_suffix, |file| {
+            Self::open_reader(file, index_file_suffix, &block_cache, &index_cache)
+        })?;
+        Ok(Arc::new(SharedSSTableReader {
+            versions,
+            snapshots: Arc::new(SnapshotList::new()),
+            block_cache,
+            index_cache,
             sst_dir: sst_dir.to_string(),
             index_file_suffix: index_file_suffix.to_string(),
-        })
+        }))
     }
 
-    pub fn drop_resource(self: &Arc<Self>, file: &str) {
-        let mut inner = self.inner.lock().unwrap();
-        let resource = inner.get(file);
-        if resource.is_none() {
-            return;
-        }
-        let resource = resource.unwrap();
-        let strong_count = Arc::strong_count(resource);
-        dbg!("strong_count: {:?}", strong_count);
-        if strong_count > 1 {
-            return;
-        }
-        if let Some(reader) = inner.remove(file) {
-            dbg!("ここにはきてる");
-            drop(reader);
-        }
+    fn open_reader(file: &str, index_file_suffix: &str, block_cache: &Arc<BlockCache>, index_cache: &Arc<IndexCache>) -> Result<SSTableReaderManager, String> {
+        let index_file = format!("{}.{}", file, index_file_suffix);
+        Ok(SSTableReaderManager::new(file, &index_file)?
+            .with_block_cache(block_cache.clone())
+            .with_index_cache(index_cache.clone()))
     }
 
-    pub fn get_reader(self: &Arc<Self>, file: &str) -> Option<Arc<SSTableReaderManager>> {
-        let inner = self.inner.lock().unwrap();
-        let resource = inner.get(file);
-        if let Some(resource) = resource {
-            if resource.is_deleted() {
-                return None;
-            }
-            return Some(resource.clone());
-        }
-        drop(inner);
-        Some(self.add_reader(file))
-    }
-
-    pub fn get_all(self: &Arc<Self>) -> Vec<Arc<SSTableReaderManager>> {
-        let mut result = vec![];
-        let dir = std::fs::read_dir(self.sst_dir.clone()).unwrap();
-        for entry in dir {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                let file_name = path.file_name().unwrap().to_str().unwrap();
-                if file_name.ends_with(".sst") {
-                    let idx_file_name = format!("{}.{}", file_name, self.index_file_suffix);
-                    let idx_path = path.with_file_name(idx_file_name);
-                    if !idx_path.exists() {
-                        continue;
-                    }
-                    if let Some(reader) = self.get_reader(path.to_str().unwrap()) {
-                        result.push(reader.clone());
-                    }
-                }
-            }
-        }
-        result
+    pub fn snapshots(&self) -> Arc<SnapshotList> {
+        self.snapshots.clone()
     }
 
-    pub fn add_reader(self: &Arc<Self>, file: &str) -> Arc<SSTableReaderManager> {
-        let mut inner = self.inner.lock().unwrap();
-        let resource = inner.get(file);
-        if resource.is_some() {
-            return resource.unwrap().clone();
-        }
-        let index_file = format!("{}.{}", file, self.index_file_suffix);
-        let reader = SSTableReaderManager::new(file, &index_file).unwrap();
-        let reader = Arc::new(reader);
-        inner.insert(file.to_string(), reader.clone());
-        reader
-    }
-
-    pub fn to_vec(self: &Arc<Self>) -> Vec<Arc<SSTableReaderManager>> {
-        let inner = self.inner.lock().unwrap();
-        let mut result = vec![];
-        for (_, reader) in inner.iter() {
-            result.push(reader.clone());
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.block_cache.stats()
+    }
+
+    pub fn index_cache_stats(&self) -> IndexCacheStats {
+        self.index_cache.stats()
+    }
+
+    // fileのSSTableのindexを、メモリ上限を超えても捨てないようにする
+    pub fn pin_index(&self, file: &str) -> Result<(), String> {
+        self.get_reader(file)?.pin_index();
+        Ok(())
+    }
+
+    pub fn unpin_index(&self, file: &str) -> Result<(), String> {
+        self.get_reader(file)?.unpin_index();
+        Ok(())
+    }
+
+    fn get_reader(&self, file: &str) -> Result<Arc<SSTableReaderManager>, String> {
+        self.current()
+            .readers()
+            .into_iter()
+            .find(|reader| reader.file() == file)
+            .ok_or(format!("{} is not in the current version", file))
+    }
+
+    // 今のバージョン. 持っている間は、そのSSTableは消えない
+    pub fn current(&self) -> Arc<Version> {
+        self.versions.current()
+    }
+
+    // 今のバージョンのSSTable. max_sequenceの大きい順
+    pub fn get_all(&self) -> Vec<Arc<SSTableReaderManager>> {
+        self.current().readers()
+    }
+
+    // 新しいSSTableのwriter. ファイル名はMANIFESTに記録する番号から決める
+    pub fn new_writer(&self) -> SSTableWriter {
+        SSTableWriter::numbered(&self.sst_dir, self.versions.new_file_number())
+    }
+
+    // writerで書き終えたSSTableを加え、removedを外す
+    // MANIFESTに記録するまで、加えたSSTableはgetやscanからは見えない
+    pub fn install(&self, writer: &SSTableWriter, removed: &[Arc<SSTableReaderManager>]) -> Result<(), String> {
+        let reader = Arc::new(Self::open_reader(&writer.file, &self.index_file_suffix, &self.block_cache, &self.index_cache)?);
+        let applied = self.versions.log_and_apply(writer.file_number, reader.clone(), removed);
+        if applied.is_err() {
+            // どのバージョンにも入らないので、使われなくなったら消す
+            reader.delete();
         }
-        result
+        applied
     }
 }
 
@@ -126,9 +150,18 @@ where
     index_interval: usize,
     index_file_suffix: String,
     enable_compaction: bool,   // コンパクションを有効にするかどうか
+    recovery_mode: RecoveryMode,
+    sync_mode: SyncMode,
+    write_stall: WriteStallConf,
+    memtable_factory: Arc<dyn MemTableFactory>,
+    bloom_bits_per_key: usize,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    block_cache_capacity: usize,
+    index_memory_budget: Option<usize>,
 }
 
 impl<T: Compaction + Clone + Send + Sync + 'static, U: TimeStampGenerator + Send + Sync + 'static> LSMTreeConf<T, U> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         compaction: T,
         timestamp_generator: U,
@@ -156,6 +189,87 @@ impl<T: Compaction + Clone + Send + Sync + 'static, U: TimeStampGenerator + Send
             index_interval,
             index_file_suffix,
             enable_compaction,
+            recovery_mode: RecoveryMode::default(),
+            sync_mode: SyncMode::default(),
+            write_stall: WriteStallConf::default(),
+            memtable_factory: Arc::new(SkipListMemTableFactory),
+            bloom_bits_per_key: DEFAULT_BITS_PER_KEY,
+            prefix_extractor: None,
+            block_cache_capacity: DEFAULT_BLOCK_CACHE_CAPACITY,
+            index_memory_budget: None,
+        }
+    }
+
+    pub fn with_recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    pub fn with_sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    pub fn with_write_stall(mut self, write_stall: WriteStallConf) -> Self {
+        self.write_stall = write_stall;
+        self
+    }
+
+    // memtableの実装を選ぶ. デフォルトはskiplist
+    pub fn with_memtable_factory<F: MemTableFactory + 'static>(mut self, memtable_factory: F) -> Self {
+        self.memtable_factory = Arc::new(memtable_factory);
+        self
+    }
+
+    // SSTableごとのBloomフィルタの1キーあたりのビット数. 0ならフィルタを作らない
+    pub fn with_bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    // SSTableにprefixのBloomフィルタも作り、scan_prefixでprefixのないSSTableを飛ばす
+    pub fn with_prefix_extractor<P: PrefixExtractor + 'static>(mut self, prefix_extractor: P) -> Self {
+        self.prefix_extractor = Some(Arc::new(prefix_extractor));
+        self
+    }
+
+    // 全てのSSTableで共有するブロックキャッシュの容量(バイト). 0ならキャッシュしない
+    pub fn with_block_cache_capacity(mut self, block_cache_capacity: usize) -> Self {
+        self.block_cache_capacity = block_cache_capacity;
+        self
+    }
+
+    // メモリに置くSSTableのindexの合計の上限(バイト). 超えたら使われていないindexから捨てる
+    // 指定しなければ、開いたSSTableのindexは全てメモリに置く
+    pub fn with_index_memory_budget(mut self, index_memory_budget: usize) -> Self {
+        self.index_memory_budget = Some(index_memory_budget);
+        self
+    }
+}
+
+// 書き込みごとのオプション
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    // trueならSyncModeに関わらず、この書き込みをfsyncしてから返す
+    pub sync: bool,
+}
+
+// 読み込みごとのオプション
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    // trueならSSTableのblockを読むたびにCRC32Cを確かめ、合わなければエラーにする
+    // キャッシュに入れるblockとindexは、falseでも初めて読むときに必ず確かめる
+    pub verify_checksums: bool,
+    // falseなら読んだblockをブロックキャッシュに入れない. キャッシュにあるblockは使う
+    // 大きなscanで、よく読まれるblockを追い出さないようにする
+    pub fill_cache: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            verify_checksums: true,
+            fill_cache: true,
         }
     }
 }
@@ -166,15 +280,40 @@ where
     T: Compaction,
     U: TimeStampGenerator,
 {
-    memtable: Mutex<MemTable>,
-    commitlog: Mutex<CommitLog>,
+    // スナップショットと共有する. 書き込みはロックを取らずにArcの先に挿入する
+    // memtableとcommitlogはrotation_lockの書き込みロックを取って入れ替える
+    memtable: Mutex<Arc<MemTable>>,
+    // flush中のmemtable. 新しい順
+    // SSTableが書き終わるまではgetやscanはここから読む
+    immutable_memtables: Arc<Mutex<VecDeque<Arc<MemTable>>>>,
+    // SyncMode::Intervalのfsync用のスレッドと共有する
+    commitlog: Arc<Mutex<CommitLog>>,
+    group_commit: GroupCommit,
+    // flushやコンパクションが追いつかないときに書き込みを遅らせる、止める
+    write_controller: WriteController,
+    // 最後に割り当てたsequence. 割り当てとコミットログへの追加はこのロックの中で行い、ログの順番をsequence順にする
+    allocated_sequence: Mutex<u64>,
+    // 最後にmemtableへ反映したレコードのsequence. スナップショットはここまでを読む
+    // memtableへの反映は同時に行い、last_sequenceは前のsequenceが反映されるまでapply_condで待ってから進める
+    last_sequence: AtomicU64,
+    apply_lock: Mutex<()>,
+    apply_cond: Condvar,
+    // 書き込みは読み込みロックを持ったままコミットログへの追加からmemtableへの反映までを行う
+    // memtableとcommitlogの入れ替えは書き込みロックで、途中の書き込みがないときに行う
+    rotation_lock: RwLock<()>,
     memtable_threshold: usize,
+    memtable_factory: Arc<dyn MemTableFactory>,
     index_interval: Arc<usize>,
+    bloom_bits_per_key: usize,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    bloom_filter_counter: BloomFilterCounter,
+    get_stats_counter: GetStatsCounter,
     sst_dir: Arc<String>,
     shared_sstables: Arc<SharedSSTableReader>,
     compaction: T,
-    timestamp_generator: U,
-    rwlock_for_sstable_reader: Arc<RwLock<()>>,
+    // senderを捨てるとコンパクションのスレッドが止まる
+    compaction_thread: Option<(Sender<()>, thread::JoinHandle<()>)>,
+    timestamp_generator: Mutex<U>,
     thread_pool: thread_pool::ThreadPool,
 }
 
@@ -183,33 +322,52 @@ impl<T: Compaction + Clone + Send + Sync + 'static, U: TimeStampGenerator +  Sen
         Self::create_dir(&conf.sst_dir)?;
         Self::create_dir(&conf.commitlog_dir)?;
         let sst_dir = Arc::new(conf.sst_dir.clone());
-        let rwlock_for_sstable_reader = Arc::new(RwLock::new(()));
 
-        let shared_sstable = SharedSSTableReader::new(
+        let shared_sstable = SharedSSTableReader::with_caches(
                 sst_dir.as_ref(),
-                &conf.index_file_suffix
-            );
-        let _ = if conf.enable_compaction {
-            Some(Self::start_compaction_thread(
-                sst_dir.clone(),
+                &conf.index_file_suffix,
+                Arc::new(BlockCache::new(conf.block_cache_capacity)),
+                Arc::new(IndexCache::new(conf.index_memory_budget)),
+            )?;
+        let last_sequence = Self::recover(&conf, &shared_sstable)?.max(Self::max_sequence(&shared_sstable));
+        let compaction_thread = if conf.enable_compaction {
+            let (sender, receiver) = channel();
+            Some((sender, Self::start_compaction_thread(
+                conf.bloom_bits_per_key,
+                conf.prefix_extractor.clone(),
                 conf.compaction.clone(),
-                rwlock_for_sstable_reader.clone(),
                 shared_sstable.clone(),
-            ))
+                receiver,
+            )))
         } else {
             None
         };
+        let commitlog = Arc::new(Mutex::new(CommitLog::with_sync_mode(&conf.commitlog_dir, conf.sync_mode)?));
+        CommitLog::start_interval_syncer(&commitlog)?;
 
         let lsm_tree = LSMTree {
-            memtable: Mutex::new(MemTable::new()),
+            memtable: Mutex::new(Arc::new(MemTable::with_factory(conf.memtable_factory.clone()))),
+            immutable_memtables: Arc::new(Mutex::new(VecDeque::new())),
             memtable_threshold: conf.memtable_threshold,
+            memtable_factory: conf.memtable_factory.clone(),
             index_interval: Arc::new(conf.index_interval),
-            commitlog: Mutex::new(CommitLog::new(&conf.commitlog_dir)?),
+            bloom_bits_per_key: conf.bloom_bits_per_key,
+            prefix_extractor: conf.prefix_extractor.clone(),
+            bloom_filter_counter: BloomFilterCounter::new(),
+            get_stats_counter: GetStatsCounter::new(),
+            commitlog,
+            group_commit: GroupCommit::new(),
+            write_controller: WriteController::new(conf.write_stall),
+            allocated_sequence: Mutex::new(last_sequence),
+            last_sequence: AtomicU64::new(last_sequence),
+            apply_lock: Mutex::new(()),
+            apply_cond: Condvar::new(),
+            rotation_lock: RwLock::new(()),
             shared_sstables: shared_sstable,
-            sst_dir: sst_dir,
+            sst_dir,
             compaction: conf.compaction,
-            timestamp_generator: conf.timestamp_generator,
-            rwlock_for_sstable_reader: rwlock_for_sstable_reader,
+            compaction_thread,
+            timestamp_generator: Mutex::new(conf.timestamp_generator),
             thread_pool: thread_pool::ThreadPool::new(100),
         };
 
@@ -217,35 +375,89 @@ impl<T: Compaction + Clone + Send + Sync + 'static, U: TimeStampGenerator +  Sen
     }
 
     fn start_compaction_thread(
-        sst_dir: Arc<String>,
+        bloom_bits_per_key: usize,
+        prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
         compaction: T,
-        rwlock_for_sstable_reader: Arc<RwLock<()>>,
         shared_sstable: Arc<SharedSSTableReader>,
+        stop: Receiver<()>,
     ) -> thread::JoinHandle<()> {
-        let sst_dir = sst_dir.clone();
-        let rwl = rwlock_for_sstable_reader.clone();
-
         spawn(move || {
             loop {
-                sleep(std::time::Duration::from_secs(1));
-                
-                match SSTableWriter::new(&sst_dir) {
-                    Ok(writer) => {
-                        let _unused = rwl.write().unwrap();
-                        match compaction.compact(
-                            shared_sstable.clone(),
-                            writer
-                        ) {
-                            Ok(_) => println!("compaction completed successfully"),
-                            Err(e) => eprintln!("ERROR: compaction failed: {}", e),
-                        }
-                    },
-                    Err(e) => eprintln!("ERROR: Failed to create SSTableWriter for compaction: {}", e),
+                // LSMTreeがdropされたら止まる
+                match stop.recv_timeout(std::time::Duration::from_secs(1)) {
+                    Err(RecvTimeoutError::Timeout) => {},
+                    _ => return,
+                }
+
+                // getやscanは読み始めたときのバージョンを持つので、コンパクション中も止めない
+                let new_writer = || shared_sstable.new_writer()
+                    .with_bloom_bits_per_key(bloom_bits_per_key)
+                    .with_prefix_extractor(prefix_extractor.clone());
+                match compaction.compact(
+                    shared_sstable.clone(),
+                    &new_writer
+                ) {
+                    Ok(_) => println!("compaction completed successfully"),
+                    Err(e) => eprintln!("ERROR: compaction failed: {}", e),
                 }
             }
         })
     }
 
+    // 前回終了時に残ったコミットログを読み直し、SSTableに書き出してからログを消す
+    // 復元したレコードの最大のsequenceを返す
+    fn recover(conf: &LSMTreeConf<T, U>, shared_sstable: &Arc<SharedSSTableReader>) -> Result<u64, String> {
+        let logs = CommitLog::list_logs(&conf.commitlog_dir)?;
+        let mut last_sequence = 0;
+        if logs.is_empty() {
+            return Ok(last_sequence);
+        }
+
+        let mut memtable = MemTable::with_factory(conf.memtable_factory.clone());
+        for log in logs.iter() {
+            let replayed = CommitLog::replay(log, conf.recovery_mode)?;
+            println!("INFO: replay {} records from {}", replayed.records.len(), log);
+            for record in replayed.records {
+                Self::apply_entries(&memtable, &record.entries, record.sequence);
+                last_sequence = last_sequence.max(record.sequence);
+                if memtable.allocated_bytes() >= conf.memtable_threshold {
+                    Self::write_recovered(conf, shared_sstable, &memtable)?;
+                    memtable = MemTable::with_factory(conf.memtable_factory.clone());
+                }
+            }
+            if replayed.corrupted && conf.recovery_mode == RecoveryMode::TruncateTail {
+                break;
+            }
+        }
+        if !memtable.is_empty() {
+            Self::write_recovered(conf, shared_sstable, &memtable)?;
+        }
+
+        for log in logs.iter() {
+            std::fs::remove_file(log).map_err(|e| e.to_string())?;
+        }
+        Ok(last_sequence)
+    }
+
+    // ログを消す前に、書いたSSTableをMANIFESTに記録する
+    fn write_recovered(conf: &LSMTreeConf<T, U>, shared_sstable: &Arc<SharedSSTableReader>, memtable: &MemTable) -> Result<(), String> {
+        let writer = shared_sstable.new_writer()
+            .with_bloom_bits_per_key(conf.bloom_bits_per_key)
+            .with_prefix_extractor(conf.prefix_extractor.clone());
+        writer.write(memtable, conf.index_interval)?;
+        shared_sstable.install(&writer, &[])
+    }
+
+    // SSTableに書かれている最大のsequence. MANIFESTに記録したものを使う
+    fn max_sequence(shared_sstable: &Arc<SharedSSTableReader>) -> u64 {
+        shared_sstable.current()
+            .files()
+            .iter()
+            .map(|file| file.meta.max_sequence)
+            .max()
+            .unwrap_or(0)
+    }
+
     fn create_dir(path: &str) -> Result<(), String> {
         match std::fs::metadata(path).map(|m| m.is_dir()){
             Ok(false) => {
@@ -264,81 +476,183 @@ impl<T: Compaction + Clone + Send + Sync + 'static, U: TimeStampGenerator +  Sen
         }
     }
 
-    pub fn put(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
-        let timestamp = self.timestamp_generator.get_timestamp();
-        let ret = self.atomic_write_memtable(key, value, timestamp)?;
-        if let Some((memtable, commitlog)) = ret {
-            let dir = self.sst_dir.clone();
-            let index_interval = self.index_interval.clone();
-
-            self.thread_pool.execute(move || {
-                Self::flush_memtable(
-                    dir.as_ref(),
-                    memtable, 
-                    commitlog, 
-                    *index_interval.as_ref(),
-                );
-            });
-        }
-        Ok(())
+    // コミットログへの書き込みに一度失敗すると、開き直すまで以降の書き込みは
+    // group_commit::POISONED_ERROR_PREFIXで始まるエラーになる
+    pub fn put(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), String> {
+        self.put_with_options(key, value, &WriteOptions::default())
     }
 
-    fn atomic_write_memtable(&mut self, key: &str, value: Option<&str>, timestamp: u64) -> Result<Option<(MemTable, CommitLog)>, String> {
-        let mut commitlog = self.commitlog.lock().map_err(|e| e.to_string())?;
-        let mut memtable = self.memtable.lock().map_err(|e| e.to_string())?;
+    // 文字列のキー、値用
+    pub fn put_str(&self, key: &str, value: Option<&str>) -> Result<(), String> {
+        self.put(key.as_bytes(), value.map(|value| value.as_bytes()))
+    }
 
-        let _ = match value {
-            Some(value) => {
-                commitlog.write_put(key, value, timestamp);
-                memtable.put(key, value, timestamp)
-            },
-            None => {
-                commitlog.write_delete(key, timestamp);
-                memtable.delete(key, timestamp)
-            }
+    pub fn put_with_options(&self, key: &[u8], value: Option<&[u8]>, options: &WriteOptions) -> Result<(), String> {
+        let entry = match value {
+            Some(value) => CommitLogEntry::new("PUT", key, Some(value)),
+            None => CommitLogEntry::new("DELETE", key, None),
         };
+        let entries = [entry];
+        self.write_entries(&entries, |sequence, timestamp| CommitLog::encode_record(&entries[0], sequence, timestamp), options)
+    }
 
-        let ret = if memtable.len() >= self.memtable_threshold {
-            let cloned_memtable = memtable.clone();
-            let cloned_commitlog = commitlog.try_clone()?;
-            drop(memtable);
-            self.memtable = Mutex::new(MemTable::new());
-            drop(commitlog);
-            self.commitlog = Mutex::new(CommitLog::new(&cloned_commitlog.get_dir()).unwrap());
+    pub fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.put(key, None)
+    }
 
-            Some((cloned_memtable, cloned_commitlog))
-        } else {
-            None
-        };
+    pub fn delete_str(&self, key: &str) -> Result<(), String> {
+        self.delete(key.as_bytes())
+    }
+
+    // batchを1つのコミットログレコードとして書き込み、memtableにまとめて反映する
+    pub fn write(&self, batch: &WriteBatch) -> Result<(), String> {
+        self.write_with_options(batch, &WriteOptions::default())
+    }
 
-        Ok(ret)
+    pub fn write_with_options(&self, batch: &WriteBatch, options: &WriteOptions) -> Result<(), String> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_entries(batch.entries(), |sequence, timestamp| CommitLog::encode_batch(batch.entries(), sequence, timestamp), options)
+    }
+
+    // encodeでsequenceとtimestampからコミットログのフレームを作る
+    fn write_entries<F>(&self, entries: &[CommitLogEntry], encode: F, options: &WriteOptions) -> Result<(), String>
+    where
+        F: FnOnce(u64, u64) -> Vec<u8>,
+    {
+        self.write_controller.wait(
+            || Ok(self.immutable_memtables.lock().map_err(|e| e.to_string())?.len()),
+            || self.sstable_sizes(),
+        )?;
+
+        let rotation = self.rotation_lock.read().map_err(|e| e.to_string())?;
+        let (sequence, ticket) = self.enqueue(encode, options)?;
+        // 書き込みに失敗しても、後ろのsequenceが待たないように順番だけは進める
+        let committed = self.group_commit.wait(&self.commitlog, ticket);
+        let should_rotate = self.apply_in_order(entries, sequence, committed.is_ok())?;
+        committed?;
+        drop(rotation);
+
+        if should_rotate {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    // sequenceを割り当て、その順番でコミットログのキューに積む
+    fn enqueue<F>(&self, encode: F, options: &WriteOptions) -> Result<(u64, u64), String>
+    where
+        F: FnOnce(u64, u64) -> Vec<u8>,
+    {
+        let mut allocated_sequence = self.allocated_sequence.lock().map_err(|e| e.to_string())?;
+        let sequence = *allocated_sequence + 1;
+        let timestamp = self.timestamp_generator.lock().map_err(|e| e.to_string())?.get_timestamp();
+        let ticket = self.group_commit.enqueue(&encode(sequence, timestamp), options.sync)?;
+        *allocated_sequence = sequence;
+        Ok((sequence, ticket))
+    }
+
+    // memtableに反映し、1つ前のsequenceまで反映されるのを待ってからlast_sequenceを進める
+    // memtableがthresholdを超えたらtrueを返す
+    fn apply_in_order(&self, entries: &[CommitLogEntry], sequence: u64, apply: bool) -> Result<bool, String> {
+        let memtable = self.memtable.lock().map_err(|e| e.to_string())?.clone();
+        if apply {
+            Self::apply_entries(&memtable, entries, sequence);
+        }
+        let mut apply_lock = self.apply_lock.lock().map_err(|e| e.to_string())?;
+        while self.last_sequence.load(Ordering::SeqCst) + 1 < sequence {
+            apply_lock = self.apply_cond.wait(apply_lock).map_err(|e| e.to_string())?;
+        }
+        self.last_sequence.store(sequence, Ordering::SeqCst);
+        self.apply_cond.notify_all();
+        drop(apply_lock);
+        Ok(memtable.allocated_bytes() >= self.memtable_threshold)
+    }
+
+    // memtableとコミットログを新しいものに入れ替え、古い方をflushする
+    fn rotate(&self) -> Result<(), String> {
+        let _rotation = self.rotation_lock.write().map_err(|e| e.to_string())?;
+        let mut memtable = self.memtable.lock().map_err(|e| e.to_string())?;
+        // 他の書き込みが先に入れ替えている
+        if memtable.allocated_bytes() < self.memtable_threshold {
+            return Ok(());
+        }
+        let mut commitlog = self.commitlog.lock().map_err(|e| e.to_string())?;
+        let cloned_memtable = std::mem::replace(&mut *memtable, Arc::new(MemTable::with_factory(self.memtable_factory.clone())));
+        let cloned_commitlog = commitlog.try_clone()?;
+        self.immutable_memtables
+            .lock()
+            .map_err(|e| e.to_string())?
+            .push_front(cloned_memtable.clone());
+        *commitlog = commitlog.rotate()?;
+        drop(commitlog);
+        drop(memtable);
+
+        let shared_sstables = self.shared_sstables.clone();
+        let index_interval = self.index_interval.clone();
+        let bloom_bits_per_key = self.bloom_bits_per_key;
+        let prefix_extractor = self.prefix_extractor.clone();
+        let immutable_memtables = self.immutable_memtables.clone();
+        self.thread_pool.execute(move || {
+            Self::flush_memtable(
+                shared_sstables,
+                cloned_memtable,
+                cloned_commitlog,
+                *index_interval.as_ref(),
+                bloom_bits_per_key,
+                prefix_extractor,
+                immutable_memtables,
+            );
+        });
+        Ok(())
+    }
+
+    fn apply_entries(memtable: &MemTable, entries: &[CommitLogEntry], sequence: u64) {
+        for entry in entries {
+            let _ = match entry.cmd {
+                CommitLogCmd::Put => memtable.put(&entry.key, entry.value.as_deref().unwrap_or_default(), sequence),
+                CommitLogCmd::Delete => memtable.delete(&entry.key, sequence),
+            };
+        }
     }
 
     pub fn launch_compaction(&self) -> Result<(), String> {
-        // let sstables: Vec<SSTableReader> = self.reader_iter().collect();
-        let sstables = self.shared_sstables.to_vec();
-        if sstables.len() <= 1 {
+        if self.shared_sstables.current().len() <= 1 {
             return Ok(());
         }
         
-        let writer = SSTableWriter::new(&self.sst_dir)?;
+        let new_writer = || self.shared_sstables.new_writer()
+            .with_bloom_bits_per_key(self.bloom_bits_per_key)
+            .with_prefix_extractor(self.prefix_extractor.clone());
         self.compaction.compact(
             Arc::clone(&self.shared_sstables),
-            writer,
+            &new_writer,
         )
     }
 
     fn flush_memtable(
-        dir: &String, 
-        memtable: MemTable, 
+        shared_sstables: Arc<SharedSSTableReader>,
+        memtable: Arc<MemTable>, 
         commitlog: CommitLog, 
         index_interval: usize,
+        bloom_bits_per_key: usize,
+        prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+        immutable_memtables: Arc<Mutex<VecDeque<Arc<MemTable>>>>,
     ) {
-        let sstable = SSTableWriter::new(&dir).unwrap();
-        let ret = sstable.write(&memtable, index_interval);
+        let sstable = shared_sstables.new_writer()
+            .with_bloom_bits_per_key(bloom_bits_per_key)
+            .with_prefix_extractor(prefix_extractor);
+        // writeはSSTableをfsyncしてから返り、installでMANIFESTに記録してから読めるようにする
+        // それまではmemtableもコミットログも残しておく
+        let ret = sstable.write(&memtable, index_interval)
+            .and_then(|_| shared_sstables.install(&sstable, &[]));
         match ret {
             Ok(_) => {
                 println!("Flushed memtable");
+                if let Ok(mut immutable_memtables) = immutable_memtables.lock() {
+                    immutable_memtables.retain(|m| !Arc::ptr_eq(m, &memtable));
+                }
                 match commitlog.delete_log() {
                     Err(e) => eprintln!("ERROR: delete {} Error because of: {}", commitlog.get_file_path(), e),
                     _ => println!("INFO: {} is deleted", commitlog.get_file_path()),
@@ -348,74 +662,263 @@ impl<T: Compaction + Clone + Send + Sync + 'static, U: TimeStampGenerator +  Sen
         }
     }
 
-    pub fn get(&self, key: &str) -> Result<Option<Value>, String> {
-        let value = {
-            self.memtable.lock().map_err(|e| e.to_string()).and_then(|memtable| {
-                Ok(memtable.get(key))
-            })?
-        };
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>, String> {
+        self.get_with_options(key, &ReadOptions::default())
+    }
+
+    pub fn get_with_options(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Value>, String> {
+        match Self::get_from_memtables(self.memtables()?.iter(), key, u64::MAX) {
+            Some(value) => Ok(value),
+            None => self.get_from_sstable(key, u64::MAX, options),
+        }
+    }
+
+    // アクティブなmemtableとflush中のmemtable. 新しい順
+    fn memtables(&self) -> Result<Vec<Arc<MemTable>>, String> {
+        let mut memtables = vec![self.memtable.lock().map_err(|e| e.to_string())?.clone()];
+        memtables.extend(self.immutable_memtables.lock().map_err(|e| e.to_string())?.iter().cloned());
+        Ok(memtables)
+    }
+
+    // 新しいmemtableから順にsequence以下のバージョンを探し、最初に見つかったものを返す
+    // 見つからなければNone, Tombstoneが見つかればSome(None)
+    fn get_from_memtables<'a>(memtables: impl Iterator<Item = &'a Arc<MemTable>>, key: &[u8], sequence: u64) -> Option<Option<Value>> {
+        for memtable in memtables {
+            match memtable.get_at(key, sequence) {
+                Some(memtable::Value::Data(value, _)) => return Some(Some(value)),
+                Some(memtable::Value::Tombstone(_)) => return Some(None),
+                None => continue,
+            }
+        }
+        None
+    }
+
+    // 文字列のキー、値用. 値がUTF-8でなければエラーになる
+    pub fn get_str(&self, key: &str) -> Result<Option<String>, String> {
+        Self::to_string_value(self.get(key.as_bytes())?)
+    }
+
+    // 今の状態を読むスナップショットを作る
+    pub fn snapshot(&self) -> Result<Snapshot, String> {
+        // memtableのロック中に取り、入れ替えの途中が見えないようにする
+        // memtableにはこの後も書き込まれるが、sequenceより新しいバージョンは読まない
+        let memtable = self.memtable.lock().map_err(|e| e.to_string())?;
+        let immutable_memtables = self.immutable_memtables
+            .lock()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .cloned()
+            .collect();
+        let sequence = self.last_sequence.load(Ordering::SeqCst);
+        Ok(Snapshot::new(
+            sequence,
+            Arc::clone(&memtable),
+            immutable_memtables,
+            self.shared_sstables.snapshots(),
+        ))
+    }
+
+    // snapshotを作った時点の値を返す
+    pub fn get_at(&self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Value>, String> {
+        self.get_at_with_options(snapshot, key, &ReadOptions::default())
+    }
+
+    pub fn get_at_with_options(&self, snapshot: &Snapshot, key: &[u8], options: &ReadOptions) -> Result<Option<Value>, String> {
+        match Self::get_from_memtables(snapshot.memtables(), key, snapshot.sequence()) {
+            Some(value) => Ok(value),
+            None => self.get_from_sstable(key, snapshot.sequence(), options),
+        }
+    }
+
+    pub fn get_str_at(&self, snapshot: &Snapshot, key: &str) -> Result<Option<String>, String> {
+        Self::to_string_value(self.get_at(snapshot, key.as_bytes())?)
+    }
+
+    fn to_string_value(value: Option<Value>) -> Result<Option<String>, String> {
+        value
+            .map(|value| String::from_utf8(value).map_err(|e| e.to_string()))
+            .transpose()
+    }
+
+    // rangeに含まれるキーをキー順に返す
+    pub fn scan<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> Result<ScanIterator, String> {
+        self.scan_with_options(range, &ReadOptions::default())
+    }
+
+    pub fn scan_with_options<'a, R: RangeBounds<&'a [u8]>>(&self, range: R, options: &ReadOptions) -> Result<ScanIterator, String> {
+        self.scan_impl(self.memtables()?, u64::MAX, range, None, options)
+    }
+
+    // snapshotを作った時点の状態をscanする
+    pub fn scan_at<'a, R: RangeBounds<&'a [u8]>>(&self, snapshot: &Snapshot, range: R) -> Result<ScanIterator, String> {
+        self.scan_at_with_options(snapshot, range, &ReadOptions::default())
+    }
 
-        match value {
-            Some(value) => {
-                match value {
-                    memtable::Value::Data(value, _) => Ok(Some(value.to_string())),
-                    memtable::Value::Tombstone(_) => Ok(None),
+    pub fn scan_at_with_options<'a, R: RangeBounds<&'a [u8]>>(&self, snapshot: &Snapshot, range: R, options: &ReadOptions) -> Result<ScanIterator, String> {
+        let memtables = snapshot.memtables().cloned().collect();
+        self.scan_impl(memtables, snapshot.sequence(), range, None, options)
+    }
+
+    // prefixで始まるキーをキー順に返す
+    // PrefixExtractorのprefixそのものなら、prefixのBloomフィルタがないと答えたSSTableは読まない
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<ScanIterator, String> {
+        self.scan_prefix_with_options(prefix, &ReadOptions::default())
+    }
+
+    pub fn scan_prefix_with_options(&self, prefix: &[u8], options: &ReadOptions) -> Result<ScanIterator, String> {
+        let upper = prefix_upper_bound(prefix);
+        let range = (Bound::Included(prefix), upper.as_deref().map_or(Bound::Unbounded, Bound::Excluded));
+        self.scan_impl(self.memtables()?, u64::MAX, range, Some(prefix), options)
+    }
+
+    // memtablesは新しい順
+    fn scan_impl<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        memtables: Vec<Arc<MemTable>>,
+        sequence: u64,
+        range: R,
+        prefix: Option<&[u8]>,
+        options: &ReadOptions,
+    ) -> Result<ScanIterator, String> {
+        let lower = range.start_bound().map(|key| key.to_vec());
+        let upper = range.end_bound().map(|key| key.to_vec());
+        let mut cursors: Vec<Box<dyn ScanCursor>> = vec![];
+
+        // memtableは取り出しておかず、カーソルが進むたびに引く
+        for memtable in memtables {
+            cursors.push(Box::new(MemTableCursor::new(memtable, lower.clone(), upper.clone())));
+        }
+
+        // 各iteratorがreaderを持つので、コンパクションで外されても読み終わるまで消えない
+        let prefix_filter = self.prefix_filter(prefix);
+        for reader in self.readers() {
+            if let Some((name, prefix)) = &prefix_filter {
+                if let Some(may_contain) = reader.may_contain_prefix(name, prefix)? {
+                    self.bloom_filter_counter.record_prefix(may_contain);
+                    if !may_contain {
+                        continue;
+                    }
                 }
-            },
-            None => {
-                self.get_from_sstable(key)
             }
+            cursors.push(Box::new(SSTableIterator::new(reader, lower.clone(), upper.clone(), *options)?));
         }
+
+        Ok(ScanIterator::with_sequence(cursors, sequence))
     }
 
+    // prefixがPrefixExtractorで取り出したprefixそのものなら、フィルタを引くための(name, prefix)
+    // 短い、長いprefixはフィルタに入っていないので引けない
+    fn prefix_filter<'a>(&self, prefix: Option<&'a [u8]>) -> Option<(String, &'a [u8])> {
+        let prefix = prefix?;
+        let extractor = self.prefix_extractor.as_ref()?;
+        (extractor.prefix(prefix) == Some(prefix)).then(|| (extractor.name(), prefix))
+    }
+
+    // sequence以下のバージョンだけを見る
+    // SSTableを新しい順に読み、残りのSSTableに見つけたものより新しいバージョンがなければ打ち切る
     fn get_from_sstable(
         &self, 
-        key: &str
+        key: &[u8],
+        sequence: u64,
+        options: &ReadOptions,
     ) -> Result<Option<Value>, String> {
-        let mut candidate = vec![];
-        let rwlock = self.rwlock_for_sstable_reader.read().map_err(|e| e.to_string())?;
-        for reader in self.readers() {
-            match reader.read(key) {
-                Ok(None) => continue,
-                Ok(value) => {
-                    candidate.push(value.unwrap());
+        let mut found: Option<(Option<Value>, u64)> = None;
+        let (mut lookups, mut key_range_skipped, mut early_stopped) = (0, 0, false);
+        // 読み終わるまでバージョンを持ち、途中でコンパクションされても同じSSTableを読む
+        let version = self.shared_sstables.current();
+        for file in version.files() {
+            let (meta, reader) = (&file.meta, &file.reader);
+            // コンパクションしたSSTableはsequenceの範囲が他と重なるので、max_sequenceで比べる
+            if found.as_ref().is_some_and(|(_, found)| meta.max_sequence <= *found) {
+                early_stopped = true;
+                break;
+            }
+            if key < meta.smallest_key.as_slice() || meta.largest_key.as_slice() < key {
+                key_range_skipped += 1;
+                continue;
+            }
+            // フィルタがないと答えたSSTableは読まない
+            let may_contain = reader.may_contain(key)?;
+            if let Some(may_contain) = may_contain {
+                self.bloom_filter_counter.record(may_contain);
+                if !may_contain {
+                    continue;
+                }
+            }
+            lookups += 1;
+            match reader.read_at(key, sequence, options)? {
+                None => {
+                    if may_contain.is_some() {
+                        self.bloom_filter_counter.record_false_positive();
+                    }
                 },
-                Err(e) => {
-                    dbg!("ERROR: get_from_sstable Error because of: {}", &e);
-                    return Err(e)
+                // Tombstoneも見つかったバージョンとして扱う
+                Some(value) => {
+                    if found.as_ref().is_none_or(|(_, found)| *found < value.1) {
+                        found = Some(value);
+                    }
                 },
             }
         }
-        drop(rwlock);
-
-        if candidate.is_empty() {
-            return Ok(None);
-        }
-        candidate.sort_by(|a, b| {
-            let a = a.1;
-            let b = b.1;
-            if a == b {
-                return std::cmp::Ordering::Equal;
-            }
-            if a > b {
-                return std::cmp::Ordering::Greater;
-            }
-            std::cmp::Ordering::Less
-        });
-        let last = candidate.last().unwrap();
-        dbg!(last);
-        Ok(candidate.last().unwrap().0.clone())
+        self.get_stats_counter.record(lookups, key_range_skipped, early_stopped);
+        Ok(found.and_then(|(value, _)| value))
     }
 
     pub fn get_memtable(&self) -> MemTable {
         let memtable = self.memtable.lock().map_err(|e| e.to_string()).unwrap();
-        memtable.clone()
+        memtable.as_ref().clone()
     }
 
     pub fn get_sst_dir(&self) -> &str {
         &self.sst_dir
     }
 
+    pub fn get_stats(&self) -> GetStats {
+        self.get_stats_counter.stats(self.shared_sstables.current().len() as u64)
+    }
+
+    pub fn bloom_filter_stats(&self) -> BloomFilterStats {
+        self.bloom_filter_counter.stats()
+    }
+
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.shared_sstables.block_cache_stats()
+    }
+
+    pub fn index_cache_stats(&self) -> IndexCacheStats {
+        self.shared_sstables.index_cache_stats()
+    }
+
+    // よく読むSSTableのindexを、with_index_memory_budgetの上限を超えても捨てないようにする
+    pub fn pin_index(&self, file: &str) -> Result<(), String> {
+        self.shared_sstables.pin_index(file)
+    }
+
+    pub fn unpin_index(&self, file: &str) -> Result<(), String> {
+        self.shared_sstables.unpin_index(file)
+    }
+
+    pub fn group_commit_stats(&self) -> GroupCommitStats {
+        self.group_commit.stats()
+    }
+
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.write_controller.stats()
+    }
+
+    // SSTableの数と、コンパクションで書き直す必要のあるバイト数
+    // 全てが1つのSSTableにまとまるまでに、一番大きいもの以外は書き直されるとみなす
+    fn sstable_sizes(&self) -> Result<(usize, u64), String> {
+        let sizes: Vec<u64> = self.shared_sstables.current()
+            .files()
+            .iter()
+            .map(|file| file.meta.size)
+            .collect();
+        let total: u64 = sizes.iter().sum();
+        let largest = sizes.iter().max().copied().unwrap_or(0);
+        Ok((sizes.len(), total - largest))
+    }
+
     pub fn get_commitlog(&self) -> CommitLog {
         let commitlog = self.commitlog.lock().map_err(|e| e.to_string()).unwrap();
         commitlog.try_clone().unwrap()
@@ -430,6 +933,18 @@ impl<T: Compaction + Clone + Send + Sync + 'static, U: TimeStampGenerator +  Sen
     }
 }
 
+impl<T: Compaction, U: TimeStampGenerator> Drop for LSMTree<T, U> {
+    fn drop(&mut self) {
+        // 実行中のコンパクションが終わるのを待つ
+        if let Some((stop, handle)) = self.compaction_thread.take() {
+            drop(stop);
+            if handle.join().is_err() {
+                eprintln!("ERROR: compaction thread panicked");
+            }
+        }
+    }
+}
+
 pub trait TimeStampGenerator {
     fn get_timestamp(&mut self) -> u64;
 }
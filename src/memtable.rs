@@ -1,121 +1,323 @@
-use std::{collections::BTreeMap, fmt::Display};
+mod arena;
+pub mod btree;
+pub mod hash;
+pub mod skiplist;
 
-type Key = String;
+use std::{cmp::Ordering, fmt::{Debug, Display}, ops::Bound, sync::Arc};
+
+use skiplist::SkipListMemTableFactory;
+
+use crate::sstable::RecordType;
+
+type Key = Vec<u8>;
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
-    Data(String, u64), // (value, timestamp)
-    /*
-    Tombstone: 削除されたデータを表す
-        1, 0, 0, 0, 0, 0, 0, 0, // key_len: 1
-        0,
-    empty string:
-        0, 0, 0, 0, 0, 0, 0, 0, // key_len: 0
-     */
+    Data(Vec<u8>, u64), // (value, sequence)
+    // Tombstone: 削除されたデータを表す. 書き込むときは種類で区別する
     Tombstone(u64),
 }
 
+impl Value {
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Value::Data(_, sequence) | Value::Tombstone(sequence) => *sequence,
+        }
+    }
+}
+
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Value::Data(value, timestamp) => write!(f, "value: {}, timestamp: {}", value, timestamp),
-            Value::Tombstone(timestamp) => write!(f, "Tombstone, timestamp: {}", timestamp),
+            Value::Data(value, sequence) => write!(f, "value: {}, sequence: {}", String::from_utf8_lossy(value), sequence),
+            Value::Tombstone(sequence) => write!(f, "Tombstone, sequence: {}", sequence),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/*
+memtableの実装が満たすべきもの
+同じキーでもsequenceが違えば別のバージョンとして持ち、同じキーとsequenceなら置き換える
+putやdeleteは&selfで複数のスレッドから同時に呼ばれる
+*/
+pub trait MemTableRep: Send + Sync {
+    // 同じキーとsequenceがあれば置き換え、前の値を返す
+    fn put(&self, key: &[u8], value: &[u8], sequence: u64) -> Option<Value>;
+
+    fn delete(&self, key: &[u8], sequence: u64) -> Option<Value>;
+
+    // sequence以下で一番新しいバージョン
+    fn get_at(&self, key: &[u8], sequence: u64) -> Option<Value>;
+
+    // 一番新しいバージョン
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        self.get_at(key, u64::MAX)
+    }
+
+    // 全バージョンを(キーの昇順, sequenceの降順)で返す
+    fn iter(&self) -> Box<dyn Iterator<Item = (Key, Value)> + '_>;
+
+    /*
+    iterと同じ順で、(key, sequence)のtarget以上(Excludedなら超える)の最初のバージョン
+    scanのカーソルは位置だけを持ち、進むたびにこれを引く
+    デフォルトは先頭からたどるので、実装ごとに探す
+    */
+    fn next_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, Value)> {
+        self.iter().find(|(key, value)| match target {
+            Bound::Included(target) => compare_version(key, value.sequence(), target) != Ordering::Less,
+            Bound::Excluded(target) => compare_version(key, value.sequence(), target) == Ordering::Greater,
+            Bound::Unbounded => true,
+        })
+    }
+
+    // iterと同じ順で、(key, sequence)のtarget以下(Excludedなら未満)の最後のバージョン
+    fn prev_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, Value)> {
+        self.iter()
+            .take_while(|(key, value)| match target {
+                Bound::Included(target) => compare_version(key, value.sequence(), target) != Ordering::Greater,
+                Bound::Excluded(target) => compare_version(key, value.sequence(), target) == Ordering::Less,
+                Bound::Unbounded => true,
+            })
+            .last()
+    }
+
+    // 全バージョンのキーと値とsequenceの合計バイト数
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // 実際に確保しているバイト数
+    fn memory_usage(&self) -> usize {
+        self.len()
+    }
+
+    // 書き込みで使ったバイト数. ノードやタワーの分も含み、memtable_thresholdと比べる
+    fn allocated_bytes(&self) -> usize {
+        self.len()
+    }
+
+    // SSTableのレコードの形式で並べる
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (key, value) in self.iter() {
+            let (value, sequence) = match value {
+                Value::Data(value, sequence) => (Some(value), sequence),
+                Value::Tombstone(sequence) => (None, sequence),
+            };
+            buf.extend_from_slice(
+                &MemTable::encode_key_value(&key, value.as_deref(), sequence),
+            );
+        }
+        buf
+    }
+}
+
+// LSMTreeがmemtableを作り直すたびに呼ぶ
+pub trait MemTableFactory: Send + Sync + Debug {
+    fn create(&self) -> Box<dyn MemTableRep>;
+}
+
+// (キーの昇順, sequenceの降順)で比べる
+pub(crate) fn compare_version(key: &[u8], sequence: u64, target: (&[u8], u64)) -> Ordering {
+    key.cmp(target.0).then(target.1.cmp(&sequence))
+}
+
+// キーの範囲の下限を、全バージョンを含むバージョンの下限にする
+pub(crate) fn lower_version_bound(lower: Bound<&[u8]>) -> Bound<(&[u8], u64)> {
+    match lower {
+        Bound::Included(key) => Bound::Included((key, u64::MAX)),
+        Bound::Excluded(key) => Bound::Excluded((key, 0)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// キーの範囲の上限を、全バージョンを含むバージョンの上限にする
+pub(crate) fn upper_version_bound(upper: Bound<&[u8]>) -> Bound<(&[u8], u64)> {
+    match upper {
+        Bound::Included(key) => Bound::Included((key, 0)),
+        Bound::Excluded(key) => Bound::Excluded((key, u64::MAX)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+pub(crate) fn is_below_upper(key: &[u8], upper: Bound<&[u8]>) -> bool {
+    match upper {
+        Bound::Included(upper) => key <= upper,
+        Bound::Excluded(upper) => key < upper,
+        Bound::Unbounded => true,
+    }
+}
+
+pub(crate) fn is_above_lower(key: &[u8], lower: Bound<&[u8]>) -> bool {
+    match lower {
+        Bound::Included(lower) => key >= lower,
+        Bound::Excluded(lower) => key > lower,
+        Bound::Unbounded => true,
+    }
+}
+
+// lenで数える1バージョンの大きさ
+pub(crate) fn entry_size(key: &[u8], value_len: usize) -> usize {
+    key.len() + std::mem::size_of::<u64>() + value_len // key_len + sequence + value_len
+}
+
+// factoryで作った実装を包む. デフォルトはskiplist
 pub struct MemTable {
-    data: BTreeMap<Key, Value>, 
+    rep: Box<dyn MemTableRep>,
+    factory: Arc<dyn MemTableFactory>,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemTable {
     pub fn new() -> MemTable {
+        Self::with_factory(Arc::new(SkipListMemTableFactory))
+    }
+
+    pub fn with_factory(factory: Arc<dyn MemTableFactory>) -> MemTable {
         MemTable {
-            data: BTreeMap::new(),
+            rep: factory.create(),
+            factory,
         }
     }
 
-    pub fn put(&mut self, key: &str, value: &str, timestamp: u64) -> Option<Value> {
-        self.data.insert(key.to_string(), Value::Data(value.to_string(), timestamp))
+    pub fn put(&self, key: &[u8], value: &[u8], sequence: u64) -> Option<Value> {
+        self.rep.put(key, value, sequence)
     }
 
-    pub fn delete(&mut self, key: &str, timestamp: u64) -> Option<Value> {
-        self.data.insert(key.to_string(), Value::Tombstone(timestamp))
+    pub fn delete(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        self.rep.delete(key, sequence)
     }
 
-    pub fn get(&self, key: &str) -> Option<Value> {
-        self.data.get(key).map(|value| {
-            value.clone()
-        })
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        self.rep.get(key)
+    }
+
+    pub fn get_at(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        self.rep.get_at(key, sequence)
     }
 
     pub fn clear(&mut self) {
-        self.data.clear();
+        self.rep = self.factory.create();
     }
 
     pub fn len(&self) -> usize {
-        self.data.iter().map(|(key, value)| {
-            let key_len = key.len() + std::mem::size_of::<u64>(); // key_len + timestamp
-            match value {
-                Value::Data(value, _) => key_len + value.len(),
-                Value::Tombstone(_) => key_len,
-            }
-        }).sum()
+        self.rep.len()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.rep.memory_usage()
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.rep.allocated_bytes()
     }
 
-    // Memtableの責務かどうかは微妙
     pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        for (key, value) in self.data.iter() {
-            let (value, timestamp) = match value {
-                Value::Data(value, timestamp) => (Some(value), *timestamp),
-                Value::Tombstone(timestamp) => (None, *timestamp),
-            };
-            buf.extend_from_slice(
-                &Self::encode_key_value(key, value.as_deref().map(|x| x.as_str()), timestamp),
-            );
-        }
-        buf
+        self.rep.encode()
     }
 
-    pub fn encode_key_value(key: &str, value: Option<&str>, timestamp: u64) -> Vec<u8> {
+    // SSTableRecordと同じ形式. Tombstoneは値長0で書く
+    pub fn encode_key_value(key: &[u8], value: Option<&[u8]>, sequence: u64) -> Vec<u8> {
+        let (record_type, value) = match value {
+            Some(value) => (RecordType::Value, value),
+            None => (RecordType::Tombstone, [].as_slice()),
+        };
         let mut buf = Vec::new();
         buf.extend_from_slice(&key.len().to_ne_bytes());
-        buf.extend_from_slice(key.as_bytes());
-        if let Some(value) = value {
-            buf.extend_from_slice(&value.len().to_ne_bytes());
-            buf.extend_from_slice(value.as_bytes());
-        } else {
-            buf.extend_from_slice(&1usize.to_ne_bytes());
-            buf.extend_from_slice(&[0]);
-        }
-        buf.extend_from_slice(&timestamp.to_ne_bytes());
+        buf.extend_from_slice(key);
+        buf.push(record_type as u8);
+        buf.extend_from_slice(&value.len().to_ne_bytes());
+        buf.extend_from_slice(value);
+        buf.extend_from_slice(&sequence.to_ne_bytes());
         buf
     }
 
-    pub fn iter(&self) -> MemtableIterator {
+    pub fn iter(&self) -> MemtableIterator<'_> {
         MemtableIterator {
-            iter: self.data.iter(),
+            iter: self.rep.iter(),
         }
     }
 
+    // lowerからupperまでの範囲をiterと同じ順で、進むたびに1つずつ取り出す
+    pub fn range<'a>(&'a self, lower: Bound<&'a [u8]>, upper: Bound<&'a [u8]>) -> MemtableIterator<'a> {
+        let mut last: Option<(Key, u64)> = None;
+        let iter = std::iter::from_fn(move || {
+            let target = match &last {
+                Some((key, sequence)) => Bound::Excluded((key.as_slice(), *sequence)),
+                None => lower_version_bound(lower),
+            };
+            let entry = self.rep.next_entry(target).filter(|(key, _)| is_below_upper(key, upper))?;
+            last = Some((entry.0.clone(), entry.1.sequence()));
+            Some(entry)
+        });
+        MemtableIterator {
+            iter: Box::new(iter),
+        }
+    }
+
+    pub fn next_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, Value)> {
+        self.rep.next_entry(target)
+    }
+
+    pub fn prev_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, Value)> {
+        self.rep.prev_entry(target)
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
+        self.rep.is_empty()
+    }
+}
+
+// 同じfactoryで作ったmemtableに全バージョンを写す
+impl Clone for MemTable {
+    fn clone(&self) -> Self {
+        let memtable = MemTable::with_factory(self.factory.clone());
+        for (key, value) in self.iter() {
+            match value {
+                Value::Data(value, sequence) => memtable.put(&key, &value, sequence),
+                Value::Tombstone(sequence) => memtable.delete(&key, sequence),
+            };
+        }
+        memtable
+    }
+}
+
+impl PartialEq for MemTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for MemTable {}
+
+impl Debug for MemTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemTable")
+            .field("factory", &self.factory)
+            .field("len", &self.len())
+            .field("memory_usage", &self.memory_usage())
+            .field("allocated_bytes", &self.allocated_bytes())
+            .finish()
     }
 }
 
 pub struct MemtableIterator<'a> {
-    pub iter: std::collections::btree_map::Iter<'a, Key, Value>,
+    iter: Box<dyn Iterator<Item = (Key, Value)> + 'a>,
 }
 
-impl<'a> Iterator for MemtableIterator<'a> {
+impl Iterator for MemtableIterator<'_> {
     type Item = (Key, Value);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|(key, value)| (key.clone(), value.clone()))
+        self.iter.next()
     }
 }
 
-
 #[cfg(test)]
 mod tests;
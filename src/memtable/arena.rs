@@ -0,0 +1,115 @@
+use std::{ptr, sync::{atomic::{AtomicPtr, AtomicUsize, Ordering}, Mutex}};
+
+const BLOCK_SIZE: usize = 64 * 1024;
+const ALIGN: usize = std::mem::align_of::<u64>();
+
+struct Block {
+    // u64で確保して8バイト境界に揃える
+    data: *mut u64,
+    len: usize,
+    offset: AtomicUsize,
+}
+
+impl Block {
+    fn new(size: usize) -> Block {
+        let data = vec![0u64; size.div_ceil(ALIGN)].into_boxed_slice();
+        let len = data.len();
+        Block {
+            data: Box::into_raw(data) as *mut u64,
+            len,
+            offset: AtomicUsize::new(0),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.len * ALIGN
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        // SAFETY: dataはBlock::newでBox<[u64]>から作ったもの
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(self.data, self.len)) });
+    }
+}
+
+/*
+skiplistのノード、キー、値を置く領域
+確保はブロック内のoffsetをfetch_addで進めるだけで、ブロックが足りなくなったときだけロックを取る
+確保した領域はArenaがdropされるまで解放されず、動かない
+*/
+pub struct Arena {
+    current: AtomicPtr<Block>,
+    // currentが指す先が動かないようにBoxで持つ
+    #[allow(clippy::vec_box)]
+    blocks: Mutex<Vec<Box<Block>>>,
+    // 確保したブロックの合計サイズ
+    memory_usage: AtomicUsize,
+    // allocateで渡した領域の合計サイズ. ブロックの使われていない部分は含まない
+    allocated: AtomicUsize,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        let mut block = Box::new(Block::new(BLOCK_SIZE));
+        let current = AtomicPtr::new(block.as_mut() as *mut Block);
+        Arena {
+            current,
+            blocks: Mutex::new(vec![block]),
+            memory_usage: AtomicUsize::new(BLOCK_SIZE),
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    // 8バイト境界に揃ったsizeバイトの領域を返す. 中身は0で埋まっている
+    pub fn allocate(&self, size: usize) -> *mut u8 {
+        let size = size.div_ceil(ALIGN).max(1) * ALIGN;
+        loop {
+            let block = self.current.load(Ordering::Acquire);
+            // SAFETY: currentはblocksが持っているブロックを指し、Arenaが生きている間は解放されない
+            let block_ref = unsafe { &*block };
+            let offset = block_ref.offset.fetch_add(size, Ordering::Relaxed);
+            if offset + size <= block_ref.size() {
+                self.allocated.fetch_add(size, Ordering::Relaxed);
+                // SAFETY: offset..offset+sizeはブロックの範囲内で、他のスレッドに渡されることはない
+                return unsafe { (block_ref.data as *mut u8).add(offset) };
+            }
+
+            let mut blocks = self.blocks.lock().unwrap();
+            // 他のスレッドが先に新しいブロックを作っていれば、そちらから確保し直す
+            if self.current.load(Ordering::Acquire) != block {
+                continue;
+            }
+            let mut next = Box::new(Block::new(BLOCK_SIZE.max(size)));
+            self.memory_usage.fetch_add(next.size(), Ordering::Relaxed);
+            self.current.store(next.as_mut() as *mut Block, Ordering::Release);
+            blocks.push(next);
+        }
+    }
+
+    // bytesをコピーした領域を返す
+    pub fn allocate_bytes(&self, bytes: &[u8]) -> *const u8 {
+        let dst = self.allocate(bytes.len());
+        // SAFETY: dstはbytes.len()バイト以上あり、bytesとは重ならない
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len()) };
+        dst
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// SAFETY: ブロックはArenaの中でしか解放されず、確保は原子的に行う
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
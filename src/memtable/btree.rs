@@ -0,0 +1,107 @@
+use std::{cmp::Reverse, collections::BTreeMap, ops::Bound, sync::RwLock};
+
+use super::{entry_size, MemTableFactory, MemTableRep, Value};
+
+// (キー, sequenceの降順)
+type VersionedKey = (Vec<u8>, Reverse<u64>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    // 値がNoneならTombstone
+    data: BTreeMap<VersionedKey, Option<Vec<u8>>>,
+    size: usize,
+}
+
+/*
+BTreeMapのmemtable
+書き込みはロックで直列化されるが、範囲の読み出しは木をそのままたどれる
+iterは読み込みロックの中で取り出したものを返し、scanは1つずつ木を引く
+*/
+#[derive(Debug, Default)]
+pub struct BTreeMemTable {
+    inner: RwLock<Inner>,
+}
+
+impl BTreeMemTable {
+    pub fn new() -> BTreeMemTable {
+        Self::default()
+    }
+
+    fn insert(&self, key: &[u8], value: Option<&[u8]>, sequence: u64) -> Option<Value> {
+        let mut inner = self.inner.write().unwrap();
+        inner.size += entry_size(key, value.map_or(0, |value| value.len()));
+        let replaced = inner.data.insert((key.to_vec(), Reverse(sequence)), value.map(|value| value.to_vec()))?;
+        inner.size -= entry_size(key, replaced.as_ref().map_or(0, |value| value.len()));
+        Some(to_value(replaced, sequence))
+    }
+}
+
+fn to_value(value: Option<Vec<u8>>, sequence: u64) -> Value {
+    match value {
+        Some(value) => Value::Data(value, sequence),
+        None => Value::Tombstone(sequence),
+    }
+}
+
+fn to_versioned_key(bound: Bound<(&[u8], u64)>) -> Bound<VersionedKey> {
+    bound.map(|(key, sequence)| (key.to_vec(), Reverse(sequence)))
+}
+
+impl MemTableRep for BTreeMemTable {
+    fn put(&self, key: &[u8], value: &[u8], sequence: u64) -> Option<Value> {
+        self.insert(key, Some(value), sequence)
+    }
+
+    fn delete(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        self.insert(key, None, sequence)
+    }
+
+    fn get_at(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        let inner = self.inner.read().unwrap();
+        let ((found, Reverse(sequence)), value) = inner.data
+            .range((key.to_vec(), Reverse(sequence))..)
+            .next()?;
+        if found.as_slice() != key {
+            return None;
+        }
+        Some(to_value(value.clone(), *sequence))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Value)> + '_> {
+        let inner = self.inner.read().unwrap();
+        let entries: Vec<_> = inner.data
+            .iter()
+            .map(|((key, Reverse(sequence)), value)| (key.clone(), to_value(value.clone(), *sequence)))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn next_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Vec<u8>, Value)> {
+        let inner = self.inner.read().unwrap();
+        let ((key, Reverse(sequence)), value) = inner.data
+            .range((to_versioned_key(target), Bound::Unbounded))
+            .next()?;
+        Some((key.clone(), to_value(value.clone(), *sequence)))
+    }
+
+    fn prev_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Vec<u8>, Value)> {
+        let inner = self.inner.read().unwrap();
+        let ((key, Reverse(sequence)), value) = inner.data
+            .range((Bound::Unbounded, to_versioned_key(target)))
+            .next_back()?;
+        Some((key.clone(), to_value(value.clone(), *sequence)))
+    }
+
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().size
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BTreeMemTableFactory;
+
+impl MemTableFactory for BTreeMemTableFactory {
+    fn create(&self) -> Box<dyn MemTableRep> {
+        Box::new(BTreeMemTable::new())
+    }
+}
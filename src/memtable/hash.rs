@@ -0,0 +1,165 @@
+use std::{collections::HashMap, ops::Bound, sync::{Arc, RwLock}};
+
+use super::{entry_size, Key, MemTableFactory, MemTableRep, Value};
+
+// (sequence, 値)をsequenceの降順に並べたもの. 値がNoneならTombstone
+type Versions = Vec<(u64, Option<Vec<u8>>)>;
+
+#[derive(Debug, Default)]
+struct Inner {
+    data: HashMap<Vec<u8>, Versions>,
+    size: usize,
+    // 並べ替えたキー. 新しいキーが入ったら捨て、次にscanで引いたときに並べ直す
+    sorted_keys: Option<Arc<Vec<Vec<u8>>>>,
+}
+
+/*
+HashMapのmemtable
+キーを並べずに持つので、getはキーの数によらず速い
+iterは呼ばれるたびにキーを並べ替えるので、flushは遅い
+scanは並べ替えたキーを新しいキーが入るまで使い回し、値は1つずつ引く
+*/
+#[derive(Debug, Default)]
+pub struct HashMemTable {
+    inner: RwLock<Inner>,
+}
+
+impl HashMemTable {
+    pub fn new() -> HashMemTable {
+        Self::default()
+    }
+
+    fn insert(&self, key: &[u8], value: Option<&[u8]>, sequence: u64) -> Option<Value> {
+        let mut inner = self.inner.write().unwrap();
+        inner.size += entry_size(key, value.map_or(0, |value| value.len()));
+        let value = value.map(|value| value.to_vec());
+        if !inner.data.contains_key(key) {
+            inner.sorted_keys = None;
+        }
+        let versions = inner.data.entry(key.to_vec()).or_default();
+        let replaced = match versions.binary_search_by(|(s, _)| sequence.cmp(s)) {
+            Ok(i) => Some(std::mem::replace(&mut versions[i].1, value)),
+            Err(i) => {
+                versions.insert(i, (sequence, value));
+                None
+            },
+        }?;
+        inner.size -= entry_size(key, replaced.as_ref().map_or(0, |value| value.len()));
+        Some(to_value(replaced, sequence))
+    }
+
+    fn sorted_keys(&self) -> Arc<Vec<Vec<u8>>> {
+        if let Some(sorted_keys) = &self.inner.read().unwrap().sorted_keys {
+            return sorted_keys.clone();
+        }
+        let mut inner = self.inner.write().unwrap();
+        let Inner { data, sorted_keys, .. } = &mut *inner;
+        sorted_keys.get_or_insert_with(|| {
+            let mut keys: Vec<Vec<u8>> = data.keys().cloned().collect();
+            keys.sort_unstable();
+            Arc::new(keys)
+        }).clone()
+    }
+}
+
+fn to_value(value: Option<Vec<u8>>, sequence: u64) -> Value {
+    match value {
+        Some(value) => Value::Data(value, sequence),
+        None => Value::Tombstone(sequence),
+    }
+}
+
+impl MemTableRep for HashMemTable {
+    fn put(&self, key: &[u8], value: &[u8], sequence: u64) -> Option<Value> {
+        self.insert(key, Some(value), sequence)
+    }
+
+    fn delete(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        self.insert(key, None, sequence)
+    }
+
+    fn get_at(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        let inner = self.inner.read().unwrap();
+        let (sequence, value) = inner.data
+            .get(key)?
+            .iter()
+            .find(|(s, _)| *s <= sequence)?;
+        Some(to_value(value.clone(), *sequence))
+    }
+
+    // flushのときにここでキーを並べ替える
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Value)> + '_> {
+        let inner = self.inner.read().unwrap();
+        let mut keys: Vec<&Vec<u8>> = inner.data.keys().collect();
+        keys.sort_unstable();
+        let entries: Vec<_> = keys
+            .into_iter()
+            .flat_map(|key| {
+                inner.data[key]
+                    .iter()
+                    .map(move |(sequence, value)| (key.clone(), to_value(value.clone(), *sequence)))
+            })
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    // sorted_keysを二分探索し、そのキーのバージョンから探す
+    fn next_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, Value)> {
+        let keys = self.sorted_keys();
+        let inner = self.inner.read().unwrap();
+        let (i, in_key) = match target {
+            Bound::Included((key, sequence)) => (keys.partition_point(|k| k.as_slice() < key), Some((key, sequence, true))),
+            Bound::Excluded((key, sequence)) => (keys.partition_point(|k| k.as_slice() < key), Some((key, sequence, false))),
+            Bound::Unbounded => (0, None),
+        };
+        for key in &keys[i..] {
+            let versions = &inner.data[key];
+            let found = match in_key {
+                Some((target, sequence, included)) if key.as_slice() == target => {
+                    versions.iter().find(|(s, _)| *s < sequence || (included && *s == sequence))
+                },
+                _ => versions.first(),
+            };
+            if let Some((sequence, value)) = found {
+                return Some((key.clone(), to_value(value.clone(), *sequence)));
+            }
+        }
+        None
+    }
+
+    fn prev_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, Value)> {
+        let keys = self.sorted_keys();
+        let inner = self.inner.read().unwrap();
+        let (i, in_key) = match target {
+            Bound::Included((key, sequence)) => (keys.partition_point(|k| k.as_slice() <= key), Some((key, sequence, true))),
+            Bound::Excluded((key, sequence)) => (keys.partition_point(|k| k.as_slice() <= key), Some((key, sequence, false))),
+            Bound::Unbounded => (keys.len(), None),
+        };
+        for key in keys[..i].iter().rev() {
+            let versions = &inner.data[key];
+            let found = match in_key {
+                Some((target, sequence, included)) if key.as_slice() == target => {
+                    versions.iter().rev().find(|(s, _)| *s > sequence || (included && *s == sequence))
+                },
+                _ => versions.last(),
+            };
+            if let Some((sequence, value)) = found {
+                return Some((key.clone(), to_value(value.clone(), *sequence)));
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().size
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashMemTableFactory;
+
+impl MemTableFactory for HashMemTableFactory {
+    fn create(&self) -> Box<dyn MemTableRep> {
+        Box::new(HashMemTable::new())
+    }
+}
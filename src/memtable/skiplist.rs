@@ -0,0 +1,423 @@
+use std::{cell::Cell, cmp::Ordering as CmpOrdering, mem::size_of, ops::Bound, ptr, slice, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+
+use super::{arena::Arena, entry_size, MemTableFactory, MemTableRep, Value};
+
+const MAX_HEIGHT: usize = 12;
+// 1/4の確率で1段高くする
+const BRANCHING: u64 = 4;
+
+// 値. 上書きされた古い値はarenaに残ったまま参照されなくなる
+#[repr(C)]
+struct ValueNode {
+    tombstone: bool,
+    data: *const u8,
+    len: usize,
+}
+
+/*
+ノードの後ろにはheight個のAtomicPtr<Node>(各段の次のノード)が続く
+段の次のノードはtower()で生ポインタから取り出す
+*/
+#[repr(C)]
+struct Node {
+    key: *const u8,
+    key_len: usize,
+    sequence: u64,
+    value: AtomicPtr<ValueNode>,
+}
+
+// SAFETY: nodeはarenaで確保したノードかhead
+unsafe fn tower<'a>(node: *const Node, level: usize) -> &'a AtomicPtr<Node> {
+    let tower = (node as *const u8).add(size_of::<Node>()) as *const AtomicPtr<Node>;
+    &*tower.add(level)
+}
+
+// SAFETY: nodeはarenaで確保したノード
+unsafe fn node_key<'a>(node: *const Node) -> &'a [u8] {
+    slice::from_raw_parts((*node).key, (*node).key_len)
+}
+
+// SAFETY: valueはarenaで確保した値
+unsafe fn to_value(value: *const ValueNode, sequence: u64) -> Value {
+    let value = &*value;
+    if value.tombstone {
+        return Value::Tombstone(sequence);
+    }
+    Value::Data(slice::from_raw_parts(value.data, value.len).to_vec(), sequence)
+}
+
+// SAFETY: nodeはnullかarenaで確保したノード
+unsafe fn entry(node: *const Node) -> Option<(Vec<u8>, Value)> {
+    if node.is_null() {
+        return None;
+    }
+    let value = to_value((*node).value.load(Ordering::Acquire), (*node).sequence);
+    Some((node_key(node).to_vec(), value))
+}
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(crate::utils::get_timestamp() | 1);
+}
+
+// xorshift64
+fn random_height() -> usize {
+    let mut height = 1;
+    RNG.with(|rng| {
+        while height < MAX_HEIGHT {
+            let mut x = rng.get();
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            rng.set(x);
+            if x % BRANCHING != 0 {
+                break;
+            }
+            height += 1;
+        }
+    });
+    height
+}
+
+/*
+arenaの上に作るskiplist
+(キーの昇順, sequenceの降順)に並べるので、同じキーは新しいバージョンから順に並ぶ
+挿入はCASで各段に繋ぐだけなので、複数のスレッドから同時に挿入できる
+ノードは削除しないので、読み込みはロックを取らずに段をたどる
+*/
+pub struct SkipList {
+    arena: Arena,
+    head: *mut Node,
+    height: AtomicUsize,
+}
+
+impl SkipList {
+    pub fn new() -> SkipList {
+        let arena = Arena::new();
+        let head = Self::allocate_node(&arena, &[], 0, ptr::null_mut(), MAX_HEIGHT);
+        SkipList {
+            arena,
+            head,
+            height: AtomicUsize::new(1),
+        }
+    }
+
+    fn allocate_node(arena: &Arena, key: &[u8], sequence: u64, value: *mut ValueNode, height: usize) -> *mut Node {
+        let key_len = key.len();
+        let key = arena.allocate_bytes(key);
+        let node = arena.allocate(size_of::<Node>() + height * size_of::<AtomicPtr<Node>>()) as *mut Node;
+        // SAFETY: nodeはNodeと塔が入る大きさで、8バイト境界に揃っている. 塔はarenaが0で埋めているのでnull
+        unsafe {
+            ptr::write(node, Node {
+                key,
+                key_len,
+                sequence,
+                value: AtomicPtr::new(value),
+            });
+        }
+        node
+    }
+
+    fn allocate_value(&self, value: Option<&[u8]>) -> *mut ValueNode {
+        let node = self.arena.allocate(size_of::<ValueNode>()) as *mut ValueNode;
+        let (tombstone, data, len) = match value {
+            Some(value) => (false, self.arena.allocate_bytes(value), value.len()),
+            None => (true, ptr::null(), 0),
+        };
+        // SAFETY: nodeはValueNodeが入る大きさで、8バイト境界に揃っている
+        unsafe { ptr::write(node, ValueNode { tombstone, data, len }) };
+        node
+    }
+
+    // nodeと(key, sequence)を比べる. headは何よりも小さい
+    fn compare(&self, node: *const Node, key: &[u8], sequence: u64) -> CmpOrdering {
+        if ptr::eq(node, self.head) {
+            return CmpOrdering::Less;
+        }
+        // SAFETY: nodeはarenaで確保したノード
+        unsafe { node_key(node).cmp(key).then(sequence.cmp(&(*node).sequence)) }
+    }
+
+    // before以降で、level段目の(key, sequence)の直前と直後のノード
+    fn find_splice_for_level(&self, key: &[u8], sequence: u64, before: *mut Node, level: usize) -> (*mut Node, *mut Node) {
+        let mut prev = before;
+        loop {
+            // SAFETY: prevはheadか、arenaで確保したlevel段以上あるノード
+            let next = unsafe { tower(prev, level) }.load(Ordering::Acquire);
+            if next.is_null() || self.compare(next, key, sequence) != CmpOrdering::Less {
+                return (prev, next);
+            }
+            prev = next;
+        }
+    }
+
+    // (key, sequence)以上の最初のノード
+    fn find_greater_or_equal(&self, key: &[u8], sequence: u64) -> *mut Node {
+        let mut prev = self.head;
+        let mut next = ptr::null_mut();
+        for level in (0..self.height.load(Ordering::Acquire)).rev() {
+            (prev, next) = self.find_splice_for_level(key, sequence, prev, level);
+        }
+        next
+    }
+
+    // (key, sequence)未満の最後のノード. targetがNoneなら最後のノード. なければnull
+    fn find_less_than(&self, target: Option<(&[u8], u64)>) -> *mut Node {
+        let mut prev = self.head;
+        for level in (0..self.height.load(Ordering::Acquire)).rev() {
+            loop {
+                // SAFETY: prevはheadか、arenaで確保したlevel段以上あるノード
+                let next = unsafe { tower(prev, level) }.load(Ordering::Acquire);
+                if next.is_null() {
+                    break;
+                }
+                if matches!(target, Some((key, sequence)) if self.compare(next, key, sequence) != CmpOrdering::Less) {
+                    break;
+                }
+                prev = next;
+            }
+        }
+        if ptr::eq(prev, self.head) {
+            return ptr::null_mut();
+        }
+        prev
+    }
+
+    // targetの直後のノード. 挿入と同時にたどってもよい
+    pub fn seek(&self, target: Bound<(&[u8], u64)>) -> Option<(Vec<u8>, Value)> {
+        let node = match target {
+            Bound::Included((key, sequence)) => self.find_greater_or_equal(key, sequence),
+            Bound::Excluded((key, sequence)) => {
+                let node = self.find_greater_or_equal(key, sequence);
+                if !node.is_null() && self.compare(node, key, sequence) == CmpOrdering::Equal {
+                    // SAFETY: nodeはarenaで確保したノード
+                    unsafe { tower(node, 0) }.load(Ordering::Acquire)
+                } else {
+                    node
+                }
+            },
+            // SAFETY: headはMAX_HEIGHT段ある
+            Bound::Unbounded => unsafe { tower(self.head, 0) }.load(Ordering::Acquire),
+        };
+        // SAFETY: nodeはnullかarenaで確保したノード
+        unsafe { entry(node) }
+    }
+
+    // targetの直前のノード
+    pub fn seek_for_prev(&self, target: Bound<(&[u8], u64)>) -> Option<(Vec<u8>, Value)> {
+        let node = match target {
+            Bound::Included((key, sequence)) => {
+                let node = self.find_greater_or_equal(key, sequence);
+                if !node.is_null() && self.compare(node, key, sequence) == CmpOrdering::Equal {
+                    node
+                } else {
+                    self.find_less_than(Some((key, sequence)))
+                }
+            },
+            Bound::Excluded(target) => self.find_less_than(Some(target)),
+            Bound::Unbounded => self.find_less_than(None),
+        };
+        // SAFETY: nodeはnullかarenaで確保したノード
+        unsafe { entry(node) }
+    }
+
+    /*
+    (key, sequence)に値を入れる. valueがNoneならTombstone
+    同じキーとsequenceが既にあれば値を入れ替え、前の値を返す
+    */
+    pub fn insert(&self, key: &[u8], sequence: u64, value: Option<&[u8]>) -> Option<Value> {
+        let value = self.allocate_value(value);
+        let mut prev = [self.head; MAX_HEIGHT];
+        let mut next = [ptr::null_mut(); MAX_HEIGHT];
+        let mut before = self.head;
+        for level in (0..self.height.load(Ordering::Acquire)).rev() {
+            (prev[level], next[level]) = self.find_splice_for_level(key, sequence, before, level);
+            before = prev[level];
+        }
+        if let Some(replaced) = self.replace_if_equal(next[0], key, sequence, value) {
+            return Some(replaced);
+        }
+
+        let height = random_height();
+        self.height.fetch_max(height, Ordering::AcqRel);
+        let node = Self::allocate_node(&self.arena, key, sequence, value, height);
+        for level in 0..height {
+            loop {
+                // SAFETY: nodeはheight段あり、prev[level]はheadかlevel段以上あるノード
+                unsafe {
+                    tower(node, level).store(next[level], Ordering::Relaxed);
+                    if tower(prev[level], level)
+                        .compare_exchange(next[level], node, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+                // 他のスレッドが間に挿入したので探し直す
+                (prev[level], next[level]) = self.find_splice_for_level(key, sequence, prev[level], level);
+                if level == 0 {
+                    // 同じキーとsequenceが先に挿入された
+                    if let Some(replaced) = self.replace_if_equal(next[0], key, sequence, value) {
+                        return Some(replaced);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn replace_if_equal(&self, node: *mut Node, key: &[u8], sequence: u64, value: *mut ValueNode) -> Option<Value> {
+        if node.is_null() || self.compare(node, key, sequence) != CmpOrdering::Equal {
+            return None;
+        }
+        // SAFETY: nodeとその値はarenaで確保したもの
+        unsafe {
+            let replaced = (*node).value.swap(value, Ordering::AcqRel);
+            Some(to_value(replaced, sequence))
+        }
+    }
+
+    // keyのsequence以下で一番新しいバージョン
+    pub fn get(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        let node = self.find_greater_or_equal(key, sequence);
+        if node.is_null() {
+            return None;
+        }
+        // SAFETY: nodeはarenaで確保したノード
+        unsafe {
+            if node_key(node) != key {
+                return None;
+            }
+            Some(to_value((*node).value.load(Ordering::Acquire), (*node).sequence))
+        }
+    }
+
+    // keyのバージョンの中で最初のもの以降を順にたどる
+    pub fn iter_from(&self, key: Option<&[u8]>) -> SkipListIter<'_> {
+        let node = match key {
+            Some(key) => self.find_greater_or_equal(key, u64::MAX),
+            // SAFETY: headはMAX_HEIGHT段ある
+            None => unsafe { tower(self.head, 0) }.load(Ordering::Acquire),
+        };
+        SkipListIter {
+            _list: self,
+            node,
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.arena.memory_usage()
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.arena.allocated_bytes()
+    }
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// SAFETY: ノードはarenaの中にあり、arenaと一緒にしか解放されない. ノードの書き換えは全て原子的に行う
+unsafe impl Send for SkipList {}
+unsafe impl Sync for SkipList {}
+
+// 挿入と同時にたどってもよい. たどった後に挿入されたノードは見えないことがある
+pub struct SkipListIter<'a> {
+    _list: &'a SkipList,
+    node: *mut Node,
+}
+
+impl Iterator for SkipListIter<'_> {
+    type Item = (Vec<u8>, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.node.is_null() {
+            return None;
+        }
+        // SAFETY: nodeはarenaで確保したノードで、arenaは_listが生きている間は解放されない
+        unsafe {
+            let node = self.node;
+            self.node = tower(node, 0).load(Ordering::Acquire);
+            entry(node)
+        }
+    }
+}
+
+/*
+skiplistのmemtable. デフォルトの実装
+挿入はロックを取らずに同時に行え、getやiterは書き込みを止めない
+*/
+#[derive(Default)]
+pub struct SkipListMemTable {
+    data: SkipList,
+    // lenの値. 書き込みごとに増減させ、全体をたどらずに返す
+    size: AtomicUsize,
+}
+
+impl SkipListMemTable {
+    pub fn new() -> SkipListMemTable {
+        Self::default()
+    }
+
+    fn insert(&self, key: &[u8], value: Option<&[u8]>, sequence: u64) -> Option<Value> {
+        let replaced = self.data.insert(key, sequence, value);
+        match &replaced {
+            Some(Value::Data(old, _)) => self.size.fetch_sub(entry_size(key, old.len()), Ordering::Relaxed),
+            Some(Value::Tombstone(_)) => self.size.fetch_sub(entry_size(key, 0), Ordering::Relaxed),
+            None => 0,
+        };
+        self.size.fetch_add(entry_size(key, value.map_or(0, |value| value.len())), Ordering::Relaxed);
+        replaced
+    }
+}
+
+impl MemTableRep for SkipListMemTable {
+    fn put(&self, key: &[u8], value: &[u8], sequence: u64) -> Option<Value> {
+        self.insert(key, Some(value), sequence)
+    }
+
+    fn delete(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        self.insert(key, None, sequence)
+    }
+
+    fn get_at(&self, key: &[u8], sequence: u64) -> Option<Value> {
+        self.data.get(key, sequence)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Value)> + '_> {
+        Box::new(self.data.iter_from(None))
+    }
+
+    // 段をたどって飛ぶ
+    fn next_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Vec<u8>, Value)> {
+        self.data.seek(target)
+    }
+
+    fn prev_entry(&self, target: Bound<(&[u8], u64)>) -> Option<(Vec<u8>, Value)> {
+        self.data.seek_for_prev(target)
+    }
+
+    fn len(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    // arenaが確保しているバイト数. 上書きされた古い値も含む
+    fn memory_usage(&self) -> usize {
+        self.data.memory_usage()
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        self.data.allocated_bytes()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SkipListMemTableFactory;
+
+impl MemTableFactory for SkipListMemTableFactory {
+    fn create(&self) -> Box<dyn MemTableRep> {
+        Box::new(SkipListMemTable::new())
+    }
+}
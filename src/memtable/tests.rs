@@ -1,27 +1,38 @@
+use crate::sstable::RecordType;
+
 use super::*;
+use super::{btree::BTreeMemTableFactory, hash::HashMemTableFactory, skiplist::SkipListMemTableFactory};
+
+fn factories() -> Vec<Arc<dyn MemTableFactory>> {
+    vec![
+        Arc::new(SkipListMemTableFactory),
+        Arc::new(BTreeMemTableFactory),
+        Arc::new(HashMemTableFactory),
+    ]
+}
 
 #[test]
 fn test_mt_len() {
-    let timestamp = crate::utils::get_timestamp() as u64;
-    let mut memtable = MemTable::new();
+    let timestamp = crate::utils::get_timestamp();
+    let memtable = MemTable::new();
     assert_eq!(memtable.len(), 0);
-    memtable.put("key1", "value1", timestamp);
+    memtable.put(b"key1", b"value1", timestamp);
     assert_eq!(memtable.len(), 18);
-    memtable.put("key2", "value3", timestamp);
+    memtable.put(b"key2", b"value3", timestamp);
     assert_eq!(memtable.len(), 36);
-    memtable.delete("key1", timestamp);
+    memtable.delete(b"key1", timestamp);
     assert_eq!(memtable.len(), 30);
 }
 
 #[test]
 fn test_mt_len_empty() {
-    let timestamp = crate::utils::get_timestamp() as u64;
-    let mut memtable = MemTable::new();
-    memtable.put("key1", "", timestamp);
+    let timestamp = crate::utils::get_timestamp();
+    let memtable = MemTable::new();
+    memtable.put(b"key1", b"", timestamp);
     let encoded = memtable.encode();
     println!("{:?}", encoded);
 
-    memtable.delete("key1", timestamp);
+    memtable.delete(b"key1", timestamp);
     let encoded = memtable.encode();
     println!("{:?}", encoded);
 }
@@ -29,86 +40,222 @@ fn test_mt_len_empty() {
 
 #[test]
 fn test_mt_len_dup() {
-    let timestamp = crate::utils::get_timestamp() as u64;
-    let mut memtable = MemTable::new();
-    memtable.put("key1", "value2", timestamp);
+    let timestamp = crate::utils::get_timestamp();
+    let memtable = MemTable::new();
+    memtable.put(b"key1", b"value2", timestamp);
     assert_eq!(memtable.len(), 18);
-    memtable.put("key1", "value3", timestamp);
+    memtable.put(b"key1", b"value3", timestamp);
     assert_eq!(memtable.len(), 18);
 }
 
 #[test]
 fn test_mt_len_multi_byte() {
-    let timestamp = crate::utils::get_timestamp() as u64;
-    let mut memtable = MemTable::new();
-    memtable.put("キー", "バリュー", timestamp);
+    let timestamp = crate::utils::get_timestamp();
+    let memtable = MemTable::new();
+    memtable.put("キー".as_bytes(), "バリュー".as_bytes(), timestamp);
     assert_eq!(memtable.len(), 26);
 }
 
 #[test]
 fn test_mt_encode() {
-    let timestamp = crate::utils::get_timestamp() as u64;
-    let mut memtable = MemTable::new();
-    memtable.put("1",   "a", timestamp);
-    memtable.put("234", "bcd", timestamp);
-    memtable.put("キー", "バリュー", timestamp);
+    let timestamp = crate::utils::get_timestamp();
+    let memtable = MemTable::new();
+    memtable.put(b"1",   b"a", timestamp);
+    memtable.put(b"234", b"bcd", timestamp);
+    memtable.put("キー".as_bytes(), "バリュー".as_bytes(), timestamp);
     
     let encoded = memtable.encode();
     
     // タイムスタンプ以外の部分を検証
-    assert_eq!(&encoded[0..26], &[
+    assert_eq!(&encoded[0..27], &[
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "1".as_bytes().to_vec(),                        // key: "1"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "a".as_bytes().to_vec(),                        // value: "a"
         timestamp.to_ne_bytes().to_vec(), // timestamp: 8 bytes
     ].concat());
-    
+
     // 2番目のレコードの検証（タイムスタンプを除く）
-    assert_eq!(&encoded[26..56], &[
+    assert_eq!(&encoded[27..58], &[
         3u64.to_ne_bytes().to_vec(), // key_len: 3
         "234".as_bytes().to_vec(),                // key: "234"
+        vec![RecordType::Value as u8], // record_type
         3u64.to_ne_bytes().to_vec(), // value_len: 3
         "bcd".as_bytes().to_vec(),                // value: "bcd"
         timestamp.to_ne_bytes().to_vec(), // timestamp: 8 bytes
     ].concat());
 
     // 3番目のレコードの検証（タイムスタンプを除く）
-    assert_eq!(&encoded[56..98], &[
+    assert_eq!(&encoded[58..101], &[
         6u64.to_ne_bytes().to_vec(), // key_len: 6
         "キー".as_bytes().to_vec(),                // key: "キー"
+        vec![RecordType::Value as u8], // record_type
         12u64.to_ne_bytes().to_vec(), // value_len: 12
         "バリュー".as_bytes().to_vec(),                // value: "バリュー"
         timestamp.to_ne_bytes().to_vec(), // timestamp: 8 bytes
     ].concat());
     
-    memtable.delete("1", timestamp + 1);
+    memtable.delete(b"1", timestamp + 1);
     let encoded = memtable.encode();
     
-    // 削除後のエンコードも検証
-    // assert_eq!(&encoded[0..25], &[
-    //     1u64.to_ne_bytes().to_vec(), // key_len: 1
-    //     "1".as_bytes().to_vec(),                        // key: "1"
-    //     0u64.to_ne_bytes().to_vec(), // value_len: 0
-    //     (timestamp + 1).to_ne_bytes().to_vec(), // timestamp: 8 bytes
-    // ].concat());
-
+    // 削除後のエンコードも検証. Tombstoneは種類で区別し、値を持たない
     assert_eq!(&encoded[0..26], &[
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "1".as_bytes().to_vec(),                        // key: "1"
-        1u64.to_ne_bytes().to_vec(), // value_len: 0
-        0u8.to_ne_bytes().to_vec(), // value: 0
+        vec![RecordType::Tombstone as u8], // record_type
+        0u64.to_ne_bytes().to_vec(), // value_len: 0
         (timestamp + 1).to_ne_bytes().to_vec(), // timestamp: 8 bytes
     ].concat());
+
+    // "\0"の値は削除と区別される
+    memtable.put(b"1", b"\0", timestamp + 2);
+    let data = crate::sstable::SSTableData::try_from(memtable.encode()).unwrap();
+    assert_eq!(data.get(b"1", None), Some(&(Some(b"\0".to_vec()), timestamp + 2)));
 }
 
 #[test]
 fn test_mt_delete() {
-    let timestamp = crate::utils::get_timestamp() as u64;
-    let mut memtable = MemTable::new();
-    memtable.put("key1", "value1", timestamp);
-    assert_eq!(memtable.get("key1"), Some(Value::Data("value1".to_owned(), timestamp)));
+    let timestamp = crate::utils::get_timestamp();
+    let memtable = MemTable::new();
+    memtable.put(b"key1", b"value1", timestamp);
+    assert_eq!(memtable.get(b"key1"), Some(Value::Data(b"value1".to_vec(), timestamp)));
+
+    memtable.delete(b"key1", timestamp);
+    assert_eq!(memtable.get(b"key1"), Some(Value::Tombstone(timestamp)));
+}
+
+#[test]
+fn test_mt_versions() {
+    for factory in factories() {
+        let memtable = MemTable::with_factory(factory);
+        memtable.put(b"key1", b"value1", 1);
+        memtable.put(b"key2", b"value2", 2);
+        memtable.put(b"key1", b"value3", 3);
+        memtable.delete(b"key1", 4);
+        // 別のsequenceは別のバージョンとして残る
+        assert_eq!(memtable.len(), 18 + 18 + 18 + 12);
+        // 同じsequenceなら置き換わる
+        assert_eq!(memtable.put(b"key2", b"value4", 2), Some(Value::Data(b"value2".to_vec(), 2)));
+        assert_eq!(memtable.len(), 18 + 18 + 18 + 12);
+
+        assert_eq!(memtable.get(b"key1"), Some(Value::Tombstone(4)));
+        assert_eq!(memtable.get_at(b"key1", 3), Some(Value::Data(b"value3".to_vec(), 3)));
+        assert_eq!(memtable.get_at(b"key1", 2), Some(Value::Data(b"value1".to_vec(), 1)));
+        assert_eq!(memtable.get_at(b"key1", 0), None);
+        assert_eq!(memtable.get(b"key0"), None);
+
+        // キーの昇順、sequenceの降順
+        let entries: Vec<_> = memtable.iter().collect();
+        assert_eq!(entries, vec![
+            (b"key1".to_vec(), Value::Tombstone(4)),
+            (b"key1".to_vec(), Value::Data(b"value3".to_vec(), 3)),
+            (b"key1".to_vec(), Value::Data(b"value1".to_vec(), 1)),
+            (b"key2".to_vec(), Value::Data(b"value4".to_vec(), 2)),
+        ]);
+    }
+}
+
+#[test]
+fn test_mt_range() {
+    for factory in factories() {
+        let memtable = MemTable::with_factory(factory);
+        for (i, key) in ["a", "b", "c", "d"].iter().enumerate() {
+            memtable.put(key.as_bytes(), key.as_bytes(), i as u64);
+        }
+        let keys = |lower: Bound<&[u8]>, upper: Bound<&[u8]>| -> Vec<Key> {
+            memtable.range(lower, upper).map(|(key, _)| key).collect()
+        };
+        assert_eq!(keys(Bound::Included(b"b"), Bound::Excluded(b"d")), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(keys(Bound::Excluded(b"b"), Bound::Included(b"d")), vec![b"c".to_vec(), b"d".to_vec()]);
+        assert_eq!(keys(Bound::Unbounded, Bound::Excluded(b"b")), vec![b"a".to_vec()]);
+        assert_eq!(keys(Bound::Included(b"d"), Bound::Excluded(b"a")), Vec::<Key>::new());
+    }
+}
+
+#[test]
+fn test_mt_next_prev_entry() {
+    for factory in factories() {
+        let memtable = MemTable::with_factory(factory);
+        let check = |memtable: &MemTable| {
+            let all: Vec<(Key, Value)> = memtable.iter().collect();
+            let keys: [&[u8]; 6] = [b"", b"a", b"b", b"bb", b"c", b"d"];
+            for key in keys {
+                for sequence in [0, 1, 2, 3, u64::MAX] {
+                    for target in [Bound::Included((key, sequence)), Bound::Excluded((key, sequence)), Bound::Unbounded] {
+                        let is_after = |(k, v): &&(Key, Value)| match target {
+                            Bound::Included(target) => compare_version(k, v.sequence(), target) != Ordering::Less,
+                            Bound::Excluded(target) => compare_version(k, v.sequence(), target) == Ordering::Greater,
+                            Bound::Unbounded => true,
+                        };
+                        let is_before = |(k, v): &&(Key, Value)| match target {
+                            Bound::Included(target) => compare_version(k, v.sequence(), target) != Ordering::Greater,
+                            Bound::Excluded(target) => compare_version(k, v.sequence(), target) == Ordering::Less,
+                            Bound::Unbounded => true,
+                        };
+                        assert_eq!(memtable.next_entry(target), all.iter().find(is_after).cloned(), "{:?}", target);
+                        assert_eq!(memtable.prev_entry(target), all.iter().rev().find(is_before).cloned(), "{:?}", target);
+                    }
+                }
+            }
+        };
+        check(&memtable);
+        memtable.put(b"b", b"b1", 1);
+        memtable.put(b"b", b"b3", 3);
+        memtable.delete(b"c", 2);
+        check(&memtable);
+        // 引いた後に入ったキーも見える
+        memtable.put(b"a", b"a2", 2);
+        memtable.put(b"bb", b"bb1", 1);
+        memtable.put(b"d", b"d0", 0);
+        check(&memtable);
+    }
+}
+
+#[test]
+fn test_mt_concurrent_put() {
+    for factory in factories() {
+        let memtable = Arc::new(MemTable::with_factory(factory));
+        let handles: Vec<_> = (0..8u64).map(|t| {
+            let memtable = memtable.clone();
+            std::thread::spawn(move || {
+                for i in 0..1000u64 {
+                    let key = format!("key{:04}", i);
+                    memtable.put(key.as_bytes(), format!("value{}", t).as_bytes(), t * 1000 + i);
+                    // 書き込み中でも読める
+                    assert!(memtable.get(key.as_bytes()).is_some());
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let entries: Vec<_> = memtable.iter().collect();
+        assert_eq!(entries.len(), 8000);
+        assert!(entries.windows(2).all(|w| {
+            let sequence = |value: &Value| match value {
+                Value::Data(_, sequence) | Value::Tombstone(sequence) => *sequence,
+            };
+            (w[0].0.as_slice(), std::cmp::Reverse(sequence(&w[0].1))) < (w[1].0.as_slice(), std::cmp::Reverse(sequence(&w[1].1)))
+        }));
+        assert_eq!(memtable.len(), 8 * 1000 * (7 + 8 + 6));
+        assert!(memtable.memory_usage() >= memtable.len());
+        assert!(memtable.allocated_bytes() >= memtable.len());
+        assert_eq!(memtable.get(b"key0042"), Some(Value::Data(b"value7".to_vec(), 7042)));
+    }
+}
+
+#[test]
+fn test_mt_allocated_bytes() {
+    let memtable = MemTable::with_factory(Arc::new(SkipListMemTableFactory));
+    let before = memtable.allocated_bytes();
+    memtable.put(b"key1", b"value1", 1);
+    // キーと値に加えて、ノードとタワーの分も数える
+    assert!(memtable.allocated_bytes() - before > memtable.len());
+    assert!(memtable.allocated_bytes() <= memtable.memory_usage());
 
-    memtable.delete("key1", timestamp);
-    assert_eq!(memtable.get("key1"), Some(Value::Tombstone(timestamp)));
+    let memtable = MemTable::with_factory(Arc::new(BTreeMemTableFactory));
+    memtable.put(b"key1", b"value1", 1);
+    assert_eq!(memtable.allocated_bytes(), memtable.len());
 }
@@ -0,0 +1,104 @@
+use std::fmt::Debug;
+
+/*
+キーからprefixを取り出す
+SSTableにはprefixのBloomフィルタとnameを書き、scan_prefixは同じnameのフィルタだけを使う
+nameが変わるようなら、古いSSTableのprefixのフィルタは使われない
+*/
+pub trait PrefixExtractor: Send + Sync + Debug {
+    fn name(&self) -> String;
+
+    // keyのprefix. prefixを持たないキーならNone
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+// 先頭のlenバイト
+#[derive(Debug, Clone, Copy)]
+pub struct FixedPrefixExtractor {
+    len: usize,
+}
+
+impl FixedPrefixExtractor {
+    pub fn new(len: usize) -> FixedPrefixExtractor {
+        FixedPrefixExtractor { len }
+    }
+}
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn name(&self) -> String {
+        format!("fixed:{}", self.len)
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.len)
+    }
+}
+
+// count個目のdelimiterまで(delimiterを含む)
+// `tenant/entity/id`のキーならDelimitedPrefixExtractor::new(b'/', 2)で`tenant/entity/`になる
+#[derive(Debug, Clone, Copy)]
+pub struct DelimitedPrefixExtractor {
+    delimiter: u8,
+    count: usize,
+}
+
+impl DelimitedPrefixExtractor {
+    pub fn new(delimiter: u8, count: usize) -> DelimitedPrefixExtractor {
+        DelimitedPrefixExtractor { delimiter, count }
+    }
+}
+
+impl PrefixExtractor for DelimitedPrefixExtractor {
+    fn name(&self) -> String {
+        format!("delimited:{}:{}", self.delimiter, self.count)
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        if self.count == 0 {
+            return Some(&key[..0]);
+        }
+        let (end, _) = key.iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte == self.delimiter)
+            .nth(self.count - 1)?;
+        Some(&key[..=end])
+    }
+}
+
+// prefixで始まるキーより大きい最小のキー. なければNone
+pub fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prefix_upper_bound, DelimitedPrefixExtractor, FixedPrefixExtractor, PrefixExtractor};
+
+    #[test]
+    fn test_prefix_extractor() {
+        let fixed = FixedPrefixExtractor::new(3);
+        assert_eq!(fixed.prefix(b"abcd"), Some(&b"abc"[..]));
+        assert_eq!(fixed.prefix(b"ab"), None);
+
+        let delimited = DelimitedPrefixExtractor::new(b'/', 2);
+        assert_eq!(delimited.prefix(b"tenant/entity/id"), Some(&b"tenant/entity/"[..]));
+        assert_eq!(delimited.prefix(b"tenant/entity/"), Some(&b"tenant/entity/"[..]));
+        assert_eq!(delimited.prefix(b"tenant/entity"), None);
+        assert_ne!(fixed.name(), delimited.name());
+    }
+
+    #[test]
+    fn test_prefix_upper_bound() {
+        assert_eq!(prefix_upper_bound(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_upper_bound(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(b"\xff\xff"), None);
+        assert_eq!(prefix_upper_bound(b""), None);
+    }
+}
@@ -0,0 +1,319 @@
+use std::{cmp::Ordering, collections::BinaryHeap, ops::Bound, sync::Arc};
+
+use crate::{memtable::{self, MemTable}, Key, Value};
+
+// (value, sequence)  valueがNoneならTombstone
+pub type VersionedValue = (Option<Value>, u64);
+
+// 前後に動けるカーソル
+// 位置はエントリとエントリの間を指し、next()は位置の後ろ、prev()は位置の前のエントリを返して位置を動かす
+pub trait ScanCursor: Send {
+    fn next(&mut self) -> Option<Result<(Key, VersionedValue), String>>;
+    fn prev(&mut self) -> Option<Result<(Key, VersionedValue), String>>;
+    // key以上の最初のエントリの前に移動する
+    fn seek(&mut self, key: &[u8]) -> Result<(), String>;
+    // key以下の最後のエントリの後ろに移動する
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<(), String>;
+    fn seek_to_first(&mut self) -> Result<(), String>;
+    fn seek_to_last(&mut self) -> Result<(), String>;
+}
+
+/*
+memtableの[lower, upper)上のカーソル
+エントリを取り出しておかず、位置の直前のエントリだけを持ち、動くたびにmemtableを引く
+カーソルを作った後に書き込まれたバージョンは見えることがあるが、ScanIteratorがsequenceで除く
+*/
+pub struct MemTableCursor {
+    memtable: Arc<MemTable>,
+    lower: Bound<Key>,
+    upper: Bound<Key>,
+    // 位置の直前のエントリ. Noneなら先頭
+    prev: Option<(Key, VersionedValue)>,
+}
+
+impl MemTableCursor {
+    pub fn new(memtable: Arc<MemTable>, lower: Bound<Key>, upper: Bound<Key>) -> MemTableCursor {
+        MemTableCursor {
+            memtable,
+            lower,
+            upper,
+            prev: None,
+        }
+    }
+
+    // targetより後ろで、範囲に入る最初のエントリ
+    fn next_in_range(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, VersionedValue)> {
+        let lower = self.lower.as_ref().map(|key| key.as_slice());
+        let mut entry = self.memtable.next_entry(target)?;
+        if !memtable::is_above_lower(&entry.0, lower) {
+            entry = self.memtable.next_entry(memtable::lower_version_bound(lower))?;
+        }
+        if !memtable::is_below_upper(&entry.0, self.upper.as_ref().map(|key| key.as_slice())) {
+            return None;
+        }
+        Some(to_versioned(entry))
+    }
+
+    // targetより前で、範囲に入る最後のエントリ
+    fn prev_in_range(&self, target: Bound<(&[u8], u64)>) -> Option<(Key, VersionedValue)> {
+        let upper = self.upper.as_ref().map(|key| key.as_slice());
+        let mut entry = self.memtable.prev_entry(target)?;
+        if !memtable::is_below_upper(&entry.0, upper) {
+            entry = self.memtable.prev_entry(memtable::upper_version_bound(upper))?;
+        }
+        if !memtable::is_above_lower(&entry.0, self.lower.as_ref().map(|key| key.as_slice())) {
+            return None;
+        }
+        Some(to_versioned(entry))
+    }
+}
+
+fn to_versioned((key, value): (Key, memtable::Value)) -> (Key, VersionedValue) {
+    let value = match value {
+        memtable::Value::Data(value, sequence) => (Some(value), sequence),
+        memtable::Value::Tombstone(sequence) => (None, sequence),
+    };
+    (key, value)
+}
+
+impl ScanCursor for MemTableCursor {
+    fn next(&mut self) -> Option<Result<(Key, VersionedValue), String>> {
+        let target = match &self.prev {
+            Some((key, (_, sequence))) => Bound::Excluded((key.as_slice(), *sequence)),
+            None => Bound::Unbounded,
+        };
+        let entry = self.next_in_range(target)?;
+        self.prev = Some(entry.clone());
+        Some(Ok(entry))
+    }
+
+    fn prev(&mut self) -> Option<Result<(Key, VersionedValue), String>> {
+        let entry = self.prev.take()?;
+        self.prev = self.prev_in_range(Bound::Excluded((&entry.0, entry.1.1)));
+        Some(Ok(entry))
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), String> {
+        self.prev = self.prev_in_range(Bound::Excluded((key, u64::MAX)));
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<(), String> {
+        self.prev = self.prev_in_range(Bound::Included((key, 0)));
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<(), String> {
+        self.prev = None;
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<(), String> {
+        self.prev = self.prev_in_range(Bound::Unbounded);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+// 進む向きで一番先に返すべきものがheapの先頭に来る
+// 同じキーなら新しいcursorが先
+#[derive(Debug, PartialEq, Eq)]
+struct HeapEntry {
+    key: Key,
+    cursor: usize,
+    direction: Direction,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = match self.direction {
+            Direction::Forward => other.key.cmp(&self.key),
+            Direction::Backward => self.key.cmp(&other.key),
+        };
+        key.then(other.cursor.cmp(&self.cursor))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/*
+memtableと各SSTableのカーソルをk-wayマージする
+cursorsは新しい順に並べる(0番目がアクティブなmemtable)
+同じキーが複数のcursorにある場合はsequenceが大きいものを採用し、
+sequenceが同じなら新しいcursorのものを採用する
+Tombstoneになっているキーは返さない
+sequenceがself.sequenceより大きいバージョンは無いものとして扱う(スナップショット用)
+
+headsには各cursorから現在の向きに読み出したエントリを持つ
+向きを変えるときは読み出した分を戻してから、逆向きに読み直す
+*/
+pub struct ScanIterator {
+    cursors: Vec<Box<dyn ScanCursor>>,
+    heads: Vec<Option<(Key, VersionedValue)>>,
+    heap: BinaryHeap<HeapEntry>,
+    direction: Direction,
+    sequence: u64,
+    error: Option<String>,
+    // エラーを返した後は、seekし直すまで何も返さない
+    failed: bool,
+}
+
+impl ScanIterator {
+    pub fn new(cursors: Vec<Box<dyn ScanCursor>>) -> ScanIterator {
+        Self::with_sequence(cursors, u64::MAX)
+    }
+
+    pub fn with_sequence(cursors: Vec<Box<dyn ScanCursor>>, sequence: u64) -> ScanIterator {
+        let heads = cursors.iter().map(|_| None).collect();
+        let mut iter = ScanIterator {
+            cursors,
+            heads,
+            heap: BinaryHeap::new(),
+            direction: Direction::Forward,
+            sequence,
+            error: None,
+            failed: false,
+        };
+        iter.fill_all();
+        iter
+    }
+
+    pub fn seek(&mut self, key: &[u8]) -> Result<(), String> {
+        self.reposition(Direction::Forward, |cursor| cursor.seek(key))
+    }
+
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<(), String> {
+        self.reposition(Direction::Backward, |cursor| cursor.seek_for_prev(key))
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<(), String> {
+        self.reposition(Direction::Forward, |cursor| cursor.seek_to_first())
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<(), String> {
+        self.reposition(Direction::Backward, |cursor| cursor.seek_to_last())
+    }
+
+    // 位置の前のエントリを返し、位置を一つ前に動かす
+    #[allow(clippy::should_implement_trait)]
+    pub fn prev(&mut self) -> Option<Result<(Key, Value), String>> {
+        self.step(Direction::Backward)
+    }
+
+    fn reposition<F>(&mut self, direction: Direction, mut seek: F) -> Result<(), String>
+    where
+        F: FnMut(&mut dyn ScanCursor) -> Result<(), String>,
+    {
+        self.heads.iter_mut().for_each(|head| *head = None);
+        self.heap.clear();
+        self.error = None;
+        self.failed = false;
+        for cursor in self.cursors.iter_mut() {
+            seek(cursor.as_mut())?;
+        }
+        self.direction = direction;
+        self.fill_all();
+        Ok(())
+    }
+
+    fn fill_all(&mut self) {
+        for i in 0..self.cursors.len() {
+            self.fill(i);
+        }
+    }
+
+    fn fill(&mut self, i: usize) {
+        let entry = match self.direction {
+            Direction::Forward => self.cursors[i].next(),
+            Direction::Backward => self.cursors[i].prev(),
+        };
+        self.heads[i] = match entry {
+            Some(Ok(entry)) => {
+                self.heap.push(HeapEntry {
+                    key: entry.0.clone(),
+                    cursor: i,
+                    direction: self.direction,
+                });
+                Some(entry)
+            },
+            Some(Err(e)) => {
+                self.error.get_or_insert(e);
+                None
+            },
+            None => None,
+        };
+    }
+
+    fn switch_direction(&mut self, direction: Direction) {
+        // 読み出し済みのエントリをcursorに戻す
+        for i in 0..self.cursors.len() {
+            if self.heads[i].take().is_some() {
+                let _ = match self.direction {
+                    Direction::Forward => self.cursors[i].prev(),
+                    Direction::Backward => self.cursors[i].next(),
+                };
+            }
+        }
+        self.heap.clear();
+        self.direction = direction;
+        self.fill_all();
+    }
+
+    fn step(&mut self, direction: Direction) -> Option<Result<(Key, Value), String>> {
+        if self.failed {
+            return None;
+        }
+        if self.direction != direction {
+            self.switch_direction(direction);
+        }
+        loop {
+            if let Some(e) = self.error.take() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+            let top = self.heap.pop()?;
+
+            let sequence = self.sequence;
+            let (_, value) = self.heads[top.cursor].take().unwrap();
+            let mut newest = Some(value).filter(|value| value.1 <= sequence);
+            self.fill(top.cursor);
+            while self.heap.peek().is_some_and(|entry| entry.key == top.key) {
+                let entry = self.heap.pop().unwrap();
+                let (_, value) = self.heads[entry.cursor].take().unwrap();
+                if value.1 <= sequence && newest.as_ref().is_none_or(|newest| value.1 > newest.1) {
+                    newest = Some(value);
+                }
+                self.fill(entry.cursor);
+            }
+            // 読めなかったcursorにより新しい値があるかもしれない
+            if self.error.is_some() {
+                continue;
+            }
+
+            if let Some((Some(value), _)) = newest {
+                return Some(Ok((top.key, value)));
+            }
+        }
+    }
+}
+
+impl Iterator for ScanIterator {
+    type Item = Result<(Key, Value), String>;
+
+    // 位置の後ろのエントリを返し、位置を一つ後ろに動かす
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(Direction::Forward)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,130 @@
+use std::{ops::Bound, sync::Arc};
+
+use crate::{memtable::MemTable, Key, Value};
+
+use super::{MemTableCursor, ScanCursor, ScanIterator, VersionedValue};
+
+fn cursor(entries: &[(&str, Option<&str>, u64)]) -> Box<dyn ScanCursor> {
+    let memtable = MemTable::new();
+    for (key, value, timestamp) in entries {
+        match value {
+            Some(value) => memtable.put(key.as_bytes(), value.as_bytes(), *timestamp),
+            None => memtable.delete(key.as_bytes(), *timestamp),
+        };
+    }
+    Box::new(MemTableCursor::new(Arc::new(memtable), Bound::Unbounded, Bound::Unbounded))
+}
+
+fn merged() -> ScanIterator {
+    ScanIterator::new(vec![
+        cursor(&[("b", Some("b3"), 3), ("d", None, 3)]),
+        cursor(&[("a", Some("a2"), 2), ("c", Some("c2"), 2), ("d", Some("d2"), 2)]),
+        cursor(&[("a", Some("a1"), 1), ("b", Some("b1"), 1), ("e", Some("e1"), 1)]),
+    ])
+}
+
+fn key(entry: Option<Result<(Key, Value), String>>) -> Option<String> {
+    entry.map(|entry| String::from_utf8(entry.unwrap().0).unwrap())
+}
+
+fn collect_str(iter: ScanIterator) -> Vec<(String, String)> {
+    iter.map(|entry| {
+        let (key, value) = entry.unwrap();
+        (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap())
+    }).collect()
+}
+
+#[test]
+fn test_scan_memtable_cursor_bounds() {
+    let memtable = MemTable::new();
+    for (i, key) in ["a", "b", "c", "d"].iter().enumerate() {
+        memtable.put(key.as_bytes(), key.as_bytes(), i as u64 + 1);
+        memtable.put(key.as_bytes(), key.as_bytes(), i as u64 + 11);
+    }
+    let mut cursor = MemTableCursor::new(Arc::new(memtable), Bound::Excluded(b"a".to_vec()), Bound::Included(b"c".to_vec()));
+    let versions = |entry: Option<Result<(Key, VersionedValue), String>>| entry.map(|entry| {
+        let (key, (_, sequence)) = entry.unwrap();
+        (String::from_utf8(key).unwrap(), sequence)
+    });
+    let forward: Vec<_> = std::iter::from_fn(|| versions(cursor.next())).collect();
+    assert_eq!(forward, vec![("b".to_owned(), 12), ("b".to_owned(), 2), ("c".to_owned(), 13), ("c".to_owned(), 3)]);
+    assert_eq!(versions(cursor.next()), None);
+    let backward: Vec<_> = std::iter::from_fn(|| versions(cursor.prev())).collect();
+    assert_eq!(backward, vec![("c".to_owned(), 3), ("c".to_owned(), 13), ("b".to_owned(), 2), ("b".to_owned(), 12)]);
+
+    // 範囲の外をseekしても範囲の端に止まる
+    cursor.seek(b"a").unwrap();
+    assert_eq!(versions(cursor.next()), Some(("b".to_owned(), 12)));
+    cursor.seek_for_prev(b"z").unwrap();
+    assert_eq!(versions(cursor.prev()), Some(("c".to_owned(), 3)));
+    cursor.seek(b"c").unwrap();
+    assert_eq!(versions(cursor.prev()), Some(("b".to_owned(), 2)));
+    cursor.seek_to_last().unwrap();
+    assert_eq!(versions(cursor.next()), None);
+    cursor.seek_to_first().unwrap();
+    assert_eq!(versions(cursor.prev()), None);
+}
+
+#[test]
+fn test_scan_merge_newest_version() {
+    let all = collect_str(merged());
+    assert_eq!(all, vec![
+        ("a".to_owned(), "a2".to_owned()),
+        ("b".to_owned(), "b3".to_owned()),
+        ("c".to_owned(), "c2".to_owned()),
+        ("e".to_owned(), "e1".to_owned()),
+    ]);
+}
+
+#[test]
+fn test_scan_merge_same_timestamp_prefers_newer_cursor() {
+    let iter = ScanIterator::new(vec![
+        cursor(&[("a", Some("new"), 1)]),
+        cursor(&[("a", Some("old"), 1)]),
+    ]);
+    assert_eq!(collect_str(iter), vec![("a".to_owned(), "new".to_owned())]);
+}
+
+#[test]
+fn test_scan_merge_prev() {
+    let mut iter = merged();
+    iter.seek_to_last().unwrap();
+    let reversed = std::iter::from_fn(|| key(iter.prev()))
+        .collect::<Vec<_>>();
+    assert_eq!(reversed, vec!["e", "c", "b", "a"]);
+    assert_eq!(key(iter.prev()), None);
+    assert_eq!(key(iter.next()), Some("a".to_owned()));
+}
+
+#[test]
+fn test_scan_merge_change_direction() {
+    let mut iter = merged();
+    assert_eq!(key(iter.next()), Some("a".to_owned()));
+    assert_eq!(key(iter.next()), Some("b".to_owned()));
+    assert_eq!(key(iter.prev()), Some("b".to_owned()));
+    assert_eq!(key(iter.prev()), Some("a".to_owned()));
+    assert_eq!(key(iter.next()), Some("a".to_owned()));
+    assert_eq!(key(iter.next()), Some("b".to_owned()));
+    assert_eq!(key(iter.next()), Some("c".to_owned()));
+}
+
+#[test]
+fn test_scan_merge_seek() {
+    let mut iter = merged();
+    // dはTombstoneなので飛ばされる
+    iter.seek(b"d").unwrap();
+    assert_eq!(key(iter.next()), Some("e".to_owned()));
+    iter.seek(b"d").unwrap();
+    assert_eq!(key(iter.prev()), Some("c".to_owned()));
+
+    iter.seek_for_prev(b"d").unwrap();
+    assert_eq!(key(iter.prev()), Some("c".to_owned()));
+    iter.seek_for_prev(b"c").unwrap();
+    assert_eq!(key(iter.next()), Some("e".to_owned()));
+    iter.seek_for_prev(b"c").unwrap();
+    assert_eq!(key(iter.prev()), Some("c".to_owned()));
+
+    iter.seek(b"f").unwrap();
+    assert_eq!(key(iter.next()), None);
+    assert_eq!(key(iter.prev()), Some("e".to_owned()));
+}
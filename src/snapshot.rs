@@ -0,0 +1,89 @@
+use std::{collections::BTreeMap, sync::{Arc, Mutex}};
+
+use crate::memtable::MemTable;
+
+// 生きているスナップショットのsequenceと、それぞれの参照数
+// コンパクションはここにあるsequenceから見えるバージョンを残す
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    sequences: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> SnapshotList {
+        SnapshotList {
+            sequences: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn acquire(&self, sequence: u64) {
+        let mut sequences = self.sequences.lock().unwrap();
+        *sequences.entry(sequence).or_insert(0) += 1;
+    }
+
+    fn release(&self, sequence: u64) {
+        let mut sequences = self.sequences.lock().unwrap();
+        if let Some(count) = sequences.get_mut(&sequence) {
+            *count -= 1;
+            if *count == 0 {
+                sequences.remove(&sequence);
+            }
+        }
+    }
+
+    // 昇順
+    pub fn sequences(&self) -> Vec<u64> {
+        self.sequences.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequences.lock().unwrap().is_empty()
+    }
+}
+
+/*
+ある時点のLSMTreeの状態を読むためのハンドル
+sequence以下のバージョンだけが見える
+memtableは作成時点のものを共有して持ち、SSTableはsequenceで絞り込んで読む
+dropされるとSnapshotListから外れ、古いバージョンはコンパクションで消せるようになる
+*/
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence: u64,
+    memtable: Arc<MemTable>,
+    // 新しい順
+    immutable_memtables: Vec<Arc<MemTable>>,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        sequence: u64,
+        memtable: Arc<MemTable>,
+        immutable_memtables: Vec<Arc<MemTable>>,
+        list: Arc<SnapshotList>,
+    ) -> Snapshot {
+        list.acquire(sequence);
+        Snapshot {
+            sequence,
+            memtable,
+            immutable_memtables,
+            list,
+        }
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // 新しい順
+    pub(crate) fn memtables(&self) -> impl Iterator<Item = &Arc<MemTable>> {
+        std::iter::once(&self.memtable).chain(self.immutable_memtables.iter())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.sequence);
+    }
+}
@@ -1,52 +1,215 @@
+pub mod block_cache;
+pub mod bloom;
+pub mod index_cache;
 pub mod compaction;
+pub mod iterator;
 pub mod reader;
 pub mod writer;
 
-type Key = String;
-type Value = (Option<String>, u64); // (value, timestamp)
+type Key = Vec<u8>;
+type Value = (Option<Vec<u8>>, u64); // (value, sequence)
 type Offset = u64;
 
-use std::{collections::BTreeMap, fmt, ops::Index, vec};
+use std::{collections::BTreeMap, fmt, ops::{Bound, Index}, vec};
 pub use reader::SSTableReader;
 pub use writer::SSTableWriter;
 
 use crate::{memtable::MemTable, utils::get_page_size};
 
-#[derive(Debug, Clone)]
-pub struct SSTableHeader {
-    pub header_size: u64,
-    pub data_size: u64,
+/*
+SSTableは1つのファイルに次の順で書く
+| data block ... | index block | filter block | meta block | footer |
+data blockはSSTableRecordを並べたもので、index blockは各data blockの先頭キーとoffsetを持つ
+filter blockはキーとprefixのBloomフィルタで、作らなかったときは大きさが0になる
+各blockの後ろには中身のCRC32C(4バイト)を置き、indexのoffsetやfooterのsizeはこれを含む
+footerはファイルの末尾にあり、末尾のversionとmagicからfooterの大きさがわかる
+footerのないファイルは、indexを別ファイル(*.sst.idx)に持つ旧形式として読む
+*/
+pub const SSTABLE_MAGIC: [u8; 4] = *b"LSMS";
+pub const SSTABLE_VERSION: u32 = 5;
+// blockにCRC32Cがない
+const SSTABLE_VERSION_WITHOUT_CHECKSUM: u32 = 1;
+// filter blockがない
+const SSTABLE_VERSION_WITHOUT_FILTER: u32 = 2;
+// filter blockにキーのフィルタしかない
+pub const SSTABLE_VERSION_WITHOUT_PREFIX_FILTER: u32 = 3;
+// レコードに種類がなく、"\0"の値をTombstoneとして読む. 旧形式とversion 1も同じ
+pub const SSTABLE_VERSION_WITHOUT_RECORD_TYPE: u32 = 4;
+pub const BLOCK_TRAILER_SIZE: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SSTableFooter {
+    pub index_offset: u64,
+    pub index_size: u64,
+    pub filter_offset: u64,
+    pub filter_size: u64,
+    pub meta_offset: u64,
+    pub meta_size: u64,
+    pub version: u32,
 }
 
-impl SSTableHeader {
-    pub const SIZE: u64 = 16;
+impl SSTableFooter {
+    // index_offset, index_size, filter_offset, filter_size, meta_offset, meta_size, version, magic
+    pub const SIZE: u64 = 8 * 6 + 4 + 4;
+    // filterのないversionではfilter_offset, filter_sizeを書かない
+    const SIZE_WITHOUT_FILTER: u64 = 8 * 4 + 4 + 4;
+    // footerの末尾のversion, magic
+    pub const TAIL_SIZE: u64 = 4 + 4;
+
+    pub fn new(index_offset: u64, index_size: u64, filter_offset: u64, filter_size: u64, meta_offset: u64, meta_size: u64) -> SSTableFooter {
+        SSTableFooter {
+            index_offset,
+            index_size,
+            filter_offset,
+            filter_size,
+            meta_offset,
+            meta_size,
+            version: SSTABLE_VERSION,
+        }
+    }
+
+    // versionごとのfooterの大きさ. 知らないversionならエラー
+    pub fn size_of(version: u32) -> Result<u64, String> {
+        match version {
+            SSTABLE_VERSION | SSTABLE_VERSION_WITHOUT_RECORD_TYPE | SSTABLE_VERSION_WITHOUT_PREFIX_FILTER => Ok(Self::SIZE),
+            SSTABLE_VERSION_WITHOUT_CHECKSUM | SSTABLE_VERSION_WITHOUT_FILTER => Ok(Self::SIZE_WITHOUT_FILTER),
+            _ => Err(format!("unsupported sstable version: {}", version)),
+        }
+    }
 
-    pub fn new(header_size: u64, data_size: u64) -> SSTableHeader {
-        SSTableHeader {
-            header_size,
-            data_size,
+    // footerの末尾からversionを読む. magicがなければNone
+    pub fn decode_tail(tail: &[u8]) -> Option<u32> {
+        if tail.len() != Self::TAIL_SIZE as usize || !tail.ends_with(&SSTABLE_MAGIC) {
+            return None;
         }
+        Some(u32::from_ne_bytes(tail[0..4].try_into().unwrap()))
+    }
+
+    // data blockはファイルの先頭からindex blockの手前まで
+    pub fn data_size(&self) -> u64 {
+        self.index_offset
+    }
+
+    pub fn has_checksum(&self) -> bool {
+        self.version != SSTABLE_VERSION_WITHOUT_CHECKSUM
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.filter_size > 0
+    }
+
+    pub fn has_record_type(&self) -> bool {
+        self.version > SSTABLE_VERSION_WITHOUT_RECORD_TYPE
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&self.index_offset.to_ne_bytes());
+        buf.extend_from_slice(&self.index_size.to_ne_bytes());
+        if Self::size_of(self.version) == Ok(Self::SIZE) {
+            buf.extend_from_slice(&self.filter_offset.to_ne_bytes());
+            buf.extend_from_slice(&self.filter_size.to_ne_bytes());
+        }
+        buf.extend_from_slice(&self.meta_offset.to_ne_bytes());
+        buf.extend_from_slice(&self.meta_size.to_ne_bytes());
+        buf.extend_from_slice(&self.version.to_ne_bytes());
+        buf.extend_from_slice(&SSTABLE_MAGIC);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<SSTableFooter, String> {
+        let tail = data.len().checked_sub(Self::TAIL_SIZE as usize).map(|i| &data[i..]);
+        let version = tail
+            .and_then(Self::decode_tail)
+            .ok_or("footer magic is not found")?;
+        let size = Self::size_of(version)?;
+        if data.len() != size as usize {
+            return Err(format!("footer size must be {} but {}", size, data.len()));
+        }
+        let u64_at = |i: usize| u64::from_ne_bytes(data[i..(i + 8)].try_into().unwrap());
+        let (filter_offset, filter_size, meta_at) = if size == Self::SIZE {
+            (u64_at(16), u64_at(24), 32)
+        } else {
+            (0, 0, 16)
+        };
+        Ok(SSTableFooter {
+            index_offset: u64_at(0),
+            index_size: u64_at(8),
+            filter_offset,
+            filter_size,
+            meta_offset: u64_at(meta_at),
+            meta_size: u64_at(meta_at + 8),
+            version,
+        })
     }
 }
 
-impl SSTableHeader {
+// SSTable全体についての情報. データを読まずにsequenceやキーの範囲がわかる
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SSTableMeta {
+    pub num_records: u64,
+    pub max_sequence: u64,
+    // レコードがなければ空
+    pub smallest_key: Key,
+    pub largest_key: Key,
+    // metaの末尾に後から足したので、持たない古いSSTableでは0になる
+    pub min_sequence: u64,
+}
+
+impl SSTableMeta {
+    pub fn from_sstable_data(data: &SSTableData) -> SSTableMeta {
+        let mut meta = SSTableMeta::default();
+        for record in data.iter() {
+            if meta.num_records == 0 {
+                meta.smallest_key = record.key().clone();
+                meta.min_sequence = record.sequence();
+            }
+            meta.num_records += 1;
+            meta.min_sequence = meta.min_sequence.min(record.sequence());
+            meta.max_sequence = meta.max_sequence.max(record.sequence());
+            meta.largest_key = record.key().clone();
+        }
+        meta
+    }
+
     pub fn encode(&self) -> Vec<u8> {
-        vec![
-            self.header_size.to_ne_bytes().to_vec(),
-            self.data_size.to_ne_bytes().to_vec(),
+        [
+            self.num_records.to_ne_bytes().to_vec(),
+            self.max_sequence.to_ne_bytes().to_vec(),
+            self.smallest_key.len().to_ne_bytes().to_vec(),
+            self.smallest_key.clone(),
+            self.largest_key.len().to_ne_bytes().to_vec(),
+            self.largest_key.clone(),
+            self.min_sequence.to_ne_bytes().to_vec(),
         ].concat()
     }
 
-    pub fn decode(data: &[u8]) -> Result<SSTableHeader, String> {
-        let header_size = u64::from_ne_bytes(data[0..8]
+    pub fn decode(data: &[u8]) -> Result<SSTableMeta, String> {
+        let u64_at = |i: usize| -> Result<u64, String> {
+            Ok(u64::from_ne_bytes(data.get(i..(i + 8))
+                .ok_or("meta block is too short")?
                 .try_into()
-                .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
-        let data_size = u64::from_ne_bytes(data[8..16]
-                .try_into()
-                .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
-        Ok(SSTableHeader {
-            header_size,
-            data_size,
+                .map_err(|e: std::array::TryFromSliceError| e.to_string())?))
+        };
+        let key_at = |i: usize| -> Result<(Key, usize), String> {
+            let len = u64_at(i)? as usize;
+            let end = (i + 8).checked_add(len).ok_or("corruption: key_len in meta block is too large")?;
+            let key = data.get((i + 8)..end)
+                .ok_or("meta block is too short")?
+                .to_vec();
+            Ok((key, end))
+        };
+        let num_records = u64_at(0)?;
+        let max_sequence = u64_at(8)?;
+        let (smallest_key, i) = key_at(16)?;
+        let (largest_key, i) = key_at(i)?;
+        let min_sequence = if i < data.len() { u64_at(i)? } else { 0 };
+        Ok(SSTableMeta {
+            num_records,
+            max_sequence,
+            smallest_key,
+            largest_key,
+            min_sequence,
         })
     }
 }
@@ -75,11 +238,33 @@ impl SSTableIndex {
         index
     }
 
+    // 旧形式(encode_without_record_type)で書いたdataのindex
+    // 以前はレコードの種類を含まない大きさでchunkに分けていたので、同じように分け直す
+    fn from_sstable_data_without_record_type(data: &SSTableData) -> Self {
+        let mut index = SSTableIndex::new();
+        let mut offset: u64 = 0;
+        let mut chunk_size = 0;
+        let mut last_key: Option<&Key> = None;
+
+        for record in data.iter() {
+            // SSTableRecords::pushと同じく、同じキーの古いバージョンはchunkをまたがない
+            let same_key = last_key == Some(record.key());
+            if last_key.is_none() || (chunk_size >= get_page_size() && !same_key) {
+                index.insert(record.key().clone(), offset);
+                chunk_size = 0;
+            }
+            chunk_size += record.size_without_record_type();
+            offset += record.size_without_record_type() as u64;
+            last_key = Some(record.key());
+        }
+        index
+    }
+
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
         for (key, offset) in self.0.iter() {
             buf.extend_from_slice(&key.len().to_ne_bytes());
-            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(key);
             buf.extend_from_slice(&offset.to_ne_bytes());
         }
         buf
@@ -89,32 +274,23 @@ impl SSTableIndex {
         let mut i = 0;
         let mut index = SSTableIndex::new();
         while i < data.len() {
-            let key_len = u64::from_ne_bytes(data[i..(i + 8)]
-                .try_into()
-                .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
-            let key = String::from_utf8(data[(i + 8)..(i + 8 + key_len as usize)].to_vec())
-                .map_err(|e| e.to_string())?;
-            let offset = u64::from_ne_bytes(data[(i + 8 + key_len as usize)..(i + 16 + key_len as usize)]
-                .try_into()
-                .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
+            let key_len = read_u64(data, i, "key_len")? as usize;
+            let key_end = (i + 8).checked_add(key_len).ok_or("key_len is too large")?;
+            let key = data.get((i + 8)..key_end)
+                .ok_or("key is not found")?
+                .to_vec();
+            let offset = read_u64(data, key_end, "offset")?;
             index.insert(key, offset);
-            i += 16 + key_len as usize;
+            i = key_end + 8;
         }
         Ok(index)
     }
 
-    pub fn find_key_range(&self, key: &Key) -> Option<(u64, Option<u64>)> {
-        for i in 0..self.0.len() {
-            let (k, offset) = self.0.iter().nth(i).unwrap();
-            let next = self.0.iter().nth(i + 1);
-            if key < k {
-                break;
-            }
-            if k <= key && next.map_or(true, |(k, _)| key < k) {
-                return Some((*offset, next.map(|(_, v)| *v)));
-            }
-        }
-        None
+    // keyを含む可能性のあるblockの[begin, end). endがNoneなら最後のblock
+    pub fn find_key_range(&self, key: &[u8]) -> Option<(u64, Option<u64>)> {
+        let (_, begin) = self.0.range::<[u8], _>((Bound::Unbounded, Bound::Included(key))).next_back()?;
+        let end = self.0.range::<[u8], _>((Bound::Excluded(key), Bound::Unbounded)).next();
+        Some((*begin, end.map(|(_, offset)| *offset)))
     }
 
     pub fn size(&self) -> u64 {
@@ -126,7 +302,7 @@ impl SSTableIndex {
     })}
 
     #[cfg(test)]
-    fn get(&self, key: &Key) -> Option<&Offset> {
+    fn get(&self, key: &[u8]) -> Option<&Offset> {
         self.0.get(key)
     }
 
@@ -187,22 +363,32 @@ impl SSTableData {
     }
 
     pub fn encode(&self) -> Vec<u8> {
-        self.chunks.iter().fold(vec![], |acc, chunk| {
-            chunk.iter().fold(acc, |mut acc, record| {
-                acc.extend_from_slice(&record.encode());
-                acc
-            })
-        })
+        self.chunks.iter().flat_map(|chunk| chunk.encode(true)).collect()
+    }
+
+    // 旧形式で書く. Tombstoneは"\0"の値になる
+    pub fn encode_without_record_type(&self) -> Vec<u8> {
+        self.chunks.iter().flat_map(|chunk| chunk.encode(false)).collect()
     }
 
     pub fn decode(data: &[u8]) -> Result<SSTableData, String> {
+        Self::decode_impl(data, true)
+    }
+
+    // version 4以前と旧形式のdata block
+    pub fn decode_without_record_type(data: &[u8]) -> Result<SSTableData, String> {
+        Self::decode_impl(data, false)
+    }
+
+    fn decode_impl(data: &[u8], has_record_type: bool) -> Result<SSTableData, String> {
         let mut offset = 0;
         let mut chunks = vec![];
         let threadhold = get_page_size();
         while offset < data.len() {
             let (records, size) = SSTableRecords::decode(
                     &data[offset..], 
-                    threadhold)
+                    threadhold,
+                    has_record_type)
                 .map_err(|e| e.to_string())?;
             chunks.push(records);
             offset += size;
@@ -241,18 +427,24 @@ impl SSTableData {
         }
     }
 
-    pub fn get(&self, key: &Key, hint: Option<Offset>) -> Option<&Value> {
+    pub fn get(&self, key: &[u8], hint: Option<Offset>) -> Option<&Value> {
         if let Some(hint) = hint {
             if let Some(value) = self.chunks[hint as usize].get(key) {
                 return Some(value);
             }
         }
-        self.binary_search_get(key)
+        self.binary_search_get(key, u64::MAX)
+    }
+
+    // sequence以下のバージョンのうち、最新のものを返す
+    pub fn get_at(&self, key: &[u8], sequence: u64) -> Option<&Value> {
+        self.binary_search_get(key, sequence)
     }
 
     // [left, right)
     // mid <= key < mid + 1 → chunk.get(mid)
-    fn binary_search_get(&self, key: &Key) -> Option<&Value> {
+    // 同じキーのバージョンは1つのchunkにまとまっている
+    fn binary_search_get(&self, key: &[u8], sequence: u64) -> Option<&Value> {
         let mut left = 0;
         let mut right = self.chunks.len();
         while left < right {
@@ -263,10 +455,10 @@ impl SSTableData {
             } else {
                 None
             };
-            if mid_letf_chunk_first_key <= key && mid_right_chunk_first_key.map_or(true, |k| key < k) {
-                return self.chunks[mid].get(key);
+            if mid_letf_chunk_first_key.as_slice() <= key && mid_right_chunk_first_key.is_none_or(|k| key < k.as_slice()) {
+                return self.chunks[mid].get_at(key, sequence);
             }
-            if mid_letf_chunk_first_key > key {
+            if mid_letf_chunk_first_key.as_slice() > key {
                 right = mid;
             } else {
                 left = mid + 1;
@@ -275,7 +467,12 @@ impl SSTableData {
         None
     }
 
-    pub fn iter(&self) -> SSTableDataIterator {
+    // 書かれているレコードの最大のsequence
+    pub fn max_sequence(&self) -> u64 {
+        self.iter().map(|record| record.sequence()).max().unwrap_or(0)
+    }
+
+    pub fn iter(&self) -> SSTableDataIterator<'_> {
         SSTableDataIterator {
             chunks: &self.chunks,
             index: (0, 0),
@@ -298,8 +495,8 @@ impl From<MemTable> for SSTableData {
         let mut data = SSTableData::new();
         for record in memtable.iter() {
             let value = match record.1 {
-                super::memtable::Value::Data(value, timestamp) => (Some(value), timestamp),
-                super::memtable::Value::Tombstone(timestamp) => (None, timestamp),
+                super::memtable::Value::Data(value, sequence) => (Some(value), sequence),
+                super::memtable::Value::Tombstone(sequence) => (None, sequence),
             };
             let record = SSTableRecord::new(
                 record.0.clone(), 
@@ -365,11 +562,11 @@ impl SSTableRecords {
         SSTableRecords(vec![])
     }
 
-    fn decode(data: &[u8], threadhold: usize) -> Result<(Self, usize), String> {
+    fn decode(data: &[u8], threadhold: usize, has_record_type: bool) -> Result<(Self, usize), String> {
         let mut offset = 0;
         let mut records = Self::new();
         while offset < data.len() {
-            let (record, record_size) = SSTableRecord::decode(&data[offset..])
+            let (record, record_size) = SSTableRecord::decode(&data[offset..], has_record_type)
                 .map_err(|e| e.to_string())?;
             let ret = records.push(record, threadhold);
             if ret.is_err() {
@@ -380,7 +577,13 @@ impl SSTableRecords {
         Ok((records, offset))
     }
 
-    fn iter(&self) -> SSTableRecordsIterator {
+    fn encode(&self, has_record_type: bool) -> Vec<u8> {
+        self.iter()
+            .flat_map(|record| if has_record_type { record.encode() } else { record.encode_without_record_type() })
+            .collect()
+    }
+
+    fn iter(&self) -> SSTableRecordsIterator<'_> {
         SSTableRecordsIterator {
             iter: self.0.iter(),
         }
@@ -396,31 +599,23 @@ impl SSTableRecords {
         })
     }
 
-    fn get(&self, key: &Key) -> Option<&Value> {
-        self.binary_search_get(key)
+    fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.get_at(key, u64::MAX)
     }
 
-    // [left, right)
-    fn binary_search_get(&self, key: &Key) -> Option<&Value> {
-        let mut left = 0;
-        let mut right = self.0.len();
-        while left < right {
-            let mid = (left + right) / 2;
-            let record = &self.0[mid];
-            if record.0 == *key {
-                return Some(record.value());
-            }
-            if record.0 < *key {
-                left = mid + 1;
-            } else {
-                right = mid;
-            }
-        }
-        None
+    // 同じキーのレコードはsequenceの降順に並んでいる
+    fn get_at(&self, key: &[u8], sequence: u64) -> Option<&Value> {
+        let begin = self.0.partition_point(|record| record.0.as_slice() < key);
+        self.0[begin..].iter()
+            .take_while(|record| record.0 == key)
+            .find(|record| record.sequence() <= sequence)
+            .map(|record| record.value())
     }
 
     fn push(&mut self, record: SSTableRecord, threadhold: usize) -> Result<(), String> {
-        if self.size() >= threadhold {
+        // 同じキーの古いバージョンはchunkをまたがないようにする
+        let same_key = self.0.last().is_some_and(|last| last.0 == record.0);
+        if self.size() >= threadhold && !same_key {
             return Err("page is full".to_owned());
         }
         self.0.push(record);
@@ -481,12 +676,13 @@ impl<'a> Iterator for SSTableRecordsIterator<'a> {
     }
 }
 
+// SSTableRecordはキーの昇順、同じキーならsequenceの降順に並ぶ
+// スナップショットから見える古いバージョンは同じキーで複数残ることがある
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct SSTableRecord(Key, Value); // キー、値、タイムスタンプ
+pub struct SSTableRecord(Key, Value); // キー、値、sequence
 
 impl SSTableRecord {
     fn new(key: Key, value: Value) -> SSTableRecord {
-        // 現在のタイムスタンプを取得し、u64に変換
         SSTableRecord(key, value)
     }
 
@@ -498,69 +694,118 @@ impl SSTableRecord {
         &self.1
     }
 
-    fn timestamp(&self) -> u64 {
+    fn sequence(&self) -> u64 {
         self.1.1
     }
 
+    /*
+    キー長、キー、種類、値長、値、sequenceの順に書き込む
+    | key_len(8) | key | record_type(1) | value_len(8) | value | sequence(8) |
+    Tombstoneの値長は0
+    */
     fn encode(&self) -> Vec<u8> {
-        let default_value = "\0".to_owned();
+        let (record_type, value) = match self.value().0.as_ref() {
+            Some(value) => (RecordType::Value, value.as_slice()),
+            None => (RecordType::Tombstone, [].as_slice()),
+        };
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.0.len().to_ne_bytes());
+        buf.extend_from_slice(&self.0);
+        buf.push(record_type as u8);
+        buf.extend_from_slice(&value.len().to_ne_bytes());
+        buf.extend_from_slice(value);
+        buf.extend_from_slice(&self.value().1.to_ne_bytes());
+        buf
+    }
+
+    // version 4以前の形式. 種類を持たず、Tombstoneは"\0"の値として書く
+    fn encode_without_record_type(&self) -> Vec<u8> {
+        let default_value = vec![0u8];
         let value = self.value().0.as_ref().unwrap_or(&default_value);
         let mut buf = Vec::new();
-        // キー長、キー、値長、値、タイムスタンプの順に書き込む
+        // 以前はタイムスタンプを書いていたので、古いSSTableではその値がsequenceとして扱われる
         buf.extend_from_slice(&self.0.len().to_ne_bytes());
-        buf.extend_from_slice(self.0.as_bytes());
+        buf.extend_from_slice(&self.0);
         buf.extend_from_slice(&value.len().to_ne_bytes());
-        buf.extend_from_slice(value.as_bytes());
-        // タイムスタンプを最後に書き込む
+        buf.extend_from_slice(value);
         buf.extend_from_slice(&self.value().1.to_ne_bytes());
         buf
     }
 
-    fn decode(data: &[u8]) -> Result<(SSTableRecord, usize), String> {
-        let key_len = u64::from_ne_bytes(
-                data.get(0..8)
-                    .ok_or("key_len is not found")?
-                    .try_into()
-                    .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
-        let key = String::from_utf8(
-                data.get(8..(8 + key_len as usize))
-                    .ok_or("key is not found")?
-                    .to_vec())
-                .map_err(|e| e.to_string())?;
-        let value_len = u64::from_ne_bytes(
-                data.get((8 + key_len as usize)..(16 + key_len as usize))
-                    .ok_or("value_len is not found")?
-                    .try_into()
-                    .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
-        let value = String::from_utf8(
-                data.get((16 + key_len as usize)..(16 + key_len as usize + value_len as usize))
-                    .ok_or("value is not found")?
-                    .to_vec())
-                .map(|s| {
-                    if &s == "\0" {
-                        None
-                    } else {
-                        Some(s)
-                    }
-                })
-            .map_err(|e| e.to_string())?;
+    // 壊れたデータでもpanicせずにエラーを返す
+    // has_record_typeがfalseなら、"\0"の値をTombstoneとして読む
+    fn decode(data: &[u8], has_record_type: bool) -> Result<(SSTableRecord, usize), String> {
+        let key_len = read_u64(data, 0, "key_len")? as usize;
+        let key_end = 8usize.checked_add(key_len).ok_or("key_len is too large")?;
+        let key = data.get(8..key_end)
+                .ok_or("key is not found")?
+                .to_vec();
+        let (record_type, value_at) = if has_record_type {
+            let record_type = *data.get(key_end).ok_or("record_type is not found")?;
+            (Some(RecordType::try_from(record_type)?), key_end + 1)
+        } else {
+            (None, key_end)
+        };
+        let value_len = read_u64(data, value_at, "value_len")? as usize;
+        let value_end = (value_at + 8).checked_add(value_len).ok_or("value_len is too large")?;
+        let value = data.get((value_at + 8)..value_end)
+                .ok_or("value is not found")?;
+        let value = match record_type {
+            Some(RecordType::Value) => Some(value.to_vec()),
+            Some(RecordType::Tombstone) => None,
+            None if value == [0] => None,
+            None => Some(value.to_vec()),
+        };
         
-        // タイムスタンプを最後から読み込む
-        let timestamp_start = 16 + key_len as usize + value_len as usize;
-        let timestamp = u64::from_ne_bytes(data[timestamp_start..(timestamp_start + 8)]
-            .try_into()
-            .map_err(|e: std::array::TryFromSliceError| e.to_string())?);
+        let sequence = read_u64(data, value_end, "sequence")?;
         
-        let len = std::mem::size_of::<u64>() * 3 + key_len as usize + value_len as usize;
-        Ok((SSTableRecord(key, (value, timestamp)), len))
+        Ok((SSTableRecord(key, (value, sequence)), value_end + 8))
     }
 
+    // 新しい形式で書いたときの大きさ
     fn size(&self) -> usize {
         self.key().len()
             + self.value().0.as_ref().map_or(0, |v| v.len())
-            + std::mem::size_of::<u64>() * 3 // タイムスタンプ分を追加
+            + 1 // 種類
+            + std::mem::size_of::<u64>() * 3 // キー長、値長、sequence
+    }
+
+    // 旧形式で書いたときの大きさ. Tombstoneは"\0"の1バイトの値になる
+    fn size_without_record_type(&self) -> usize {
+        self.key().len()
+            + self.value().0.as_ref().map_or(1, |v| v.len())
+            + std::mem::size_of::<u64>() * 3 // キー長、値長、sequence
     }
 }
 
+/*
+SSTableのレコードの種類. コミットログのCommitLogCmdと同じ値を使う
+mergeやrange deleteを足すときは、コミットログのバッチの印(3)と重ならない値にする
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    Value = 1,
+    Tombstone = 2,
+}
+
+impl TryFrom<u8> for RecordType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(RecordType::Value),
+            2 => Ok(RecordType::Tombstone),
+            _ => Err(format!("invalid record type: {}", value)),
+        }
+    }
+}
+
+fn read_u64(data: &[u8], at: usize, name: &str) -> Result<u64, String> {
+    Ok(u64::from_ne_bytes(data.get(at..(at + 8))
+        .ok_or(format!("{} is not found", name))?
+        .try_into()
+        .map_err(|e: std::array::TryFromSliceError| e.to_string())?))
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,223 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use super::SSTableData;
+
+// ブロックキャッシュの容量のデフォルト(バイト)
+pub const DEFAULT_BLOCK_CACHE_CAPACITY: usize = 8 * 1024 * 1024;
+
+// (SSTableのid, blockのoffset)
+// idはreaderごとにnew_idで振り、同じファイルを開き直しても古いブロックとは混ざらない
+type BlockKey = (u64, u64);
+
+/*
+全てのSSTableReaderManagerで共有する、decode済みのdata blockのキャッシュ
+容量はblockのファイル上の大きさの合計で数え、超えたら最後に使ったのが古いものから捨てる(LRU)
+*/
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<LruState>,
+    next_id: AtomicU64,
+    hit: AtomicU64,
+    miss: AtomicU64,
+    insert: AtomicU64,
+    evict: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct LruState {
+    entries: HashMap<BlockKey, LruEntry>,
+    // 最後に使ったtick -> key. 先頭が一番古い
+    order: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    usage: usize,
+}
+
+#[derive(Debug)]
+struct LruEntry {
+    block: Arc<SSTableData>,
+    charge: usize,
+    tick: u64,
+}
+
+impl LruState {
+    fn touch(&mut self, key: BlockKey) -> Option<Arc<SSTableData>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&key)?;
+        self.order.remove(&entry.tick);
+        entry.tick = tick;
+        self.order.insert(tick, key);
+        Some(entry.block.clone())
+    }
+
+    fn remove(&mut self, key: &BlockKey) -> Option<LruEntry> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.usage -= entry.charge;
+        Some(entry)
+    }
+}
+
+impl BlockCache {
+    // capacityが0なら何も入れない
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            inner: Mutex::new(LruState::default()),
+            next_id: AtomicU64::new(0),
+            hit: AtomicU64::new(0),
+            miss: AtomicU64::new(0),
+            insert: AtomicU64::new(0),
+            evict: AtomicU64::new(0),
+        }
+    }
+
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn lookup(&self, id: u64, offset: u64) -> Option<Arc<SSTableData>> {
+        let block = self.inner.lock().unwrap().touch((id, offset));
+        match block {
+            Some(_) => self.hit.fetch_add(1, Ordering::Relaxed),
+            None => self.miss.fetch_add(1, Ordering::Relaxed),
+        };
+        block
+    }
+
+    // chargeがcapacityより大きいblockは入れない
+    pub fn insert(&self, id: u64, offset: u64, block: Arc<SSTableData>, charge: usize) {
+        if charge > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let key = (id, offset);
+        inner.remove(&key);
+        let mut evicted = 0;
+        while inner.usage + charge > self.capacity {
+            let Some((_, oldest)) = inner.order.pop_first() else {
+                break;
+            };
+            let entry = inner.entries.remove(&oldest).unwrap();
+            inner.usage -= entry.charge;
+            evicted += 1;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.insert(key, LruEntry { block, charge, tick });
+        inner.order.insert(tick, key);
+        inner.usage += charge;
+        drop(inner);
+        self.insert.fetch_add(1, Ordering::Relaxed);
+        self.evict.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    // SSTableを閉じたときに、そのブロックを全て捨てる
+    pub fn erase(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<BlockKey> = inner.entries.keys().filter(|key| key.0 == id).copied().collect();
+        for key in keys {
+            inner.remove(&key);
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        let inner = self.inner.lock().unwrap();
+        BlockCacheStats {
+            hit: self.hit.load(Ordering::Relaxed),
+            miss: self.miss.load(Ordering::Relaxed),
+            insert: self.insert.load(Ordering::Relaxed),
+            evict: self.evict.load(Ordering::Relaxed),
+            usage: inner.usage,
+            capacity: self.capacity,
+        }
+    }
+}
+
+// 同じキャッシュを共有しているかどうかで比べる
+impl PartialEq for BlockCache {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for BlockCache {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockCacheStats {
+    pub hit: u64,
+    pub miss: u64,
+    pub insert: u64,
+    pub evict: u64,
+    // 今キャッシュしているblockの大きさの合計(バイト)
+    pub usage: usize,
+    pub capacity: usize,
+}
+
+impl BlockCacheStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hit + self.miss;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hit as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::BlockCache;
+    use crate::{memtable::MemTable, sstable::SSTableData};
+
+    fn block(key: &[u8]) -> Arc<SSTableData> {
+        let memtable = MemTable::new();
+        memtable.put(key, b"value", 1);
+        Arc::new(SSTableData::from(memtable))
+    }
+
+    #[test]
+    fn test_block_cache_lru() {
+        let cache = BlockCache::new(300);
+        let id = cache.new_id();
+        cache.insert(id, 0, block(b"a"), 100);
+        cache.insert(id, 100, block(b"b"), 100);
+        cache.insert(id, 200, block(b"c"), 100);
+        // 0を使ったので、次に捨てられるのは100
+        assert!(cache.lookup(id, 0).is_some());
+        cache.insert(id, 300, block(b"d"), 100);
+        assert!(cache.lookup(id, 100).is_none());
+        assert!(cache.lookup(id, 0).is_some());
+        assert!(cache.lookup(id, 200).is_some());
+        assert!(cache.lookup(id, 300).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hit, stats.miss, stats.insert, stats.evict), (4, 1, 4, 1));
+        assert_eq!(stats.usage, 300);
+
+        // 容量より大きいblockは入れない
+        cache.insert(id, 400, block(b"e"), 301);
+        assert!(cache.lookup(id, 400).is_none());
+        assert_eq!(cache.stats().usage, 300);
+    }
+
+    #[test]
+    fn test_block_cache_erase() {
+        let cache = BlockCache::new(1000);
+        let (id1, id2) = (cache.new_id(), cache.new_id());
+        assert_ne!(id1, id2);
+        cache.insert(id1, 0, block(b"a"), 100);
+        cache.insert(id2, 0, block(b"b"), 100);
+        cache.erase(id1);
+        assert!(cache.lookup(id1, 0).is_none());
+        assert_eq!(cache.lookup(id2, 0), Some(block(b"b")));
+        assert_eq!(cache.stats().usage, 100);
+
+        // 容量が0なら何も入れない
+        let cache = BlockCache::new(0);
+        cache.insert(0, 0, block(b"a"), 1);
+        assert!(cache.lookup(0, 0).is_none());
+    }
+}
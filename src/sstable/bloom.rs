@@ -0,0 +1,266 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::SSTABLE_VERSION_WITHOUT_PREFIX_FILTER;
+
+// 1キーあたりのビット数のデフォルト. 偽陽性率はおよそ1%
+pub const DEFAULT_BITS_PER_KEY: usize = 10;
+
+/*
+SSTableごとのBloomフィルタ
+| bits | num_probes(1) |
+キーのハッシュからdouble hashingでnum_probes個のビットを立てる
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u8,
+}
+
+impl BloomFilter {
+    pub fn from_keys(keys: &[&[u8]], bits_per_key: usize) -> BloomFilter {
+        // キーが少ないと偽陽性率が高くなるので、最低64ビットにする
+        let num_bits = (keys.len() * bits_per_key).max(64).div_ceil(8) * 8;
+        // ln(2) * bits_per_keyが最適
+        let num_probes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        let mut filter = BloomFilter {
+            bits: vec![0u8; num_bits / 8],
+            num_probes,
+        };
+        for key in keys {
+            for bit in filter.probes(key) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    // falseならキーは確実にない
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(key).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn probes(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = hash(key);
+        let num_bits = (self.bits.len() * 8) as u64;
+        let delta = (hash >> 32) | 1;
+        (0..self.num_probes as u64).map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % num_bits) as usize)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.bits.clone();
+        buf.push(self.num_probes);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<BloomFilter, String> {
+        let (num_probes, bits) = data.split_last().ok_or("bloom filter is empty")?;
+        if bits.is_empty() || *num_probes == 0 {
+            return Err("bloom filter is broken".to_owned());
+        }
+        Ok(BloomFilter {
+            bits: bits.to_vec(),
+            num_probes: *num_probes,
+        })
+    }
+}
+
+/*
+SSTableのfilterブロック
+| key_filter_len(8) | key_filter | name_len(8) | name | prefix_filter_len(8) | prefix_filter |
+prefixのフィルタがなければname_lenとprefix_filter_lenは0
+version 3ではブロック全体がキーのフィルタ
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SSTableFilter {
+    pub key: BloomFilter,
+    // (PrefixExtractorのname, prefixのフィルタ)
+    pub prefix: Option<(String, BloomFilter)>,
+}
+
+impl SSTableFilter {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        let key = self.key.encode();
+        buf.extend_from_slice(&(key.len() as u64).to_ne_bytes());
+        buf.extend_from_slice(&key);
+        let (name, prefix) = match &self.prefix {
+            Some((name, filter)) => (name.as_bytes().to_vec(), filter.encode()),
+            None => (vec![], vec![]),
+        };
+        buf.extend_from_slice(&(name.len() as u64).to_ne_bytes());
+        buf.extend_from_slice(&name);
+        buf.extend_from_slice(&(prefix.len() as u64).to_ne_bytes());
+        buf.extend_from_slice(&prefix);
+        buf
+    }
+
+    pub fn decode(data: &[u8], version: u32) -> Result<SSTableFilter, String> {
+        if version == SSTABLE_VERSION_WITHOUT_PREFIX_FILTER {
+            return Ok(SSTableFilter {
+                key: BloomFilter::decode(data)?,
+                prefix: None,
+            });
+        }
+        let mut rest = data;
+        let key = BloomFilter::decode(split_field(&mut rest, "key filter")?)?;
+        let name = split_field(&mut rest, "prefix extractor name")?;
+        let prefix = split_field(&mut rest, "prefix filter")?;
+        if !rest.is_empty() {
+            return Err("filter block has trailing bytes".to_owned());
+        }
+        let prefix = if name.is_empty() {
+            None
+        } else {
+            let name = String::from_utf8(name.to_vec()).map_err(|e| e.to_string())?;
+            Some((name, BloomFilter::decode(prefix)?))
+        };
+        Ok(SSTableFilter { key, prefix })
+    }
+
+    // 同じPrefixExtractorで作ったフィルタがなければNone
+    pub fn may_contain_prefix(&self, name: &str, prefix: &[u8]) -> Option<bool> {
+        match &self.prefix {
+            Some((filter_name, filter)) if filter_name == name => Some(filter.may_contain(prefix)),
+            _ => None,
+        }
+    }
+}
+
+// | len(8) | bytes | を切り出す
+fn split_field<'a>(data: &mut &'a [u8], name: &str) -> Result<&'a [u8], String> {
+    let broken = || format!("{} in filter block is broken", name);
+    let len = data.get(..8).ok_or_else(broken)?;
+    let len = u64::from_ne_bytes(len.try_into().unwrap());
+    let end = usize::try_from(len).ok().and_then(|len| len.checked_add(8)).ok_or_else(broken)?;
+    let field = data.get(8..end).ok_or_else(broken)?;
+    *data = &data[end..];
+    Ok(field)
+}
+
+// FNV-1aの後にsplitmix64で混ぜる
+fn hash(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+// getでフィルタを引いた回数と、そのうち読まずに済んだ回数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BloomFilterStats {
+    pub checked: u64,
+    // フィルタがないと答え、SSTableを読まずに済んだ
+    pub useful: u64,
+    // フィルタはあると答えたが、読んでみるとなかった
+    pub false_positive: u64,
+    // scan_prefixでprefixのフィルタを引いた回数と、SSTableを丸ごと飛ばせた回数
+    pub prefix_checked: u64,
+    pub prefix_useful: u64,
+}
+
+impl BloomFilterStats {
+    pub fn useful_ratio(&self) -> f64 {
+        if self.checked == 0 {
+            return 0.0;
+        }
+        self.useful as f64 / self.checked as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BloomFilterCounter {
+    checked: AtomicU64,
+    useful: AtomicU64,
+    false_positive: AtomicU64,
+    prefix_checked: AtomicU64,
+    prefix_useful: AtomicU64,
+}
+
+impl BloomFilterCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, may_contain: bool) {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        if !may_contain {
+            self.useful.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_false_positive(&self) {
+        self.false_positive.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_prefix(&self, may_contain: bool) {
+        self.prefix_checked.fetch_add(1, Ordering::Relaxed);
+        if !may_contain {
+            self.prefix_useful.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> BloomFilterStats {
+        BloomFilterStats {
+            checked: self.checked.load(Ordering::Relaxed),
+            useful: self.useful.load(Ordering::Relaxed),
+            false_positive: self.false_positive.load(Ordering::Relaxed),
+            prefix_checked: self.prefix_checked.load(Ordering::Relaxed),
+            prefix_useful: self.prefix_useful.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BloomFilter, SSTableFilter};
+    use crate::sstable::{SSTABLE_VERSION, SSTABLE_VERSION_WITHOUT_PREFIX_FILTER};
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys: Vec<Vec<u8>> = (0..10000).map(|i| format!("key{}", i).into_bytes()).collect();
+        let filter = BloomFilter::from_keys(&keys.iter().map(|key| key.as_slice()).collect::<Vec<_>>(), 10);
+        // 入れたキーは必ずあると答える
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("other{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "false_positives = {}", false_positives);
+
+        let decoded = BloomFilter::decode(&filter.encode()).unwrap();
+        assert_eq!(decoded, filter);
+        assert!(BloomFilter::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_bloom_filter_empty() {
+        let filter = BloomFilter::from_keys(&[], 10);
+        assert!(!filter.may_contain(b"key"));
+    }
+
+    #[test]
+    fn test_sstable_filter() {
+        let key = BloomFilter::from_keys(&[b"a/1", b"a/2", b"b/1"], 10);
+        let prefix = BloomFilter::from_keys(&[b"a/", b"b/"], 10);
+        let filter = SSTableFilter { key: key.clone(), prefix: Some(("delimited:47:1".to_owned(), prefix)) };
+        let decoded = SSTableFilter::decode(&filter.encode(), SSTABLE_VERSION).unwrap();
+        assert_eq!(decoded, filter);
+        assert_eq!(decoded.may_contain_prefix("delimited:47:1", b"a/"), Some(true));
+        // 違うPrefixExtractorで作ったフィルタは使わない
+        assert_eq!(decoded.may_contain_prefix("fixed:2", b"a/"), None);
+
+        let without_prefix = SSTableFilter { key: key.clone(), prefix: None };
+        assert_eq!(SSTableFilter::decode(&without_prefix.encode(), SSTABLE_VERSION).unwrap(), without_prefix);
+        // version 3はキーのフィルタだけ
+        assert_eq!(SSTableFilter::decode(&key.encode(), SSTABLE_VERSION_WITHOUT_PREFIX_FILTER).unwrap(), without_prefix);
+
+        let encoded = filter.encode();
+        assert!(SSTableFilter::decode(&encoded[..encoded.len() - 1], SSTABLE_VERSION).is_err());
+    }
+}
@@ -8,8 +8,9 @@ use crate::SharedSSTableReader;
 use super::SSTableWriter;
 
 pub trait Compaction {
+    // new_writerは呼ぶたびにファイル番号を1つ使うので、マージするSSTableを決めてから呼ぶ
     fn compact(
         &self, 
         sstables: Arc<SharedSSTableReader>, 
-        writer: SSTableWriter) -> Result<(), String>;
+        new_writer: &dyn Fn() -> SSTableWriter) -> Result<(), String>;
 }
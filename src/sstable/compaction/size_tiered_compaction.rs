@@ -1,7 +1,8 @@
-use std::sync::Arc;
+use std::{cmp::Reverse, sync::Arc};
 
 use crate::sstable::reader::SSTableReaderManager;
 use crate::sstable::SSTableData;
+use crate::sstable::SSTableRecord;
 use crate::sstable::SSTableWriter;
 use crate::SharedSSTableReader;
 
@@ -25,76 +26,75 @@ impl SizeTieredCompaction {
         }
     }
 
-    fn merge(&self, sstables: Vec<SSTableData>) -> SSTableData {
+    // snapshotsは生きているスナップショットのsequence(昇順)
+    fn merge(&self, sstables: Vec<SSTableData>, snapshots: &[u64]) -> SSTableData {
         let mut target = sstables;
         while target.len() != 1 {
             target = target.chunks(2).map(|pair| {
                 if pair.len() == 1 {
                     return pair[0].clone();
                 }
-                let ret = self.merge_impl(&pair[0], &pair[1]);
-                ret
+                self.merge_impl(&pair[0], &pair[1], snapshots)
             }).collect::<Vec<SSTableData>>();
         }
         target.pop().unwrap()
     }
 
     // TODO: エラー処理
-    fn merge_impl(&self, left: &SSTableData, right: &SSTableData) -> SSTableData {
+    fn merge_impl(&self, left: &SSTableData, right: &SSTableData, snapshots: &[u64]) -> SSTableData {
         let mut merged = SSTableData::new();
-        let mut left_iter = left.iter();
-        let mut right_iter = right.iter();
-
-        let mut left = left_iter.next();
-        let mut right = right_iter.next();
-        while left.is_some() && right.is_some() {
-            let left_v = left.unwrap();
-            let right_v = right.unwrap();
-            if left_v.key() < right_v.key() {
-                let _ = merged.push(left_v.clone());
-                left = left_iter.next();
-            } else if left_v.key() > right_v.key() {
-                let _ = merged.push(right_v.clone());
-                right = right_iter.next();
-            } else {
-                // keyが重複している場合、timestampが大きい方を選ぶ
-                if left_v.timestamp() > right_v.timestamp() {
-                    let _ = merged.push(left_v.clone());
-                } else {
-                    let _ = merged.push(right_v.clone());
-                }
-                left = left_iter.next();
-                right = right_iter.next();
+        let mut left_iter = left.iter().peekable();
+        let mut right_iter = right.iter().peekable();
+
+        // キーの昇順、同じキーならsequenceの降順に取り出す
+        // sequenceも同じならrightを先にする
+        let mut prev: Option<&SSTableRecord> = None;
+        loop {
+            let record = match (left_iter.peek(), right_iter.peek()) {
+                (Some(left_v), Some(right_v)) => {
+                    if (left_v.key(), Reverse(left_v.sequence())) < (right_v.key(), Reverse(right_v.sequence())) {
+                        left_iter.next()
+                    } else {
+                        right_iter.next()
+                    }
+                },
+                (Some(_), None) => left_iter.next(),
+                (None, Some(_)) => right_iter.next(),
+                (None, None) => break,
+            }.unwrap();
+
+            // keyが重複している場合、sequenceが大きい方を選ぶ
+            // 古い方もスナップショットから見えるなら残す
+            let keep = match prev {
+                Some(newer) if newer.key() == record.key() => Self::is_visible(record.sequence(), newer.sequence(), snapshots),
+                _ => true,
+            };
+            if keep {
+                let _ = merged.push(record.clone());
             }
-        }
-
-        while left.is_some() {
-            let left_v = left.unwrap();
-            let _ = merged.push(left_v.clone());
-            left = left_iter.next();
-        }
-
-        while right.is_some() {
-            let right_v = right.unwrap();
-            let _ = merged.push(right_v.clone());
-            right = right_iter.next();
+            prev = Some(record);
         }
         merged
     }
 
-    fn get_interesting_bucket(&self, sstables: &Vec<Arc<SSTableReaderManager>>) -> Vec<Arc<SSTableReaderManager>> {
-        let mut buckets = Vec::new();
+    // sequenceのバージョンが、次に新しいバージョン(newer)ができるまでの間に取られたスナップショットから見えるか
+    fn is_visible(sequence: u64, newer: u64, snapshots: &[u64]) -> bool {
+        let i = snapshots.partition_point(|snapshot| *snapshot < sequence);
+        snapshots.get(i).is_some_and(|snapshot| *snapshot < newer)
+    }
+
+    // ファイルの大きさが読めなければエラーを返す
+    fn get_interesting_bucket(&self, sstables: &[Arc<SSTableReaderManager>]) -> Result<Vec<Arc<SSTableReaderManager>>, String> {
+        let mut buckets: Vec<Vec<(f64, Arc<SSTableReaderManager>)>> = Vec::new();
 
         dbg!(sstables.len());
 
         for sstable in sstables.iter() {
-            let metadata = sstable.metadata().unwrap();
+            let metadata = sstable.metadata()?;
             let len = metadata.len() as f64;
             
-            fn bucket_median_size(sstables: &Vec<Arc<SSTableReaderManager>>) -> f64 {
-                let sum = sstables.iter().map(|sstable| {
-                    sstable.metadata().unwrap().len() as f64
-                }).sum::<f64>();
+            fn bucket_median_size(sstables: &[(f64, Arc<SSTableReaderManager>)]) -> f64 {
+                let sum = sstables.iter().map(|(len, _)| len).sum::<f64>();
                 let len = sstables.len() as f64;
                 if len == 0.0 {
                     return 0.0;
@@ -102,24 +102,26 @@ impl SizeTieredCompaction {
                 sum / len
             }
 
+            #[allow(clippy::needless_borrow)]
             let bucket = buckets.iter_mut().find(|bucket| {
                 let bucket_median = bucket_median_size(&bucket);
                 bucket_median * self.min_threshold < len && len < bucket_median * self.max_threshold
             });
             match bucket {
                 Some(bucket) => {
-                    bucket.push(sstable.clone());
+                    bucket.push((len, sstable.clone()));
                 }
                 None => {
-                    buckets.push(vec![sstable.clone()]);
+                    buckets.push(vec![(len, sstable.clone())]);
                 }
                 
             }
         }
 
-        buckets.into_iter().max_by(|a, b| {
+        let bucket = buckets.into_iter().max_by(|a, b| {
             a.len().cmp(&b.len())
-        }).unwrap_or_else(Vec::new)
+        }).unwrap_or_else(Vec::new);
+        Ok(bucket.into_iter().map(|(_, sstable)| sstable).collect())
     }
 
 }
@@ -128,19 +130,16 @@ impl Compaction for SizeTieredCompaction {
     fn compact(
         &self, 
         shared: Arc<SharedSSTableReader>, 
-        writer: SSTableWriter
+        new_writer: &dyn Fn() -> SSTableWriter
     ) -> Result<(), String> {
-        let mut sstables = shared.get_all();
-        sstables.sort_by(|a, b| {
-            a.metadata().unwrap().len().cmp(&b.metadata().unwrap().len())
-        });
-
-        let interestings = self.get_interesting_bucket(&sstables);
-        let interestings_data = interestings
-            .iter()
-            .map(|sstable| sstable.data().unwrap())
-            .collect::<Vec<SSTableData>>();
-
+        let mut sstables = shared.get_all()
+            .into_iter()
+            .map(|sstable| Ok((sstable.metadata()?.len(), sstable)))
+            .collect::<Result<Vec<_>, String>>()?;
+        sstables.sort_by_key(|(len, _)| *len);
+        let sstables: Vec<_> = sstables.into_iter().map(|(_, sstable)| sstable).collect();
+
+        let interestings = self.get_interesting_bucket(&sstables)?;
         if interestings.len() < self.bucket_threshold {
             dbg!("skip compaction");
             dbg!(interestings.len());
@@ -148,13 +147,18 @@ impl Compaction for SizeTieredCompaction {
             return Ok(());
         }
 
-        let compacted = self.merge(interestings_data);
+        // 壊れたSSTableがあればエラーを返し、入力はそのまま残す
+        let interestings_data = interestings
+            .iter()
+            .map(|sstable| sstable.data().map_err(|e| format!("compaction can not read {}: {}", sstable.file(), e)))
+            .collect::<Result<Vec<SSTableData>, String>>()?;
+
+        let compacted = self.merge(interestings_data, &shared.snapshots().sequences());
 
+        let writer = new_writer();
         writer.write_with_index(&compacted, self.index_interval)?;
-        interestings.iter().for_each(|sstable| {
-            sstable.delete();
-        });
-        Ok(())
+        // 入力のSSTableは、読んでいるバージョンがなくなったときに消える
+        shared.install(&writer, &interestings)
     }
 }
 
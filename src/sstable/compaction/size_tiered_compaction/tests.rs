@@ -2,14 +2,14 @@ use std::{collections::BTreeMap, fs, path, sync::Arc};
 
 use libc::sleep;
 
-use crate::{SharedSSTableReader, memtable::MemTable, sstable::{compaction::Compaction, reader::SSTableReaderManager, SSTableData, SSTableWriter}, utils::get_page_size};
+use crate::{SharedSSTableReader, memtable::MemTable, sstable::{compaction::Compaction, reader::{SSTableFormat, SSTableReaderManager}, SSTableData, BLOCK_TRAILER_SIZE, SSTableRecord, SSTableWriter}, utils::get_page_size};
 
 use super::SizeTieredCompaction;
 
 fn create_sstable_data(data: Vec<(&str, &str, u64)>) -> SSTableData {
-    let mut memtable = MemTable::new();
+    let memtable = MemTable::new();
     for (key, value, timestamp) in data.iter() {
-        memtable.put(key, value, *timestamp);
+        memtable.put(key.as_bytes(), value.as_bytes(), *timestamp);
     }
     SSTableData::from(memtable)
 }
@@ -41,7 +41,7 @@ fn test_merge_impl_simple() {
         None,
         None,
     );
-    let merged = size_tiered_compaction.merge_impl(&left, &right);
+    let merged = size_tiered_compaction.merge_impl(&left, &right, &[]);
     assert_eq!(merged, expected);
 }
 
@@ -71,7 +71,7 @@ fn test_merge_impl_duplicated() {
         None,
         None,
     );
-    let merged = size_tiered_compaction.merge_impl(&left, &right);
+    let merged = size_tiered_compaction.merge_impl(&left, &right, &[]);
     assert_eq!(merged, expected);
 }
 
@@ -102,10 +102,56 @@ fn test_merge_impl_interleaved() {
         None,
         None,
     );
-    let merged = size_tiered_compaction.merge_impl(&left, &right);
+    let merged = size_tiered_compaction.merge_impl(&left, &right, &[]);
     assert_eq!(merged, expected);
 }
 
+#[test]
+fn test_merge_impl_with_snapshots() {
+    let left = create_sstable_data(vec![
+        ("key1", "value1", 1),
+        ("key2", "value2", 2),
+    ]);
+    let right = create_sstable_data(vec![
+        ("key1", "value5", 5),
+        ("key2", "value6", 6),
+    ]);
+    let newer = create_sstable_data(vec![
+        ("key1", "value9", 9),
+    ]);
+    let size_tiered_compaction = super::SizeTieredCompaction::new(
+        get_page_size(),
+        None,
+        None,
+        None,
+    );
+
+    // スナップショット3からはkey1=value1, key2=value2が見える
+    let merged = size_tiered_compaction.merge_impl(&left, &right, &[3]);
+    assert_eq!(merged.iter().cloned().collect::<Vec<_>>(), vec![
+        SSTableRecord::new(b"key1".to_vec(), (Some(b"value5".to_vec()), 5)),
+        SSTableRecord::new(b"key1".to_vec(), (Some(b"value1".to_vec()), 1)),
+        SSTableRecord::new(b"key2".to_vec(), (Some(b"value6".to_vec()), 6)),
+        SSTableRecord::new(b"key2".to_vec(), (Some(b"value2".to_vec()), 2)),
+    ]);
+
+    // 複数バージョンを持つSSTableをさらにマージする
+    // スナップショット3はkey1=value1、7はkey1=value5を見る. key2=value2は1からしか見えない
+    let merged = size_tiered_compaction.merge_impl(&merged, &newer, &[1, 7]);
+    assert_eq!(merged.iter().cloned().collect::<Vec<_>>(), vec![
+        SSTableRecord::new(b"key1".to_vec(), (Some(b"value9".to_vec()), 9)),
+        SSTableRecord::new(b"key1".to_vec(), (Some(b"value5".to_vec()), 5)),
+        SSTableRecord::new(b"key1".to_vec(), (Some(b"value1".to_vec()), 1)),
+        SSTableRecord::new(b"key2".to_vec(), (Some(b"value6".to_vec()), 6)),
+    ]);
+    assert_eq!(merged.get_at(b"key1", 7), Some(&(Some(b"value5".to_vec()), 5)));
+    assert_eq!(merged.get_at(b"key2", 1), None);
+
+    // スナップショットが無ければ最新だけが残る
+    let merged = size_tiered_compaction.merge_impl(&merged, &create_sstable_data(vec![]), &[]);
+    assert_eq!(merged.iter().count(), 2);
+}
+
 #[test]
 fn test_merge() {
     let data1 = vec![
@@ -145,11 +191,12 @@ fn test_merge() {
         None,
         None,
     );
-    let merged = size_tiered_compaction.merge(vec);
+    let merged = size_tiered_compaction.merge(vec, &[]);
     assert_eq!(merged, expected);
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args, clippy::useless_vec)]
 fn test_get_interesting_bucket_with_med() {
     let ssts = SizeTieredCompaction::new(
         get_page_size(),
@@ -201,7 +248,7 @@ fn test_get_interesting_bucket_with_med() {
     }).collect::<Vec<Arc<SSTableReaderManager>>>();
 
 
-    let actual = ssts.get_interesting_bucket(&sstables);
+    let actual = ssts.get_interesting_bucket(&sstables).unwrap();
     for i in 0..actual.len() {
         assert_eq!(
             actual[i].reader(),
@@ -216,6 +263,7 @@ fn test_get_interesting_bucket_with_med() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args, clippy::useless_vec)]
 fn test_get_interesting_bucket_with_min() {
     let ssts = SizeTieredCompaction::new(
         get_page_size(),
@@ -272,7 +320,7 @@ fn test_get_interesting_bucket_with_min() {
         ).unwrap())
     }).collect::<Vec<Arc<SSTableReaderManager>>>();
 
-    let actual = ssts.get_interesting_bucket(&sstables);
+    let actual = ssts.get_interesting_bucket(&sstables).unwrap();
     for i in 0..actual.len() {
         assert_eq!(
             actual[i].reader(),
@@ -289,6 +337,7 @@ fn test_get_interesting_bucket_with_min() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args, clippy::useless_vec)]
 fn test_get_interesting_bucket_with_max() {
     let ssts = SizeTieredCompaction::new(
         get_page_size(),
@@ -340,7 +389,7 @@ fn test_get_interesting_bucket_with_max() {
         ).unwrap())
     }).collect::<Vec<Arc<SSTableReaderManager>>>();
 
-    let actual = ssts.get_interesting_bucket(&sstables);
+    let actual = ssts.get_interesting_bucket(&sstables).unwrap();
     for i in 0..actual.len() {
         assert_eq!(
             actual[i].reader(),
@@ -355,6 +404,7 @@ fn test_get_interesting_bucket_with_max() {
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_window_usage() {
     let vec = vec![1, 2, 3, 4, 5];
     for v in vec.windows(2) {
@@ -363,6 +413,7 @@ fn test_window_usage() {
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_chunk_usage() {
     let vec = vec![1, 2, 3, 4, 5];
     for v in vec.chunks(2) {
@@ -371,6 +422,7 @@ fn test_chunk_usage() {
 }
 
 #[test]
+#[allow(clippy::needless_borrow, clippy::needless_borrows_for_generic_args)]
 fn test_compact_simple() {
     let path = ".test_compact_test_simple";
     let data = vec![
@@ -421,19 +473,12 @@ fn test_compact_simple() {
     let sstable_data5 = create_sstable_data(data[12..].to_vec());
     writer.write_with_index(&sstable_data5, 34).unwrap();
 
-    let tables = fs::read_dir(&path).unwrap();
+    // MANIFESTがないので、ディレクトリにあるSSTableを全て読む
     let shared_sstable = SharedSSTableReader::new(
         path,
         "idx",
-    );
-
-    for table in tables {
-        let table = table.unwrap();
-        let path = table.path();
-        if path.is_file() && path.extension().unwrap() == "sst" {
-            shared_sstable.add_reader(path.to_str().unwrap());
-        }
-    }
+    ).unwrap();
+    assert_eq!(shared_sstable.get_all().len(), 5);
 
     let size_tiered_compaction = super::SizeTieredCompaction::new(
         get_page_size(),
@@ -442,14 +487,13 @@ fn test_compact_simple() {
         Some(4)
     );
 
-    let writer = SSTableWriter::new(&path).unwrap();
     assert!(size_tiered_compaction.compact(
         shared_sstable.clone(),
-        writer
+        &|| SSTableWriter::new(path).unwrap()
     ).is_ok());
 
-    let tables = fs::read_dir(&path).unwrap().filter(|v| {
-        v.as_ref().unwrap().path().extension().unwrap() == "sst"
+    let tables = fs::read_dir(path).unwrap().filter(|v| {
+        v.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "sst")
     })
     .map(|v| v.unwrap())
     .collect::<Vec<_>>();
@@ -459,7 +503,8 @@ fn test_compact_simple() {
     }).unwrap();
 
     let mut unique = BTreeMap::new();
-    for (k, v, ts) in data[0..12].iter() {
+    // indexなどの分だけファイルが大きくなるので、小さい5つ目のSSTableも同じbucketに入る
+    for (k, v, ts) in data.iter() {
         if let Some((prev_v, prev_ts)) = unique.get_mut(*k) {
             if *ts > *prev_ts {
                 *prev_v = *v;
@@ -469,23 +514,89 @@ fn test_compact_simple() {
             unique.insert(*k, (*v, *ts));
         }
     }
+    // indexとfooterも同じファイルにあるので、CRC32Cを除いたdata blockの大きさを比べる
+    let compacted_path = compacted.path().to_str().unwrap().to_string();
+    let compacted = SSTableReaderManager::new(&compacted_path, &(compacted_path.clone() + ".idx")).unwrap();
+    let SSTableFormat::Block(footer) = compacted.reader().format else {
+        panic!("compacted sstable must have a footer");
+    };
     assert_eq!(
-        compacted.metadata().unwrap().len(),
+        footer.data_size() - BLOCK_TRAILER_SIZE * compacted.index().unwrap().0.len() as u64,
         unique.iter().fold(0, 
-            |acc, (k, (v, _))| acc + k.len() + v.len() + 8 * 3
+            |acc, (k, (v, _))| acc + k.len() + 1 + v.len() + 8 * 3
         ) as u64 
     );
 
-    let files = fs::read_dir(&path).unwrap();
-    for file in files {
-        let file = file.unwrap().path();
-        shared_sstable.drop_resource(file.to_str().unwrap());
-    }
-
-    let tables = fs::read_dir(&path).unwrap();
+    // 入力のSSTableは今のバージョンから外れ、もう誰も読んでいないので消えている
+    assert_eq!(shared_sstable.get_all().len(), 1);
+    assert_eq!(shared_sstable.get_all()[0].file(), compacted_path);
+    let tables = fs::read_dir(path).unwrap();
     assert_eq!(
-        tables.filter(|v| v.as_ref().unwrap().path().extension().unwrap() == "sst").count(), 
-        2
+        tables.filter(|v| v.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "sst")).count(), 
+        1
     );
-    fs::remove_dir_all(&path).unwrap();
-}
\ No newline at end of file
+    drop(shared_sstable);
+
+    // 開き直しても、MANIFESTに記録したコンパクション後のSSTableだけを読む
+    let shared_sstable = SharedSSTableReader::new(path, "idx").unwrap();
+    assert_eq!(shared_sstable.get_all().len(), 1);
+    assert_eq!(shared_sstable.get_all()[0].file(), compacted_path);
+    drop(shared_sstable);
+    fs::remove_dir_all(path).unwrap();
+}
+#[test]
+fn test_compact_skips_without_writer() {
+    let path = ".test_compact_skips_without_writer";
+    if path::Path::new(path).exists() {
+        fs::remove_dir_all(path).unwrap();
+    }
+    fs::create_dir(path).unwrap();
+    let shared_sstable = SharedSSTableReader::new(path, "idx").unwrap();
+    let writer = shared_sstable.new_writer();
+    writer.write_with_index(&create_sstable_data(vec![("key1", "value1", 1)]), 34).unwrap();
+    shared_sstable.install(&writer, &[]).unwrap();
+
+    // マージするSSTableが足りなければwriterを作らないので、ファイル番号を使わない
+    let size_tiered_compaction = super::SizeTieredCompaction::new(get_page_size(), None, None, Some(4));
+    let result = size_tiered_compaction.compact(
+        shared_sstable.clone(),
+        &|| panic!("writer must not be created")
+    );
+    assert!(result.is_ok());
+    assert_eq!(shared_sstable.new_writer().file_number, Some(2));
+
+    drop(shared_sstable);
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_compact_corrupted_sstable() {
+    let path = ".test_compact_corrupted_sstable";
+    if path::Path::new(path).exists() {
+        fs::remove_dir_all(path).unwrap();
+    }
+    fs::create_dir(path).unwrap();
+    let shared_sstable = SharedSSTableReader::new(path, "idx").unwrap();
+    for i in 0..4 {
+        let writer = shared_sstable.new_writer();
+        writer.write_with_index(&create_sstable_data(vec![("key1", "value1", i)]), 34).unwrap();
+        shared_sstable.install(&writer, &[]).unwrap();
+    }
+    // 1つのSSTableのvalue1を書き換える. key_len(8) + key(4) + record_type(1) + value_len(8)
+    let corrupted = shared_sstable.get_all()[0].file().to_owned();
+    let mut content = fs::read(&corrupted).unwrap();
+    content[21] ^= 0xff;
+    fs::write(&corrupted, content).unwrap();
+
+    // panicせずにエラーを返し、入力のSSTableはそのまま残る
+    let size_tiered_compaction = super::SizeTieredCompaction::new(get_page_size(), None, None, Some(4));
+    let err = size_tiered_compaction.compact(
+        shared_sstable.clone(),
+        &|| shared_sstable.new_writer()
+    ).unwrap_err();
+    assert!(err.contains("corruption") && err.contains(&corrupted), "{}", err);
+    assert_eq!(shared_sstable.get_all().len(), 4);
+
+    drop(shared_sstable);
+    fs::remove_dir_all(path).unwrap();
+}
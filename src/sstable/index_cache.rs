@@ -0,0 +1,261 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use super::SSTableIndex;
+
+/*
+全てのSSTableReaderManagerで共有する、decode済みのindexのキャッシュ
+キーはreaderごとにnew_idで振るid
+容量(バイト)を決めると、超えたときに最後に使ったのが古いindexから捨てる. 捨てたindexは次に引いたときに読み直す
+pinしたSSTableのindexは捨てないが、容量には数える
+*/
+#[derive(Debug)]
+pub struct IndexCache {
+    // Noneなら捨てない
+    capacity: Option<usize>,
+    inner: Mutex<IndexCacheState>,
+    next_id: AtomicU64,
+    hit: AtomicU64,
+    miss: AtomicU64,
+    evict: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct IndexCacheState {
+    entries: HashMap<u64, IndexEntry>,
+    // 最後に使ったtick -> id. pinしていないものだけを持つ
+    order: BTreeMap<u64, u64>,
+    pinned: HashSet<u64>,
+    tick: u64,
+    usage: usize,
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+    index: Arc<SSTableIndex>,
+    charge: usize,
+    tick: u64,
+}
+
+impl IndexCacheState {
+    fn remove(&mut self, id: u64) -> Option<IndexEntry> {
+        let entry = self.entries.remove(&id)?;
+        self.order.remove(&entry.tick);
+        self.usage -= entry.charge;
+        Some(entry)
+    }
+}
+
+impl IndexCache {
+    pub fn new(capacity: Option<usize>) -> IndexCache {
+        IndexCache {
+            capacity,
+            inner: Mutex::new(IndexCacheState::default()),
+            next_id: AtomicU64::new(0),
+            hit: AtomicU64::new(0),
+            miss: AtomicU64::new(0),
+            evict: AtomicU64::new(0),
+        }
+    }
+
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn lookup(&self, id: u64) -> Option<Arc<SSTableIndex>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let pinned = inner.pinned.contains(&id);
+        let Some(entry) = inner.entries.get_mut(&id) else {
+            drop(inner);
+            self.miss.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let old_tick = std::mem::replace(&mut entry.tick, tick);
+        let index = entry.index.clone();
+        if !pinned {
+            inner.order.remove(&old_tick);
+            inner.order.insert(tick, id);
+        }
+        drop(inner);
+        self.hit.fetch_add(1, Ordering::Relaxed);
+        Some(index)
+    }
+
+    pub fn insert(&self, id: u64, index: Arc<SSTableIndex>) {
+        let charge = index.size() as usize;
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(id);
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.insert(id, IndexEntry { index, charge, tick });
+        if !inner.pinned.contains(&id) {
+            inner.order.insert(tick, id);
+        }
+        inner.usage += charge;
+        let evicted = self.evict_over_capacity(&mut inner);
+        drop(inner);
+        self.evict.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    // pinしたものだけで容量を超えるなら、超えたままにする
+    fn evict_over_capacity(&self, inner: &mut IndexCacheState) -> u64 {
+        let Some(capacity) = self.capacity else {
+            return 0;
+        };
+        let mut evicted = 0;
+        while inner.usage > capacity {
+            let Some((_, oldest)) = inner.order.pop_first() else {
+                break;
+            };
+            let entry = inner.entries.remove(&oldest).unwrap();
+            inner.usage -= entry.charge;
+            evicted += 1;
+        }
+        evicted
+    }
+
+    // pinしたindexは、unpinするかSSTableを閉じるまで捨てない
+    pub fn pin(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pinned.insert(id);
+        if let Some(entry) = inner.entries.get(&id) {
+            let tick = entry.tick;
+            inner.order.remove(&tick);
+        }
+    }
+
+    pub fn unpin(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.pinned.remove(&id) {
+            return;
+        }
+        if let Some(entry) = inner.entries.get(&id) {
+            let tick = entry.tick;
+            inner.order.insert(tick, id);
+        }
+        let evicted = self.evict_over_capacity(&mut inner);
+        drop(inner);
+        self.evict.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    // SSTableを閉じたときに捨てる
+    pub fn erase(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pinned.remove(&id);
+        inner.remove(id);
+    }
+
+    pub fn stats(&self) -> IndexCacheStats {
+        let inner = self.inner.lock().unwrap();
+        let pinned_usage = inner.pinned.iter()
+            .filter_map(|id| inner.entries.get(id))
+            .map(|entry| entry.charge)
+            .sum();
+        IndexCacheStats {
+            hit: self.hit.load(Ordering::Relaxed),
+            miss: self.miss.load(Ordering::Relaxed),
+            evict: self.evict.load(Ordering::Relaxed),
+            usage: inner.usage,
+            pinned_usage,
+            capacity: self.capacity,
+        }
+    }
+}
+
+// 同じキャッシュを共有しているかどうかで比べる
+impl PartialEq for IndexCache {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for IndexCache {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IndexCacheStats {
+    pub hit: u64,
+    // 初めて読んだときと、捨てられた後に読み直したとき
+    pub miss: u64,
+    pub evict: u64,
+    // メモリにあるindexの大きさの合計(バイト). pinしたものを含む
+    pub usage: usize,
+    pub pinned_usage: usize,
+    pub capacity: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::IndexCache;
+    use crate::sstable::SSTableIndex;
+
+    // 1エントリで8 + 4 + 8 = 20バイト
+    fn index(key: &[u8]) -> Arc<SSTableIndex> {
+        Arc::new([(key.to_vec(), 0)].into_iter().collect())
+    }
+
+    #[test]
+    fn test_index_cache_evict() {
+        let cache = IndexCache::new(Some(40));
+        let (id1, id2, id3) = (cache.new_id(), cache.new_id(), cache.new_id());
+        cache.insert(id1, index(b"key1"));
+        cache.insert(id2, index(b"key2"));
+        assert!(cache.lookup(id1).is_some());
+        // id2が一番古い
+        cache.insert(id3, index(b"key3"));
+        assert!(cache.lookup(id2).is_none());
+        assert_eq!(cache.lookup(id1), Some(index(b"key1")));
+        assert_eq!(cache.lookup(id3), Some(index(b"key3")));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hit, stats.miss, stats.evict, stats.usage), (3, 1, 1, 40));
+
+        // 容量を決めなければ捨てない
+        let cache = IndexCache::new(None);
+        for id in 0..100 {
+            cache.insert(id, index(b"key1"));
+        }
+        assert_eq!(cache.stats().usage, 2000);
+        assert_eq!(cache.stats().evict, 0);
+    }
+
+    #[test]
+    fn test_index_cache_pin() {
+        let cache = IndexCache::new(Some(40));
+        let (id1, id2, id3) = (cache.new_id(), cache.new_id(), cache.new_id());
+        cache.insert(id1, index(b"key1"));
+        cache.pin(id1);
+        cache.insert(id2, index(b"key2"));
+        cache.insert(id3, index(b"key3"));
+        // pinしたid1は古くても捨てない
+        assert!(cache.lookup(id1).is_some());
+        assert!(cache.lookup(id2).is_none());
+        assert_eq!(cache.stats().pinned_usage, 20);
+
+        // 読み込む前にpinしておいても捨てない
+        cache.pin(id2);
+        cache.insert(id2, index(b"key2"));
+        assert!(cache.lookup(id3).is_none());
+        assert_eq!(cache.stats().usage, 40);
+
+        // pinしたものだけで容量がいっぱいなら、pinしていないものは入れてもすぐ捨てる
+        cache.insert(id3, index(b"key3"));
+        assert!(cache.lookup(id3).is_none());
+        let id4 = cache.new_id();
+        cache.pin(id4);
+        cache.insert(id4, index(b"key4"));
+        assert_eq!(cache.stats().usage, 60);
+
+        // unpinすると容量に収まるまで捨てる
+        cache.unpin(id1);
+        assert!(cache.lookup(id1).is_none());
+        assert_eq!(cache.stats().usage, 40);
+
+        // 閉じたSSTableのindexはpinしていても捨てる
+        cache.erase(id2);
+        assert!(cache.lookup(id2).is_none());
+        assert_eq!(cache.stats().pinned_usage, 20);
+    }
+}
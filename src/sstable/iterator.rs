@@ -0,0 +1,175 @@
+use std::{ops::Bound, sync::Arc};
+
+use crate::{scan::ScanCursor, ReadOptions};
+
+use super::{reader::SSTableReaderManager, Key, Offset, SSTableIndex, SSTableRecord, Value};
+
+// SSTableをブロック(indexの1エントリ)単位で前後に読み進めるイテレータ
+// ファイル全体を読み込まず、必要になったブロックだけを読む
+// 位置はレコードとレコードの間を指し、next()は位置の後ろ、prev()は位置の前のレコードを返す
+pub struct SSTableIterator {
+    reader: Arc<SSTableReaderManager>,
+    index: Arc<SSTableIndex>,
+    // [begin, end)  endがNoneならdata blockの終わりまで
+    blocks: Vec<(Offset, Option<Offset>)>,
+    block: Option<usize>,
+    records: Vec<SSTableRecord>,
+    pos: usize,
+    lower: Bound<Key>,
+    upper: Bound<Key>,
+    options: ReadOptions,
+}
+
+impl SSTableIterator {
+    pub fn new(reader: Arc<SSTableReaderManager>, lower: Bound<Key>, upper: Bound<Key>, options: ReadOptions) -> Result<SSTableIterator, String> {
+        let index = reader.index_with_options(&options)?;
+        let offsets: Vec<Offset> = index.0.values().copied().collect();
+        let blocks = offsets.iter().enumerate()
+            .map(|(i, offset)| (*offset, offsets.get(i + 1).copied()))
+            .collect();
+
+        let mut iter = SSTableIterator {
+            reader,
+            index,
+            blocks,
+            block: None,
+            records: vec![],
+            pos: 0,
+            lower,
+            upper,
+            options,
+        };
+        iter.seek_to_first()?;
+        Ok(iter)
+    }
+
+    fn load_block(&mut self, block: usize) -> Result<(), String> {
+        if self.block == Some(block) {
+            return Ok(());
+        }
+        let (begin, end) = self.blocks[block];
+        let data = self.reader.read_block(begin, end, &self.options)?;
+        self.records = data.iter().cloned().collect();
+        self.block = Some(block);
+        Ok(())
+    }
+
+    // keyを含む可能性のあるブロック
+    // blocksはindexと同じ順に並んでいるので、先頭のキーが見つかったらoffsetで二分探索する
+    fn find_block(&self, key: &[u8]) -> usize {
+        self.index.0.range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .and_then(|(_, offset)| self.blocks.binary_search_by_key(offset, |(begin, _)| *begin).ok())
+            .unwrap_or(0)
+    }
+
+    fn is_below_lower(&self, key: &[u8]) -> bool {
+        match &self.lower {
+            Bound::Included(lower) => key < lower.as_slice(),
+            Bound::Excluded(lower) => key <= lower.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn is_above_upper(&self, key: &[u8]) -> bool {
+        match &self.upper {
+            Bound::Included(upper) => key > upper.as_slice(),
+            Bound::Excluded(upper) => key >= upper.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl ScanCursor for SSTableIterator {
+    fn next(&mut self) -> Option<Result<(Key, Value), String>> {
+        let mut block = self.block?;
+        loop {
+            if self.pos < self.records.len() {
+                let SSTableRecord(key, value) = &self.records[self.pos];
+                if self.is_above_upper(key) {
+                    return None;
+                }
+                self.pos += 1;
+                if self.is_below_lower(key) {
+                    continue;
+                }
+                return Some(Ok((key.clone(), value.clone())));
+            }
+            if block + 1 >= self.blocks.len() {
+                return None;
+            }
+            block += 1;
+            if let Err(e) = self.load_block(block) {
+                return Some(Err(e));
+            }
+            self.pos = 0;
+        }
+    }
+
+    fn prev(&mut self) -> Option<Result<(Key, Value), String>> {
+        let mut block = self.block?;
+        loop {
+            if self.pos > 0 {
+                let SSTableRecord(key, value) = &self.records[self.pos - 1];
+                if self.is_below_lower(key) {
+                    return None;
+                }
+                self.pos -= 1;
+                if self.is_above_upper(key) {
+                    continue;
+                }
+                return Some(Ok((key.clone(), value.clone())));
+            }
+            if block == 0 {
+                return None;
+            }
+            block -= 1;
+            if let Err(e) = self.load_block(block) {
+                return Some(Err(e));
+            }
+            self.pos = self.records.len();
+        }
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), String> {
+        if self.blocks.is_empty() {
+            return Ok(());
+        }
+        self.load_block(self.find_block(key))?;
+        self.pos = self.records.partition_point(|record| record.key().as_slice() < key);
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<(), String> {
+        if self.blocks.is_empty() {
+            return Ok(());
+        }
+        self.load_block(self.find_block(key))?;
+        self.pos = self.records.partition_point(|record| record.key().as_slice() <= key);
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<(), String> {
+        match self.lower.clone() {
+            Bound::Included(key) | Bound::Excluded(key) => self.seek(&key),
+            Bound::Unbounded if self.blocks.is_empty() => Ok(()),
+            Bound::Unbounded => {
+                self.load_block(0)?;
+                self.pos = 0;
+                Ok(())
+            },
+        }
+    }
+
+    fn seek_to_last(&mut self) -> Result<(), String> {
+        match self.upper.clone() {
+            Bound::Included(key) | Bound::Excluded(key) => self.seek_for_prev(&key),
+            Bound::Unbounded if self.blocks.is_empty() => Ok(()),
+            Bound::Unbounded => {
+                self.load_block(self.blocks.len() - 1)?;
+                self.pos = self.records.len();
+                Ok(())
+            },
+        }
+    }
+}
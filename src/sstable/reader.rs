@@ -1,6 +1,8 @@
-use std::{fs::{File, Metadata}, io::{Read, Seek}, sync::atomic::AtomicBool};
+use std::{fs::{File, Metadata}, io::{Read, Seek}, sync::{atomic::AtomicBool, Arc, OnceLock}};
 
-use super::{SSTableData, SSTableIndex, Value};
+use crate::{utils, ReadOptions};
+
+use super::{block_cache::BlockCache, bloom::SSTableFilter, index_cache::IndexCache, SSTableData, SSTableFooter, SSTableIndex, SSTableMeta, Value, BLOCK_TRAILER_SIZE};
 
 
 #[derive(Debug)]
@@ -18,14 +20,43 @@ impl SSTableReaderManager {
         })
     }
 
+    pub fn with_block_cache(mut self, block_cache: Arc<BlockCache>) -> Self {
+        let id = block_cache.new_id();
+        self.reader.block_cache = Some((block_cache, id));
+        self
+    }
+
+    pub fn with_index_cache(mut self, index_cache: Arc<IndexCache>) -> Self {
+        let id = index_cache.new_id();
+        self.reader.index_cache = Some((index_cache, id));
+        self
+    }
+
+    // indexをメモリ上限で捨てないようにする. index cacheがなければ元から捨てない
+    pub fn pin_index(&self) {
+        if let Some((index_cache, id)) = &self.reader.index_cache {
+            index_cache.pin(*id);
+        }
+    }
+
+    pub fn unpin_index(&self) {
+        if let Some((index_cache, id)) = &self.reader.index_cache {
+            index_cache.unpin(*id);
+        }
+    }
+
     pub fn file(&self) -> &str {
         &self.reader.file
     }
 
-    pub fn read(&self, key: &str) -> Result<Option<Value>, String> {
+    pub fn read(&self, key: &[u8]) -> Result<Option<Value>, String> {
         self.reader.read(key)
     }
 
+    pub fn read_at(&self, key: &[u8], sequence: u64, options: &ReadOptions) -> Result<Option<Value>, String> {
+        self.reader.read_at(key, sequence, options)
+    }
+
     pub fn metadata(&self) -> Result<Metadata, String> {
         self.reader.metadata()
     }
@@ -34,6 +65,30 @@ impl SSTableReaderManager {
         self.reader.data()
     }
 
+    pub fn index(&self) -> Result<Arc<SSTableIndex>, String> {
+        self.reader.index()
+    }
+
+    pub fn index_with_options(&self, options: &ReadOptions) -> Result<Arc<SSTableIndex>, String> {
+        self.reader.index_with_options(options)
+    }
+
+    pub fn meta(&self) -> Result<Arc<SSTableMeta>, String> {
+        self.reader.meta()
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> Result<Option<bool>, String> {
+        self.reader.may_contain(key)
+    }
+
+    pub fn may_contain_prefix(&self, name: &str, prefix: &[u8]) -> Result<Option<bool>, String> {
+        self.reader.may_contain_prefix(name, prefix)
+    }
+
+    pub fn read_block(&self, begin: u64, end: Option<u64>, options: &ReadOptions) -> Result<Arc<SSTableData>, String> {
+        self.reader.read_block(begin, end, options)
+    }
+
     pub fn delete(&self) {
         self.delete.store(true, std::sync::atomic::Ordering::Release);
     }
@@ -52,8 +107,16 @@ impl Drop for SSTableReaderManager {
     fn drop(&mut self) {
         let deleted = self.delete.load(std::sync::atomic::Ordering::Acquire);
         // dbg!("SSTableReaderManager drop: deleted = {}", deleted);
+        // idは使い回さないので、閉じたSSTableのblockは二度と引かれない
+        if let Some((block_cache, id)) = &self.reader.block_cache {
+            block_cache.erase(*id);
+        }
+        if let Some((index_cache, id)) = &self.reader.index_cache {
+            index_cache.erase(*id);
+        }
         if deleted {
             std::fs::remove_file(&self.reader.file).ok();
+            // 旧形式のときだけindexファイルがある
             std::fs::remove_file(&self.reader.index_file).ok();
         }
     }
@@ -62,12 +125,30 @@ impl Drop for SSTableReaderManager {
 
 type Offset = usize;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SSTableFormat {
+    // data, index, metaを1つのファイルに持つ
+    Block(SSTableFooter),
+    // indexを別ファイルに持つ
+    Legacy,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SSTableReader {
     pub file: String,
+    // 旧形式のindexファイル. Blockのときは使わない
     pub index_file: String,
-    pub index: SSTableIndex,
+    pub format: SSTableFormat,
+    // index cacheがなければ、初めて引いたときに読んだindexをここに持ち続ける
+    index: OnceLock<Arc<SSTableIndex>>,
     pub data: SSTableData,
+    // 初めて引いたときにfilter blockを読んで持っておく
+    filter: OnceLock<SSTableFilter>,
+    meta: OnceLock<Arc<SSTableMeta>>,
+    // 共有のブロックキャッシュと、その中でこのSSTableを表すid
+    block_cache: Option<(Arc<BlockCache>, u64)>,
+    // あればindexはここに置き、メモリ上限を超えたら捨てて読み直す
+    index_cache: Option<(Arc<IndexCache>, u64)>,
 }
 
 impl SSTableReader {
@@ -76,70 +157,291 @@ impl SSTableReader {
         if !std::path::Path::new(file).exists() {
             return Err(format!("{} not found", file));
         }
-        if !std::path::Path::new(index_file).exists() {
-            return Err(format!("{} not found", index_file));
-        }
 
-        // 3. index, dataの初期化
-        let index = SSTableIndex::new();
+        // 2. footerがあればBlock、なければindexファイルのある旧形式
+        let format = match Self::read_footer(file)? {
+            Some(footer) => SSTableFormat::Block(footer),
+            None if std::path::Path::new(index_file).exists() => SSTableFormat::Legacy,
+            None => return Err(format!("{} has no footer and {} not found", file, index_file)),
+        };
+
+        // 3. index, dataの初期化. indexは初めて引いたときに読む
+        let index = OnceLock::new();
         let data = SSTableData::new();
         Ok(
             SSTableReader {
                 file: file.to_string(),
                 index_file: index_file.to_string(),
+                format,
                 index,
                 data,
+                filter: OnceLock::new(),
+                meta: OnceLock::new(),
+                block_cache: None,
+                index_cache: None,
             }
         )
     }
 
+    pub fn with_block_cache(mut self, block_cache: Arc<BlockCache>) -> Self {
+        let id = block_cache.new_id();
+        self.block_cache = Some((block_cache, id));
+        self
+    }
+
+    // 末尾がmagicでなければNone
+    fn read_footer(file: &str) -> Result<Option<SSTableFooter>, String> {
+        let mut f = File::open(file).map_err(|e| e.to_string())?;
+        let file_size = f.metadata().map_err(|e| e.to_string())?.len();
+        if file_size < SSTableFooter::TAIL_SIZE {
+            return Ok(None);
+        }
+        let mut tail = vec![0u8; SSTableFooter::TAIL_SIZE as usize];
+        f.seek(std::io::SeekFrom::Start(file_size - SSTableFooter::TAIL_SIZE)).map_err(|e| e.to_string())?;
+        f.read_exact(&mut tail).map_err(|e| e.to_string())?;
+        let Some(version) = SSTableFooter::decode_tail(&tail) else {
+            return Ok(None);
+        };
+        let footer_size = SSTableFooter::size_of(version).map_err(|e| format!("{} in {}", e, file))?;
+        if file_size < footer_size {
+            return Err(format!("footer is truncated in {}", file));
+        }
+        let mut buf = vec![0u8; footer_size as usize];
+        f.seek(std::io::SeekFrom::Start(file_size - footer_size)).map_err(|e| e.to_string())?;
+        f.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        let footer = SSTableFooter::decode(&buf).map_err(|e| format!("{} in {}", e, file))?;
+        // 壊れたfooterのoffset + sizeはu64をあふれうる
+        let broken = || format!("corruption: footer is broken: {:?} in {}", footer, file);
+        let index_end = footer.index_offset.checked_add(footer.index_size).ok_or_else(broken)?;
+        let filter_end = footer.filter_offset.checked_add(footer.filter_size).ok_or_else(broken)?;
+        let file_end = footer.meta_offset.checked_add(footer.meta_size)
+            .and_then(|meta_end| meta_end.checked_add(footer_size))
+            .ok_or_else(broken)?;
+        if index_end > footer.meta_offset
+            || (footer.has_filter() && filter_end > footer.meta_offset)
+            || file_end != file_size {
+            return Err(broken());
+        }
+        Ok(Some(footer))
+    }
+
     pub fn metadata(&self) -> Result<Metadata, String> {
         std::fs::metadata(&self.file).map_err(|e| e.to_string())
     }
 
+    // コンパクションなどで全体を読むときは、常にCRC32Cを確かめる
     pub fn data(&self) -> Result<SSTableData, String> {
-        let mut f = File::open(&self.file).map_err(|e| e.to_string())?;
-        let mut buf = vec![];
-        f.read_to_end(buf.as_mut()).map_err(|e| e.to_string())?;
-        let data = SSTableData::decode(&buf).map_err(|e| e.to_string());
-        if let Err(e) = data {
-            return Err(format!("read_data error: {} in {}", e, self.file));
+        let end = self.data_end()?;
+        if !self.has_checksum() {
+            return Self::read_data(&self.file, 0, end);
+        }
+        let buf = self.read_bytes(0, end)?;
+        let offsets: Vec<u64> = self.index()?.0.values().copied().collect();
+        let mut data = SSTableData::new();
+        for (i, begin) in offsets.iter().enumerate() {
+            let block_end = offsets.get(i + 1).copied().unwrap_or(end);
+            let block = buf.get((*begin as usize)..(block_end as usize))
+                .ok_or(format!("corruption: block [{}, {}) is out of range in {}", begin, block_end, self.file))?;
+            let block = self.verify_block(block, *begin, true)?;
+            data.chunks.extend(self.decode_block(block, *begin)?.chunks);
         }
-        let data = data.unwrap();
         Ok(data)
     }
 
+    // 旧形式ではmetaがないので、データを全て読んで作る
+    // getのたびに引くので、一度読んだら持っておく
+    pub fn meta(&self) -> Result<Arc<SSTableMeta>, String> {
+        if let Some(meta) = self.meta.get() {
+            return Ok(meta.clone());
+        }
+        let meta = match self.format {
+            SSTableFormat::Block(footer) => {
+                let buf = self.read_bytes(footer.meta_offset, self.block_end(footer.meta_offset, footer.meta_size)?)?;
+                let buf = self.verify_block(&buf, footer.meta_offset, true)?;
+                SSTableMeta::decode(buf).map_err(|e| format!("{} in {}", e, self.file))?
+            },
+            SSTableFormat::Legacy => SSTableMeta::from_sstable_data(&self.data()?),
+        };
+        Ok(self.meta.get_or_init(|| Arc::new(meta)).clone())
+    }
+
+    // filterがなければNone. Some(false)ならキーはこのSSTableにない
+    pub fn may_contain(&self, key: &[u8]) -> Result<Option<bool>, String> {
+        Ok(self.filter()?.map(|filter| filter.key.may_contain(key)))
+    }
+
+    // nameのPrefixExtractorで作ったprefixのフィルタがなければNone
+    // Some(false)ならprefixで始まるキーはこのSSTableにない
+    pub fn may_contain_prefix(&self, name: &str, prefix: &[u8]) -> Result<Option<bool>, String> {
+        Ok(self.filter()?.and_then(|filter| filter.may_contain_prefix(name, prefix)))
+    }
+
+    fn filter(&self) -> Result<Option<&SSTableFilter>, String> {
+        let SSTableFormat::Block(footer) = self.format else {
+            return Ok(None);
+        };
+        if !footer.has_filter() {
+            return Ok(None);
+        }
+        if self.filter.get().is_none() {
+            let begin = footer.filter_offset;
+            let buf = self.read_bytes(begin, self.block_end(begin, footer.filter_size)?)?;
+            let buf = self.verify_block(&buf, begin, true)?;
+            let filter = SSTableFilter::decode(buf, footer.version)
+                .map_err(|e| format!("{} in {} at offset {}", e, self.file, begin))?;
+            // 同時に読んだときは先に入れた方を使う
+            let _ = self.filter.set(filter);
+        }
+        Ok(self.filter.get())
+    }
+
     pub fn is_file_exists(&self) -> bool {
         std::path::Path::new(&self.file).exists()
     }
 
-    pub fn read(&self, key: &str) -> Result<Option<Value>, String> {
-        Self::read_impl(&self.file, &self.index_file, key)
+    pub fn read(&self, key: &[u8]) -> Result<Option<Value>, String> {
+        self.read_at(key, u64::MAX, &ReadOptions::default())
     }
 
-    fn read_impl(file: &str, index_file: &str, key: &str) -> Result<Option<Value>, String> {
-        // let (header, offset) = Self::read_header(file)?;
-        let idx_file_size = std::fs::metadata(index_file).map_err(|e| e.to_string())?.len() as usize;
-        let index = Self::read_index(index_file, 0, idx_file_size)?;
-        if let Some((begin, end)) = index.find_key_range(&key.to_owned()) {
-            let end = end.unwrap_or(
-                File::open(file).map_err(|e| e.to_string())?.metadata().map_err(|e| e.to_string())?.len() as u64
-            );
-            let data = Self::read_data(file, begin, end)?;
-            let value = data.get(&key.to_owned(), None).cloned();
+    // sequence以下のバージョンのうち、最新のものを読む
+    pub fn read_at(&self, key: &[u8], sequence: u64, options: &ReadOptions) -> Result<Option<Value>, String> {
+        let index = self.index_with_options(options)?;
+        if let Some((begin, end)) = index.find_key_range(key) {
+            let data = self.read_block(begin, end, options)?;
+            let value = data.get_at(key, sequence).cloned();
             return Ok(value)
         }
         Ok(None)
     }
 
-    // pub fn read_header(file: &str) -> Result<(SSTableHeader, Offset), String> {
-    //     let mut buf = vec![0u8; 16];
-    //     let mut f = File::open(file).map_err(|e| e.to_string())?;
-    //     let _ = f.read_exact(&mut buf).map_err(|e| e.to_string())?;
-    //     let header = SSTableHeader::decode(&buf).map_err(|e| e.to_string())?;
-    //     Ok((header, 16))
-    // }
+    pub fn index(&self) -> Result<Arc<SSTableIndex>, String> {
+        self.index_with_options(&ReadOptions::default())
+    }
+
+    // 一度読んだindexはメモリに置いておき、ファイルを読み直さない
+    pub fn index_with_options(&self, _options: &ReadOptions) -> Result<Arc<SSTableIndex>, String> {
+        if let Some(index) = self.index.get() {
+            return Ok(index.clone());
+        }
+        if let Some((index_cache, id)) = &self.index_cache {
+            if let Some(index) = index_cache.lookup(*id) {
+                return Ok(index);
+            }
+        }
+        // indexは必ず持っておくので、optionsによらず初めて読むときにCRC32Cを確かめる
+        let index = Arc::new(self.read_index_block()?);
+        match &self.index_cache {
+            Some((index_cache, id)) => index_cache.insert(*id, index.clone()),
+            None => {
+                let _ = self.index.set(index.clone());
+            },
+        }
+        Ok(index)
+    }
+
+    fn read_index_block(&self) -> Result<SSTableIndex, String> {
+        match self.format {
+            SSTableFormat::Block(footer) => {
+                let begin = footer.index_offset;
+                let buf = self.read_bytes(begin, self.block_end(begin, footer.index_size)?)?;
+                let buf = self.verify_block(&buf, begin, true)?;
+                SSTableIndex::decode(buf).map_err(|e| format!("read_index error: {} in {} at offset {}", e, self.file, begin))
+            },
+            SSTableFormat::Legacy => Self::read_whole_index(&self.index_file),
+        }
+    }
+
+    // indexの指すブロック[begin, end)を読む. endがNoneならdata blockの終わりまで
+    // ブロックキャッシュにあればファイルを読まない
+    pub fn read_block(&self, begin: u64, end: Option<u64>, options: &ReadOptions) -> Result<Arc<SSTableData>, String> {
+        if let Some((block_cache, id)) = &self.block_cache {
+            if let Some(block) = block_cache.lookup(*id, begin) {
+                return Ok(block);
+            }
+        }
+        let end = match end {
+            Some(end) => end,
+            None => self.data_end()?,
+        };
+        // キャッシュに入れるblockは、optionsによらず初めて読むときにCRC32Cを確かめる
+        // キャッシュに入れずに読むときだけ、verify_checksumsで確かめるかを決める
+        let fill_cache = options.fill_cache && self.block_cache.is_some();
+        let block = if self.has_checksum() {
+            let buf = self.read_bytes(begin, end)?;
+            let block = self.verify_block(&buf, begin, fill_cache || options.verify_checksums)?;
+            Arc::new(self.decode_block(block, begin)?)
+        } else {
+            Arc::new(Self::read_data(&self.file, begin, end)?)
+        };
+        if let Some((block_cache, id)) = &self.block_cache {
+            if fill_cache {
+                block_cache.insert(*id, begin, block.clone(), (end - begin) as usize);
+            }
+        }
+        Ok(block)
+    }
+
+    fn data_end(&self) -> Result<u64, String> {
+        match self.format {
+            SSTableFormat::Block(footer) => Ok(footer.data_size()),
+            SSTableFormat::Legacy => Ok(self.metadata()?.len()),
+        }
+    }
+
+    fn has_checksum(&self) -> bool {
+        matches!(self.format, SSTableFormat::Block(footer) if footer.has_checksum())
+    }
+
+    // blockの末尾のCRC32Cを除いた中身を返す. offsetはblockのファイル内の位置
+    fn verify_block<'a>(&self, block: &'a [u8], offset: u64, verify: bool) -> Result<&'a [u8], String> {
+        if !self.has_checksum() {
+            return Ok(block);
+        }
+        let Some(content_len) = block.len().checked_sub(BLOCK_TRAILER_SIZE as usize) else {
+            return Err(format!("corruption: block is too short in {} at offset {}", self.file, offset));
+        };
+        let (content, trailer) = block.split_at(content_len);
+        let expected = u32::from_ne_bytes(trailer.try_into().map_err(|e: std::array::TryFromSliceError| e.to_string())?);
+        if verify {
+            let actual = utils::crc32c(content);
+            if actual != expected {
+                return Err(format!(
+                    "corruption: checksum mismatch in {} at offset {}: expected {:08x}, actual {:08x}",
+                    self.file, offset, expected, actual,
+                ));
+            }
+        }
+        Ok(content)
+    }
+
+    fn decode_block(&self, block: &[u8], offset: u64) -> Result<SSTableData, String> {
+        let data = match self.format {
+            SSTableFormat::Block(footer) if footer.has_record_type() => SSTableData::decode(block),
+            _ => SSTableData::decode_without_record_type(block),
+        };
+        data.map_err(|e| format!("read_data error: {} in {} at offset {}", e, self.file, offset))
+    }
 
+    // offsetからsizeバイトのblockの終わり
+    fn block_end(&self, offset: u64, size: u64) -> Result<u64, String> {
+        offset.checked_add(size).ok_or(format!("corruption: block at offset {} with size {} is out of range in {}", offset, size, self.file))
+    }
+
+    // [begin, end)
+    fn read_bytes(&self, begin: u64, end: u64) -> Result<Vec<u8>, String> {
+        let mut f = File::open(&self.file).map_err(|e| e.to_string())?;
+        let mut buf = vec![0u8; end.checked_sub(begin).ok_or(format!("corruption: invalid range [{}, {}) in {}", begin, end, self.file))? as usize];
+        f.seek(std::io::SeekFrom::Start(begin)).map_err(|e| e.to_string())?;
+        f.read_exact(&mut buf).map_err(|e| format!("{} in {} at offset {}", e, self.file, begin))?;
+        Ok(buf)
+    }
+
+    fn read_whole_index(index_file: &str) -> Result<SSTableIndex, String> {
+        let idx_file_size = std::fs::metadata(index_file).map_err(|e| e.to_string())?.len() as usize;
+        Self::read_index(index_file, 0, idx_file_size)
+    }
+
+    #[allow(clippy::let_unit_value)]
     pub fn read_index(file: &str, offset: Offset, size: usize) -> Result<SSTableIndex, String> {
         let mut buf = vec![0u8; size];
         let mut f = File::open(file).map_err(|e| e.to_string())?;
@@ -150,12 +452,15 @@ impl SSTableReader {
     }
 
     // [begin, end)
+    // CRC32Cのないversion 1と旧形式のファイルを読むので、レコードに種類はない
+    #[allow(clippy::needless_return)]
     pub fn read_data(file: &str, begin: u64, end: u64) -> Result<SSTableData, String> {
         let mut f = File::open(file).map_err(|e| e.to_string())?;
-        let mut buf = vec![0u8; (end - begin) as usize];
+        let len = end.checked_sub(begin).ok_or(format!("corruption: invalid range [{}, {}) in {}", begin, end, file))?;
+        let mut buf = vec![0u8; len as usize];
         f.seek(std::io::SeekFrom::Start(begin)).map_err(|e| e.to_string())?;
-        let _ = f.read_exact(&mut buf).map_err(|e| e.to_string())?;
-        let data = SSTableData::decode(&buf).map_err(|e| e.to_string());
+        f.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        let data = SSTableData::decode_without_record_type(&buf).map_err(|e| e.to_string());
         match data {
             Err(e) => {
                 return Err(format!("read_data error: {} in {}", e, file));
@@ -171,11 +476,12 @@ impl SSTableReader {
 
 #[cfg(test)]
 mod tests{
-    use std::fs;
+    use std::{fs, sync::Arc};
 
-    use crate::sstable::reader::SSTableReader;
+    use crate::{memtable::MemTable, prefix_extractor::{DelimitedPrefixExtractor, PrefixExtractor}, sstable::{reader::{SSTableFormat, SSTableReader}, SSTableData, SSTableFooter, SSTableIndex, SSTableMeta, SSTableWriter}, utils::{crc32c, get_page_size}, ReadOptions};
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_sst_reader_new() {
         let path = "/tmp/test_sst_reader_new.sst";
         let index_path = "/tmp/test_sst_reader_new.sst.idx";
@@ -205,6 +511,7 @@ mod tests{
     }
 
     #[test]
+    #[allow(clippy::map_flatten, clippy::useless_vec)]
     fn test_sst_reader_simple_read() {
         let path = "/tmp/test_sst_reader_simple_read.sst";
         let idx_path = path.to_string() + ".idx";
@@ -248,13 +555,14 @@ mod tests{
     
         let sst_reader = SSTableReader::new(path, &idx_path).unwrap();
         for (k, v) in kvs {
-            let value = sst_reader.read(k).unwrap();
-            assert_eq!(value, Some((Some(v.to_string()), timestamp)));
+            let value = sst_reader.read(k.as_bytes()).unwrap();
+            assert_eq!(value, Some((Some(v.as_bytes().to_vec()), timestamp)));
         }
         fs::remove_file(path).unwrap();
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_sst_reader_read_deleted() {
         let path = "/tmp/test_sst_reader_read_deleted.sst";
         let idx_path = path.to_string() + ".idx";
@@ -301,17 +609,18 @@ mod tests{
         let sst_reader = SSTableReader::new(path, &idx_path).unwrap();
         
         // 削除されたキーの読み取りテスト
-        let value = sst_reader.read("key2").unwrap();
+        let value = sst_reader.read(b"key2").unwrap();
         assert_eq!(value, Some((None, timestamp))); // 削除されたキーはNoneが返される
         
         // 通常のキーの読み取りテスト
-        let value = sst_reader.read("key1").unwrap();
-        assert_eq!(value, Some((Some("value1".to_string()), timestamp)));
+        let value = sst_reader.read(b"key1").unwrap();
+        assert_eq!(value, Some((Some(b"value1".to_vec()), timestamp)));
         
         fs::remove_file(path).unwrap();
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_sst_reader_read_not_exists() {
         let path = "/tmp/test_sst_reader_read_not_exists.sst";
         let idx_path = path.to_string() + ".idx";
@@ -357,17 +666,18 @@ mod tests{
         let sst_reader = SSTableReader::new(path, &idx_path).unwrap();
         
         // 存在しないキーの読み取りテスト
-        let value = sst_reader.read("key2").unwrap();
+        let value = sst_reader.read(b"key2").unwrap();
         assert_eq!(value, None); // 存在しないキーはNoneが返される
         
         // 存在するキーの読み取りテスト
-        let value = sst_reader.read("key1").unwrap();
-        assert_eq!(value, Some((Some("value1".to_string()), timestamp)));
+        let value = sst_reader.read(b"key1").unwrap();
+        assert_eq!(value, Some((Some(b"value1".to_vec()), timestamp)));
         
         fs::remove_file(path).unwrap();
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_sst_reader_read_big_data() {
         let path = "/tmp/test_sst_reader_read_big_data.sst";
         let idx_path = path.to_string() + ".idx";
@@ -417,13 +727,316 @@ mod tests{
         let sst_reader = SSTableReader::new(path, &idx_path).unwrap();
         
         // 大きなデータの読み取りテスト
-        let value = sst_reader.read("key2").unwrap();
-        assert_eq!(value, Some((Some(big_value), timestamp))); // 大きなデータは正しく読み取れる
+        let value = sst_reader.read(b"key2").unwrap();
+        assert_eq!(value, Some((Some(big_value.into_bytes()), timestamp))); // 大きなデータは正しく読み取れる
 
         // 通常のキーの読み取りテスト
-        let value = sst_reader.read("key1").unwrap();
-        assert_eq!(value, Some((Some("value1".to_string()), timestamp)));
+        let value = sst_reader.read(b"key1").unwrap();
+        assert_eq!(value, Some((Some(b"value1".to_vec()), timestamp)));
         
         fs::remove_file(path).unwrap();
     }
+
+    #[test]
+    fn test_sst_reader_block_format() {
+        let dir = "/tmp/test_sst_reader_block_format";
+        if fs::exists(dir).unwrap() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(dir).unwrap();
+
+        let memtable = MemTable::new();
+        for i in 0..1000u64 {
+            if i % 5 == 0 {
+                memtable.delete(format!("key{:04}", i).as_bytes(), i + 1);
+            } else {
+                memtable.put(format!("key{:04}", i).as_bytes(), format!("value{}", i).as_bytes(), i + 1);
+            }
+        }
+        let writer = SSTableWriter::new(dir).unwrap();
+        writer.write(&memtable, get_page_size()).unwrap();
+        // indexファイルは作られない
+        assert!(!fs::exists(&writer.index_file).unwrap());
+
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        assert!(matches!(sst_reader.format, SSTableFormat::Block(_)));
+        assert!(sst_reader.index().unwrap().0.len() > 1);
+        assert_eq!(sst_reader.read(b"key0000").unwrap(), Some((None, 1)));
+        assert_eq!(sst_reader.read(b"key0999").unwrap(), Some((Some(b"value999".to_vec()), 1000)));
+        assert_eq!(sst_reader.read(b"key1000").unwrap(), None);
+        // data blockだけを読む
+        assert_eq!(sst_reader.data().unwrap().iter().count(), 1000);
+        assert_eq!(*sst_reader.meta().unwrap(), SSTableMeta {
+            num_records: 1000,
+            max_sequence: 1000,
+            smallest_key: b"key0000".to_vec(),
+            largest_key: b"key0999".to_vec(),
+            min_sequence: 1,
+        });
+        // 書いたキーはTombstoneも含めてフィルタを通る
+        assert!((0..1000).all(|i| sst_reader.may_contain(format!("key{:04}", i).as_bytes()).unwrap() == Some(true)));
+        let false_positives = (1000..2000)
+            .filter(|i| sst_reader.may_contain(format!("key{:04}", i).as_bytes()).unwrap() == Some(true))
+            .count();
+        assert!(false_positives < 50, "false_positives = {}", false_positives);
+
+        // フィルタを作らなければNone
+        let writer = SSTableWriter::new(dir).unwrap().with_bloom_bits_per_key(0);
+        writer.write(&memtable, get_page_size()).unwrap();
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        assert_eq!(sst_reader.may_contain(b"key2000").unwrap(), None);
+        assert_eq!(sst_reader.read(b"key0999").unwrap(), Some((Some(b"value999".to_vec()), 1000)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sst_reader_prefix_filter() {
+        let dir = "/tmp/test_sst_reader_prefix_filter";
+        if fs::exists(dir).unwrap() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(dir).unwrap();
+
+        let memtable = MemTable::new();
+        for i in 0..100 {
+            memtable.put(format!("tenant{:02}/entity/{}", i, i).as_bytes(), b"value", i + 1);
+        }
+        // prefixを持たないキーも書ける
+        memtable.put(b"noprefix", b"value", 101);
+        let extractor = DelimitedPrefixExtractor::new(b'/', 2);
+        let writer = SSTableWriter::new(dir).unwrap().with_prefix_extractor(Some(Arc::new(extractor)));
+        writer.write(&memtable, get_page_size()).unwrap();
+
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        let name = extractor.name();
+        assert!((0..100).all(|i| sst_reader.may_contain_prefix(&name, format!("tenant{:02}/entity/", i).as_bytes()).unwrap() == Some(true)));
+        let false_positives = (100..1000)
+            .filter(|i| sst_reader.may_contain_prefix(&name, format!("tenant{:02}/entity/", i).as_bytes()).unwrap() == Some(true))
+            .count();
+        assert!(false_positives < 50, "false_positives = {}", false_positives);
+        // 違うPrefixExtractorのフィルタは使わない
+        assert_eq!(sst_reader.may_contain_prefix("fixed:7", b"tenant").unwrap(), None);
+        assert_eq!(sst_reader.may_contain(b"noprefix").unwrap(), Some(true));
+
+        // PrefixExtractorがなければprefixのフィルタは作らない
+        let writer = SSTableWriter::new(dir).unwrap();
+        writer.write(&memtable, get_page_size()).unwrap();
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        assert_eq!(sst_reader.may_contain_prefix(&name, b"tenant00/entity/").unwrap(), None);
+        assert_eq!(sst_reader.may_contain(b"tenant00/entity/0").unwrap(), Some(true));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sst_reader_legacy_format() {
+        let dir = "/tmp/test_sst_reader_legacy_format";
+        if fs::exists(dir).unwrap() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(dir).unwrap();
+
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", 1);
+        memtable.delete(b"key2", 2);
+        let data = SSTableData::from(memtable);
+        let writer = SSTableWriter::new(dir).unwrap();
+        writer.write_data(&data).unwrap();
+        writer.write_index(&data).unwrap();
+
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        assert_eq!(sst_reader.format, SSTableFormat::Legacy);
+        assert_eq!(sst_reader.read(b"key1").unwrap(), Some((Some(b"value1".to_vec()), 1)));
+        assert_eq!(sst_reader.read(b"key2").unwrap(), Some((None, 2)));
+        assert_eq!(sst_reader.meta().unwrap().max_sequence, 2);
+        assert_eq!(sst_reader.may_contain(b"key3").unwrap(), None);
+
+        // indexは一度だけ読み、indexファイルを消しても読める
+        fs::remove_file(&writer.index_file).unwrap();
+        assert_eq!(sst_reader.read(b"key1").unwrap(), Some((Some(b"value1".to_vec()), 1)));
+
+        // footerもindexファイルもなければ開けない
+        assert!(SSTableReader::new(&writer.file, &writer.index_file).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sst_reader_legacy_format_multi_block() {
+        let dir = "/tmp/test_sst_reader_legacy_format_multi_block";
+        if fs::exists(dir).unwrap() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(dir).unwrap();
+
+        let memtable = MemTable::new();
+        for i in 0..500u64 {
+            let key = format!("key{:03}", i);
+            if i % 7 == 0 {
+                memtable.delete(key.as_bytes(), i);
+            } else {
+                memtable.put(key.as_bytes(), format!("value{}", i).as_bytes(), i);
+            }
+        }
+        let data = SSTableData::from(memtable);
+        let writer = SSTableWriter::new(dir).unwrap();
+        writer.write_data(&data).unwrap();
+        writer.write_index(&data).unwrap();
+
+        // indexのoffsetはレコードの種類を含まない旧形式のdataを指す
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        assert!(sst_reader.index().unwrap().0.len() > 1);
+        for i in 0..500u64 {
+            let expected = if i % 7 == 0 { None } else { Some(format!("value{}", i).into_bytes()) };
+            assert_eq!(sst_reader.read(format!("key{:03}", i).as_bytes()).unwrap(), Some((expected, i)));
+        }
+        assert_eq!(sst_reader.data().unwrap(), data);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sst_reader_detects_corruption() {
+        let dir = "/tmp/test_sst_reader_detects_corruption";
+        if fs::exists(dir).unwrap() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(dir).unwrap();
+
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", 1);
+        memtable.put(b"key2", b"value2", 2);
+        let writer = SSTableWriter::new(dir).unwrap();
+        writer.write(&memtable, get_page_size()).unwrap();
+
+        // key1の値の1バイト目を書き換える. key_len(8) + key(4) + record_type(1) + value_len(8)
+        let mut content = fs::read(&writer.file).unwrap();
+        content[21] ^= 0xff;
+        fs::write(&writer.file, &content).unwrap();
+
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        let err = sst_reader.read(b"key1").unwrap_err();
+        assert!(err.starts_with("corruption"), "{}", err);
+        assert!(err.contains(&writer.file) && err.contains("offset 0"), "{}", err);
+        assert!(sst_reader.data().is_err());
+
+        // 確かめなければ、壊れた値がそのまま読める
+        let options = ReadOptions { verify_checksums: false, ..Default::default() };
+        let value = sst_reader.read_at(b"key1", u64::MAX, &options).unwrap().unwrap();
+        assert_ne!(value, (Some(b"value1".to_vec()), 1));
+
+        // indexが壊れていてもpanicしない
+        let SSTableFormat::Block(footer) = sst_reader.format else {
+            panic!("sstable must have a footer");
+        };
+        content[21] ^= 0xff;
+        content[footer.index_offset as usize] = 0xff;
+        fs::write(&writer.file, &content).unwrap();
+        // 読み込み済みのindexはファイルを読み直さない
+        assert_eq!(sst_reader.read(b"key1").unwrap(), Some((Some(b"value1".to_vec()), 1)));
+        let sst_reader = SSTableReader::new(&writer.file, &writer.index_file).unwrap();
+        let err = sst_reader.read(b"key1").unwrap_err();
+        assert!(err.contains(&format!("offset {}", footer.index_offset)), "{}", err);
+        assert!(sst_reader.read_at(b"key1", u64::MAX, &options).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sst_reader_rejects_overflowing_footer() {
+        let dir = "/tmp/test_sst_reader_rejects_overflowing_footer";
+        if fs::exists(dir).unwrap() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(dir).unwrap();
+
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", 1);
+        let writer = SSTableWriter::new(dir).unwrap();
+        writer.write(&memtable, get_page_size()).unwrap();
+        let SSTableFormat::Block(footer) = SSTableReader::new(&writer.file, &writer.index_file).unwrap().format else {
+            panic!("sstable must have a footer");
+        };
+        let content = fs::read(&writer.file).unwrap();
+        let body = &content[..content.len() - SSTableFooter::size_of(footer.version).unwrap() as usize];
+
+        // offset + sizeがu64をあふれると、足した結果は範囲内に見える
+        let meta_end = footer.meta_offset + footer.meta_size;
+        for broken in [
+            SSTableFooter { index_size: u64::MAX, ..footer },
+            SSTableFooter { filter_size: u64::MAX, ..footer },
+            SSTableFooter { meta_offset: u64::MAX, meta_size: meta_end + 1, ..footer },
+        ] {
+            fs::write(&writer.file, [body, &broken.encode()].concat()).unwrap();
+            let err = SSTableReader::new(&writer.file, &writer.index_file).unwrap_err();
+            assert!(err.starts_with("corruption: footer is broken"), "{}", err);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sst_reader_without_checksum() {
+        let path = "/tmp/test_sst_reader_without_checksum.sst";
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", 1);
+        memtable.delete(b"key2", 2);
+        let data = SSTableData::from(memtable);
+
+        // version 1ではblockにCRC32Cがなく、レコードに種類もない
+        let mut buf = data.encode_without_record_type();
+        let index_offset = buf.len() as u64;
+        buf.extend_from_slice(&SSTableIndex::from_sstable_data(&data, get_page_size() as u64).encode());
+        let meta_offset = buf.len() as u64;
+        buf.extend_from_slice(&SSTableMeta::from_sstable_data(&data).encode());
+        let footer = SSTableFooter {
+            version: 1,
+            ..SSTableFooter::new(index_offset, meta_offset - index_offset, meta_offset, 0, meta_offset, buf.len() as u64 - meta_offset)
+        };
+        buf.extend_from_slice(&footer.encode());
+        fs::write(path, buf).unwrap();
+
+        let sst_reader = SSTableReader::new(path, &(path.to_string() + ".idx")).unwrap();
+        assert_eq!(sst_reader.read(b"key1").unwrap(), Some((Some(b"value1".to_vec()), 1)));
+        assert_eq!(sst_reader.read(b"key2").unwrap(), Some((None, 2)));
+        assert_eq!(sst_reader.data().unwrap(), data);
+        assert_eq!(sst_reader.meta().unwrap().max_sequence, 2);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sst_reader_without_record_type() {
+        let path = "/tmp/test_sst_reader_without_record_type.sst";
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", 1);
+        memtable.delete(b"key2", 2);
+        let data = SSTableData::from(memtable);
+
+        // version 4ではレコードに種類がなく、"\0"の値がTombstoneになる
+        let block = |buf: &mut Vec<u8>, content: &[u8]| {
+            buf.extend_from_slice(content);
+            buf.extend_from_slice(&crc32c(content).to_ne_bytes());
+        };
+        let mut buf = vec![];
+        block(&mut buf, &data.encode_without_record_type());
+        let index_offset = buf.len() as u64;
+        block(&mut buf, &SSTableIndex::from_sstable_data(&data, get_page_size() as u64).encode());
+        let meta_offset = buf.len() as u64;
+        block(&mut buf, &SSTableMeta::from_sstable_data(&data).encode());
+        let footer = SSTableFooter {
+            version: 4,
+            ..SSTableFooter::new(index_offset, meta_offset - index_offset, meta_offset, 0, meta_offset, buf.len() as u64 - meta_offset)
+        };
+        buf.extend_from_slice(&footer.encode());
+        fs::write(path, buf).unwrap();
+
+        let sst_reader = SSTableReader::new(path, &(path.to_string() + ".idx")).unwrap();
+        assert_eq!(sst_reader.read(b"key1").unwrap(), Some((Some(b"value1".to_vec()), 1)));
+        assert_eq!(sst_reader.read(b"key2").unwrap(), Some((None, 2)));
+        assert_eq!(sst_reader.data().unwrap(), data);
+
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -1,6 +1,6 @@
 use std::vec;
 
-use crate::memtable;
+use crate::{memtable, scan::ScanCursor};
 
 use super::*;
 
@@ -8,15 +8,15 @@ use super::*;
 fn test_sst_index_from_memtable() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
     let page_size = get_page_size() as u64;
-    let mut memtable = memtable::MemTable::new();
-    memtable.put("a", "1", timestamp);
-    memtable.put("b", "2", timestamp);
-    memtable.put("c", "3", timestamp);
+    let memtable = memtable::MemTable::new();
+    memtable.put(b"a", b"1", timestamp);
+    memtable.put(b"b", b"2", timestamp);
+    memtable.put(b"c", b"3", timestamp);
     let data = SSTableData::from(memtable);
     let sst_index = SSTableIndex::from_sstable_data(&data, page_size);
     assert_eq!(sst_index.0.len(), 1);
     assert_eq!(sst_index.get(
-        &"a".to_owned()).unwrap(), 
+        b"a").unwrap(), 
         &0
     );
 }
@@ -25,12 +25,12 @@ fn test_sst_index_from_memtable() {
 fn test_sst_index_from_memtable_page_size_data() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
     let page_size = get_page_size() as u64;
-    let mut memtable = memtable::MemTable::new();
+    let memtable = memtable::MemTable::new();
     for i in 0..4 {
-        let value = "a".repeat(get_page_size() - 25); // 25 is the (bits of length of key and value) + (key length)
+        let value = "a".repeat(get_page_size() - 26); // 26 is the (bits of length of key and value) + (key length) + (record type)
         memtable.put(
-            &i.to_string(), 
-            &value, 
+            i.to_string().as_bytes(), 
+            value.as_bytes(), 
             timestamp,
         );
     }
@@ -40,10 +40,10 @@ fn test_sst_index_from_memtable_page_size_data() {
     assert_eq!(sst_index.0.len(), 4);
     
     // 実際の値を取得して検証
-    let actual_offset_0 = sst_index.get(&"0".to_owned()).unwrap();
-    let actual_offset_1 = sst_index.get(&"1".to_owned()).unwrap();
-    let actual_offset_2 = sst_index.get(&"2".to_owned()).unwrap();
-    let actual_offset_3 = sst_index.get(&"3".to_owned()).unwrap();
+    let actual_offset_0 = sst_index.get(b"0").unwrap();
+    let actual_offset_1 = sst_index.get(b"1").unwrap();
+    let actual_offset_2 = sst_index.get(b"2").unwrap();
+    let actual_offset_3 = sst_index.get(b"3").unwrap();
     
     // 実際の値を出力（デバッグ用）
     println!("actual_offset_0: {}", actual_offset_0);
@@ -80,11 +80,11 @@ fn test_sst_index_from_memtable_page_size_data() {
 fn test_sst_index_from_memtable_crossing_page_size() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
     let page_size = get_page_size() as u64;
-    let mut memtable = MemTable::new();
-    memtable.put("1", "a".repeat(get_page_size()).as_str(), timestamp);
-    memtable.put("3", "c".repeat(get_page_size()).as_str(), timestamp);
-    memtable.put("2", "b".repeat(get_page_size() / 2).as_str(), timestamp);
-    memtable.put("キー4", "d", timestamp);
+    let memtable = MemTable::new();
+    memtable.put(b"1", "a".repeat(get_page_size()).as_bytes(), timestamp);
+    memtable.put(b"3", "c".repeat(get_page_size()).as_bytes(), timestamp);
+    memtable.put(b"2", "b".repeat(get_page_size() / 2).as_bytes(), timestamp);
+    memtable.put("キー4".as_bytes(), b"d", timestamp);
 
     let data = SSTableData::from(memtable);
     let sst_index = SSTableIndex::from_sstable_data(&data, page_size);
@@ -92,9 +92,9 @@ fn test_sst_index_from_memtable_crossing_page_size() {
     assert_eq!(sst_index.0.len(), 3);
     
     // 実際の値を取得して検証
-    let actual_offset_1 = sst_index.get(&"1".to_owned()).unwrap();
-    let actual_offset_2 = sst_index.get(&"2".to_owned()).unwrap();
-    let actual_offset_key4 = sst_index.get(&"キー4".to_owned()).unwrap();
+    let actual_offset_1 = sst_index.get(b"1").unwrap();
+    let actual_offset_2 = sst_index.get(b"2").unwrap();
+    let actual_offset_key4 = sst_index.get("キー4".as_bytes()).unwrap();
     
     // 実際の値を出力（デバッグ用）
     println!("actual_offset_1: {}", actual_offset_1);
@@ -103,8 +103,8 @@ fn test_sst_index_from_memtable_crossing_page_size() {
     
     // 期待値を計算
     let expected_offset_1 = 0;
-    let expected_offset_2 = page_size + 25;
-    let expected_offset_key4 = page_size * 2 + 75 + page_size / 2;
+    let expected_offset_2 = page_size + 26;
+    let expected_offset_key4 = page_size * 2 + 78 + page_size / 2;
     
     // 期待値を出力（デバッグ用）
     println!("expected_offset_1: {}", expected_offset_1);
@@ -133,18 +133,21 @@ fn test_sst_index_tryfrom_data() {
     let data = SSTableData::try_from(vec![
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "a".as_bytes().to_vec(), // key: "a"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "1".as_bytes().to_vec(), // value: "1"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "b".as_bytes().to_vec(), // key: "b"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "2".as_bytes().to_vec(), // value: "2"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "c".as_bytes().to_vec(), // key: "c"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "3".as_bytes().to_vec(), // value: "3"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
@@ -153,7 +156,7 @@ fn test_sst_index_tryfrom_data() {
     let index = SSTableIndex::from_sstable_data(&data, page_size);
     assert_eq!(index.0.len(), 1);
     assert_eq!(
-        index.get(&"a".to_owned()).unwrap(), 
+        index.get(b"a").unwrap(), 
         &0
     );
 }
@@ -162,13 +165,14 @@ fn test_sst_index_tryfrom_data() {
 fn test_sst_index_tryfrom_data_page_size_data() {
     let mut data = vec![];
     let page_size = get_page_size() as u64;
-    let value = "a".repeat(get_page_size() - 25);
+    let value = "a".repeat(get_page_size() - 26);
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
 
     for i in 0usize..4usize {
         data.extend_from_slice(&[
             i.to_string().len().to_ne_bytes().to_vec(),
             i.to_string().as_bytes().to_vec(),
+            vec![RecordType::Value as u8],
             value.len().to_ne_bytes().to_vec(),
             value.as_bytes().to_vec(),
             timestamp.to_ne_bytes().to_vec(), // タイムスタンプを最後に
@@ -180,10 +184,10 @@ fn test_sst_index_tryfrom_data_page_size_data() {
     assert_eq!(index.0.len(), 4);
 
     // 実際の値を取得して検証
-    let actual_offset_0 = index.get(&"0".to_owned()).unwrap();
-    let actual_offset_1 = index.get(&"1".to_owned()).unwrap();
-    let actual_offset_2 = index.get(&"2".to_owned()).unwrap();
-    let actual_offset_3 = index.get(&"3".to_owned()).unwrap();
+    let actual_offset_0 = index.get(b"0").unwrap();
+    let actual_offset_1 = index.get(b"1").unwrap();
+    let actual_offset_2 = index.get(b"2").unwrap();
+    let actual_offset_3 = index.get(b"3").unwrap();
     
     // 実際の値を出力（デバッグ用）
     println!("actual_offset_0: {}", actual_offset_0);
@@ -224,6 +228,7 @@ fn test_sst_index_tryfrom_data_crossing_page_size() {
         // 1つ目のレコード
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "1".as_bytes().to_vec(), // key: "1"
+        vec![RecordType::Value as u8], // record_type
         get_page_size().to_ne_bytes().to_vec(), // value_len
         "a".repeat(get_page_size()).as_bytes().to_vec(), // value
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
@@ -231,6 +236,7 @@ fn test_sst_index_tryfrom_data_crossing_page_size() {
         // 2つ目のレコード
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "2".as_bytes().to_vec(), // key: "2"
+        vec![RecordType::Value as u8], // record_type
         (get_page_size() / 2).to_ne_bytes().to_vec(), // value_len
         "b".repeat(get_page_size() / 2).as_bytes().to_vec(), // value
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
@@ -238,6 +244,7 @@ fn test_sst_index_tryfrom_data_crossing_page_size() {
         // 3つ目のレコード
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "3".as_bytes().to_vec(), // key: "3"
+        vec![RecordType::Value as u8], // record_type
         get_page_size().to_ne_bytes().to_vec(), // value_len
         "c".repeat(get_page_size()).as_bytes().to_vec(), // value
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
@@ -245,6 +252,7 @@ fn test_sst_index_tryfrom_data_crossing_page_size() {
         // 4つ目のレコード
         7u64.to_ne_bytes().to_vec(), // key_len: 7
         "キー4".as_bytes().to_vec(), // key: "キー4"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "d".as_bytes().to_vec(), // value: "d"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
@@ -257,9 +265,9 @@ fn test_sst_index_tryfrom_data_crossing_page_size() {
     );
     
     // 実際の値を取得して検証
-    let actual_offset_1 = index.get(&"1".to_owned()).unwrap();
-    let actual_offset_2 = index.get(&"2".to_owned()).unwrap();
-    let actual_offset_key4 = index.get(&"キー4".to_owned()).unwrap();
+    let actual_offset_1 = index.get(b"1").unwrap();
+    let actual_offset_2 = index.get(b"2").unwrap();
+    let actual_offset_key4 = index.get("キー4".as_bytes()).unwrap();
     
     // 実際の値を出力（デバッグ用）
     println!("actual_offset_1: {}", actual_offset_1);
@@ -268,9 +276,9 @@ fn test_sst_index_tryfrom_data_crossing_page_size() {
     
     // 期待値を計算
     let expected_offset_1 = 0;
-    let protruding_2 = 25u64; // 17u64 + 8u64(タイムスタンプ)
+    let protruding_2 = 26u64; // 18u64 + 8u64(タイムスタンプ)
     let expected_offset_2 = page_size + protruding_2;
-    let protruding_key4 = 25u64 + 42u64 + 8u64;
+    let protruding_key4 = 26u64 + 43u64 + 9u64;
     let expected_offset_key4 = page_size * 2 + protruding_key4 + page_size / 2;
     
     // 期待値を出力（デバッグ用）
@@ -290,6 +298,7 @@ fn test_sst_index_tryfrom_data_crossing_page_size() {
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_sst_index_encode() {
     let mut vec = vec![
         ("c", 1000u64),
@@ -299,7 +308,7 @@ fn test_sst_index_encode() {
     let mut sst_index = SSTableIndex::new();
 
     vec.iter().for_each(|(key, offset)| {
-        sst_index.insert((*key).to_owned(), *offset);
+        sst_index.insert(key.as_bytes().to_vec(), *offset);
     });
     let encoded = sst_index.encode();
     let mut buf = Vec::new();
@@ -327,12 +336,13 @@ fn test_sst_index_decode() {
     ];
     let decoded = SSTableIndex::decode(&encoded).unwrap();
     assert_eq!(decoded.0.len(), 3);
-    assert_eq!(decoded.get(&"a".to_owned()).unwrap(), &0);
-    assert_eq!(decoded.get(&"b".to_owned()).unwrap(), &3);
-    assert_eq!(decoded.get(&"c".to_owned()).unwrap(), &1000);
+    assert_eq!(decoded.get(b"a").unwrap(), &0);
+    assert_eq!(decoded.get(b"b").unwrap(), &3);
+    assert_eq!(decoded.get(b"c").unwrap(), &1000);
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_sst_index_find_key_range() {
     let vec = vec![
         ("c", 1000u64),
@@ -342,15 +352,15 @@ fn test_sst_index_find_key_range() {
     let mut sst_index = SSTableIndex::new();
 
     vec.iter().for_each(|(key, offset)| {
-        sst_index.insert((*key).to_owned(), *offset);
+        sst_index.insert(key.as_bytes().to_vec(), *offset);
     });
 
-    assert_eq!(sst_index.find_key_range(&"a".to_owned()), None);
-    assert_eq!(sst_index.find_key_range(&"b".to_owned()), Some((0, Some(1000))));
-    assert_eq!(sst_index.find_key_range(&"c".to_owned()), Some((1000, Some(2000))));
-    assert_eq!(sst_index.find_key_range(&"d".to_owned()), Some((1000, Some(2000))));
-    assert_eq!(sst_index.find_key_range(&"e".to_owned()), Some((2000, None)));
-    assert_eq!(sst_index.find_key_range(&"f".to_owned()), Some((2000, None)));
+    assert_eq!(sst_index.find_key_range(b"a"), None);
+    assert_eq!(sst_index.find_key_range(b"b"), Some((0, Some(1000))));
+    assert_eq!(sst_index.find_key_range(b"c"), Some((1000, Some(2000))));
+    assert_eq!(sst_index.find_key_range(b"d"), Some((1000, Some(2000))));
+    assert_eq!(sst_index.find_key_range(b"e"), Some((2000, None)));
+    assert_eq!(sst_index.find_key_range(b"f"), Some((2000, None)));
 }
 
 #[test]
@@ -359,28 +369,56 @@ fn test_sst_data_try_from_u8_slice() {
     let data = SSTableData::try_from(vec![
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "a".as_bytes().to_vec(), // key: "a"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "1".as_bytes().to_vec(), // value: "1"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "b".as_bytes().to_vec(), // key: "b"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "2".as_bytes().to_vec(), // value: "2"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "c".as_bytes().to_vec(), // key: "c"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "3".as_bytes().to_vec(), // value: "3"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
     ].concat()).unwrap();
 
     // タイムスタンプを含むため、データサイズが増加
-    assert_eq!(data.len(), 78); // 3 * (8(timestamp) + 8(key_len) + 1(key) + 8(value_len) + 1(value)) = 78
-    assert_eq!(data.get(&"a".to_owned(), Some(0)), Some(&(Some("1".to_owned()), timestamp)));
-    assert_eq!(data.get(&"b".to_owned(), Some(0)), Some(&(Some("2".to_owned()), timestamp)));
-    assert_eq!(data.get(&"c".to_owned(), None), Some(&(Some("3".to_owned()), timestamp)));
+    assert_eq!(data.len(), 81); // 3 * (8(timestamp) + 8(key_len) + 1(key) + 1(record_type) + 8(value_len) + 1(value)) = 81
+    assert_eq!(data.get(b"a", Some(0)), Some(&(Some(b"1".to_vec()), timestamp)));
+    assert_eq!(data.get(b"b", Some(0)), Some(&(Some(b"2".to_vec()), timestamp)));
+    assert_eq!(data.get(b"c", None), Some(&(Some(b"3".to_vec()), timestamp)));
+}
+
+#[test]
+fn test_sst_data_multi_version() {
+    // 同じキーの古いバージョンがページサイズを超えて並ぶ
+    let versions = get_page_size() / 32 + 10;
+    let mut data = SSTableData::new();
+    data.push(SSTableRecord::new(b"a".to_vec(), (Some(b"1".to_vec()), 1))).unwrap();
+    for timestamp in (1..=versions as u64).rev() {
+        data.push(SSTableRecord::new(b"b".to_vec(), (Some(timestamp.to_string().into_bytes()), timestamp * 10))).unwrap();
+    }
+    data.push(SSTableRecord::new(b"c".to_vec(), (None, 5))).unwrap();
+
+    // 同じキーのバージョンは1つのchunkに収まる
+    let chunk = data.chunks.iter().position(|chunk| chunk.0.iter().any(|record| record.key() == b"b")).unwrap();
+    assert!(data.chunks.iter().skip(chunk + 1).all(|chunk| chunk.0.iter().all(|record| record.key() != b"b")));
+
+    let decoded = SSTableData::decode(&data.encode()).unwrap();
+    assert_eq!(decoded, data);
+    assert_eq!(decoded.get(b"b", None), Some(&(Some(versions.to_string().into_bytes()), versions as u64 * 10)));
+    assert_eq!(decoded.get_at(b"b", 25), Some(&(Some(b"2".to_vec()), 20)));
+    assert_eq!(decoded.get_at(b"b", 9), None);
+    assert_eq!(decoded.get_at(b"a", 9), Some(&(Some(b"1".to_vec()), 1)));
+    assert_eq!(decoded.get_at(b"c", 4), None);
+    assert_eq!(decoded.get_at(b"c", 5), Some(&(None, 5)));
 }
 
 #[test]
@@ -390,18 +428,21 @@ fn test_sst_data_iter() {
     let data = SSTableData::try_from(vec![
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "a".as_bytes().to_vec(), // key: "a"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "1".as_bytes().to_vec(), // value: "1"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "b".as_bytes().to_vec(), // key: "b"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "2".as_bytes().to_vec(), // value: "2"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "c".as_bytes().to_vec(), // key: "c"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "3".as_bytes().to_vec(), // value: "3"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
@@ -411,19 +452,19 @@ fn test_sst_data_iter() {
 
     // イテレータから取得したレコードを検証
     let record = iter.next().unwrap();
-    assert_eq!(record.key(), &"a".to_owned());
-    assert_eq!(record.value(), &(Some("1".to_owned()), timestamp));
-    assert_eq!(record.timestamp(), timestamp);
+    assert_eq!(record.key(), &b"a".to_vec());
+    assert_eq!(record.value(), &(Some(b"1".to_vec()), timestamp));
+    assert_eq!(record.sequence(), timestamp);
 
     let record = iter.next().unwrap();
-    assert_eq!(record.key(), &"b".to_owned());
-    assert_eq!(record.value(), &(Some("2".to_owned()), timestamp));
-    assert_eq!(record.timestamp(), timestamp);
+    assert_eq!(record.key(), &b"b".to_vec());
+    assert_eq!(record.value(), &(Some(b"2".to_vec()), timestamp));
+    assert_eq!(record.sequence(), timestamp);
 
     let record = iter.next().unwrap();
-    assert_eq!(record.key(), &"c".to_owned());
-    assert_eq!(record.value(), &(Some("3".to_owned()), timestamp));
-    assert_eq!(record.timestamp(), timestamp);
+    assert_eq!(record.key(), &b"c".to_vec());
+    assert_eq!(record.value(), &(Some(b"3".to_vec()), timestamp));
+    assert_eq!(record.sequence(), timestamp);
 
     assert_eq!(iter.next(), None);
 }
@@ -434,40 +475,44 @@ fn test_sst_records_get_existed_key() {
     let data = SSTableData::try_from(vec![
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "a".as_bytes().to_vec(), // key: "a"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "1".as_bytes().to_vec(), // value: "1"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "b".as_bytes().to_vec(), // key: "b"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "2".as_bytes().to_vec(), // value: "2"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "c".as_bytes().to_vec(), // key: "c"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "3".as_bytes().to_vec(), // value: "3"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
     ].concat()).unwrap();
 
-    assert_eq!(data.get(&"a".to_owned(), Some(0)), Some(&(Some("1".to_owned()), timestamp)));
-    assert_eq!(data.get(&"b".to_owned(), Some(0)), Some(&(Some("2".to_owned()), timestamp)));
-    assert_eq!(data.get(&"c".to_owned(), Some(0)), Some(&(Some("3".to_owned()), timestamp)));
+    assert_eq!(data.get(b"a", Some(0)), Some(&(Some(b"1".to_vec()), timestamp)));
+    assert_eq!(data.get(b"b", Some(0)), Some(&(Some(b"2".to_vec()), timestamp)));
+    assert_eq!(data.get(b"c", Some(0)), Some(&(Some(b"3".to_vec()), timestamp)));
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_sst_records_get_deleted_key() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
     let data = SSTableData::try_from(vec![
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "a".as_bytes().to_vec(), // key: "a"
-        1u64.to_ne_bytes().to_vec(), // value_len: 1
-        "\0".as_bytes().to_vec(), // value: "1"
+        vec![RecordType::Tombstone as u8], // record_type
+        0u64.to_ne_bytes().to_vec(), // value_len: 0
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
     ].concat()).unwrap();
 
-    assert_eq!(data.get(&"a".to_owned(), Some(0)), Some(&(None, timestamp)));
+    assert_eq!(data.get(b"a", Some(0)), Some(&(None, timestamp)));
 }
 
 #[test]
@@ -476,22 +521,25 @@ fn test_sst_records_get_not_existed_key() {
     let data = SSTableData::try_from(vec![
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "a".as_bytes().to_vec(), // key: "a"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "1".as_bytes().to_vec(), // value: "1"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "b".as_bytes().to_vec(), // key: "b"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "2".as_bytes().to_vec(), // value: "2"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
         1u64.to_ne_bytes().to_vec(), // key_len: 1
         "c".as_bytes().to_vec(), // key: "c"
+        vec![RecordType::Value as u8], // record_type
         1u64.to_ne_bytes().to_vec(), // value_len: 1
         "3".as_bytes().to_vec(), // value: "3"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
     ].concat()).unwrap();
 
-    assert_eq!(data.get(&"d".to_owned(), Some(0)), None);
+    assert_eq!(data.get(b"d", Some(0)), None);
 }
 
 #[test]
@@ -511,6 +559,7 @@ fn test_sst_records_get_many_chunks_with_small_record() {
         sst_raw_data.extend_from_slice(&[
             key.len().to_ne_bytes().to_vec(),
             key.to_vec(),
+            vec![RecordType::Value as u8],
             value.len().to_ne_bytes().to_vec(),
             value.to_vec(),
             timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
@@ -521,39 +570,68 @@ fn test_sst_records_get_many_chunks_with_small_record() {
     for i in 0..chunk_size {
         let key = i.to_string();
         let value = i.to_string();
-        assert_eq!(data.get(&key, None), Some(&(Some(value), timestamp)));
+        assert_eq!(data.get(key.as_bytes(), None), Some(&(Some(value.into_bytes()), timestamp)));
     }
 }
 
 #[test]
 fn test_sst_record_encode() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
-    let record = SSTableRecord::new("a".to_owned(), (Some("1".to_owned()), timestamp));
+    let record = SSTableRecord::new(b"a".to_vec(), (Some(b"1".to_vec()), timestamp));
     let encoded = record.encode();
     let mut buf = Vec::new();
     buf.extend_from_slice(&1u64.to_ne_bytes());
     buf.extend_from_slice("a".as_bytes());
+    buf.push(RecordType::Value as u8);
     buf.extend_from_slice(&1u64.to_ne_bytes());
     buf.extend_from_slice("1".as_bytes());
     buf.extend_from_slice(&timestamp.to_ne_bytes());
     assert_eq!(encoded, buf);
+    assert_eq!(encoded.len(), record.size());
 }
 
 #[test]
 fn test_sst_record_encode_deleted() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
-    let record = SSTableRecord::new("a".to_owned(), (None, timestamp));
+    let record = SSTableRecord::new(b"a".to_vec(), (None, timestamp));
     let encoded = record.encode();
     let mut buf = Vec::new();
     buf.extend_from_slice(&1u64.to_ne_bytes());
     buf.extend_from_slice("a".as_bytes());
+    buf.push(RecordType::Tombstone as u8);
+    buf.extend_from_slice(&0u64.to_ne_bytes());
+    buf.extend_from_slice(&timestamp.to_ne_bytes());
+    assert_eq!(encoded, buf);
+    assert_eq!(encoded.len(), record.size());
+
+    // 旧形式では"\0"の値として書く
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&1u64.to_ne_bytes());
+    buf.extend_from_slice("a".as_bytes());
     buf.extend_from_slice(&1u64.to_ne_bytes());
     buf.extend_from_slice("\0".as_bytes());
     buf.extend_from_slice(&timestamp.to_ne_bytes());
-    assert_eq!(encoded, buf);
+    assert_eq!(record.encode_without_record_type(), buf);
 }
 
 #[test]
+fn test_sst_record_decode_zero_value() {
+    let timestamp = 12345u64; // テスト用の固定タイムスタンプ
+    // "\0"の値も種類で区別するので、削除にはならない
+    let record = SSTableRecord::new(b"a".to_vec(), (Some(b"\0".to_vec()), timestamp));
+    let encoded = record.encode();
+    assert_eq!(SSTableRecord::decode(&encoded, true).unwrap(), (record, 27));
+    let deleted = SSTableRecord::new(b"a".to_vec(), (None, timestamp));
+    assert_eq!(SSTableRecord::decode(&deleted.encode(), true).unwrap(), (deleted, 26));
+
+    let mut broken = encoded.clone();
+    broken[9] = 9;
+    assert!(SSTableRecord::decode(&broken, true).is_err());
+}
+
+// 以下の2つは種類を持たない旧形式
+#[test]
+#[allow(clippy::useless_vec)]
 fn test_sst_record_decode_inserted() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
     let encoded = vec![
@@ -563,12 +641,13 @@ fn test_sst_record_decode_inserted() {
         "1".as_bytes().to_vec(), // value: "1"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
     ].concat();
-    let decoded = SSTableRecord::decode(&encoded).unwrap();
-    assert_eq!(decoded.0, SSTableRecord("a".to_owned(), (Some("1".to_owned()), timestamp)));
+    let decoded = SSTableRecord::decode(&encoded, false).unwrap();
+    assert_eq!(decoded.0, SSTableRecord(b"a".to_vec(), (Some(b"1".to_vec()), timestamp)));
     assert_eq!(decoded.1, 26);
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_sst_record_decode_deleted() {
     let timestamp = 12345u64; // テスト用の固定タイムスタンプ
     let encoded = vec![
@@ -578,7 +657,86 @@ fn test_sst_record_decode_deleted() {
         "\0".as_bytes().to_vec(), // value: "1"
         timestamp.to_ne_bytes().to_vec(), // タイムスタンプ
     ].concat();
-    let decoded = SSTableRecord::decode(&encoded).unwrap();
-    assert_eq!(decoded.0, SSTableRecord("a".to_owned(), (None, timestamp)));
+    let decoded = SSTableRecord::decode(&encoded, false).unwrap();
+    assert_eq!(decoded.0, SSTableRecord(b"a".to_vec(), (None, timestamp)));
     assert_eq!(decoded.1, 26);
 }
+
+#[test]
+fn test_sst_meta_decode_overflowing_key_len() {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&1u64.to_ne_bytes());
+    buf.extend_from_slice(&1u64.to_ne_bytes());
+    // smallest_keyの長さが大きすぎて、終わりの位置があふれる
+    buf.extend_from_slice(&u64::MAX.to_ne_bytes());
+    buf.extend_from_slice(b"a");
+    let err = SSTableMeta::decode(&buf).unwrap_err();
+    assert!(err.starts_with("corruption"), "{}", err);
+}
+
+#[test]
+fn test_sst_iterator_moves_across_blocks() {
+    let dir = "/tmp/test_sst_iterator_reads_blocks_in_range";
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+
+    let timestamp = 12345u64;
+    let memtable = memtable::MemTable::new();
+    for i in 0..1000 {
+        // Tombstoneを混ぜて、複数ブロックにまたがるindexのoffsetがずれないことを確認する
+        if i % 7 == 0 {
+            memtable.delete(format!("key{:04}", i).as_bytes(), timestamp);
+        } else {
+            memtable.put(format!("key{:04}", i).as_bytes(), format!("value{}", i).as_bytes(), timestamp);
+        }
+    }
+    let writer = SSTableWriter::new(dir).unwrap();
+    writer.write(&memtable, get_page_size()).unwrap();
+    let reader = std::sync::Arc::new(
+        reader::SSTableReaderManager::new(&writer.file, &writer.index_file).unwrap()
+    );
+    assert!(reader.index().unwrap().0.len() > 1);
+
+    let mut iter = iterator::SSTableIterator::new(reader.clone(), std::ops::Bound::Unbounded, std::ops::Bound::Unbounded, crate::ReadOptions::default()).unwrap();
+    let all = std::iter::from_fn(|| iter.next())
+        .collect::<Result<Vec<_>, String>>()
+        .unwrap();
+    assert_eq!(all.len(), 1000);
+    assert_eq!(all[0], (b"key0000".to_vec(), (None, timestamp)));
+    assert_eq!(all[1], (b"key0001".to_vec(), (Some(b"value1".to_vec()), timestamp)));
+
+    assert!(iter.next().is_none());
+    // 末尾から逆向きに、ブロックをまたいで戻れる
+    let reversed = std::iter::from_fn(|| iter.prev())
+        .map(|entry| entry.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(reversed, (0..1000).rev().map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+
+    let mut iter = iterator::SSTableIterator::new(
+            reader.clone(),
+            std::ops::Bound::Excluded(b"key0500".to_vec()),
+            std::ops::Bound::Included(b"key0600".to_vec()),
+            crate::ReadOptions::default(),
+        ).unwrap();
+    let ranged = std::iter::from_fn(|| iter.next())
+        .map(|entry| entry.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(ranged, (501..=600).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+
+    iter.seek(b"key0550").unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0, b"key0550");
+    assert_eq!(iter.prev().unwrap().unwrap().0, b"key0550");
+    assert_eq!(iter.prev().unwrap().unwrap().0, b"key0549");
+    iter.seek_for_prev(b"key0550").unwrap();
+    assert_eq!(iter.prev().unwrap().unwrap().0, b"key0550");
+    iter.seek_to_first().unwrap();
+    assert!(iter.prev().is_none());
+    assert_eq!(iter.next().unwrap().unwrap().0, b"key0501");
+    iter.seek_to_last().unwrap();
+    assert!(iter.next().is_none());
+    assert_eq!(iter.prev().unwrap().unwrap().0, b"key0600");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -1,14 +1,21 @@
-use std::{fs::File, io::Write, thread, time::Duration};
+use std::{fs::File, io::Write, sync::Arc, thread, time::Duration};
 
-use crate::{memtable::MemTable, utils};
+use crate::{memtable::MemTable, prefix_extractor::PrefixExtractor, utils};
 
-use super::{SSTableData, SSTableIndex};
+use super::{bloom::{BloomFilter, SSTableFilter, DEFAULT_BITS_PER_KEY}, SSTableData, SSTableFooter, SSTableIndex, SSTableMeta, BLOCK_TRAILER_SIZE};
 
 
 #[derive(Debug)]
 pub struct SSTableWriter {
     pub file: String,
+    // MANIFESTの番号. newで作ったときはなく、バージョンに加えるときに割り当てる
+    pub file_number: Option<u64>,
+    // 旧形式(write_data, write_index)で書くときのindexファイル
     pub index_file: String,
+    // Bloomフィルタの1キーあたりのビット数. 0ならフィルタを作らない
+    pub bloom_bits_per_key: usize,
+    // あればprefixのBloomフィルタも作る
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
 }
 
 impl SSTableWriter {
@@ -17,52 +24,134 @@ impl SSTableWriter {
         let index_file = format!("{}.idx", file);
         Ok(SSTableWriter {
             file,
+            file_number: None,
             index_file,
+            bloom_bits_per_key: DEFAULT_BITS_PER_KEY,
+            prefix_extractor: None,
         })
     }
 
+    // ファイル名をMANIFESTの番号から決める
+    pub fn numbered(dir: &str, file_number: u64) -> SSTableWriter {
+        let file = format!("{}/{:06}.sst", dir, file_number);
+        let index_file = format!("{}.idx", file);
+        SSTableWriter {
+            file,
+            file_number: Some(file_number),
+            index_file,
+            bloom_bits_per_key: DEFAULT_BITS_PER_KEY,
+            prefix_extractor: None,
+        }
+    }
+
+    pub fn with_bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Option<Arc<dyn PrefixExtractor>>) -> Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+
     pub fn write(&self, memtable: &MemTable, index_interval: usize) -> Result<(), String> {
         thread::sleep(Duration::from_millis(1));
-        Self::write_impl(memtable, &self.file, &self.index_file, index_interval)
+        self.write_impl(memtable, &self.file, index_interval)
     }
 
-    fn write_impl(memtable: &MemTable, file: &str, index_file: &str, index_interval: usize) -> Result<(), String> {
-        let mut file = File::create(file).map_err(|e| e.to_string())?;
-        let mut index_file = File::create(index_file).map_err(|e| e.to_string())?;
-        let data = SSTableData::try_from(memtable.encode())?;
-        let index = SSTableIndex::from_sstable_data(&data, index_interval as u64);
+    fn write_impl(&self, memtable: &MemTable, file: &str, index_interval: usize) -> Result<(), String> {
         let data = SSTableData::try_from(memtable.encode())?;
-        Self::write_data_impl(&mut file, &data)?;
-        Self::write_index_impl(&mut index_file, &index)
+        self.write_durably(&data, file, index_interval)
     }
 
     pub fn write_with_index(&self, data: &SSTableData, index_interval: usize) -> Result<(), String> {
-        let mut file = File::create(&self.file).map_err(|e| e.to_string())?;
-        let mut index_file = File::create(&self.index_file).map_err(|e| e.to_string())?;
-        let index = SSTableIndex::from_sstable_data(data, index_interval as u64);
-        Self::write_data_impl(&mut file, data)?;
-        Self::write_index_impl(&mut index_file, &index)
+        self.write_durably(data, &self.file, index_interval)
+    }
+
+    /*
+    data, index, metaの後にfooterを書き、一時ファイルからrenameする
+    fileは書き終えてfsyncした後にしか現れないので、書き込み途中のSSTableは読まれない
+    Okが返ったら、SSTableは再起動後も読める
+    */
+    fn write_durably(&self, data: &SSTableData, file: &str, index_interval: usize) -> Result<(), String> {
+        let buf = self.encode_table(data, index_interval);
+        utils::write_file_durably(file, |f| f.write_all(&buf).map_err(|e| e.to_string()))
+    }
+
+    // | data block ... | index block | filter block | meta block | footer |
+    // 各blockの後ろにはCRC32Cを置く
+    fn encode_table(&self, data: &SSTableData, index_interval: usize) -> Vec<u8> {
+        // data blockのoffsetは、それより前のblockのCRC32Cの分だけずれる
+        let index: SSTableIndex = SSTableIndex::from_sstable_data(data, index_interval as u64)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (key, offset))| (key, offset + BLOCK_TRAILER_SIZE * i as u64))
+            .collect();
+        let mut buf = vec![];
+        for chunk in data.chunks.iter() {
+            Self::append_block(&mut buf, &chunk.encode(true));
+        }
+        let index_offset = buf.len() as u64;
+        Self::append_block(&mut buf, &index.encode());
+        let filter_offset = buf.len() as u64;
+        if self.bloom_bits_per_key > 0 {
+            // 同じキーの古いバージョンは1つにまとめる
+            let mut keys: Vec<&[u8]> = data.iter().map(|record| record.key().as_slice()).collect();
+            keys.dedup();
+            Self::append_block(&mut buf, &self.build_filter(&keys).encode());
+        }
+        let meta_offset = buf.len() as u64;
+        Self::append_block(&mut buf, &SSTableMeta::from_sstable_data(data).encode());
+        let footer = SSTableFooter::new(
+            index_offset,
+            filter_offset - index_offset,
+            filter_offset,
+            meta_offset - filter_offset,
+            meta_offset,
+            buf.len() as u64 - meta_offset,
+        );
+        buf.extend_from_slice(&footer.encode());
+        buf
+    }
+
+    fn build_filter(&self, keys: &[&[u8]]) -> SSTableFilter {
+        let prefix = self.prefix_extractor.as_ref().map(|extractor| {
+            // prefixを持たないキーは入れない. キーはソート済みなので同じprefixは並ぶ
+            let mut prefixes: Vec<&[u8]> = keys.iter().filter_map(|key| extractor.prefix(key)).collect();
+            prefixes.dedup();
+            (extractor.name(), BloomFilter::from_keys(&prefixes, self.bloom_bits_per_key))
+        });
+        SSTableFilter {
+            key: BloomFilter::from_keys(keys, self.bloom_bits_per_key),
+            prefix,
+        }
+    }
+
+    fn append_block(buf: &mut Vec<u8>, block: &[u8]) {
+        buf.extend_from_slice(block);
+        buf.extend_from_slice(&utils::crc32c(block).to_ne_bytes());
     }
 
+    // 以下は旧形式(dataとindexが別ファイル)で書く
     pub fn write_data(&self, data: &SSTableData) -> Result<(), String> {
-        let mut file = File::create(&self.file).map_err(|e| e.to_string())?;
-        Self::write_data_impl(&mut file, data)
+        utils::write_file_durably(&self.file, |file| Self::write_data_impl(file, data))
     }
 
-    pub fn write_index(&self, index: &SSTableIndex) -> Result<(), String> {
-        let mut file = File::create(&self.index_file).map_err(|e| e.to_string())?;
-        Self::write_index_impl(&mut file, index)
+    // write_dataで書いたdataのindexを書く
+    pub fn write_index(&self, data: &SSTableData) -> Result<(), String> {
+        let index = SSTableIndex::from_sstable_data_without_record_type(data);
+        utils::write_file_durably(&self.index_file, |file| Self::write_index_impl(file, &index))
     }
 
+    #[allow(clippy::unnecessary_mut_passed)]
     fn write_index_impl(file: &mut File, index: &SSTableIndex) -> Result<(), String> {
         let mut index = index.encode();
         file.write_all(&mut index).map_err(|e| e.to_string())
     }
 
     fn write_data_impl(file: &mut File, data: &SSTableData) -> Result<(), String> {
-        let mut data =  data.encode().clone();
-        dbg!(&data[&data.len() - 10..]);
-        file.write_all(&mut data).map_err(|e| e.to_string())
+        let data = data.encode_without_record_type();
+        file.write_all(&data).map_err(|e| e.to_string())
     }
 }
 
@@ -70,48 +159,94 @@ impl SSTableWriter {
 mod tests {
     use std::fs::{self, File};
 
-    use crate::{memtable::MemTable, sstable::{writer::SSTableWriter, SSTableData, SSTableIndex}, utils::get_page_size};
+    use crate::{memtable::MemTable, sstable::{bloom::{BloomFilter, SSTableFilter}, writer::SSTableWriter, SSTableData, SSTableIndex}, utils::{crc32c, get_page_size}};
 
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_sst_writer_wirte_impl() {
         let timestamp = crate::utils::get_timestamp() as u64; // 実際のタイムスタンプ
         let page_size = get_page_size();
-        let mut memtable = MemTable::new();
-        memtable.put("key1", "value1", timestamp);
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", timestamp);
         let path = "/tmp/test_sst_writer_wirte_impl.sst";
         let index_path = "/tmp/test_sst_writer_wirte_impl.sst.idx";
-        assert!(SSTableWriter::write_impl(&memtable, path, index_path, page_size).is_ok());
+        fs::remove_file(index_path).ok();
+        let writer = SSTableWriter {
+            file: path.to_string(),
+            file_number: None,
+            index_file: index_path.to_string(),
+            bloom_bits_per_key: 10,
+            prefix_extractor: None,
+        };
+        assert!(writer.write_impl(&memtable, path, page_size).is_ok());
 
         let mut expected_data = vec![
             4, 0, 0, 0, 0, 0, 0, 0,
             107, 101, 121, 49,
+            1, // 値のレコード
             6, 0, 0, 0, 0, 0, 0, 0,
             118, 97, 108, 117, 101, 49,
         ];
         expected_data.extend_from_slice(&timestamp.to_ne_bytes());
-        let content = fs::read(path).unwrap();
 
         let expected_index = vec![
             4, 0, 0, 0, 0, 0, 0, 0,
             107, 101, 121, 49,
             0, 0, 0, 0, 0, 0, 0, 0,
         ];
-        let index = fs::read(index_path).unwrap();
 
-        assert_eq!(content.len(), expected_data.len());
-        assert_eq!(index.len(), expected_index.len());
-        assert_eq!(content, expected_data);
-        assert_eq!(index, expected_index);
+        let expected_filter = SSTableFilter { key: BloomFilter::from_keys(&[b"key1"], 10), prefix: None }.encode();
+        // 最低64ビットとnum_probes, prefixのフィルタはない
+        assert_eq!(expected_filter.len(), 8 + 8 + 1 + 8 + 8);
+
+        let mut expected_meta = vec![
+            1, 0, 0, 0, 0, 0, 0, 0, // レコード数
+        ];
+        expected_meta.extend_from_slice(&timestamp.to_ne_bytes()); // 最大のsequence
+        expected_meta.extend_from_slice(&[
+            4, 0, 0, 0, 0, 0, 0, 0,
+            107, 101, 121, 49, // 最小のキー
+            4, 0, 0, 0, 0, 0, 0, 0,
+            107, 101, 121, 49, // 最大のキー
+        ]);
+        expected_meta.extend_from_slice(&timestamp.to_ne_bytes()); // 最小のsequence
+
+        // 各blockの後ろにはCRC32Cがつく
+        let expected_footer = [
+            39u64.to_ne_bytes().to_vec(), // index_offset
+            24u64.to_ne_bytes().to_vec(), // index_size
+            63u64.to_ne_bytes().to_vec(), // filter_offset
+            37u64.to_ne_bytes().to_vec(), // filter_size
+            100u64.to_ne_bytes().to_vec(), // meta_offset
+            52u64.to_ne_bytes().to_vec(), // meta_size
+            5u32.to_ne_bytes().to_vec(), // version
+            b"LSMS".to_vec(), // magic
+        ].concat();
+
+        let content = fs::read(path).unwrap();
+        assert_eq!(content, [
+            expected_data.clone(),
+            crc32c(&expected_data).to_ne_bytes().to_vec(),
+            expected_index.clone(),
+            crc32c(&expected_index).to_ne_bytes().to_vec(),
+            expected_filter.clone(),
+            crc32c(&expected_filter).to_ne_bytes().to_vec(),
+            expected_meta.clone(),
+            crc32c(&expected_meta).to_ne_bytes().to_vec(),
+            expected_footer,
+        ].concat());
+        // indexは同じファイルに書かれる
+        assert!(!fs::exists(index_path).unwrap());
         fs::remove_file(path).unwrap();
-        fs::remove_file(index_path).unwrap();
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_fn_writer_write_index_impl() {
         let timestamp = 12345u64; // テスト用の固定タイムスタンプ
         let page_size = get_page_size() as u64;
-        let mut memtable = MemTable::new();
-        memtable.put("key1", "value1", timestamp);
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", timestamp);
         let path = "/tmp/test_sst_writer_write_index_impl.sst.idx";
         let mut file = File::create(path).unwrap();
         let data = SSTableData::try_from(memtable.encode()).unwrap();
@@ -131,17 +266,18 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_cast, clippy::useless_vec)]
     fn test_fn_writer_write_index_impl_complex() {
         let timestamp = crate::utils::get_timestamp() as u64; // 実際のタイムスタンプ
-        let page_size = get_page_size() as u64;
-        let mut memtable = MemTable::new();
-        memtable.put("key1", "value1", timestamp); // 8 + 4 + 8 + 6 + 8 = 34
-        memtable.put("キー4", "c", timestamp);
-        memtable.put("key3", "b".repeat(get_page_size() as usize).as_str(), timestamp); // これはページの先頭から始まる. 超過分: key_len(8) + 4 + value_len(8)+ timestamp_len(8) = 28
-        memtable.put("key2", "a".repeat(get_page_size() as usize - (34 + 28)).as_str(), timestamp); // 34 + key_len(8) + 4 + value_len(8) + timestamp_len(8) 
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", timestamp); // 8 + 4 + 8 + 6 + 8 = 34
+        memtable.put("キー4".as_bytes(), b"c", timestamp);
+        memtable.put(b"key3", "b".repeat(get_page_size()).as_bytes(), timestamp); // これはページの先頭から始まる. 超過分: key_len(8) + 4 + value_len(8)+ timestamp_len(8) = 28
+        memtable.put(b"key2", "a".repeat(get_page_size() - (34 + 28)).as_bytes(), timestamp); // 34 + key_len(8) + 4 + value_len(8) + timestamp_len(8) 
 
+        // 旧形式なので、レコードの種類を含まない大きさで分ける
         let data = SSTableData::try_from(memtable.encode()).unwrap();
-        let index = SSTableIndex::from_sstable_data(&data, page_size);
+        let index = SSTableIndex::from_sstable_data_without_record_type(&data);
         let path = "/tmp/test_fn_writer_write_index_impl_complex.sst.idx";
         let mut file = File::create(path).unwrap();
         assert!(SSTableWriter::write_index_impl(&mut file, &index).is_ok());
@@ -166,9 +302,9 @@ mod tests {
 
     #[test]
     fn test_sst_writer_wirte_data_impl() {
-        let timestamp = crate::utils::get_timestamp() as u64; // 実際のタイムスタンプ
-        let mut memtable = MemTable::new();
-        memtable.put("key1", "value1", timestamp);
+        let timestamp = crate::utils::get_timestamp(); // 実際のタイムスタンプ
+        let memtable = MemTable::new();
+        memtable.put(b"key1", b"value1", timestamp);
         let path = "/tmp/test_sst_writer_wirte_data_impl.sst";
         let mut file = File::create(path).unwrap();
         let data = SSTableData::try_from(memtable.encode()).unwrap();
@@ -192,9 +328,9 @@ mod tests {
 
     #[test]
     fn test_sst_writer_wirte_data_impl_deleted() {
-        let timestamp = crate::utils::get_timestamp() as u64; // 実際のタイムスタンプ
-        let mut memtable = MemTable::new();
-        memtable.delete("key1", timestamp);
+        let timestamp = crate::utils::get_timestamp(); // 実際のタイムスタンプ
+        let memtable = MemTable::new();
+        memtable.delete(b"key1", timestamp);
         let path = "/tmp/test_sst_writer_wirte_data_impl_deleted.sst";
         let mut file = File::create(path).unwrap();
         let data = SSTableData::try_from(memtable.encode()).unwrap();
@@ -203,7 +339,7 @@ mod tests {
         // バイナリデータを含むため、read_to_stringではなくreadを使用
         let content = fs::read(path).unwrap();
         
-        // タイムスタンプを含むデータ形式に更新
+        // 旧形式なので、Tombstoneは"\0"の値として書く
         let mut expected_data = Vec::new();
         expected_data.extend_from_slice(&4u64.to_ne_bytes()); // key_len: 4
         expected_data.extend_from_slice("key1".as_bytes()); // key: "key1"
@@ -211,7 +347,7 @@ mod tests {
         expected_data.extend_from_slice("\0".as_bytes()); // value: "\0"
         expected_data.extend_from_slice(&timestamp.to_ne_bytes()); // タイムスタンプ
         
-        assert_eq!(expected_data, memtable.encode());
+        assert_eq!(SSTableData::decode_without_record_type(&content).unwrap(), data);
         
         assert_eq!(&content, &expected_data);
         
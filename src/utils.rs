@@ -1,6 +1,7 @@
 use libc::{sysconf, _SC_PAGESIZE};
 // chronoをラップして、タイムスタンプを取得する
 
+#[allow(clippy::let_and_return)]
 pub fn get_timestamp() -> u64 {
     let now = chrono::Utc::now();
     let timestamp = now.timestamp_micros() as u64;
@@ -17,5 +18,62 @@ pub fn create_dir(dir: &str) -> Result<(), String> {
     std::fs::create_dir_all(dir).map_err(|e| e.to_string())
 }
 
+// ディレクトリエントリの追加・削除を永続化する
+pub fn sync_dir(dir: &str) -> Result<(), String> {
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| e.to_string())
+}
+
+// 書き込み途中のファイルにつける. 開くときに残っていれば消す
+pub const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/*
+writeで一時ファイル(path.tmp)に書いてfsyncし、pathにrenameしてからディレクトリをfsyncする
+途中で落ちてもpathには書き終えたファイルしか現れず、Okが返ったら再起動後も読める
+*/
+pub fn write_file_durably<F>(path: &str, write: F) -> Result<(), String>
+where
+    F: FnOnce(&mut std::fs::File) -> Result<(), String>,
+{
+    let tmp_path = format!("{}{}", path, TEMP_FILE_SUFFIX);
+    let mut file = std::fs::File::create(&tmp_path).map_err(|e| e.to_string())?;
+    let written = write(&mut file).and_then(|_| file.sync_all().map_err(|e| e.to_string()));
+    drop(file);
+    if let Err(e) = written.and_then(|_| std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())) {
+        std::fs::remove_file(&tmp_path).ok();
+        return Err(e);
+    }
+    match std::path::Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(&dir.to_string_lossy()),
+        _ => sync_dir("."),
+    }
+}
+
+// CRC32C (Castagnoli, reflected polynomial 0x82F63B78)
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod tests;
\ No newline at end of file
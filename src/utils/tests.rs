@@ -1,4 +1,4 @@
-use std::fs;
+use std::{fs, io::Write};
 
 #[test]
 fn test_local_timestamp() {
@@ -17,4 +17,31 @@ fn test_create_dir_with_relative_path() {
     let path = "./tmp/test";
     assert!(super::create_dir(path).is_ok());
     fs::remove_dir_all(path).unwrap();
-}
\ No newline at end of file
+}
+#[test]
+fn test_crc32c() {
+    assert_eq!(super::crc32c(b""), 0);
+    assert_eq!(super::crc32c(b"123456789"), 0xE306_9283);
+    assert_ne!(super::crc32c(b"123456789"), super::crc32c(b"123456788"));
+}
+
+#[test]
+fn test_write_file_durably() {
+    let dir = "./.test_write_file_durably";
+    super::create_dir(dir).unwrap();
+    let path = format!("{}/file", dir);
+    let tmp_path = format!("{}{}", path, super::TEMP_FILE_SUFFIX);
+    super::write_file_durably(&path, |file| file.write_all(b"data").map_err(|e| e.to_string())).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"data");
+    assert!(!fs::exists(&tmp_path).unwrap());
+
+    // 書き込みに失敗したら、前のファイルはそのまま残り、一時ファイルも残らない
+    let result = super::write_file_durably(&path, |file| {
+        file.write_all(b"broken").map_err(|e| e.to_string())?;
+        Err("failed".to_owned())
+    });
+    assert_eq!(result, Err("failed".to_owned()));
+    assert_eq!(fs::read(&path).unwrap(), b"data");
+    assert!(!fs::exists(&tmp_path).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+}
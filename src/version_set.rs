@@ -0,0 +1,247 @@
+pub mod manifest;
+
+use std::{collections::HashSet, fs, path::Path, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use manifest::{FileMetaData, Manifest, VersionEdit};
+
+use crate::{sstable::reader::SSTableReaderManager, utils::TEMP_FILE_SUFFIX};
+
+// MANIFESTのないディレクトリで開けなかったSSTableにつける. remove_unlistedでは消さない
+pub const QUARANTINE_SUFFIX: &str = ".corrupt";
+
+/*
+ある時点で読むべきSSTableの集合
+getやscanは今のバージョンのArcを取ってから読み、その間にコンパクションで外されたSSTableも読める
+外されたSSTableは、それを含むバージョンが全て使われなくなったときに消える
+*/
+#[derive(Debug, Default)]
+pub struct Version {
+    // max_sequenceの大きい順. 同じなら番号の大きい順
+    files: Vec<VersionFile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionFile {
+    pub meta: Arc<FileMetaData>,
+    pub reader: Arc<SSTableReaderManager>,
+}
+
+impl Version {
+    fn new(mut files: Vec<VersionFile>) -> Version {
+        files.sort_by(|a, b| {
+            b.meta.max_sequence.cmp(&a.meta.max_sequence).then_with(|| b.meta.number.cmp(&a.meta.number))
+        });
+        Version { files }
+    }
+
+    pub fn files(&self) -> &[VersionFile] {
+        &self.files
+    }
+
+    pub fn readers(&self) -> Vec<Arc<SSTableReaderManager>> {
+        self.files.iter().map(|file| file.reader.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn edit(&self, next_file_number: u64) -> VersionEdit {
+        VersionEdit {
+            next_file_number,
+            added: self.files.iter().map(|file| file.meta.as_ref().clone()).collect(),
+            removed: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VersionSet {
+    // MANIFESTへの追記と今のバージョンの入れ替えは、このロックの中で行う
+    manifest: Mutex<Manifest>,
+    current: Mutex<Arc<Version>>,
+    next_file_number: AtomicU64,
+}
+
+impl VersionSet {
+    /*
+    MANIFESTを読み直し、載っているSSTableをopen_readerで開いて今のバージョンにする
+    MANIFESTがなければ(前のバージョンで作ったディレクトリ)、dirにある読めるSSTableを全て加える
+    開けなかったSSTableは消さずに、QUARANTINE_SUFFIXをつけて残す
+    その後、今のバージョンだけを持つMANIFESTを書き直し、載っていないSSTableを消す
+    */
+    pub fn open<F>(dir: &str, index_file_suffix: &str, open_reader: F) -> Result<VersionSet, String>
+    where
+        F: Fn(&str) -> Result<SSTableReaderManager, String>,
+    {
+        let (files, next_file_number) = match Manifest::replay(dir)? {
+            Some(replayed) => {
+                let mut files = vec![];
+                for meta in replayed.files {
+                    let path = format!("{}/{}", dir, meta.name);
+                    let reader = open_reader(&path).map_err(|e| format!("{} in MANIFEST can not be opened: {}", path, e))?;
+                    files.push(VersionFile { meta: Arc::new(meta), reader: Arc::new(reader) });
+                }
+                (files, replayed.next_file_number)
+            },
+            None => Self::adopt(dir, index_file_suffix, open_reader)?,
+        };
+        let next_file_number = files.iter()
+            .map(|file| file.meta.number + 1)
+            .fold(next_file_number.max(1), u64::max);
+        let version = Version::new(files);
+        let manifest = Manifest::create(dir, &version.edit(next_file_number))?;
+        Self::remove_unlisted(dir, index_file_suffix, &version)?;
+        Ok(VersionSet {
+            manifest: Mutex::new(manifest),
+            current: Mutex::new(Arc::new(version)),
+            next_file_number: AtomicU64::new(next_file_number),
+        })
+    }
+
+    // 作った順(旧形式のファイル名は作った時刻)に番号を振る
+    fn adopt<F>(dir: &str, index_file_suffix: &str, open_reader: F) -> Result<(Vec<VersionFile>, u64), String>
+    where
+        F: Fn(&str) -> Result<SSTableReaderManager, String>,
+    {
+        let mut names = vec![];
+        for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+            let name = entry.map_err(|e| e.to_string())?.file_name();
+            match name.to_str() {
+                Some(name) if name.ends_with(".sst") => names.push(name.to_owned()),
+                _ => continue,
+            }
+        }
+        names.sort();
+
+        let mut files = vec![];
+        let mut next_file_number = 1;
+        for name in names {
+            let path = format!("{}/{}", dir, name);
+            let opened = open_reader(&path).and_then(|reader| {
+                let meta = Self::file_meta(next_file_number, &reader)?;
+                Ok(VersionFile { meta: Arc::new(meta), reader: Arc::new(reader) })
+            });
+            match opened {
+                Ok(file) => {
+                    files.push(file);
+                    next_file_number += 1;
+                },
+                // 書き込み途中で落ちたSSTableか、一時的に読めなかったSSTable
+                // バージョンには加えないが、後から調べられるように名前を変えて残す
+                Err(e) => {
+                    eprintln!("WARN: quarantine SSTable {}: {}", path, e);
+                    Self::quarantine(&path)?;
+                    let index_file = format!("{}.{}", path, index_file_suffix);
+                    if fs::exists(&index_file).map_err(|e| e.to_string())? {
+                        Self::quarantine(&index_file)?;
+                    }
+                },
+            }
+        }
+        Ok((files, next_file_number))
+    }
+
+    fn quarantine(path: &str) -> Result<(), String> {
+        fs::rename(path, format!("{}{}", path, QUARANTINE_SUFFIX)).map_err(|e| e.to_string())
+    }
+
+    fn file_meta(number: u64, reader: &SSTableReaderManager) -> Result<FileMetaData, String> {
+        let meta = reader.meta()?;
+        let name = Path::new(reader.file())
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(format!("invalid file name: {}", reader.file()))?;
+        Ok(FileMetaData {
+            number,
+            name: name.to_owned(),
+            size: reader.metadata()?.len(),
+            min_sequence: meta.min_sequence,
+            max_sequence: meta.max_sequence,
+            smallest_key: meta.smallest_key.clone(),
+            largest_key: meta.largest_key.clone(),
+        })
+    }
+
+    // 消し損ねたコンパクションの入力と、書き込み途中で落ちたflushやコンパクションの一時ファイル
+    fn remove_unlisted(dir: &str, index_file_suffix: &str, version: &Version) -> Result<(), String> {
+        let listed: HashSet<&str> = version.files.iter().map(|file| file.meta.name.as_str()).collect();
+        let index_suffix = format!(".{}", index_file_suffix);
+        for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.ends_with(TEMP_FILE_SUFFIX) {
+                println!("INFO: remove temporary file {}", path.display());
+                fs::remove_file(&path).map_err(|e| e.to_string())?;
+                continue;
+            }
+            // 旧形式のindexファイルはSSTableと一緒に消す
+            let table = name.strip_suffix(&index_suffix).unwrap_or(name);
+            if table.ends_with(".sst") && !listed.contains(table) {
+                println!("INFO: remove {} not in MANIFEST", path.display());
+                fs::remove_file(&path).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn current(&self) -> Arc<Version> {
+        self.current.lock().unwrap().clone()
+    }
+
+    pub fn new_file_number(&self) -> u64 {
+        self.next_file_number.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /*
+    readerを加えてremovedを外したバージョンをMANIFESTに書いてから、今のバージョンと入れ替える
+    file_numberがなければここで番号を振る
+    外したSSTableには消す印をつけ、それを含むバージョンが全て使われなくなったときに消える
+    */
+    pub fn log_and_apply(
+        &self,
+        file_number: Option<u64>,
+        reader: Arc<SSTableReaderManager>,
+        removed: &[Arc<SSTableReaderManager>],
+    ) -> Result<(), String> {
+        let mut manifest = self.manifest.lock().map_err(|e| e.to_string())?;
+        let current = self.current();
+        let mut removed_numbers = vec![];
+        for reader in removed {
+            let file = current.files.iter()
+                .find(|file| file.reader.file() == reader.file())
+                .ok_or(format!("{} is not in the current version", reader.file()))?;
+            removed_numbers.push(file.meta.number);
+        }
+        let number = file_number.unwrap_or_else(|| self.new_file_number());
+        let meta = Arc::new(Self::file_meta(number, &reader)?);
+        let edit = VersionEdit {
+            next_file_number: self.next_file_number.load(Ordering::SeqCst),
+            added: vec![meta.as_ref().clone()],
+            removed: removed_numbers.clone(),
+        };
+        manifest.append(&edit)?;
+
+        let mut files: Vec<VersionFile> = current.files.iter()
+            .filter(|file| !removed_numbers.contains(&file.meta.number))
+            .cloned()
+            .collect();
+        files.push(VersionFile { meta, reader });
+        *self.current.lock().map_err(|e| e.to_string())? = Arc::new(Version::new(files));
+        drop(manifest);
+
+        for reader in removed {
+            reader.delete();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,212 @@
+use std::{collections::BTreeMap, fs::{self, File, OpenOptions}, io::{ErrorKind, Write}};
+
+use crate::utils;
+
+/*
+SSTableの集合(バージョン)への変更を追記するログ
+| magic(4) | version(4) | frame | frame | ...
+frameはコミットログと同じ
+| payload_len(4) | crc32c(payload)(4) | payload |
+payloadはVersionEdit
+| next_file_number(8) | added_count(8) | file ... | removed_count(8) | number(8) ... |
+file:
+| number(8) | name_len(8) | name | size(8) | min_sequence(8) | max_sequence(8) | smallest_key_len(8) | smallest_key | largest_key_len(8) | largest_key |
+開くたびに今のバージョンを1つのVersionEditにまとめた新しいMANIFESTを書き、renameで置き換える
+*/
+pub const MANIFEST_FILE: &str = "MANIFEST";
+pub const MANIFEST_MAGIC: [u8; 4] = *b"LSMM";
+pub const MANIFEST_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8;
+const FRAME_HEADER_SIZE: usize = 8;
+
+// バージョンに含まれるSSTable
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FileMetaData {
+    pub number: u64,
+    // sst_dirの中のファイル名. 番号を振る前に作ったSSTableは番号と名前が一致しない
+    pub name: String,
+    pub size: u64,
+    pub min_sequence: u64,
+    pub max_sequence: u64,
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+}
+
+impl FileMetaData {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.number.to_ne_bytes());
+        buf.extend_from_slice(&self.name.len().to_ne_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.extend_from_slice(&self.size.to_ne_bytes());
+        buf.extend_from_slice(&self.min_sequence.to_ne_bytes());
+        buf.extend_from_slice(&self.max_sequence.to_ne_bytes());
+        buf.extend_from_slice(&self.smallest_key.len().to_ne_bytes());
+        buf.extend_from_slice(&self.smallest_key);
+        buf.extend_from_slice(&self.largest_key.len().to_ne_bytes());
+        buf.extend_from_slice(&self.largest_key);
+    }
+
+    fn decode(data: &mut &[u8]) -> Result<FileMetaData, String> {
+        let number = split_u64(data)?;
+        let name = String::from_utf8(split_bytes(data)?.to_vec()).map_err(|e| e.to_string())?;
+        let size = split_u64(data)?;
+        let min_sequence = split_u64(data)?;
+        let max_sequence = split_u64(data)?;
+        let smallest_key = split_bytes(data)?.to_vec();
+        let largest_key = split_bytes(data)?.to_vec();
+        Ok(FileMetaData {
+            number,
+            name,
+            size,
+            min_sequence,
+            max_sequence,
+            smallest_key,
+            largest_key,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VersionEdit {
+    // この時点で次に割り当てる番号
+    pub next_file_number: u64,
+    pub added: Vec<FileMetaData>,
+    // 外したSSTableの番号
+    pub removed: Vec<u64>,
+}
+
+impl VersionEdit {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&self.next_file_number.to_ne_bytes());
+        buf.extend_from_slice(&self.added.len().to_ne_bytes());
+        for file in self.added.iter() {
+            file.encode(&mut buf);
+        }
+        buf.extend_from_slice(&self.removed.len().to_ne_bytes());
+        for number in self.removed.iter() {
+            buf.extend_from_slice(&number.to_ne_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<VersionEdit, String> {
+        let mut rest = data;
+        let next_file_number = split_u64(&mut rest)?;
+        let added = (0..split_u64(&mut rest)?)
+            .map(|_| FileMetaData::decode(&mut rest))
+            .collect::<Result<Vec<_>, String>>()?;
+        let removed = (0..split_u64(&mut rest)?)
+            .map(|_| split_u64(&mut rest))
+            .collect::<Result<Vec<_>, String>>()?;
+        if !rest.is_empty() {
+            return Err("version edit has trailing bytes".to_owned());
+        }
+        Ok(VersionEdit { next_file_number, added, removed })
+    }
+}
+
+fn split_u64(data: &mut &[u8]) -> Result<u64, String> {
+    let value = data.get(..8).ok_or("version edit is too short")?;
+    let value = u64::from_ne_bytes(value.try_into().unwrap());
+    *data = &data[8..];
+    Ok(value)
+}
+
+fn split_bytes<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = usize::try_from(split_u64(data)?).map_err(|e| e.to_string())?;
+    let bytes = data.get(..len).ok_or("version edit is too short")?;
+    *data = &data[len..];
+    Ok(bytes)
+}
+
+// MANIFESTを読み直した結果
+#[derive(Debug, Default)]
+pub struct ReplayedManifest {
+    // 番号順
+    pub files: Vec<FileMetaData>,
+    pub next_file_number: u64,
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    file: File,
+}
+
+impl Manifest {
+    // snapshotだけを持つMANIFESTを一時ファイルに書いてから置き換える
+    // 途中で落ちても、古いMANIFESTか新しいMANIFESTのどちらかが残る
+    pub fn create(dir: &str, snapshot: &VersionEdit) -> Result<Manifest, String> {
+        let path = format!("{}/{}", dir, MANIFEST_FILE);
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.extend_from_slice(&MANIFEST_MAGIC);
+        buf.extend_from_slice(&MANIFEST_VERSION.to_ne_bytes());
+        buf.extend_from_slice(&Self::encode_frame(&snapshot.encode()));
+        utils::write_file_durably(&path, |file| file.write_all(&buf).map_err(|e| e.to_string()))?;
+        let file = OpenOptions::new().append(true).open(&path).map_err(|e| e.to_string())?;
+        Ok(Manifest { file })
+    }
+
+    // fsyncしてから返る. Okが返ったeditは再起動後も残る
+    pub fn append(&mut self, edit: &VersionEdit) -> Result<(), String> {
+        self.file.write_all(&Self::encode_frame(&edit.encode())).map_err(|e| e.to_string())?;
+        self.file.sync_data().map_err(|e| e.to_string())
+    }
+
+    fn encode_frame(payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(&utils::crc32c(payload).to_ne_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    // MANIFESTがなければNone
+    // 末尾のeditが書き込み途中で壊れていたら、そこまでを読む. そのeditは適用されなかったことになる
+    pub fn replay(dir: &str) -> Result<Option<ReplayedManifest>, String> {
+        let path = format!("{}/{}", dir, MANIFEST_FILE);
+        let buf = match fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        if buf.len() < HEADER_SIZE || !buf.starts_with(&MANIFEST_MAGIC) {
+            return Err(format!("{} has no header", path));
+        }
+        let version = u32::from_ne_bytes(buf[4..HEADER_SIZE].try_into().unwrap());
+        if version != MANIFEST_VERSION {
+            return Err(format!("unsupported manifest version {} in {}", version, path));
+        }
+
+        let mut files = BTreeMap::new();
+        let mut next_file_number = 0;
+        let mut offset = HEADER_SIZE;
+        while offset < buf.len() {
+            let Some(payload) = Self::decode_frame(&buf[offset..]) else {
+                eprintln!("WARN: {} is truncated at offset {}", path, offset);
+                break;
+            };
+            let edit = VersionEdit::decode(payload).map_err(|e| format!("{} in {} at offset {}", e, path, offset))?;
+            for number in edit.removed.iter() {
+                files.remove(number);
+            }
+            for file in edit.added {
+                files.insert(file.number, file);
+            }
+            next_file_number = next_file_number.max(edit.next_file_number);
+            offset += FRAME_HEADER_SIZE + payload.len();
+        }
+        Ok(Some(ReplayedManifest {
+            files: files.into_values().collect(),
+            next_file_number,
+        }))
+    }
+
+    // 長さが足りないかCRC32Cが合わなければNone
+    fn decode_frame(data: &[u8]) -> Option<&[u8]> {
+        let payload_len = u32::from_ne_bytes(data.get(0..4)?.try_into().unwrap()) as usize;
+        let checksum = u32::from_ne_bytes(data.get(4..FRAME_HEADER_SIZE)?.try_into().unwrap());
+        let payload = data.get(FRAME_HEADER_SIZE..(FRAME_HEADER_SIZE + payload_len))?;
+        (utils::crc32c(payload) == checksum).then_some(payload)
+    }
+}
@@ -0,0 +1,206 @@
+use std::{fs, io::Write, sync::Arc};
+
+use crate::{memtable::MemTable, sstable::{reader::SSTableReaderManager, SSTableWriter}, utils::get_page_size};
+
+use super::{manifest::{FileMetaData, Manifest, VersionEdit, MANIFEST_FILE}, VersionSet, QUARANTINE_SUFFIX};
+
+fn file_meta(number: u64) -> FileMetaData {
+    FileMetaData {
+        number,
+        name: format!("{:06}.sst", number),
+        size: 100 * number,
+        min_sequence: number * 10,
+        max_sequence: number * 10 + 9,
+        smallest_key: format!("key{}", number).into_bytes(),
+        largest_key: format!("key{}z", number).into_bytes(),
+    }
+}
+
+fn set_up(dir: &str) {
+    if fs::exists(dir).unwrap() {
+        fs::remove_dir_all(dir).unwrap();
+    }
+    fs::create_dir_all(dir).unwrap();
+}
+
+fn write_sstable(writer: &SSTableWriter, key: &[u8], sequence: u64) {
+    let memtable = MemTable::new();
+    memtable.put(key, b"value", sequence);
+    writer.write(&memtable, get_page_size()).unwrap();
+}
+
+fn open(dir: &str) -> VersionSet {
+    VersionSet::open(dir, "idx", |file| SSTableReaderManager::new(file, &format!("{}.idx", file))).unwrap()
+}
+
+fn sstables(dir: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_str().unwrap().to_owned())
+        .filter(|name| name.ends_with(".sst"))
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_version_edit_encode() {
+    let edit = VersionEdit {
+        next_file_number: 4,
+        added: vec![file_meta(1), file_meta(3)],
+        removed: vec![2],
+    };
+    assert_eq!(VersionEdit::decode(&edit.encode()), Ok(edit.clone()));
+    assert_eq!(VersionEdit::decode(&VersionEdit::default().encode()), Ok(VersionEdit::default()));
+
+    let encoded = edit.encode();
+    assert!(VersionEdit::decode(&encoded[..encoded.len() - 1]).is_err());
+    assert!(VersionEdit::decode(&[encoded.as_slice(), &[0]].concat()).is_err());
+}
+
+#[test]
+fn test_manifest_replay() {
+    let dir = ".test_manifest_replay";
+    set_up(dir);
+    assert!(Manifest::replay(dir).unwrap().is_none());
+
+    let mut manifest = Manifest::create(dir, &VersionEdit { next_file_number: 3, added: vec![file_meta(1), file_meta(2)], removed: vec![] }).unwrap();
+    manifest.append(&VersionEdit { next_file_number: 4, added: vec![file_meta(3)], removed: vec![1, 2] }).unwrap();
+    let replayed = Manifest::replay(dir).unwrap().unwrap();
+    assert_eq!(replayed.files, vec![file_meta(3)]);
+    assert_eq!(replayed.next_file_number, 4);
+
+    // 書き込み途中で落ちたeditは適用されない
+    let path = format!("{}/{}", dir, MANIFEST_FILE);
+    let edit = VersionEdit { next_file_number: 5, added: vec![file_meta(4)], removed: vec![3] }.encode();
+    let mut frame = (edit.len() as u32).to_ne_bytes().to_vec();
+    frame.extend_from_slice(&crate::utils::crc32c(&edit).to_ne_bytes());
+    frame.extend_from_slice(&edit[..edit.len() / 2]);
+    fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&frame).unwrap();
+    let replayed = Manifest::replay(dir).unwrap().unwrap();
+    assert_eq!(replayed.files, vec![file_meta(3)]);
+    assert_eq!(replayed.next_file_number, 4);
+
+    // 書き直すと、今のバージョンだけを持つ
+    drop(manifest);
+    Manifest::create(dir, &VersionEdit { next_file_number: 4, added: replayed.files, removed: vec![] }).unwrap();
+    let replayed = Manifest::replay(dir).unwrap().unwrap();
+    assert_eq!(replayed.files, vec![file_meta(3)]);
+    assert!(!fs::exists(format!("{}.tmp", path)).unwrap());
+
+    fs::write(&path, b"broken").unwrap();
+    assert!(Manifest::replay(dir).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_version_set_open() {
+    let dir = ".test_version_set_open";
+    set_up(dir);
+
+    // MANIFESTがなければ、読めるSSTableを作った順に番号を振って加える
+    write_sstable(&SSTableWriter::new(dir).unwrap(), b"key1", 1);
+    write_sstable(&SSTableWriter::new(dir).unwrap(), b"key2", 2);
+    // footerのない書き込み途中のSSTableは、名前を変えて残す
+    fs::write(format!("{}/{}", dir, "broken.sst"), b"broken").unwrap();
+    // renameする前に落ちた一時ファイルも消える
+    fs::write(format!("{}/{}", dir, "000009.sst.tmp"), b"broken").unwrap();
+    fs::write(format!("{}/{}", dir, "MANIFEST.tmp"), b"broken").unwrap();
+    let versions = open(dir);
+    let current = versions.current();
+    assert_eq!(current.len(), 2);
+    assert_eq!(current.files()[0].meta.max_sequence, 2);
+    assert_eq!(current.files()[0].meta.number, 2);
+    assert_eq!(current.files()[1].meta.smallest_key, b"key1");
+    assert_eq!(sstables(dir).len(), 2);
+    assert!(sstables(dir).iter().all(|name| name != "broken.sst"));
+    assert_eq!(fs::read(format!("{}/broken.sst{}", dir, QUARANTINE_SUFFIX)).unwrap(), b"broken");
+    assert!(!fs::exists(format!("{}/{}", dir, "000009.sst.tmp")).unwrap());
+    assert!(!fs::exists(format!("{}/{}", dir, "MANIFEST.tmp")).unwrap());
+
+    // 番号はMANIFESTに記録したところから数え直す
+    let number = versions.new_file_number();
+    assert_eq!(number, 3);
+    let writer = SSTableWriter::numbered(dir, number);
+    write_sstable(&writer, b"key3", 3);
+    let reader = Arc::new(SSTableReaderManager::new(&writer.file, &writer.index_file).unwrap());
+    versions.log_and_apply(writer.file_number, reader, &[]).unwrap();
+    // MANIFESTに記録する前に落ちたSSTable
+    write_sstable(&SSTableWriter::numbered(dir, versions.new_file_number()), b"key4", 4);
+    drop(current);
+    drop(versions);
+
+    let versions = open(dir);
+    let current = versions.current();
+    assert_eq!(current.len(), 3);
+    assert_eq!(current.files()[0].meta.name, "000003.sst");
+    assert_eq!(current.files()[0].meta.min_sequence, 3);
+    // 記録されなかった番号は、そのSSTableを消してから使い直す
+    assert!(!sstables(dir).contains(&"000004.sst".to_owned()));
+    assert_eq!(versions.new_file_number(), 4);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_version_set_log_and_apply() {
+    let dir = ".test_version_set_log_and_apply";
+    set_up(dir);
+    let versions = open(dir);
+    for sequence in 1..=2 {
+        let writer = SSTableWriter::numbered(dir, versions.new_file_number());
+        write_sstable(&writer, b"key", sequence);
+        let reader = Arc::new(SSTableReaderManager::new(&writer.file, &writer.index_file).unwrap());
+        versions.log_and_apply(writer.file_number, reader, &[]).unwrap();
+    }
+
+    // コンパクションで入力を外しても、古いバージョンを持っている間は消えない
+    let old = versions.current();
+    let writer = SSTableWriter::numbered(dir, versions.new_file_number());
+    write_sstable(&writer, b"key", 2);
+    let reader = Arc::new(SSTableReaderManager::new(&writer.file, &writer.index_file).unwrap());
+    versions.log_and_apply(writer.file_number, reader.clone(), &old.readers()).unwrap();
+    assert_eq!(versions.current().len(), 1);
+    assert_eq!(old.files()[0].reader.read(b"key").unwrap(), Some((Some(b"value".to_vec()), 2)));
+    assert_eq!(sstables(dir).len(), 3);
+    drop(old);
+    assert_eq!(sstables(dir), vec!["000003.sst".to_owned()]);
+
+    // 今のバージョンにないSSTableは外せない
+    let writer = SSTableWriter::numbered(dir, versions.new_file_number());
+    write_sstable(&writer, b"key", 3);
+    let removed = Arc::new(SSTableReaderManager::new(&writer.file, &writer.index_file).unwrap());
+    assert!(versions.log_and_apply(None, reader.clone(), &[removed]).is_err());
+    assert_eq!(versions.current().len(), 1);
+    drop(versions);
+    drop(reader);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_version_set_open_keeps_unreadable_sstable() {
+    let dir = ".test_version_set_open_keeps_unreadable_sstable";
+    set_up(dir);
+
+    let broken = SSTableWriter::new(dir).unwrap();
+    write_sstable(&broken, b"key1", 1);
+    write_sstable(&SSTableWriter::new(dir).unwrap(), b"key2", 2);
+    // MANIFESTを作る前にfooterが壊れた
+    let mut content = fs::read(&broken.file).unwrap();
+    let len = content.len();
+    content[len - 1] ^= 0xff;
+    fs::write(&broken.file, &content).unwrap();
+
+    let versions = open(dir);
+    assert_eq!(versions.current().len(), 1);
+    assert_eq!(versions.current().files()[0].meta.smallest_key, b"key2");
+    // 消さずに残っている
+    assert!(!fs::exists(&broken.file).unwrap());
+    assert_eq!(fs::read(format!("{}{}", broken.file, QUARANTINE_SUFFIX)).unwrap(), content);
+    drop(versions);
+
+    // MANIFESTができた後に開き直しても消えない
+    let versions = open(dir);
+    assert_eq!(versions.current().len(), 1);
+    assert!(fs::exists(format!("{}{}", broken.file, QUARANTINE_SUFFIX)).unwrap());
+    drop(versions);
+    fs::remove_dir_all(dir).unwrap();
+}
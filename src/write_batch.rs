@@ -0,0 +1,51 @@
+use crate::commitlog::CommitLogEntry;
+
+// まとめて書き込むputとdelete
+// LSMTree::writeで1つのコミットログレコードとして書き込まれ、全て適用されるか全く適用されないかのどちらかになる
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    entries: Vec<CommitLogEntry>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            entries: vec![],
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.entries.push(CommitLogEntry::new("PUT", key, Some(value)));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.entries.push(CommitLogEntry::new("DELETE", key, None));
+        self
+    }
+
+    // 文字列のキー、値用
+    pub fn put_str(&mut self, key: &str, value: &str) -> &mut Self {
+        self.put(key.as_bytes(), value.as_bytes())
+    }
+
+    pub fn delete_str(&mut self, key: &str) -> &mut Self {
+        self.delete(key.as_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn entries(&self) -> &[CommitLogEntry] {
+        &self.entries
+    }
+}
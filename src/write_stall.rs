@@ -0,0 +1,220 @@
+use std::{sync::Mutex, thread::sleep, time::{Duration, Instant}};
+
+/*
+flushやコンパクションが書き込みに追いつかないときの書き込み制限
+soft limitに達すると書き込みを1回ごとに少し遅らせ、hard limitに達すると下回るまで書き込みを止める
+制限はどれも設定しなければ無効
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStallConf {
+    // flush待ちのmemtableの数
+    immutable_memtables: Option<(usize, usize)>,
+    // SSTableの数
+    sstables: Option<(usize, usize)>,
+    // コンパクションで書き直す必要のあるバイト数
+    pending_compaction_bytes: Option<(u64, u64)>,
+    // soft limitに達しているときに1回の書き込みを遅らせる時間
+    slowdown_delay: Duration,
+    // 止めている間やSSTableの状態を確認し直す間隔
+    check_interval: Duration,
+}
+
+impl Default for WriteStallConf {
+    fn default() -> Self {
+        WriteStallConf {
+            immutable_memtables: None,
+            sstables: None,
+            pending_compaction_bytes: None,
+            slowdown_delay: Duration::from_millis(1),
+            check_interval: Duration::from_millis(10),
+        }
+    }
+}
+
+impl WriteStallConf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_immutable_memtable_limits(mut self, soft: usize, hard: usize) -> Self {
+        self.immutable_memtables = Some((soft, hard));
+        self
+    }
+
+    pub fn with_sstable_limits(mut self, soft: usize, hard: usize) -> Self {
+        self.sstables = Some((soft, hard));
+        self
+    }
+
+    pub fn with_pending_compaction_bytes_limits(mut self, soft: u64, hard: u64) -> Self {
+        self.pending_compaction_bytes = Some((soft, hard));
+        self
+    }
+
+    pub fn with_slowdown_delay(mut self, slowdown_delay: Duration) -> Self {
+        self.slowdown_delay = slowdown_delay;
+        self
+    }
+
+    pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+
+    fn watches_sstables(&self) -> bool {
+        self.sstables.is_some() || self.pending_compaction_bytes.is_some()
+    }
+
+    pub fn evaluate(&self, metrics: &WriteStallMetrics) -> WriteStallState {
+        let states = [
+            Self::evaluate_limit(self.immutable_memtables, metrics.immutable_memtables),
+            Self::evaluate_limit(self.sstables, metrics.sstables),
+            Self::evaluate_limit(self.pending_compaction_bytes, metrics.pending_compaction_bytes),
+        ];
+        states.into_iter().max().unwrap_or_default()
+    }
+
+    fn evaluate_limit<N: PartialOrd>(limits: Option<(N, N)>, value: N) -> WriteStallState {
+        match limits {
+            Some((_, hard)) if value >= hard => WriteStallState::Stopped,
+            Some((soft, _)) if value >= soft => WriteStallState::Delayed,
+            _ => WriteStallState::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum WriteStallState {
+    #[default]
+    Normal,
+    Delayed,
+    Stopped,
+}
+
+// 書き込み制限の判定に使う値
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteStallMetrics {
+    pub immutable_memtables: usize,
+    pub sstables: usize,
+    pub pending_compaction_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteStallStats {
+    pub state: WriteStallState,
+    // 今のstateになってからの時間. Normalなら0
+    pub stalled_for: Duration,
+    pub metrics: WriteStallMetrics,
+    // 遅らせた、止めた書き込みの数
+    pub delayed_writes: u64,
+    pub stopped_writes: u64,
+    pub delayed_duration: Duration,
+    pub stopped_duration: Duration,
+}
+
+#[derive(Debug, Default)]
+struct WriteControllerState {
+    stats: WriteStallStats,
+    state_since: Option<Instant>,
+    // SSTableの数やサイズはディレクトリを読むので、check_intervalごとにしか確認しない
+    sstables_checked_at: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct WriteController {
+    conf: WriteStallConf,
+    state: Mutex<WriteControllerState>,
+}
+
+impl WriteController {
+    pub fn new(conf: WriteStallConf) -> Self {
+        WriteController {
+            conf,
+            state: Mutex::new(WriteControllerState::default()),
+        }
+    }
+
+    /*
+    書き込みの前に呼び、制限に応じて遅らせたり止めたりする
+    immutable_memtablesは毎回、sstablesは(SSTableの数, コンパクション待ちのバイト数)を必要なときだけ呼ぶ
+    */
+    pub fn wait<F, G>(&self, immutable_memtables: F, sstables: G) -> Result<(), String>
+    where
+        F: Fn() -> Result<usize, String>,
+        G: Fn() -> Result<(usize, u64), String>,
+    {
+        let mut stopped_at: Option<Instant> = None;
+        loop {
+            let state = self.refresh(&immutable_memtables, &sstables, stopped_at.is_some())?;
+            match state {
+                WriteStallState::Normal => break,
+                WriteStallState::Delayed => {
+                    let now = Instant::now();
+                    sleep(self.conf.slowdown_delay);
+                    let mut controller = self.state.lock().map_err(|e| e.to_string())?;
+                    controller.stats.delayed_writes += 1;
+                    controller.stats.delayed_duration += now.elapsed();
+                    break;
+                },
+                WriteStallState::Stopped => {
+                    stopped_at.get_or_insert_with(Instant::now);
+                    sleep(self.conf.check_interval);
+                },
+            }
+        }
+
+        if let Some(stopped_at) = stopped_at {
+            let mut controller = self.state.lock().map_err(|e| e.to_string())?;
+            controller.stats.stopped_writes += 1;
+            controller.stats.stopped_duration += stopped_at.elapsed();
+        }
+        Ok(())
+    }
+
+    // 今の状態を計算し直してstatsに反映する
+    fn refresh<F, G>(&self, immutable_memtables: &F, sstables: &G, force: bool) -> Result<WriteStallState, String>
+    where
+        F: Fn() -> Result<usize, String>,
+        G: Fn() -> Result<(usize, u64), String>,
+    {
+        let immutable_memtables = immutable_memtables()?;
+        let mut controller = self.state.lock().map_err(|e| e.to_string())?;
+        controller.stats.metrics.immutable_memtables = immutable_memtables;
+
+        let should_check_sstables = self.conf.watches_sstables() && (force || controller
+            .sstables_checked_at
+            .is_none_or(|checked_at| checked_at.elapsed() >= self.conf.check_interval));
+        if should_check_sstables {
+            drop(controller);
+            let (count, pending_compaction_bytes) = sstables()?;
+            controller = self.state.lock().map_err(|e| e.to_string())?;
+            controller.stats.metrics.sstables = count;
+            controller.stats.metrics.pending_compaction_bytes = pending_compaction_bytes;
+            controller.sstables_checked_at = Some(Instant::now());
+        }
+
+        let state = self.conf.evaluate(&controller.stats.metrics);
+        if state != controller.stats.state {
+            match state {
+                WriteStallState::Normal => println!("INFO: write stall is cleared after {:?}", controller.state_since.map(|since| since.elapsed()).unwrap_or_default()),
+                _ => eprintln!("WARN: write stall state is {:?}: {:?}", state, controller.stats.metrics),
+            }
+            controller.stats.state = state;
+            controller.state_since = match state {
+                WriteStallState::Normal => None,
+                _ => Some(Instant::now()),
+            };
+        }
+        Ok(state)
+    }
+
+    pub fn stats(&self) -> WriteStallStats {
+        let controller = self.state.lock().unwrap();
+        let mut stats = controller.stats;
+        stats.stalled_for = controller.state_since.map(|since| since.elapsed()).unwrap_or_default();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,86 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread, time::Duration};
+
+use super::{WriteController, WriteStallConf, WriteStallMetrics, WriteStallState};
+
+fn metrics(immutable_memtables: usize, sstables: usize, pending_compaction_bytes: u64) -> WriteStallMetrics {
+    WriteStallMetrics {
+        immutable_memtables,
+        sstables,
+        pending_compaction_bytes,
+    }
+}
+
+#[test]
+fn test_write_stall_evaluate() {
+    let conf = WriteStallConf::new()
+        .with_immutable_memtable_limits(2, 4)
+        .with_sstable_limits(10, 20)
+        .with_pending_compaction_bytes_limits(100, 200);
+    assert_eq!(conf.evaluate(&metrics(1, 9, 99)), WriteStallState::Normal);
+    assert_eq!(conf.evaluate(&metrics(2, 0, 0)), WriteStallState::Delayed);
+    assert_eq!(conf.evaluate(&metrics(0, 10, 0)), WriteStallState::Delayed);
+    assert_eq!(conf.evaluate(&metrics(0, 0, 100)), WriteStallState::Delayed);
+    // 一番厳しい状態になる
+    assert_eq!(conf.evaluate(&metrics(2, 20, 0)), WriteStallState::Stopped);
+    assert_eq!(conf.evaluate(&metrics(0, 0, 200)), WriteStallState::Stopped);
+
+    // 設定しなければ制限しない
+    let conf = WriteStallConf::new();
+    assert_eq!(conf.evaluate(&metrics(usize::MAX, usize::MAX, u64::MAX)), WriteStallState::Normal);
+}
+
+#[test]
+fn test_write_stall_delay() {
+    let controller = WriteController::new(
+        WriteStallConf::new()
+            .with_immutable_memtable_limits(1, 10)
+            .with_slowdown_delay(Duration::from_millis(5))
+    );
+    controller.wait(|| Ok(0), || unreachable!()).unwrap();
+    assert_eq!(controller.stats().state, WriteStallState::Normal);
+    assert_eq!(controller.stats().delayed_writes, 0);
+
+    controller.wait(|| Ok(1), || unreachable!()).unwrap();
+    controller.wait(|| Ok(1), || unreachable!()).unwrap();
+    let stats = controller.stats();
+    assert_eq!(stats.state, WriteStallState::Delayed);
+    assert_eq!(stats.delayed_writes, 2);
+    assert!(stats.delayed_duration >= Duration::from_millis(10));
+    assert_eq!(stats.metrics.immutable_memtables, 1);
+
+    controller.wait(|| Ok(0), || unreachable!()).unwrap();
+    let stats = controller.stats();
+    assert_eq!(stats.state, WriteStallState::Normal);
+    assert_eq!(stats.stalled_for, Duration::ZERO);
+}
+
+#[test]
+fn test_write_stall_stop_until_compacted() {
+    let controller = Arc::new(WriteController::new(
+        WriteStallConf::new()
+            .with_sstable_limits(2, 3)
+            .with_check_interval(Duration::from_millis(1))
+    ));
+    let sstables = Arc::new(AtomicUsize::new(3));
+
+    let handle = {
+        let controller = controller.clone();
+        let sstables = sstables.clone();
+        thread::spawn(move || {
+            controller.wait(|| Ok(0), || Ok((sstables.load(Ordering::SeqCst), 0)))
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert!(!handle.is_finished());
+    let stats = controller.stats();
+    assert_eq!(stats.state, WriteStallState::Stopped);
+    assert!(stats.stalled_for > Duration::ZERO);
+
+    // コンパクションでSSTableが減れば書き込める
+    sstables.store(1, Ordering::SeqCst);
+    handle.join().unwrap().unwrap();
+    let stats = controller.stats();
+    assert_eq!(stats.state, WriteStallState::Normal);
+    assert_eq!(stats.stopped_writes, 1);
+    assert!(stats.stopped_duration >= Duration::from_millis(50));
+}
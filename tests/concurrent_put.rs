@@ -0,0 +1,204 @@
+use std::{fs, sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread};
+
+use lsmtree::{sstable::{compaction::Compaction, SSTableWriter}, write_batch::WriteBatch, LSMTree, LSMTreeConf, SharedSSTableReader};
+
+#[derive(Debug, Clone)]
+pub struct MockCompaction {}
+
+impl Compaction for MockCompaction {
+    fn compact(
+        &self,
+        sstables: Arc<SharedSSTableReader>,
+        new_writer: &dyn Fn() -> SSTableWriter) -> Result<(), String> {
+        let _ = sstables;
+        let _ = new_writer;
+        unimplemented!("MockCompaction::compact is not implemented");
+    }
+}
+
+struct MockTimeStampGenerator {
+    monotonic: u64,
+}
+
+impl lsmtree::TimeStampGenerator for MockTimeStampGenerator {
+    fn get_timestamp(&mut self) -> u64 {
+        self.monotonic += 1;
+        self.monotonic
+    }
+}
+
+const THREADS: usize = 8;
+
+fn open(sst_dir: &str, commitlog_dir: &str) -> Arc<LSMTree<MockCompaction, MockTimeStampGenerator>> {
+    // memtableが何度も入れ替わるように小さくする
+    Arc::new(LSMTree::new(
+        LSMTreeConf::new(
+            MockCompaction {},
+            MockTimeStampGenerator { monotonic: 0 },
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            Some(1024),
+            None,
+            Some("idx".to_owned()),
+            Some(false),
+        )
+    ).unwrap())
+}
+
+fn set_up(sst_dir: &str, commitlog_dir: &str) {
+    if fs::exists(sst_dir).unwrap() {
+        fs::remove_dir_all(sst_dir).unwrap();
+    }
+    if fs::exists(commitlog_dir).unwrap() {
+        fs::remove_dir_all(commitlog_dir).unwrap();
+    }
+}
+
+fn tear_down(sst_dir: &str, commitlog_dir: &str) {
+    fs::remove_dir_all(sst_dir).unwrap();
+    fs::remove_dir_all(commitlog_dir).unwrap();
+}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn test_concurrent_put() {
+    let sst_dir = "./.test_concurrent_put_sst";
+    let commitlog_dir = "./.test_concurrent_put_commitlog";
+    set_up(sst_dir, commitlog_dir);
+    assert_send_sync::<LSMTree<MockCompaction, MockTimeStampGenerator>>();
+
+    let lsm_tree = open(sst_dir, commitlog_dir);
+    let handles: Vec<_> = (0..THREADS).map(|t| {
+        let lsm_tree = lsm_tree.clone();
+        thread::spawn(move || {
+            for i in 0..500 {
+                lsm_tree.put_str(&format!("key{}-{}", t, i), Some(&format!("value{}-{}", t, i))).unwrap();
+            }
+            for i in (0..500).step_by(3) {
+                lsm_tree.delete_str(&format!("key{}-{}", t, i)).unwrap();
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for t in 0..THREADS {
+        for i in 0..500 {
+            let expected = if i % 3 == 0 { None } else { Some(format!("value{}-{}", t, i)) };
+            assert_eq!(lsm_tree.get_str(&format!("key{}-{}", t, i)), Ok(expected));
+        }
+    }
+    // sequenceは書き込みごとに1つずつ、重複なく割り当てられる
+    assert_eq!(lsm_tree.snapshot().unwrap().sequence(), (THREADS * (500 + 167)) as u64);
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_concurrent_put_and_get() {
+    let sst_dir = "./.test_concurrent_put_and_get_sst";
+    let commitlog_dir = "./.test_concurrent_put_and_get_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open(sst_dir, commitlog_dir);
+    // 書き込み済みのキーの数. ここまでのキーはflush中でも読めなければならない
+    let written = Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let lsm_tree = lsm_tree.clone();
+        let written = written.clone();
+        thread::spawn(move || {
+            for i in 0..2000 {
+                lsm_tree.put_str(&format!("key{}", i), Some(&format!("value{}", i))).unwrap();
+                written.store(i + 1, Ordering::SeqCst);
+            }
+        })
+    };
+    let readers: Vec<_> = (0..THREADS / 2).map(|_| {
+        let lsm_tree = lsm_tree.clone();
+        let written = written.clone();
+        thread::spawn(move || {
+            while written.load(Ordering::SeqCst) < 2000 {
+                let i = written.load(Ordering::SeqCst);
+                if i == 0 {
+                    continue;
+                }
+                let i = i - 1;
+                assert_eq!(lsm_tree.get_str(&format!("key{}", i)), Ok(Some(format!("value{}", i))));
+            }
+        })
+    }).collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_concurrent_write_batch_with_snapshot() {
+    let sst_dir = "./.test_concurrent_write_batch_with_snapshot_sst";
+    let commitlog_dir = "./.test_concurrent_write_batch_with_snapshot_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open(sst_dir, commitlog_dir);
+    let handles: Vec<_> = (0..THREADS).map(|t| {
+        let lsm_tree = lsm_tree.clone();
+        thread::spawn(move || {
+            for i in 0..200 {
+                let value = format!("value{}-{}", t, i);
+                let mut batch = WriteBatch::new();
+                batch.put_str("a", &value).put_str("b", &value);
+                lsm_tree.write(&batch).unwrap();
+            }
+        })
+    }).collect();
+    // バッチは全体が見えるか、全く見えないかのどちらか
+    while !handles.iter().all(|handle| handle.is_finished()) {
+        let snapshot = lsm_tree.snapshot().unwrap();
+        assert_eq!(lsm_tree.get_str_at(&snapshot, "a"), lsm_tree.get_str_at(&snapshot, "b"));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(lsm_tree.get_str("a"), lsm_tree.get_str("b"));
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_concurrent_put_and_recover() {
+    let sst_dir = "./.test_concurrent_put_and_recover_sst";
+    let commitlog_dir = "./.test_concurrent_put_and_recover_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open(sst_dir, commitlog_dir);
+    let handles: Vec<_> = (0..THREADS).map(|t| {
+        let lsm_tree = lsm_tree.clone();
+        thread::spawn(move || {
+            for i in 0..300 {
+                lsm_tree.put_str(&format!("key{}-{}", t, i), Some(&format!("value{}-{}", t, i))).unwrap();
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(lsm_tree);
+
+    let lsm_tree = open(sst_dir, commitlog_dir);
+    for t in 0..THREADS {
+        for i in 0..300 {
+            assert_eq!(lsm_tree.get_str(&format!("key{}-{}", t, i)), Ok(Some(format!("value{}-{}", t, i))));
+        }
+    }
+    assert_eq!(lsm_tree.snapshot().unwrap().sequence(), (THREADS * 300) as u64);
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
@@ -1,6 +1,6 @@
 use std::{fs, sync::Arc};
 
-use lsmtree::{sstable::{compaction::{size_tiered_compaction::SizeTieredCompaction, Compaction}, SSTableWriter}, utils::get_page_size, LSMTree, LSMTreeConf, SharedSSTableReader};
+use lsmtree::{memtable::MemTable, sstable::{compaction::{size_tiered_compaction::SizeTieredCompaction, Compaction}, SSTableWriter}, utils::get_page_size, LSMTree, LSMTreeConf, ReadOptions, SharedSSTableReader};
 
 #[derive(Debug, Clone)]
 pub struct MockCompaction {}
@@ -9,8 +9,8 @@ impl Compaction for MockCompaction {
     fn compact(
         &self, 
         sstables: Arc<SharedSSTableReader>, 
-        writer: SSTableWriter) -> Result<(), String> {
-        let _ = writer;
+        new_writer: &dyn Fn() -> SSTableWriter) -> Result<(), String> {
+        let _ = new_writer;
         let _ = sstables;
         unimplemented!("MockCompaction::compact is not implemented");
     }
@@ -44,7 +44,7 @@ fn test_get_with_size_tiered() {
     let sst_dir = "./.test_get_with_size_tiered_sst";
     let commitlog_dir = "./.test_get_with_size_tiered_commitlog";
     let index_interval = get_page_size();
-    let mut lsm_tree = LSMTree::new(
+    let lsm_tree = LSMTree::new(
         LSMTreeConf::new(
             SizeTieredCompaction::new(
                 get_page_size(),
@@ -61,11 +61,13 @@ fn test_get_with_size_tiered() {
             Some(true),
     )).unwrap();
     for (key, value) in data.iter() {
-        assert!(lsm_tree.put(*key, Some(*value)).is_ok());
+        assert!(lsm_tree.put_str(key, Some(*value)).is_ok());
     }
     for (key, value) in data.iter() {
-        assert_eq!(lsm_tree.get(*key), Ok(Some(value.to_string())));
+        assert_eq!(lsm_tree.get_str(key), Ok(Some(value.to_string())));
     }
+    // コンパクションのスレッドを止めてから消す
+    drop(lsm_tree);
     tear_down(sst_dir, commitlog_dir);
 }
 
@@ -83,7 +85,7 @@ fn test_get_big_quantity() {
         fs::remove_dir_all(commitlog_dir).unwrap();
     }
 
-    let mut lsm_tree = LSMTree::new(
+    let lsm_tree = LSMTree::new(
         LSMTreeConf::new(
             MockCompaction {},
             MockTimeStampGenerator::new(),
@@ -95,14 +97,16 @@ fn test_get_big_quantity() {
             Some(false),       // コンパクションを無効化
     )).unwrap();
     for i in 0..104857 {
-        assert!(lsm_tree.put(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
+        assert!(lsm_tree.put_str(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
     }
     let now = std::time::Instant::now();
-    assert_eq!(lsm_tree.get("not_exist_key"), Ok(None));
+    assert_eq!(lsm_tree.get_str("not_exist_key"), Ok(None));
     for i in 0..104857 {
-        assert_eq!(lsm_tree.get(&format!("key{}", i)), Ok(Some(format!("value{}", i))));
+        assert_eq!(lsm_tree.get_str(&format!("key{}", i)), Ok(Some(format!("value{}", i))));
     }
     println!("Elapsed time: {:?}", now.elapsed());
+    // コンパクションのスレッドを止めてから消す
+    drop(lsm_tree);
     tear_down(sst_dir, commitlog_dir);
 }
 
@@ -119,7 +123,7 @@ fn test_get_mid_quantity_with_ssts() {
         fs::remove_dir_all(commitlog_dir).unwrap();
     }
 
-    let mut lsm_tree = LSMTree::new(
+    let lsm_tree = LSMTree::new(
         LSMTreeConf::new(
             SizeTieredCompaction::new(
                 get_page_size(),
@@ -137,24 +141,343 @@ fn test_get_mid_quantity_with_ssts() {
     )).unwrap();
 
     for i in 0..48000 {
-        assert!(lsm_tree.put(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
+        assert!(lsm_tree.put_str(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
     }
-    let _ = lsm_tree.put("key1", None);
+    let _ = lsm_tree.put_str("key1", None);
     for i in 0..48000 {
-        assert!(lsm_tree.put(&format!("key{}", i), Some(&format!("value{}", i + 48000))).is_ok());
+        assert!(lsm_tree.put_str(&format!("key{}", i), Some(&format!("value{}", i + 48000))).is_ok());
     }
-    let _ = lsm_tree.put("key2", None);
-    let _ = lsm_tree.put("key12000", None);
-    let _ = lsm_tree.put("key48000", None);
+    let _ = lsm_tree.put_str("key2", None);
+    let _ = lsm_tree.put_str("key12000", None);
+    let _ = lsm_tree.put_str("key48000", None);
     let now = std::time::Instant::now();
-    assert_eq!(lsm_tree.get("not_exist_key"), Ok(None));
+    assert_eq!(lsm_tree.get_str("not_exist_key"), Ok(None));
     for i in 0..=48000 {
         if i == 2 || i == 12000 || i == 48000 {
-            assert_eq!(lsm_tree.get(&format!("key{}", i)), Ok(None));
+            assert_eq!(lsm_tree.get_str(&format!("key{}", i)), Ok(None));
         } else {
-            assert_eq!(lsm_tree.get(&format!("key{}", i)), Ok(Some(format!("value{}", i + 48000))));
+            assert_eq!(lsm_tree.get_str(&format!("key{}", i)), Ok(Some(format!("value{}", i + 48000))));
         }
     }
     println!("Elapsed time: {:?}", now.elapsed());
+    // コンパクションのスレッドを止めてから消す
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_get_while_flushing() {
+    let sst_dir = "./.test_get_while_flushing_sst";
+    let commitlog_dir = "./.test_get_while_flushing_commitlog";
+    let lsm_tree = LSMTree::new(
+        LSMTreeConf::new(
+            MockCompaction {},
+            MockTimeStampGenerator::new(),
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            None,
+            Some(get_page_size()),
+            Some("idx".to_owned()),
+            Some(false),
+    )).unwrap();
+
+    // memtableがflushに回された直後でも、書いたばかりの値が読める
+    let value = "v".repeat(100);
+    for i in 0..200 {
+        let key = format!("key{:03}", i);
+        lsm_tree.put_str(&key, Some(&value)).unwrap();
+        assert_eq!(lsm_tree.get_str(&key), Ok(Some(value.clone())), "{}", key);
+        assert_eq!(lsm_tree.get_str("key000"), Ok(Some(value.clone())), "{}", key);
+    }
+    let scanned = lsm_tree.scan(..).unwrap().count();
+    assert_eq!(scanned, 200);
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_get_corrupted_sstable() {
+    let sst_dir = "./.test_get_corrupted_sstable_sst";
+    let commitlog_dir = "./.test_get_corrupted_sstable_commitlog";
+    if fs::exists(sst_dir).unwrap() {
+        fs::remove_dir_all(sst_dir).unwrap();
+    }
+    fs::create_dir_all(sst_dir).unwrap();
+
+    let memtable = MemTable::new();
+    memtable.put(b"key1", b"value1", 1);
+    let writer = SSTableWriter::new(sst_dir).unwrap();
+    writer.write(&memtable, get_page_size()).unwrap();
+    // value1の最後のバイトを書き換える. key_len(8) + key(4) + record_type(1) + value_len(8) + 5
+    let mut content = fs::read(&writer.file).unwrap();
+    content[26] = b'X';
+    fs::write(&writer.file, content).unwrap();
+
+    let lsm_tree = LSMTree::new(
+        LSMTreeConf::new(
+            MockCompaction {},
+            MockTimeStampGenerator::new(),
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            None,
+            None,
+            Some("idx".to_owned()),
+            Some(false),
+        )
+    ).unwrap();
+
+    let err = lsm_tree.get_str("key1").unwrap_err();
+    assert!(err.starts_with("corruption") && err.contains(&writer.file), "{}", err);
+    assert!(lsm_tree.scan(..).is_err());
+
+    // キャッシュに入れるblockは、verify_checksumsがfalseでも確かめる
+    let options = ReadOptions { verify_checksums: false, ..Default::default() };
+    assert!(lsm_tree.get_with_options(b"key1", &options).unwrap_err().starts_with("corruption"));
+    assert_eq!(lsm_tree.block_cache_stats().insert, 0);
+    // キャッシュに入れなければ、確かめずに壊れた値を読む
+    let options = ReadOptions { verify_checksums: false, fill_cache: false };
+    assert_eq!(lsm_tree.get_with_options(b"key1", &options), Ok(Some(b"valueX".to_vec())));
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_get_with_bloom_filter() {
+    let sst_dir = "./.test_get_with_bloom_filter_sst";
+    let commitlog_dir = "./.test_get_with_bloom_filter_commitlog";
+    let open = |bloom_bits_per_key: usize| {
+        LSMTree::new(
+            LSMTreeConf::new(
+                MockCompaction {},
+                MockTimeStampGenerator::new(),
+                Some(sst_dir.to_owned()),
+                Some(commitlog_dir.to_owned()),
+                Some(1024),
+                None,
+                Some("idx".to_owned()),
+                Some(false),
+            ).with_bloom_bits_per_key(bloom_bits_per_key)
+        ).unwrap()
+    };
+
+    let lsm_tree = open(10);
+    for i in 0..200 {
+        lsm_tree.put_str(&format!("key{:03}", i), Some(&format!("value{}", i))).unwrap();
+    }
+    drop(lsm_tree);
+
+    // 開き直すと、全てのキーがSSTableから読まれる
+    let lsm_tree = open(10);
+    for i in 0..200 {
+        assert_eq!(lsm_tree.get_str(&format!("key{:03}", i)), Ok(Some(format!("value{}", i))));
+    }
+    let stats = lsm_tree.bloom_filter_stats();
+    assert!(stats.checked > 0);
+    // キーの範囲の中にあるが、書いていないキー
+    for i in 0..200 {
+        assert_eq!(lsm_tree.get_str(&format!("key{:03}x", i)), Ok(None));
+    }
+    // ないキーはほとんどフィルタで弾かれる
+    let stats = lsm_tree.bloom_filter_stats();
+    assert!(stats.useful > 0);
+    assert!(stats.false_positive < stats.useful / 10, "{:?}", stats);
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+
+    // フィルタを作らなければ、フィルタは引かれない
+    let lsm_tree = open(0);
+    for i in 0..200 {
+        lsm_tree.put_str(&format!("key{:03}", i), Some(&format!("value{}", i))).unwrap();
+    }
+    drop(lsm_tree);
+    let lsm_tree = open(0);
+    assert_eq!(lsm_tree.get_str("key000"), Ok(Some("value0".to_owned())));
+    assert_eq!(lsm_tree.get_str("key000x"), Ok(None));
+    assert_eq!(lsm_tree.bloom_filter_stats().checked, 0);
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_get_with_block_cache() {
+    let sst_dir = "./.test_get_with_block_cache_sst";
+    let commitlog_dir = "./.test_get_with_block_cache_commitlog";
+    let open = |block_cache_capacity: usize| {
+        LSMTree::new(
+            LSMTreeConf::new(
+                MockCompaction {},
+                MockTimeStampGenerator::new(),
+                Some(sst_dir.to_owned()),
+                Some(commitlog_dir.to_owned()),
+                Some(1 << 20),
+                Some(1024),
+                Some("idx".to_owned()),
+                Some(false),
+            ).with_block_cache_capacity(block_cache_capacity)
+        ).unwrap()
+    };
+
+    let lsm_tree = open(1 << 20);
+    for i in 0..200 {
+        lsm_tree.put_str(&format!("key{:03}", i), Some(&format!("value{}", i))).unwrap();
+    }
+    drop(lsm_tree);
+
+    // 開き直すと、全てのキーがSSTableから読まれる
+    let lsm_tree = open(1 << 20);
+    // fill_cacheがfalseなら、読んだblockはキャッシュに入らない
+    let options = ReadOptions { fill_cache: false, ..Default::default() };
+    assert!(lsm_tree.scan_with_options(.., &options).unwrap().all(|entry| entry.is_ok()));
+    let stats = lsm_tree.block_cache_stats();
+    assert_eq!((stats.hit, stats.insert, stats.usage), (0, 0, 0));
+
+    // verify_checksumsがfalseでも、読んだときに確かめたblockはキャッシュに入る
+    let options = ReadOptions { verify_checksums: false, ..Default::default() };
+    assert_eq!(lsm_tree.get_with_options(b"key100", &options), Ok(Some(b"value100".to_vec())));
+    assert_eq!(lsm_tree.get_str("key100"), Ok(Some("value100".to_owned())));
+    let stats = lsm_tree.block_cache_stats();
+    assert_eq!((stats.hit, stats.insert), (1, 1));
+    assert!(stats.usage > 0 && stats.usage <= stats.capacity);
+    drop(lsm_tree);
+
+    // 容量が0ならキャッシュしない
+    let lsm_tree = open(0);
+    for _ in 0..2 {
+        assert_eq!(lsm_tree.get_str("key100"), Ok(Some("value100".to_owned())));
+    }
+    assert_eq!(lsm_tree.block_cache_stats().hit, 0);
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_get_with_index_memory_budget() {
+    let sst_dir = "./.test_get_with_index_memory_budget_sst";
+    let commitlog_dir = "./.test_get_with_index_memory_budget_commitlog";
+    if fs::exists(sst_dir).unwrap() {
+        fs::remove_dir_all(sst_dir).unwrap();
+    }
+    let open = || {
+        LSMTree::new(
+            LSMTreeConf::new(
+                MockCompaction {},
+                MockTimeStampGenerator::new(),
+                Some(sst_dir.to_owned()),
+                Some(commitlog_dir.to_owned()),
+                Some(1 << 20),
+                None,
+                Some("idx".to_owned()),
+                Some(false),
+            ).with_index_memory_budget(30)
+        ).unwrap()
+    };
+
+    // 開き直すたびにコミットログがSSTableになる
+    // indexは1エントリ(8 + 9 + 8 = 25バイト)なので、上限には1つしか入らない
+    for table in 0..4 {
+        let lsm_tree = open();
+        for i in 0..50 {
+            lsm_tree.put_str(&format!("table{}/{:02}", table, i), Some(&format!("value{}", i))).unwrap();
+        }
+    }
+    let lsm_tree = open();
+    let mut files: Vec<String> = fs::read_dir(sst_dir).unwrap()
+        .map(|entry| entry.unwrap().path().to_str().unwrap().to_owned())
+        .filter(|file| file.ends_with(".sst"))
+        .collect();
+    files.sort();
+    assert_eq!(files.len(), 4);
+
+    for table in 0..4 {
+        assert_eq!(lsm_tree.get_str(&format!("table{}/00", table)), Ok(Some("value0".to_owned())));
+    }
+    let stats = lsm_tree.index_cache_stats();
+    assert!(stats.evict >= 3, "{:?}", stats);
+    assert!(stats.usage <= 30, "{:?}", stats);
+
+    // pinしたindexは上限を超えても捨てない
+    lsm_tree.pin_index(&files[0]).unwrap();
+    for table in [0, 1, 2, 3, 0] {
+        assert_eq!(lsm_tree.get_str(&format!("table{}/49", table)), Ok(Some("value49".to_owned())));
+    }
+    let stats = lsm_tree.index_cache_stats();
+    assert_eq!(stats.pinned_usage, 25);
+    assert!(stats.usage - stats.pinned_usage <= 30, "{:?}", stats);
+
+    lsm_tree.unpin_index(&files[0]).unwrap();
+    assert_eq!(lsm_tree.index_cache_stats().pinned_usage, 0);
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_get_newest_first() {
+    let sst_dir = "./.test_get_newest_first_sst";
+    let commitlog_dir = "./.test_get_newest_first_commitlog";
+    if fs::exists(sst_dir).unwrap() {
+        fs::remove_dir_all(sst_dir).unwrap();
+    }
+    let open = || {
+        LSMTree::new(
+            LSMTreeConf::new(
+                MockCompaction {},
+                MockTimeStampGenerator::new(),
+                Some(sst_dir.to_owned()),
+                Some(commitlog_dir.to_owned()),
+                Some(1 << 20),
+                None,
+                Some("idx".to_owned()),
+                Some(false),
+            ).with_bloom_bits_per_key(0)
+        ).unwrap()
+    };
+
+    // 開き直すたびにコミットログがSSTableになる
+    let lsm_tree = open();
+    for i in 0..50 {
+        lsm_tree.put_str(&format!("key{:02}", i), Some("v1")).unwrap();
+    }
+    drop(lsm_tree);
+    let lsm_tree = open();
+    lsm_tree.put_str("key10", Some("v2")).unwrap();
+    lsm_tree.put_str("key20", None).unwrap();
+    drop(lsm_tree);
+    let lsm_tree = open();
+    lsm_tree.put_str("other", Some("v3")).unwrap();
+    drop(lsm_tree);
+
+    let lsm_tree = open();
+    // 新しいSSTableで見つかれば、古いSSTableは読まない
+    assert_eq!(lsm_tree.get_str("key10"), Ok(Some("v2".to_owned())));
+    // Tombstoneでも打ち切る
+    assert_eq!(lsm_tree.get_str("key20"), Ok(None));
+    // otherのSSTableと、key10からkey20までのSSTableはキーの範囲で飛ばす
+    assert_eq!(lsm_tree.get_str("key30"), Ok(Some("v1".to_owned())));
+    let stats = lsm_tree.get_stats();
+    assert_eq!(stats.gets, 3);
+    assert_eq!((stats.lookups, stats.max_lookups), (3, 1));
+    assert_eq!(stats.key_range_skipped, 4);
+    assert_eq!(stats.early_stopped, 2);
+    assert_eq!(stats.depth, 3);
+    assert_eq!(stats.lookups_per_get(), 1.0);
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+
+    // コンパクションしたSSTableはsequenceの範囲が他と重なる
+    // max_sequenceの大きいSSTableで古いバージョンが見つかっても、新しいバージョンを探す
+    fs::create_dir_all(sst_dir).unwrap();
+    let compacted = MemTable::new();
+    compacted.put(b"key", b"old", 1);
+    compacted.put(b"zzz", b"value", 300);
+    SSTableWriter::new(sst_dir).unwrap().write(&compacted, get_page_size()).unwrap();
+    let newer = MemTable::new();
+    newer.put(b"key", b"new", 150);
+    SSTableWriter::new(sst_dir).unwrap().write(&newer, get_page_size()).unwrap();
+
+    let lsm_tree = open();
+    assert_eq!(lsm_tree.get_str("key"), Ok(Some("new".to_owned())));
+    assert_eq!(lsm_tree.get_stats().lookups, 2);
+    drop(lsm_tree);
     tear_down(sst_dir, commitlog_dir);
 }
@@ -1,6 +1,6 @@
-use std::{fs::{read_dir, DirEntry}, sync::Arc};
+use std::{fs::{read_dir, DirEntry}, sync::Arc, thread::sleep, time::Duration};
 
-use lsmtree::{sstable::{compaction::{size_tiered_compaction::SizeTieredCompaction, Compaction}, SSTableWriter}, utils::get_page_size, LSMTree, LSMTreeConf, SharedSSTableReader};
+use lsmtree::{memtable::{btree::BTreeMemTableFactory, hash::HashMemTableFactory, skiplist::SkipListMemTableFactory, MemTableFactory}, sstable::{compaction::{size_tiered_compaction::SizeTieredCompaction, Compaction}, SSTableWriter}, utils::get_page_size, write_stall::{WriteStallConf, WriteStallState}, LSMTree, LSMTreeConf, SharedSSTableReader};
 
 #[derive(Debug, Clone)]
 pub struct MockCompaction {}
@@ -9,9 +9,9 @@ impl Compaction for MockCompaction {
     fn compact(
         &self,
         sstables: Arc<SharedSSTableReader>,
-        writer: SSTableWriter) -> Result<(), String> {
+        new_writer: &dyn Fn() -> SSTableWriter) -> Result<(), String> {
         let _ = sstables;
-        let _ = writer;
+        let _ = new_writer;
         unimplemented!("MockCompaction::compact is not implemented");
     }
 }
@@ -29,11 +29,12 @@ fn tear_down(sst_dir: &str, commitlog_dir: &str) {
 }
 
 #[test]
+#[allow(clippy::clone_on_copy, clippy::needless_borrow)]
 fn test_put_big_quantity() {
     let sst_dir = "./.test_put_big_quantity_sst";
     let commitlog_dir = "./.test_put_big_quantity_commitlog";
     let index_interval = lsmtree::utils::get_page_size();
-    let mut lsm_tree = LSMTree::new(
+    let lsm_tree = LSMTree::new(
         LSMTreeConf::new(
             MockCompaction{},
             MockTimeStampGenerator {},
@@ -52,7 +53,7 @@ fn test_put_big_quantity() {
         * 1048576B / 10B ≒ 104857
      */
     for i in 0..104857 {
-        assert!(lsm_tree.put(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
+        assert!(lsm_tree.put_str(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
     }
     let read_dir = read_dir(sst_dir).unwrap();
 
@@ -61,19 +62,23 @@ fn test_put_big_quantity() {
         entry.file_name().to_str().unwrap().ends_with(".sst")
     }).for_each(|entry| {
         let entry = entry.unwrap();
-        assert!(entry.metadata().unwrap().len() > lsm_tree.get_memtable_threshold() as u64);
+        // memtable_thresholdはarenaのノードやタワーの分も数えるので、SSTableはthresholdより小さくなる
+        assert!(entry.metadata().unwrap().len() > lsm_tree.get_memtable_threshold() as u64 / 4);
     });
 
+    // flush中のスレッドを待ってから消す
+    drop(lsm_tree);
     tear_down(&sst_dir, &commitlog_dir);
 }
 
 
 #[test]
+#[allow(clippy::clone_on_copy, clippy::needless_borrow)]
 fn test_put_big_quantity_with_sized_tiered() {
     let sst_dir = "./.test_put_big_quantity_sst_with_sized_tiered";
     let commitlog_dir = "./.test_put_big_quantity_commitlog_with_sized_tiered";
     let index_interval = lsmtree::utils::get_page_size();
-    let mut lsm_tree = LSMTree::new(
+    let lsm_tree = LSMTree::new(
         LSMTreeConf::new(
             SizeTieredCompaction::new(
                 get_page_size(),
@@ -97,7 +102,7 @@ fn test_put_big_quantity_with_sized_tiered() {
         * 1048576B / 10B ≒ 104857
      */
     for i in 0..104857 {
-        assert!(lsm_tree.put(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
+        assert!(lsm_tree.put_str(&format!("key{}", i), Some(&format!("value{}", i))).is_ok());
     }
     let read_dir = read_dir(sst_dir).unwrap();
 
@@ -106,9 +111,16 @@ fn test_put_big_quantity_with_sized_tiered() {
         entry.file_name().to_str().unwrap().ends_with(".sst")
     }).for_each(|entry| {
         let entry = entry.unwrap();
-        assert!(entry.metadata().unwrap().len() > lsm_tree.get_memtable_threshold() as u64);
+        // 読んでいる間にコンパクションで消されたファイルは飛ばす
+        let Ok(metadata) = entry.metadata() else {
+            return;
+        };
+        // memtable_thresholdはarenaのノードやタワーの分も数えるので、SSTableはthresholdより小さくなる
+        assert!(metadata.len() > lsm_tree.get_memtable_threshold() as u64 / 4);
     });
 
+    // flush中のスレッドを待ってから消す
+    drop(lsm_tree);
     tear_down(&sst_dir, &commitlog_dir);
 }
 
@@ -117,7 +129,7 @@ fn test_put_key4503() {
     let sst_dir = "./.test_put_key4503_sst";
     let commitlog_dir = "./.test_put_key4503_commitlog";
     let index_interval = lsmtree::utils::get_page_size();
-    let mut lsm_tree = LSMTree::new(
+    let lsm_tree = LSMTree::new(
         LSMTreeConf::new(
             SizeTieredCompaction::new(
                 get_page_size(),
@@ -133,5 +145,135 @@ fn test_put_key4503() {
             Some("idx".to_owned()),
             Some(true),
     )).unwrap();
-    lsm_tree.put("key4503", Some(&"a".repeat(4503 + (104856 / 3)))).unwrap();
-}
\ No newline at end of file
+    lsm_tree.put_str("key4503", Some(&"a".repeat(4503 + (104856 / 3)))).unwrap();
+}
+#[test]
+#[allow(clippy::clone_on_copy, clippy::needless_borrow)]
+fn test_put_with_write_stall() {
+    let sst_dir = "./.test_put_with_write_stall_sst";
+    let commitlog_dir = "./.test_put_with_write_stall_commitlog";
+    let lsm_tree = LSMTree::new(
+        LSMTreeConf::new(
+            MockCompaction{},
+            MockTimeStampGenerator{},
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            Some(100),
+            None,
+            Some("idx".to_owned()),
+            Some(false),
+        ).with_write_stall(
+            WriteStallConf::new()
+                .with_immutable_memtable_limits(1, 2)
+                .with_sstable_limits(1, usize::MAX)
+                .with_check_interval(Duration::from_millis(1))
+        )
+    ).unwrap();
+    for i in 0..1000 {
+        lsm_tree.put_str(&format!("key{}", i), Some(&format!("value{}", i))).unwrap();
+    }
+    // flushが終わればflush待ちのmemtableは減るが、コンパクションしないのでSSTableは減らない
+    sleep(Duration::from_millis(100));
+    lsm_tree.put_str("key1000", Some("value1000")).unwrap();
+
+    let stats = lsm_tree.write_stall_stats();
+    assert_eq!(stats.state, WriteStallState::Delayed);
+    assert!(stats.delayed_writes > 0);
+    assert!(stats.metrics.sstables >= 1);
+    for i in 0..=1000 {
+        assert_eq!(lsm_tree.get_str(&format!("key{}", i)), Ok(Some(format!("value{}", i))));
+    }
+    drop(lsm_tree);
+
+    tear_down(&sst_dir, &commitlog_dir);
+}
+
+fn open_with_memtable_factory<F: MemTableFactory + 'static>(sst_dir: &str, commitlog_dir: &str, factory: F) -> LSMTree<MockCompaction, MockTimeStampGenerator> {
+    LSMTree::new(
+        LSMTreeConf::new(
+            MockCompaction{},
+            MockTimeStampGenerator{},
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            Some(256),
+            None,
+            Some("idx".to_owned()),
+            Some(false),
+        ).with_memtable_factory(factory)
+    ).unwrap()
+}
+
+#[test]
+fn test_put_with_memtable_factories() {
+    let sst_dir = "./.test_put_with_memtable_factories_sst";
+    let commitlog_dir = "./.test_put_with_memtable_factories_commitlog";
+    for i in 0..3 {
+        let lsm_tree = match i {
+            0 => open_with_memtable_factory(sst_dir, commitlog_dir, SkipListMemTableFactory),
+            1 => open_with_memtable_factory(sst_dir, commitlog_dir, BTreeMemTableFactory),
+            _ => open_with_memtable_factory(sst_dir, commitlog_dir, HashMemTableFactory),
+        };
+        for j in 0..100 {
+            lsm_tree.put_str(&format!("key{:03}", j), Some(&format!("value{}-{}", i, j))).unwrap();
+        }
+        lsm_tree.delete_str("key050").unwrap();
+
+        assert_eq!(lsm_tree.get_str("key010"), Ok(Some(format!("value{}-10", i))));
+        assert_eq!(lsm_tree.get_str("key050"), Ok(None));
+        let keys: Vec<_> = lsm_tree.scan(b"key048".as_slice()..b"key052".as_slice()).unwrap()
+            .map(|entry| String::from_utf8(entry.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["key048", "key049", "key051"]);
+        drop(lsm_tree);
+
+        tear_down(sst_dir, commitlog_dir);
+    }
+}
+
+#[test]
+fn test_put_rotates_by_arena_usage() {
+    let sst_dir = "./.test_put_rotates_by_arena_usage_sst";
+    let commitlog_dir = "./.test_put_rotates_by_arena_usage_commitlog";
+    let count_sst = || read_dir(sst_dir).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".sst"))
+        .count();
+    let open = |skiplist: bool| {
+        let conf = LSMTreeConf::new(
+            MockCompaction{},
+            MockTimeStampGenerator{},
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            Some(4096),
+            None,
+            Some("idx".to_owned()),
+            Some(false),
+        );
+        let conf = if skiplist {
+            conf.with_memtable_factory(SkipListMemTableFactory)
+        } else {
+            conf.with_memtable_factory(BTreeMemTableFactory)
+        };
+        LSMTree::new(conf).unwrap()
+    };
+
+    // キーと値だけなら 100 * (4 + 8 + 1) = 1300B でthresholdに届かない
+    // skiplistはノードとタワーの分もarenaから確保するので、thresholdを超えてflushされる
+    let lsm_tree = open(true);
+    for i in 0..100 {
+        lsm_tree.put_str(&format!("k{:03}", i), Some("v")).unwrap();
+    }
+    assert!(lsm_tree.get_memtable().len() < 100 * 13);
+    drop(lsm_tree);
+    assert!(count_sst() > 0);
+    tear_down(sst_dir, commitlog_dir);
+
+    // arenaを使わない実装ではキーと値の大きさで判断する
+    let lsm_tree = open(false);
+    for i in 0..100 {
+        lsm_tree.put_str(&format!("k{:03}", i), Some("v")).unwrap();
+    }
+    assert_eq!(lsm_tree.get_memtable().len(), 100 * 13);
+    drop(lsm_tree);
+    assert_eq!(count_sst(), 0);
+    tear_down(sst_dir, commitlog_dir);
+}
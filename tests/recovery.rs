@@ -0,0 +1,283 @@
+use std::{fs, io::Write, sync::Arc};
+
+use lsmtree::{commitlog::{CommitLog, RecoveryMode, SyncMode}, sstable::{compaction::Compaction, SSTableWriter}, LSMTree, LSMTreeConf, SharedSSTableReader, WriteOptions, write_batch::WriteBatch};
+
+#[derive(Debug, Clone)]
+pub struct MockCompaction {}
+
+impl Compaction for MockCompaction {
+    fn compact(
+        &self,
+        sstables: Arc<SharedSSTableReader>,
+        new_writer: &dyn Fn() -> SSTableWriter) -> Result<(), String> {
+        let _ = sstables;
+        let _ = new_writer;
+        unimplemented!("MockCompaction::compact is not implemented");
+    }
+}
+
+struct MockTimeStampGenerator {
+    monotonic: u64,
+}
+
+impl lsmtree::TimeStampGenerator for MockTimeStampGenerator {
+    fn get_timestamp(&mut self) -> u64 {
+        self.monotonic += 1;
+        self.monotonic
+    }
+}
+
+fn open(sst_dir: &str, commitlog_dir: &str, recovery_mode: RecoveryMode) -> Result<LSMTree<MockCompaction, MockTimeStampGenerator>, String> {
+    open_with_sync_mode(sst_dir, commitlog_dir, recovery_mode, SyncMode::Never)
+}
+
+fn open_with_sync_mode(sst_dir: &str, commitlog_dir: &str, recovery_mode: RecoveryMode, sync_mode: SyncMode) -> Result<LSMTree<MockCompaction, MockTimeStampGenerator>, String> {
+    LSMTree::new(
+        LSMTreeConf::new(
+            MockCompaction {},
+            MockTimeStampGenerator { monotonic: 0 },
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            None,
+            None,
+            Some("idx".to_owned()),
+            Some(false),
+        ).with_recovery_mode(recovery_mode)
+        .with_sync_mode(sync_mode)
+    )
+}
+
+fn set_up(sst_dir: &str, commitlog_dir: &str) {
+    if fs::exists(sst_dir).unwrap() {
+        fs::remove_dir_all(sst_dir).unwrap();
+    }
+    if fs::exists(commitlog_dir).unwrap() {
+        fs::remove_dir_all(commitlog_dir).unwrap();
+    }
+}
+
+fn tear_down(sst_dir: &str, commitlog_dir: &str) {
+    std::fs::remove_dir_all(sst_dir).unwrap();
+    std::fs::remove_dir_all(commitlog_dir).unwrap();
+}
+
+#[test]
+fn test_recover_from_commitlog() {
+    let sst_dir = "./.test_recover_from_commitlog_sst";
+    let commitlog_dir = "./.test_recover_from_commitlog_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put_str("key1", Some("value1")).unwrap();
+    lsm_tree.put_str("key2", Some("value2")).unwrap();
+    lsm_tree.put_str("key1", Some("value3")).unwrap();
+    lsm_tree.put_str("key2", None).unwrap();
+    // memtableをflushせずに落とす
+    drop(lsm_tree);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(Some("value3".to_owned())));
+    assert_eq!(lsm_tree.get_str("key2"), Ok(None));
+    // 復元したログは消え、新しいログだけが残る
+    assert_eq!(CommitLog::list_logs(commitlog_dir).unwrap(), vec![lsm_tree.get_commitlog().get_file_path()]);
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_recover_with_torn_tail() {
+    let sst_dir = "./.test_recover_with_torn_tail_sst";
+    let commitlog_dir = "./.test_recover_with_torn_tail_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put_str("key1", Some("value1")).unwrap();
+    lsm_tree.put_str("key2", Some("value2")).unwrap();
+    let log = lsm_tree.get_commitlog().get_file_path();
+    drop(lsm_tree);
+
+    // 書き込み途中で落ちたレコード
+    let mut file = fs::OpenOptions::new().append(true).open(&log).unwrap();
+    file.write_all(&[1, 4, 0, 0, 0, 0, 0, 0, 0, 107]).unwrap();
+    drop(file);
+
+    assert!(open(sst_dir, commitlog_dir, RecoveryMode::Fail).is_err());
+    assert!(fs::exists(&log).unwrap());
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::TruncateTail).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(Some("value1".to_owned())));
+    assert_eq!(lsm_tree.get_str("key2"), Ok(Some("value2".to_owned())));
+    assert!(!fs::exists(&log).unwrap());
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_recover_synced_writes() {
+    let sst_dir = "./.test_recover_synced_writes_sst";
+    let commitlog_dir = "./.test_recover_synced_writes_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open_with_sync_mode(sst_dir, commitlog_dir, RecoveryMode::Fail, SyncMode::Always).unwrap();
+    lsm_tree.put_str("key1", Some("value1")).unwrap();
+    drop(lsm_tree);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(Some("value1".to_owned())));
+    lsm_tree.put_with_options(b"key2", Some(b"value2".as_slice()), &WriteOptions { sync: true }).unwrap();
+    lsm_tree.put_with_options(b"key1", None, &WriteOptions { sync: true }).unwrap();
+    drop(lsm_tree);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(None));
+    assert_eq!(lsm_tree.get_str("key2"), Ok(Some("value2".to_owned())));
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_recover_binary_key_value() {
+    let sst_dir = "./.test_recover_binary_key_value_sst";
+    let commitlog_dir = "./.test_recover_binary_key_value_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    // UTF-8として不正なバイト列もそのまま保存できる
+    let key = [0xff, 0x00, 0xfe];
+    let value = 42u64.to_ne_bytes();
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put(&key, Some(&value)).unwrap();
+    assert_eq!(lsm_tree.get(&key), Ok(Some(value.to_vec())));
+    drop(lsm_tree);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    assert_eq!(lsm_tree.get(&key), Ok(Some(value.to_vec())));
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_recover_zero_value() {
+    let sst_dir = "./.test_recover_zero_value_sst";
+    let commitlog_dir = "./.test_recover_zero_value_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    // "\0"の値は削除とは区別され、SSTableに書いた後も値のまま読める
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put(b"zero", Some(b"\0")).unwrap();
+    lsm_tree.put(b"deleted", Some(b"value")).unwrap();
+    lsm_tree.delete(b"deleted").unwrap();
+    assert_eq!(lsm_tree.get(b"zero"), Ok(Some(b"\0".to_vec())));
+    drop(lsm_tree);
+
+    // 開き直すとコミットログがSSTableになる
+    for _ in 0..2 {
+        let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+        assert_eq!(lsm_tree.get(b"zero"), Ok(Some(b"\0".to_vec())));
+        assert_eq!(lsm_tree.get(b"deleted"), Ok(None));
+        assert_eq!(lsm_tree.scan(..).unwrap().map(|entry| entry.unwrap()).collect::<Vec<_>>(), vec![(b"zero".to_vec(), b"\0".to_vec())]);
+    }
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_recover_write_batch() {
+    let sst_dir = "./.test_recover_write_batch_sst";
+    let commitlog_dir = "./.test_recover_write_batch_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put_str("key1", Some("value1")).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put_str("key2", "value2").delete_str("key1").put_str("key3", "value3");
+    lsm_tree.write(&batch).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(None));
+    assert_eq!(lsm_tree.get_str("key2"), Ok(Some("value2".to_owned())));
+    let log = lsm_tree.get_commitlog().get_file_path();
+    drop(lsm_tree);
+
+    // 2つ目のバッチの書き込み途中で落ちた
+    let mut batch = WriteBatch::new();
+    batch.put_str("key4", "value4").delete_str("key2");
+    let frame = CommitLog::encode_batch(batch.entries(), 100, 100);
+    let mut file = fs::OpenOptions::new().append(true).open(&log).unwrap();
+    file.write_all(&frame[..frame.len() - 4]).unwrap();
+    drop(file);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::TruncateTail).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(None));
+    assert_eq!(lsm_tree.get_str("key2"), Ok(Some("value2".to_owned())));
+    assert_eq!(lsm_tree.get_str("key3"), Ok(Some("value3".to_owned())));
+    assert_eq!(lsm_tree.get_str("key4"), Ok(None));
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_recover_sequence() {
+    let sst_dir = "./.test_recover_sequence_sst";
+    let commitlog_dir = "./.test_recover_sequence_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    // MockTimeStampGeneratorは開くたびに1から数え直すので、timestampでは新しい値が負ける
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put_str("key1", Some("value1")).unwrap();
+    lsm_tree.put_str("key1", Some("value2")).unwrap();
+    drop(lsm_tree);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put_str("key1", Some("value3")).unwrap();
+    drop(lsm_tree);
+
+    // key1はそれぞれ別のSSTableに書き出されている
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(Some("value3".to_owned())));
+    let snapshot = lsm_tree.snapshot().unwrap();
+    assert_eq!(snapshot.sequence(), 3);
+    drop(snapshot);
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_recover_removes_sstables_not_in_manifest() {
+    let sst_dir = "./.test_recover_removes_sstables_not_in_manifest_sst";
+    let commitlog_dir = "./.test_recover_removes_sstables_not_in_manifest_commitlog";
+    set_up(sst_dir, commitlog_dir);
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    lsm_tree.put_str("key1", Some("value1")).unwrap();
+    drop(lsm_tree);
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    assert_eq!(lsm_tree.get_str("key1"), Ok(Some("value1".to_owned())));
+    drop(lsm_tree);
+
+    // 消し損ねたコンパクションの入力と、書き込み途中のflushの出力
+    let sstables = |dir: &str| {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_str().unwrap().to_owned())
+            .filter(|name| name.ends_with(".sst"))
+            .collect();
+        names.sort();
+        names
+    };
+    let listed = sstables(sst_dir);
+    assert_eq!(listed.len(), 1);
+    let stale = lsmtree::memtable::MemTable::new();
+    stale.put(b"key1", b"stale", 100);
+    SSTableWriter::numbered(sst_dir, 999).write(&stale, lsmtree::utils::get_page_size()).unwrap();
+    fs::write(format!("{}/{}", sst_dir, "001000.sst"), b"half written").unwrap();
+
+    let lsm_tree = open(sst_dir, commitlog_dir, RecoveryMode::Fail).unwrap();
+    assert_eq!(sstables(sst_dir), listed);
+    assert_eq!(lsm_tree.get_str("key1"), Ok(Some("value1".to_owned())));
+    drop(lsm_tree);
+
+    tear_down(sst_dir, commitlog_dir);
+}
@@ -0,0 +1,240 @@
+use std::{fs, sync::Arc};
+
+use lsmtree::{prefix_extractor::DelimitedPrefixExtractor, scan::ScanIterator, sstable::{compaction::Compaction, SSTableWriter}, utils::get_page_size, Key, LSMTree, LSMTreeConf, SharedSSTableReader, Value};
+
+#[derive(Debug, Clone)]
+pub struct MockCompaction {}
+
+impl Compaction for MockCompaction {
+    fn compact(
+        &self,
+        sstables: Arc<SharedSSTableReader>,
+        new_writer: &dyn Fn() -> SSTableWriter) -> Result<(), String> {
+        let _ = sstables;
+        let _ = new_writer;
+        unimplemented!("MockCompaction::compact is not implemented");
+    }
+}
+
+struct MockTimeStampGenerator {
+    monotonic: u64,
+}
+
+impl lsmtree::TimeStampGenerator for MockTimeStampGenerator {
+    fn get_timestamp(&mut self) -> u64 {
+        self.monotonic += 1;
+        self.monotonic
+    }
+}
+
+fn set_up(sst_dir: &str, commitlog_dir: &str) -> LSMTree<MockCompaction, MockTimeStampGenerator> {
+    if fs::exists(sst_dir).unwrap() {
+        fs::remove_dir_all(sst_dir).unwrap();
+    }
+    if fs::exists(commitlog_dir).unwrap() {
+        fs::remove_dir_all(commitlog_dir).unwrap();
+    }
+    LSMTree::new(
+        LSMTreeConf::new(
+            MockCompaction {},
+            MockTimeStampGenerator { monotonic: 0 },
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            None,
+            Some(get_page_size()),
+            Some("idx".to_owned()),
+            Some(false),       // コンパクションを無効化
+    )).unwrap()
+}
+
+fn tear_down(sst_dir: &str, commitlog_dir: &str) {
+    std::fs::remove_dir_all(sst_dir).unwrap();
+    std::fs::remove_dir_all(commitlog_dir).unwrap();
+}
+
+fn collect_str(iter: ScanIterator) -> Vec<(String, String)> {
+    iter.map(|entry| {
+        let (key, value) = entry.unwrap();
+        (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap())
+    }).collect()
+}
+
+fn key_str(entry: Option<Result<(Key, Value), String>>) -> String {
+    String::from_utf8(entry.unwrap().unwrap().0).unwrap()
+}
+
+// flushが終わるまで待つ
+fn wait_for_flush(lsm_tree: &LSMTree<MockCompaction, MockTimeStampGenerator>) {
+    while fs::read_dir(lsm_tree.get_commitlog().get_dir()).unwrap().count() > 1 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+// sst_dirにはMANIFESTもある
+fn count_sstables(sst_dir: &str) -> usize {
+    fs::read_dir(sst_dir).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".sst"))
+        .count()
+}
+
+#[test]
+fn test_scan_memtable_only() {
+    let sst_dir = "./.test_scan_memtable_only_sst";
+    let commitlog_dir = "./.test_scan_memtable_only_commitlog";
+    let lsm_tree = set_up(sst_dir, commitlog_dir);
+
+    lsm_tree.put_str("b", Some("2")).unwrap();
+    lsm_tree.put_str("a", Some("1")).unwrap();
+    lsm_tree.put_str("c", Some("3")).unwrap();
+    lsm_tree.put_str("d", Some("4")).unwrap();
+    lsm_tree.put_str("c", None).unwrap();
+
+    let all = collect_str(lsm_tree.scan(..).unwrap());
+    assert_eq!(all, vec![
+        ("a".to_owned(), "1".to_owned()),
+        ("b".to_owned(), "2".to_owned()),
+        ("d".to_owned(), "4".to_owned()),
+    ]);
+    let ranged = collect_str(lsm_tree.scan(b"b".as_slice()..b"d".as_slice()).unwrap());
+    assert_eq!(ranged, vec![("b".to_owned(), "2".to_owned())]);
+    assert_eq!(lsm_tree.scan(b"d".as_slice()..b"b".as_slice()).unwrap().count(), 0);
+
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_scan_merges_memtable_and_sstables() {
+    let sst_dir = "./.test_scan_merges_memtable_and_sstables_sst";
+    let commitlog_dir = "./.test_scan_merges_memtable_and_sstables_commitlog";
+    let lsm_tree = set_up(sst_dir, commitlog_dir);
+
+    for i in 0..3000 {
+        lsm_tree.put_str(&format!("key{:05}", i), Some(&format!("value{}", i))).unwrap();
+    }
+    // 古いSSTableにある値を上書き・削除する
+    for i in (0..3000).step_by(3) {
+        lsm_tree.put_str(&format!("key{:05}", i), Some(&format!("new_value{}", i))).unwrap();
+    }
+    for i in (0..3000).step_by(5) {
+        lsm_tree.put_str(&format!("key{:05}", i), None).unwrap();
+    }
+    wait_for_flush(&lsm_tree);
+    assert!(count_sstables(sst_dir) > 2);
+
+    let expected = (0..3000)
+        .filter(|i| i % 5 != 0)
+        .map(|i| {
+            let value = if i % 3 == 0 { format!("new_value{}", i) } else { format!("value{}", i) };
+            (format!("key{:05}", i), value)
+        })
+        .collect::<Vec<_>>();
+    let all = collect_str(lsm_tree.scan(..).unwrap());
+    assert_eq!(all, expected);
+
+    let ranged = collect_str(lsm_tree.scan(b"key01000".as_slice()..=b"key01010".as_slice()).unwrap());
+    assert_eq!(ranged, expected.iter()
+        .filter(|(key, _)| "key01000" <= key.as_str() && key.as_str() <= "key01010")
+        .cloned()
+        .collect::<Vec<_>>());
+
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_scan_seek_and_prev() {
+    let sst_dir = "./.test_scan_seek_and_prev_sst";
+    let commitlog_dir = "./.test_scan_seek_and_prev_commitlog";
+    let lsm_tree = set_up(sst_dir, commitlog_dir);
+
+    for i in 0..2000 {
+        lsm_tree.put_str(&format!("key{:05}", i), Some(&format!("value{}", i))).unwrap();
+    }
+    for i in (0..2000).step_by(2) {
+        lsm_tree.put_str(&format!("key{:05}", i), None).unwrap();
+    }
+    wait_for_flush(&lsm_tree);
+
+    // 新しい順に10件ずつたどる
+    let mut iter = lsm_tree.scan(..).unwrap();
+    iter.seek_to_last().unwrap();
+    let page = (0..10).map(|_| key_str(iter.prev())).collect::<Vec<_>>();
+    assert_eq!(page, (1980..2000).rev().filter(|i| i % 2 == 1).map(|i| format!("key{:05}", i)).collect::<Vec<_>>());
+    iter.seek_for_prev(b"key01000").unwrap();
+    let page = (0..10).map(|_| key_str(iter.prev())).collect::<Vec<_>>();
+    assert_eq!(page, (980..1000).rev().filter(|i| i % 2 == 1).map(|i| format!("key{:05}", i)).collect::<Vec<_>>());
+    assert_eq!(key_str(iter.next()), "key00981");
+
+    iter.seek(b"key01000").unwrap();
+    assert_eq!(iter.next().unwrap().unwrap(), (b"key01001".to_vec(), b"value1001".to_vec()));
+    assert_eq!(key_str(iter.prev()), "key01001");
+    assert_eq!(key_str(iter.prev()), "key00999");
+
+    let mut ranged = lsm_tree.scan(b"key00100".as_slice()..b"key00110".as_slice()).unwrap();
+    ranged.seek_to_last().unwrap();
+    assert_eq!(key_str(ranged.prev()), "key00109");
+    ranged.seek(b"key00000").unwrap();
+    assert_eq!(key_str(ranged.next()), "key00101");
+    assert!(ranged.prev().is_some());
+    assert!(ranged.prev().is_none());
+
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_scan_prefix() {
+    let sst_dir = "./.test_scan_prefix_sst";
+    let commitlog_dir = "./.test_scan_prefix_commitlog";
+    set_up(sst_dir, commitlog_dir);
+    let open = || {
+        LSMTree::new(
+            LSMTreeConf::new(
+                MockCompaction {},
+                MockTimeStampGenerator { monotonic: 0 },
+                Some(sst_dir.to_owned()),
+                Some(commitlog_dir.to_owned()),
+                Some(1 << 20),
+                None,
+                Some("idx".to_owned()),
+                Some(false),
+            ).with_prefix_extractor(DelimitedPrefixExtractor::new(b'/', 2))
+        ).unwrap()
+    };
+
+    // 開き直すたびにコミットログがSSTableになるので、tenantごとにSSTableができる
+    for tenant in 0..4 {
+        let lsm_tree = open();
+        for id in 0..100 {
+            lsm_tree.put_str(&format!("tenant{}/users/{:03}", tenant, id), Some(&format!("user{}", id))).unwrap();
+            lsm_tree.put_str(&format!("tenant{}/orders/{:03}", tenant, id), Some(&format!("order{}", id))).unwrap();
+        }
+    }
+    let lsm_tree = open();
+    assert_eq!(count_sstables(sst_dir), 4);
+    lsm_tree.put_str("tenant1/users/100", Some("user100")).unwrap();
+    lsm_tree.put_str("tenant1/users/000", None).unwrap();
+    lsm_tree.put_str("tenant1/usersx", Some("other")).unwrap();
+
+    let expected = (1..=100)
+        .map(|id| (format!("tenant1/users/{:03}", id), format!("user{}", id)))
+        .collect::<Vec<_>>();
+    assert_eq!(collect_str(lsm_tree.scan_prefix(b"tenant1/users/").unwrap()), expected);
+    // tenant1のSSTable以外は、prefixのフィルタで飛ばされる
+    let stats = lsm_tree.bloom_filter_stats();
+    assert_eq!(stats.prefix_checked, 4);
+    assert!(stats.prefix_useful >= 2, "{:?}", stats);
+
+    assert!(lsm_tree.scan_prefix(b"tenant9/users/").unwrap().next().is_none());
+
+    // PrefixExtractorのprefixでなければフィルタは引かず、全てのSSTableを読む
+    let checked = lsm_tree.bloom_filter_stats().prefix_checked;
+    let tenant2 = collect_str(lsm_tree.scan_prefix(b"tenant2/").unwrap());
+    assert_eq!(tenant2.len(), 200);
+    assert!(tenant2.iter().all(|(key, _)| key.starts_with("tenant2/")));
+    assert_eq!(lsm_tree.bloom_filter_stats().prefix_checked, checked);
+
+    drop(lsm_tree);
+    tear_down(sst_dir, commitlog_dir);
+}
@@ -0,0 +1,133 @@
+use std::{fs, sync::Arc};
+
+use lsmtree::{scan::ScanIterator, sstable::{compaction::{size_tiered_compaction::SizeTieredCompaction, Compaction}, SSTableWriter}, utils::get_page_size, LSMTree, LSMTreeConf, SharedSSTableReader};
+
+#[derive(Debug, Clone)]
+pub struct MockCompaction {}
+
+impl Compaction for MockCompaction {
+    fn compact(
+        &self,
+        sstables: Arc<SharedSSTableReader>,
+        new_writer: &dyn Fn() -> SSTableWriter) -> Result<(), String> {
+        let _ = sstables;
+        let _ = new_writer;
+        unimplemented!("MockCompaction::compact is not implemented");
+    }
+}
+
+struct MockTimeStampGenerator {
+    monotonic: u64,
+}
+
+impl lsmtree::TimeStampGenerator for MockTimeStampGenerator {
+    fn get_timestamp(&mut self) -> u64 {
+        self.monotonic += 1;
+        self.monotonic
+    }
+}
+
+fn set_up<T: Compaction + Clone + Send + Sync + 'static>(sst_dir: &str, commitlog_dir: &str, compaction: T) -> LSMTree<T, MockTimeStampGenerator> {
+    if fs::exists(sst_dir).unwrap() {
+        fs::remove_dir_all(sst_dir).unwrap();
+    }
+    if fs::exists(commitlog_dir).unwrap() {
+        fs::remove_dir_all(commitlog_dir).unwrap();
+    }
+    LSMTree::new(
+        LSMTreeConf::new(
+            compaction,
+            MockTimeStampGenerator { monotonic: 0 },
+            Some(sst_dir.to_owned()),
+            Some(commitlog_dir.to_owned()),
+            None,
+            Some(get_page_size()),
+            Some("idx".to_owned()),
+            Some(false),       // コンパクションは手動で実行する
+    )).unwrap()
+}
+
+fn tear_down(sst_dir: &str, commitlog_dir: &str) {
+    std::fs::remove_dir_all(sst_dir).unwrap();
+    std::fs::remove_dir_all(commitlog_dir).unwrap();
+}
+
+fn collect_str(iter: ScanIterator) -> Vec<(String, String)> {
+    iter.map(|entry| {
+        let (key, value) = entry.unwrap();
+        (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap())
+    }).collect()
+}
+
+// flushが終わるまで待つ
+fn wait_for_flush<T: Compaction + Clone + Send + Sync + 'static>(lsm_tree: &LSMTree<T, MockTimeStampGenerator>) {
+    while fs::read_dir(lsm_tree.get_commitlog().get_dir()).unwrap().count() > 1 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_snapshot_memtable() {
+    let sst_dir = "./.test_snapshot_memtable_sst";
+    let commitlog_dir = "./.test_snapshot_memtable_commitlog";
+    let lsm_tree = set_up(sst_dir, commitlog_dir, MockCompaction {});
+
+    lsm_tree.put_str("a", Some("1")).unwrap();
+    lsm_tree.put_str("b", Some("2")).unwrap();
+    let snapshot = lsm_tree.snapshot().unwrap();
+    lsm_tree.put_str("a", Some("10")).unwrap();
+    lsm_tree.put_str("b", None).unwrap();
+    lsm_tree.put_str("c", Some("3")).unwrap();
+
+    assert_eq!(lsm_tree.get_str_at(&snapshot, "a"), Ok(Some("1".to_owned())));
+    assert_eq!(lsm_tree.get_str_at(&snapshot, "b"), Ok(Some("2".to_owned())));
+    assert_eq!(lsm_tree.get_str_at(&snapshot, "c"), Ok(None));
+    assert_eq!(
+        collect_str(lsm_tree.scan_at(&snapshot, ..).unwrap()),
+        vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())],
+    );
+
+    assert_eq!(lsm_tree.get_str("a"), Ok(Some("10".to_owned())));
+    assert_eq!(lsm_tree.get_str("b"), Ok(None));
+    assert_eq!(
+        collect_str(lsm_tree.scan(..).unwrap()),
+        vec![("a".to_owned(), "10".to_owned()), ("c".to_owned(), "3".to_owned())],
+    );
+    drop(snapshot);
+
+    tear_down(sst_dir, commitlog_dir);
+}
+
+#[test]
+fn test_snapshot_survives_flush_and_compaction() {
+    let sst_dir = "./.test_snapshot_survives_flush_and_compaction_sst";
+    let commitlog_dir = "./.test_snapshot_survives_flush_and_compaction_commitlog";
+    let compaction = SizeTieredCompaction::new(get_page_size(), None, None, Some(2));
+    let lsm_tree = set_up(sst_dir, commitlog_dir, compaction);
+
+    let padding = "x".repeat(100);
+    for i in 0..100 {
+        lsm_tree.put_str(&format!("key{:03}", i), Some(&format!("old{}", padding))).unwrap();
+    }
+    wait_for_flush(&lsm_tree);
+    let snapshot = lsm_tree.snapshot().unwrap();
+    for i in 0..100 {
+        lsm_tree.put_str(&format!("key{:03}", i), Some(&format!("new{}", padding))).unwrap();
+    }
+    wait_for_flush(&lsm_tree);
+    // launch_compactionは読み込み済みのSSTableだけを見る
+    assert_eq!(lsm_tree.get_str("key000"), Ok(Some(format!("new{}", padding))));
+    lsm_tree.launch_compaction().unwrap();
+
+    for i in 0..100 {
+        let key = format!("key{:03}", i);
+        assert_eq!(lsm_tree.get_str_at(&snapshot, &key), Ok(Some(format!("old{}", padding))), "{}", key);
+        assert_eq!(lsm_tree.get_str(&key), Ok(Some(format!("new{}", padding))), "{}", key);
+    }
+    let scanned = collect_str(lsm_tree.scan_at(&snapshot, b"key010".as_slice()..b"key020".as_slice()).unwrap());
+    assert_eq!(scanned.len(), 10);
+    assert!(scanned.iter().all(|(_, value)| value.starts_with("old")));
+    drop(snapshot);
+
+    tear_down(sst_dir, commitlog_dir);
+}